@@ -1,114 +1,900 @@
-use rustls::{PrivateKey, ServerConfig};
-use tokio_rustls::TlsAcceptor;
+use rustls::server::ClientCertVerifier;
+use rustls::{KeyLog, PrivateKey, ServerConfig, ServerName};
 
 use rcgen::Certificate;
 use rcgen::CertificateParams;
+use rcgen::CustomExtension;
 use rcgen::KeyPair;
 use rcgen::SanType;
 
 use endorphin::policy::TTIPolicy;
 use endorphin::HashMap;
 
-use tracing::info;
+use tokio::sync::Notify;
 use tracing::instrument;
+use tracing::{info, warn};
 
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 use std::ops::Add;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use rcgen::RcgenError;
+use serde::Deserialize;
+use x509_parser::extensions::ParsedExtension;
+use x509_parser::pem::parse_x509_pem;
+use x509_parser::time::ASN1Time;
+
+use crate::error::Error;
+use crate::http::{ALPN_H2, ALPN_HTTP1};
+use crate::metrics::Metrics;
+use crate::tls_policy::TlsPolicy;
+
+const OID_SUBJECT_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 14];
+const OID_AUTHORITY_KEY_IDENTIFIER: &[u64] = &[2, 5, 29, 35];
+
+// Leaves that expire sooner than this are regenerated instead of loaded from the store.
+const STORE_MIN_VALIDITY: i64 = 3600 * 24;
+// Upstream fingerprints remembered before they are all forgotten.
+const MAX_UPSTREAMS: usize = 10_000;
+// Lookups of hosts in different shards never wait on each other.
+const SHARDS: usize = 16;
+
+type Shard = Mutex<HashMap<String, Cached, TTIPolicy>>;
+
+// Safe to share between connections: shards are only locked to look up or store a config, never
+// while one is generated, and concurrent misses for the same host wait on a single generation.
 pub struct AcceptorMap {
-    map: HashMap<String, Arc<TlsAcceptor>, TTIPolicy>,
+    shards: Vec<Shard>,
+    // One per host being generated; those who miss it meanwhile wait on it.
+    pending: Mutex<std::collections::HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    cache: CertCacheConfig,
+    // Fingerprints of the certificates upstreams last showed, by host.
+    upstreams: Mutex<std::collections::HashMap<String, String>>,
+    // Bumped on every lookup, so entries tell which was used last.
+    uses: AtomicU64,
     ca: Certificate,
+    ca_key: Vec<u8>,
+    ca_subject: Vec<u8>,
+    ca_key_id: Vec<u8>,
+    // Shown after each leaf, from the CA that signs them up.
+    chain: Vec<rustls::Certificate>,
+    store: Option<PathBuf>,
+    params: LeafParams,
+    key_log: Option<Arc<dyn KeyLog>>,
+    client_auth: Option<Arc<dyn ClientCertVerifier>>,
+    policy: TlsPolicy,
+    metrics: Option<Arc<Metrics>>,
+    key_pool: Option<Arc<KeyPool>>,
 }
 
-impl AcceptorMap {
-    pub fn new(ca: String, key: String) -> Self {
-        let key = KeyPair::from_pem(&key).unwrap();
-        let params = CertificateParams::from_ca_cert_pem(&ca, key).unwrap();
+// Keys ready for new leaves, topped up in the background as they are taken.
+struct KeyPool {
+    keys: Mutex<Vec<KeyPair>>,
+    size: usize,
+    taken: Arc<Notify>,
+}
+
+impl KeyPool {
+    fn spawn(size: usize) -> Arc<Self> {
+        let taken = Arc::new(Notify::new());
+        let pool = Arc::new(Self {
+            keys: Mutex::new(Vec::with_capacity(size)),
+            size,
+            taken: taken.clone(),
+        });
+        // Ends once the pool is dropped.
+        let weak = Arc::downgrade(&pool);
+        tokio::spawn(async move {
+            loop {
+                match weak.upgrade() {
+                    Some(pool) if pool.keys.lock().unwrap().len() < pool.size => {
+                        let generated = tokio::task::spawn_blocking(|| {
+                            KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)
+                        })
+                        .await;
+                        match generated {
+                            Ok(Ok(key)) => pool.keys.lock().unwrap().push(key),
+                            _ => {
+                                warn!("Fail to generate pooled key");
+                                return;
+                            }
+                        }
+                    }
+                    Some(pool) => {
+                        drop(pool);
+                        taken.notified().await;
+                    }
+                    None => return,
+                }
+            }
+        });
 
-        let cert = Certificate::from_params(params).unwrap();
+        pool
+    }
 
+    fn take(&self) -> Option<KeyPair> {
+        let key = self.keys.lock().unwrap().pop();
+        self.taken.notify_one();
+        key
+    }
+}
+
+impl Drop for KeyPool {
+    fn drop(&mut self) {
+        self.taken.notify_one();
+    }
+}
+
+struct Cached {
+    config: Arc<ServerConfig>,
+    last_used: u64,
+}
+
+// Configs unused for `idle_timeout` are dropped, and past `max_entries` the least recently used
+// one makes room.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CertCacheConfig {
+    pub max_entries: Option<usize>,
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    // Revokes the leaf of a host once its upstream shows another certificate than before, so a
+    // new one is made.
+    pub renew_on_upstream_change: bool,
+}
+
+impl Default for CertCacheConfig {
+    fn default() -> Self {
         Self {
-            map: HashMap::new(TTIPolicy::new()),
+            max_entries: None,
+            idle_timeout: Duration::from_secs(3600),
+            renew_on_upstream_change: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ecdsa,
+}
+
+// Whether hosts under the same domain share a wildcard leaf or each get one naming them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CertStrategy {
+    Wildcard,
+    Exact,
+    // Leaves copy the subject, names and validity of the certificate the upstream shows, for
+    // clients that look closer at what they are shown. Each host gets its own, like `Exact`,
+    // where the upstream is not asked first.
+    Mimic,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LeafParams {
+    #[serde(with = "humantime_serde")]
+    pub validity: Duration,
+    pub key_algorithm: KeyAlgorithm,
+    // ECDSA keys generated ahead of time, so a new host need not wait for one. RSA leaves share
+    // the key of the CA and have no use for them.
+    pub key_pool: usize,
+    pub strategy: CertStrategy,
+    pub country: Option<String>,
+    pub state: Option<String>,
+    pub locality: Option<String>,
+    pub organization: Option<String>,
+    pub organizational_unit: Option<String>,
+}
+
+impl Default for LeafParams {
+    fn default() -> Self {
+        Self {
+            // Apple and Chrome reject leaves valid for more than 398 days.
+            validity: Duration::from_secs(3600 * 24 * 397),
+            key_algorithm: KeyAlgorithm::Ecdsa,
+            key_pool: 8,
+            strategy: CertStrategy::Wildcard,
+            country: None,
+            state: Some("Yaler".to_string()),
+            locality: Some("Yaler".to_string()),
+            organization: Some("Yaler".to_string()),
+            organizational_unit: Some("Yaler".to_string()),
+        }
+    }
+}
+
+impl AcceptorMap {
+    pub fn new(ca: String, key: String) -> Result<Self, Error> {
+        let (_, pem) =
+            parse_x509_pem(ca.as_bytes()).map_err(|_| RcgenError::CouldNotParseCertificate)?;
+        let parsed = pem
+            .parse_x509()
+            .map_err(|_| RcgenError::CouldNotParseCertificate)?;
+
+        let key = KeyPair::from_pem(&key)?;
+        if key.public_key_raw() != parsed.public_key().subject_public_key.data {
+            return Err(Error::InvalidConfigError(
+                "The CA key does not match the CA certificate",
+            ));
+        }
+        let ca_key = key.serialize_der();
+        let params = CertificateParams::from_ca_cert_pem(&ca, key)?;
+
+        let cert = Certificate::from_params(params)?;
+
+        let ca_subject = parsed.subject().as_raw().to_vec();
+        // Clients only trust the root, so an intermediate has to be shown after the leaf.
+        let chain = if parsed.issuer() == parsed.subject() {
+            Vec::new()
+        } else {
+            vec![rustls::Certificate(pem.contents.clone())]
+        };
+        // Leaves have to point at the identifier the CA certificate carries, which need not be
+        // how rcgen would compute it, as with CAs made by other tools.
+        let ca_key_id = parsed
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) if id.0.len() <= 64 => {
+                    Some(id.0.to_vec())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| cert.get_key_identifier());
+
+        Ok(Self {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(HashMap::new(TTIPolicy::new())))
+                .collect(),
+            pending: Mutex::default(),
+            cache: CertCacheConfig::default(),
+            upstreams: Mutex::default(),
+            uses: AtomicU64::new(0),
             ca: cert,
+            ca_key,
+            ca_subject,
+            ca_key_id,
+            chain,
+            store: None,
+            params: LeafParams::default(),
+            key_log: None,
+            client_auth: None,
+            policy: TlsPolicy::default(),
+            metrics: None,
+            key_pool: None,
+        })
+    }
+
+    pub(crate) fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_key_log(mut self, key_log: Arc<dyn KeyLog>) -> Self {
+        self.key_log = Some(key_log);
+        self
+    }
+
+    // Clients have to present a certificate the verifier accepts.
+    pub fn with_client_auth(mut self, verifier: Arc<dyn ClientCertVerifier>) -> Self {
+        self.client_auth = Some(verifier);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: TlsPolicy) -> Result<Self, Error> {
+        policy.server_builder()?;
+        self.policy = policy;
+        Ok(self)
+    }
+
+    // Starts filling the key pool the params ask for, so it has to run within a Tokio runtime.
+    pub fn with_params(mut self, params: LeafParams) -> Self {
+        self.key_pool = (params.key_algorithm == KeyAlgorithm::Ecdsa && params.key_pool > 0)
+            .then(|| KeyPool::spawn(params.key_pool));
+        self.params = params;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: CertCacheConfig) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    // Certificates above an intermediate CA, up to but not necessarily including the root.
+    pub fn with_chain(mut self, chain: Vec<Vec<u8>>) -> Self {
+        self.chain
+            .extend(chain.into_iter().map(rustls::Certificate));
+        self
+    }
+
+    pub fn with_store(mut self, dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).map_err(Error::WriteFileError)?;
+        self.store = Some(dir);
+        Ok(self)
+    }
+
+    pub fn hosts(&self) -> Vec<String> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    // Only forgets the cached configs; leaves in the on-disk store are reused.
+    pub fn clear(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock().unwrap();
+                let len = shard.len();
+                shard.clear();
+                len
+            })
+            .sum()
+    }
+
+    // Forgets the leaves made for `host`, whichever address or upstream certificate they were
+    // made with, and with `store` deletes them from the on-disk store as well. Hosts sharing a
+    // wildcard leaf with it get a new one too.
+    pub fn revoke(&self, host: &str, store: bool) -> usize {
+        // Nothing was ever made for it, and it must not name a path in the store.
+        if !is_server_name(host) {
+            return 0;
+        }
+        let host = host.to_ascii_lowercase();
+        let name = self.normalize(host.clone());
+        let made_for = |key: &str| {
+            key == name
+                || key
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.starts_with('@'))
+                || key
+                    .strip_prefix(host.as_str())
+                    .is_some_and(|rest| rest.starts_with('#'))
+        };
+
+        let mut revoked = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<String> = shard.keys().filter(|key| made_for(key)).cloned().collect();
+            for key in keys {
+                shard.remove(&key);
+                revoked.push(key);
+            }
+        }
+        let count = revoked.len();
+
+        if store {
+            // Stored leaves outlive cached ones.
+            revoked.push(name);
+            for key in revoked {
+                if let Some((cert_path, key_path)) = self.store_paths(&key) {
+                    let _ = fs::remove_file(cert_path);
+                    let _ = fs::remove_file(key_path);
+                }
+            }
         }
+
+        count
+    }
+
+    pub async fn get(&self, host: String) -> Result<Arc<ServerConfig>, Error> {
+        self.get_for(host, None).await
     }
 
+    // Like `get`, with `ip` among the names of the leaf too, for clients that dialed an address
+    // and name the host only in the handshake.
     #[instrument(skip(self))]
-    pub fn get(&mut self, host: String) -> Arc<TlsAcceptor> {
-        let host = Self::normalize(host);
+    pub async fn get_for(
+        &self,
+        host: String,
+        ip: Option<IpAddr>,
+    ) -> Result<Arc<ServerConfig>, Error> {
+        if !is_server_name(&host) {
+            return Err(Error::BadRequestError("Invalid server name"));
+        }
+        let name = self.normalize(host);
+        let ip = ip.filter(|ip| name.parse::<IpAddr>().ok() != Some(*ip));
+        let host = match ip {
+            Some(ip) => format!("{}@{}", name, ip),
+            None => name.clone(),
+        };
+        self.get_or_create(host, |acceptors| acceptors.generate(&name, ip))
+            .await
+    }
 
-        if !self.map.contains_key(&host) {
-            let params = Self::base_cert_param(host.clone());
+    // A leaf for `host` with the subject, names and validity of `upstream`, the certificate the
+    // real server showed, when the strategy is to mimic it. Otherwise the same as `get_for`.
+    pub async fn get_like(
+        &self,
+        host: String,
+        ip: Option<IpAddr>,
+        upstream: &[u8],
+    ) -> Result<Arc<ServerConfig>, Error> {
+        if !is_server_name(&host) {
+            return Err(Error::BadRequestError("Invalid server name"));
+        }
+        let digest = ring::digest::digest(&ring::digest::SHA256, upstream);
+        let fingerprint: String = digest.as_ref()[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let host = host.to_ascii_lowercase();
+        if self.cache.renew_on_upstream_change {
+            let previous = {
+                let mut upstreams = self.upstreams.lock().unwrap();
+                // Forgetting them only lets one change go unnoticed.
+                if upstreams.len() >= MAX_UPSTREAMS {
+                    upstreams.clear();
+                }
+                upstreams.insert(host.clone(), fingerprint.clone())
+            };
+            if previous.is_some_and(|previous| previous != fingerprint) {
+                let revoked = self.revoke(&host, true);
+                info!(%host, revoked, "Upstream certificate changed, leaf renewed");
+            }
+        }
+        if self.params.strategy != CertStrategy::Mimic {
+            return self.get_for(host, ip).await;
+        }
+
+        // Leaves follow the upstream certificate when it is renewed.
+        let key = format!("{}#{}", host, fingerprint);
+        self.get_or_create(key, |acceptors| acceptors.mimic(&host, upstream))
+            .await
+    }
 
-            let cert = Certificate::from_params(params).unwrap();
+    // Concurrent misses for the same key wait for one of them to create the config.
+    async fn get_or_create<F>(&self, host: String, generate: F) -> Result<Arc<ServerConfig>, Error>
+    where
+        F: FnOnce(&Self) -> Result<(Vec<u8>, Vec<u8>), Error>,
+    {
+        if let Some(config) = self.cached(&host) {
+            return Ok(config);
+        }
 
-            let key = cert.serialize_private_key_der();
-            let cert = cert.serialize_der_with_signer(&self.ca).unwrap();
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(host.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _generating = pending.lock().await;
+            // Whoever held it before may have just generated the config.
+            match self.cached(&host) {
+                Some(config) => Ok(config),
+                None => self.create(&host, generate),
+            }
+        };
+        // Unless a later miss has already put a new one in its place.
+        let mut all_pending = self.pending.lock().unwrap();
+        if all_pending
+            .get(&host)
+            .is_some_and(|current| Arc::ptr_eq(current, &pending))
+        {
+            all_pending.remove(&host);
+        }
 
-            let cert = rustls::Certificate(cert);
+        result
+    }
 
-            let cfg = ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(vec![cert], PrivateKey(key))
-                .unwrap();
+    fn shard(&self, host: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        host.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
 
-            let acceptor = TlsAcceptor::from(Arc::new(cfg));
-            self.map
-                .insert(host.clone(), Arc::new(acceptor), Duration::from_secs(3600));
-            info!("Cert for {} generated", host);
+    fn cached(&self, host: &str) -> Option<Arc<ServerConfig>> {
+        let uses = self.uses.fetch_add(1, Ordering::Relaxed) + 1;
+        let shard = self.shard(host).lock().unwrap();
+        let cached = shard.get_mut(host)?;
+        cached.last_used = uses;
+        if let Some(metrics) = &self.metrics {
+            metrics.cert_cache(true);
         }
-        self.map.get(&host).unwrap().clone()
+
+        Some(cached.config.clone())
     }
 
-    fn normalize(host: String) -> String {
-        if host.chars().filter(|c| *c == '.').count() > 1 {
-            let first_dot = host.find('.').unwrap_or_default();
-            format!("*{}", &host[first_dot..])
-        } else {
-            host
+    fn create<F>(&self, host: &str, generate: F) -> Result<Arc<ServerConfig>, Error>
+    where
+        F: FnOnce(&Self) -> Result<(Vec<u8>, Vec<u8>), Error>,
+    {
+        if let Some(metrics) = &self.metrics {
+            metrics.cert_cache(false);
+        }
+
+        let (cert, key) = match self.load(host) {
+            Some(stored) => {
+                info!("Cert for {} loaded from store", host);
+                stored
+            }
+            None => {
+                let stored = generate(self)?;
+                self.save(host, &stored);
+                info!("Cert for {} generated", host);
+                stored
+            }
+        };
+
+        let builder = self.policy.server_builder()?;
+        let builder = match &self.client_auth {
+            Some(verifier) => builder.with_client_cert_verifier(verifier.clone()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut chain = vec![rustls::Certificate(cert)];
+        chain.extend(self.chain.iter().cloned());
+        let mut cfg = builder.with_single_cert(chain, PrivateKey(key))?;
+        cfg.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()];
+        if let Some(key_log) = &self.key_log {
+            cfg.key_log = key_log.clone();
         }
+
+        let cfg = Arc::new(cfg);
+        self.evict();
+        let cached = Cached {
+            config: cfg.clone(),
+            last_used: self.uses.fetch_add(1, Ordering::Relaxed) + 1,
+        };
+        self.shard(host)
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), cached, self.cache.idle_timeout);
+        Ok(cfg)
+    }
+
+    // Makes room for one more config by dropping the least recently used ones. Shards are
+    // locked one at a time, so under load the cap holds only roughly.
+    fn evict(&self) {
+        let max = match self.cache.max_entries {
+            Some(max) => max.max(1),
+            None => return,
+        };
+
+        while self.len() >= max {
+            let oldest = self
+                .shards
+                .iter()
+                .filter_map(|shard| {
+                    let shard = shard.lock().unwrap();
+                    shard
+                        .iter()
+                        .min_by_key(|(_, cached)| cached.last_used)
+                        .map(|(host, cached)| (cached.last_used, host.clone()))
+                })
+                .min();
+            let Some((_, oldest)) = oldest else {
+                return;
+            };
+            if self
+                .shard(&oldest)
+                .lock()
+                .unwrap()
+                .remove(&oldest)
+                .is_some()
+            {
+                if let Some(metrics) = &self.metrics {
+                    metrics.cert_cache_evicted();
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
     }
 
-    fn base_cert_param(host: String) -> CertificateParams {
+    fn generate(&self, name: &str, ip: Option<IpAddr>) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let params = self.base_cert_param(name.to_string(), ip)?;
+
+        let cert = Certificate::from_params(params)?;
+
+        let key = cert.serialize_private_key_der();
+        let cert = cert.serialize_der_with_signer(&self.ca)?;
+
+        Ok((cert, key))
+    }
+
+    fn mimic(&self, host: &str, upstream: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        use rcgen::{DnType, DnValue};
+        use x509_parser::extensions::GeneralName;
+
+        let (_, parsed) = x509_parser::parse_x509_certificate(upstream)
+            .map_err(|_| RcgenError::CouldNotParseCertificate)?;
+        let mut params = self.base_cert_param(host.to_string(), None)?;
+
+        let mut d_name = rcgen::DistinguishedName::new();
+        for attr in parsed.subject().iter_attributes() {
+            let (Some(oid), Ok(value)) = (attr.attr_type().iter(), attr.as_str()) else {
+                continue;
+            };
+            let ty = DnType::from_oid(&oid.collect::<Vec<_>>());
+            let value = match ty {
+                DnType::CountryName => DnValue::PrintableString(value.to_string()),
+                _ => DnValue::Utf8String(value.to_string()),
+            };
+            d_name.push(ty, value);
+        }
+        params.distinguished_name = d_name;
+
+        let names: Vec<SanType> = parsed
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| san.value.general_names.iter())
+            .into_iter()
+            .flatten()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(SanType::DnsName(name.to_string())),
+                GeneralName::IPAddress(&[a, b, c, d]) => {
+                    Some(SanType::IpAddress(IpAddr::from([a, b, c, d])))
+                }
+                GeneralName::IPAddress(bytes) => <[u8; 16]>::try_from(*bytes)
+                    .ok()
+                    .map(|bytes| SanType::IpAddress(IpAddr::from(bytes))),
+                _ => None,
+            })
+            .collect();
+        // Without names of its own, the leaf keeps naming the host.
+        if !names.is_empty() {
+            params.subject_alt_names = names;
+        }
+
+        let validity = parsed.validity();
+        let time = |time: &ASN1Time| time::OffsetDateTime::from_unix_timestamp(time.timestamp());
+        if let (Ok(not_before), Ok(not_after)) =
+            (time(&validity.not_before), time(&validity.not_after))
+        {
+            params.not_before = not_before;
+            params.not_after = not_after;
+        }
+
+        let cert = Certificate::from_params(params)?;
+        let key = cert.serialize_private_key_der();
+        let cert = cert.serialize_der_with_signer(&self.ca)?;
+
+        Ok((cert, key))
+    }
+
+    // Anything but lowercase letters, digits, dots and dashes is escaped, so no key can reach
+    // outside the store.
+    fn store_paths(&self, host: &str) -> Option<(PathBuf, PathBuf)> {
+        let dir = self.store.as_ref()?;
+        let name: String = host
+            .bytes()
+            .map(|byte| match byte {
+                b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' => (byte as char).to_string(),
+                _ => format!("_{:02x}", byte),
+            })
+            .collect();
+
+        Some((
+            dir.join(format!("{}.der", name)),
+            dir.join(format!("{}.key.der", name)),
+        ))
+    }
+
+    fn load(&self, host: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (cert_path, key_path) = self.store_paths(host)?;
+        let cert = fs::read(cert_path).ok()?;
+        let key = fs::read(key_path).ok()?;
+
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert).ok()?;
+        if parsed.issuer().as_raw() != self.ca_subject.as_slice() {
+            return None;
+        }
+
+        let now = ASN1Time::now().timestamp();
+        let validity = parsed.validity();
+        if validity.not_before.timestamp() > now
+            || validity.not_after.timestamp() - now < STORE_MIN_VALIDITY
+        {
+            return None;
+        }
+
+        Some((cert, key))
+    }
+
+    fn save(&self, host: &str, (cert, key): &(Vec<u8>, Vec<u8>)) {
+        let (cert_path, key_path) = match self.store_paths(host) {
+            Some(paths) => paths,
+            None => return,
+        };
+
+        if let Err(e) = fs::write(cert_path, cert).and_then(|_| fs::write(key_path, key)) {
+            warn!(?e, "Fail to store cert for {}", host);
+        }
+    }
+
+    // The name the leaf for `host` is issued to. A wildcard covers a single label and never
+    // stops at a public suffix, so `example.co.uk` and `a.example.co.uk` share `*.example.co.uk`,
+    // which names the apex too, while `co.uk` gets a leaf of its own.
+    fn normalize(&self, host: String) -> String {
+        let host = host.to_ascii_lowercase();
+        if self.params.strategy != CertStrategy::Wildcard || host.parse::<IpAddr>().is_ok() {
+            return host;
+        }
+
+        let registrable = match psl::domain_str(&host) {
+            Some(registrable) => registrable.len(),
+            None => return host,
+        };
+        if host.len() == registrable {
+            return format!("*.{}", host);
+        }
+        match host.split_once('.') {
+            Some((_, parent)) if parent.len() >= registrable => format!("*.{}", parent),
+            _ => host,
+        }
+    }
+
+    fn base_cert_param(
+        &self,
+        host: String,
+        ip: Option<IpAddr>,
+    ) -> Result<CertificateParams, Error> {
         use rcgen::{DnType, DnValue};
 
         let mut param = CertificateParams::default();
 
-        param.alg = rcgen::SignatureAlgorithm::from_oid(&[1, 2, 840, 113549, 1, 1, 11]).unwrap();
         param.not_before = time::OffsetDateTime::now_utc();
-        param.not_after =
-            time::OffsetDateTime::now_utc().add(Duration::from_secs(3600 * 24 * 3650));
-        param.subject_alt_names.push(SanType::DnsName(host.clone()));
+        param.not_after = time::OffsetDateTime::now_utc().add(self.params.validity);
+        param.subject_alt_names = subject_alt_names(&host, ip);
 
         let mut d_name = rcgen::DistinguishedName::new();
-        d_name.push(
-            DnType::CountryName,
-            DnValue::PrintableString("Yaler".to_string()),
-        );
-        d_name.push(
-            DnType::StateOrProvinceName,
-            DnValue::Utf8String("Yaler".to_string()),
-        );
-        d_name.push(
-            DnType::LocalityName,
-            DnValue::Utf8String("Yaler".to_string()),
-        );
-        d_name.push(
-            DnType::OrganizationName,
-            DnValue::Utf8String("Yaler".to_string()),
-        );
-        d_name.push(
-            DnType::OrganizationalUnitName,
-            DnValue::Utf8String("Yaler".to_string()),
-        );
-        d_name.push(DnType::CommonName, DnValue::Utf8String(host.clone()));
+        let fields = [
+            (DnType::CountryName, &self.params.country),
+            (DnType::StateOrProvinceName, &self.params.state),
+            (DnType::LocalityName, &self.params.locality),
+            (DnType::OrganizationName, &self.params.organization),
+            (
+                DnType::OrganizationalUnitName,
+                &self.params.organizational_unit,
+            ),
+        ];
+        for (ty, value) in fields {
+            if let Some(value) = value {
+                let value = match ty {
+                    DnType::CountryName => DnValue::PrintableString(value.clone()),
+                    _ => DnValue::Utf8String(value.clone()),
+                };
+                d_name.push(ty, value);
+            }
+        }
+        d_name.push(DnType::CommonName, DnValue::Utf8String(host));
 
         param.distinguished_name = d_name;
 
-        param.key_pair = KeyPair::from_der(include_bytes!("../cert/key.der")).ok();
+        match self.params.key_algorithm {
+            KeyAlgorithm::Rsa => {
+                param.alg = &rcgen::PKCS_RSA_SHA256;
+                // rcgen cannot generate RSA keys, so RSA leaves share the key of an RSA CA.
+                param.key_pair = KeyPair::from_der(&self.ca_key).ok();
+            }
+            KeyAlgorithm::Ecdsa => {
+                param.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+                let pooled = self.key_pool.as_ref().and_then(|pool| pool.take());
+                param.key_pair = match pooled {
+                    Some(key) => Some(key),
+                    None => Some(KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?),
+                };
+            }
+        }
+
+        // rcgen derives the serial from the key, which RSA leaves share.
+        let serial = ring::rand::generate::<[u8; 8]>(&ring::rand::SystemRandom::new())
+            .map_err(|_| RcgenError::RingUnspecified)?
+            .expose();
+        param.serial_number = Some(u64::from_be_bytes(serial).max(1));
 
+        // rcgen only identifies the keys of CAs.
+        if let Some(key_pair) = &param.key_pair {
+            let digest = ring::digest::digest(&ring::digest::SHA256, key_pair.public_key_raw());
+            param
+                .custom_extensions
+                .push(CustomExtension::from_oid_content(
+                    OID_SUBJECT_KEY_IDENTIFIER,
+                    der(0x04, &digest.as_ref()[..20]),
+                ));
+        }
         param
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(
+                OID_AUTHORITY_KEY_IDENTIFIER,
+                der(0x30, &der(0x80, &self.ca_key_id)),
+            ));
+
+        Ok(param)
+    }
+}
+
+// A DER value of `tag` holding `content`, short enough for a single length byte.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut value = vec![tag, content.len() as u8];
+    value.extend_from_slice(content);
+    value
+}
+
+// Names come from SNI and the admin API as clients sent them; only host names and addresses
+// get a leaf.
+fn is_server_name(host: &str) -> bool {
+    host.parse::<IpAddr>().is_ok() || ServerName::try_from(host).is_ok()
+}
+
+fn subject_alt_names(host: &str, ip: Option<IpAddr>) -> Vec<SanType> {
+    let mut names = Vec::new();
+    // A wildcard does not match the domain it is under, so that is named as well.
+    if let Some(apex) = host.strip_prefix("*.") {
+        names.push(SanType::DnsName(apex.to_string()));
+    }
+    names.push(match host.parse::<IpAddr>() {
+        Ok(ip) => SanType::IpAddress(ip),
+        Err(_) => SanType::DnsName(host.to_string()),
+    });
+    if let Some(ip) = ip {
+        names.push(SanType::IpAddress(ip));
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::strip_brackets;
+
+    #[test]
+    fn ip_literals_get_an_ip_address_san() {
+        let v4 = strip_brackets("127.0.0.1");
+        assert_eq!(
+            subject_alt_names(v4, None),
+            [SanType::IpAddress(IpAddr::from([127, 0, 0, 1]))]
+        );
+
+        let v6 = strip_brackets("[2001:db8::1]");
+        assert_eq!(
+            subject_alt_names(v6, None),
+            [SanType::IpAddress("2001:db8::1".parse().unwrap())]
+        );
+        assert!(is_server_name(v6));
+    }
+
+    #[test]
+    fn names_get_a_dns_name_san() {
+        let dialed = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(
+            subject_alt_names("example.com", Some(dialed)),
+            [
+                SanType::DnsName("example.com".to_string()),
+                SanType::IpAddress(dialed),
+            ]
+        );
+        assert_eq!(
+            subject_alt_names("*.example.com", None),
+            [
+                SanType::DnsName("example.com".to_string()),
+                SanType::DnsName("*.example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn bracketed_hosts_are_not_server_names() {
+        assert!(!is_server_name("[2001:db8::1]"));
+        assert!(!is_server_name("../escape"));
     }
 }
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::fs::OpenOptions;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, warn};
+
+use crate::error::Error;
+use crate::flow::{FlowEvent, FlowSummary};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Clf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessLog {
+    pub path: Option<PathBuf>,
+    pub format: AccessLogFormat,
+}
+
+impl AccessLog {
+    pub(crate) async fn spawn(&self, events: broadcast::Receiver<FlowEvent>) -> Result<(), Error> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match &self.path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(Error::WriteFileError)?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+
+        tokio::spawn(Self::run(self.format, events, writer));
+
+        Ok(())
+    }
+
+    async fn run(
+        format: AccessLogFormat,
+        mut events: broadcast::Receiver<FlowEvent>,
+        mut writer: Box<dyn AsyncWrite + Send + Unpin>,
+    ) {
+        loop {
+            let summary = match events.recv().await {
+                Ok(FlowEvent::Complete(summary)) => summary,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Access log fell behind");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let mut line = match format {
+                AccessLogFormat::Json => Self::json(&summary),
+                AccessLogFormat::Clf => Self::clf(&summary),
+            };
+            line.push('\n');
+
+            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                error!(?e, "Fail to write access log");
+                return;
+            }
+            let _ = writer.flush().await;
+        }
+    }
+
+    fn json(summary: &FlowSummary) -> String {
+        let timestamp = OffsetDateTime::from(summary.started)
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        json!({
+            "timestamp": timestamp,
+            "id": summary.id,
+            "flow": summary.ulid.to_string(),
+            "client": summary.client.ip().to_string(),
+            "method": summary.method.as_str(),
+            "scheme": summary.uri.scheme_str(),
+            "host": summary.uri.host(),
+            "path": summary.uri.path_and_query().map(|path| path.as_str()),
+            "version": format!("{:?}", summary.version),
+            "status": summary.status.map(|status| status.as_u16()),
+            "bytes_up": summary.request_bytes,
+            "bytes_down": summary.response_bytes,
+            "duration_ms": summary.duration.as_secs_f64() * 1000.0,
+            "tls_version": summary.tls_version.map(|version| format!("{:?}", version)),
+        })
+        .to_string()
+    }
+
+    fn clf(summary: &FlowSummary) -> String {
+        let timestamp = OffsetDateTime::from(summary.started)
+            .format(format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+            ))
+            .unwrap_or_default();
+        let status = summary
+            .status
+            .map_or_else(|| "-".to_string(), |status| status.as_u16().to_string());
+        let bytes = match summary.response_bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {}",
+            summary.client.ip(),
+            timestamp,
+            summary.method,
+            summary.uri,
+            summary.version,
+            status,
+            bytes
+        )
+    }
+}
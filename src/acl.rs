@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::policy::HostPattern;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AclConfig {
+    // CIDRs such as `10.0.0.0/8` allowed to use the proxy at all; empty lets anyone in.
+    pub clients: Vec<String>,
+    // Destinations allowed when any rule matches; empty allows everything.
+    pub allow: Vec<AclRule>,
+    // Limits CONNECT to these ports, like `[443, 8443]`, on top of `allow`; empty allows any.
+    pub connect_ports: Vec<u16>,
+}
+
+// Empty fields match anything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AclRule {
+    pub clients: Vec<String>,
+    pub hosts: Vec<String>,
+    pub ports: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfigError("Invalid CIDR in acl");
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, address: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as `::ffff:a.b.c.d`.
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => Self::same_prefix(
+                u32::from(network).into(),
+                u32::from(address).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                Self::same_prefix(network.into(), address.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    fn same_prefix(network: u128, address: u128, bits: u8, prefix: u8) -> bool {
+        let shift = bits - prefix;
+        shift == bits || network >> shift == address >> shift
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    clients: Vec<Cidr>,
+    hosts: Vec<HostPattern>,
+    ports: Vec<u16>,
+}
+
+impl Rule {
+    fn new(config: &AclRule) -> Result<Self, Error> {
+        Ok(Self {
+            clients: Acl::cidrs(&config.clients)?,
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| HostPattern::new(host))
+                .collect::<Result<_, _>>()?,
+            ports: config.ports.clone(),
+        })
+    }
+
+    fn matches(&self, client: IpAddr, host: &str, port: u16) -> bool {
+        (self.clients.is_empty() || self.clients.iter().any(|cidr| cidr.contains(client)))
+            && (self.hosts.is_empty() || self.hosts.iter().any(|pattern| pattern.matches(host)))
+            && (self.ports.is_empty() || self.ports.contains(&port))
+    }
+}
+
+// Who may use the proxy and where they may go, checked before anything is dialed.
+#[derive(Debug, Default)]
+pub(crate) struct Acl {
+    clients: Vec<Cidr>,
+    allow: Vec<Rule>,
+    connect_ports: Vec<u16>,
+}
+
+impl Acl {
+    pub(crate) fn new(config: &AclConfig) -> Result<Self, Error> {
+        Ok(Self {
+            clients: Self::cidrs(&config.clients)?,
+            allow: config
+                .allow
+                .iter()
+                .map(Rule::new)
+                .collect::<Result<_, _>>()?,
+            connect_ports: config.connect_ports.clone(),
+        })
+    }
+
+    fn cidrs(cidrs: &[String]) -> Result<Vec<Cidr>, Error> {
+        cidrs.iter().map(|cidr| cidr.parse()).collect()
+    }
+
+    pub(crate) fn allows_client(&self, client: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|cidr| cidr.contains(client))
+    }
+
+    pub(crate) fn allows(&self, client: IpAddr, host: &str, port: u16) -> bool {
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(client, host, port))
+    }
+
+    pub(crate) fn allows_connect(&self, client: IpAddr, host: &str, port: u16) -> bool {
+        (self.connect_ports.is_empty() || self.connect_ports.contains(&port))
+            && self.allows(client, host, port)
+    }
+}
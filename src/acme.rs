@@ -0,0 +1,661 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, HOST, LOCATION, USER_AGENT};
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, Uri};
+use hyper::Body;
+use rcgen::{Certificate, CertificateParams, CustomExtension, DistinguishedName, DnType};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::client::ServerName;
+use rustls::{PrivateKey, ServerConfig};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsConnector;
+use tracing::{error, info, warn};
+use x509_parser::pem::parse_x509_pem;
+use x509_parser::time::ASN1Time;
+
+use crate::ca;
+use crate::dialer::Dialer;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::http::ALPN_H2;
+use crate::reverse;
+use crate::tls_policy::TlsPolicy;
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const ACCOUNT_KEY_FILE: &str = "account.key.der";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+// How often certificates are checked for renewal.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ChallengeType {
+    // Needs the listener to be reachable on port 80 for plain HTTP.
+    #[serde(rename = "http-01")]
+    Http01,
+    // Answered in the TLS handshake, on port 443.
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    fn name(self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+// Certificates for virtual hosts with `acme = true`, issued and renewed by an ACME CA.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AcmeConfig {
+    pub directory: String,
+    // Addresses the CA may write to about the account, like `admin@example.com`.
+    pub contact: Vec<String>,
+    pub challenge: ChallengeType,
+    // Certificates are renewed once they expire within this.
+    #[serde(with = "humantime_serde")]
+    pub renew_before: Duration,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            directory: LETS_ENCRYPT.to_string(),
+            contact: Vec::new(),
+            challenge: ChallengeType::default(),
+            renew_before: Duration::from_secs(3600 * 24 * 30),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl Problem {
+    fn into_error(self) -> Error {
+        Error::AcmeError(format!("{} {}", self.kind, self.detail))
+    }
+}
+
+struct Issued {
+    config: Arc<ServerConfig>,
+    not_after: i64,
+}
+
+pub(crate) struct Acme {
+    config: AcmeConfig,
+    domains: Vec<String>,
+    dir: PathBuf,
+    tls_connector: TlsConnector,
+    dialer: Arc<dyn Dialer>,
+    // Applies to issued certificates; challenge responses keep the defaults for the CA.
+    policy: TlsPolicy,
+    issued: Mutex<HashMap<String, Issued>>,
+    // Key authorizations of pending HTTP-01 challenges, by token.
+    tokens: Mutex<HashMap<String, String>>,
+    // Certificates answering pending TLS-ALPN-01 challenges, by domain.
+    validations: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl Acme {
+    pub(crate) fn new(
+        config: AcmeConfig,
+        domains: Vec<String>,
+        dir: PathBuf,
+        tls_connector: TlsConnector,
+        dialer: Arc<dyn Dialer>,
+        policy: TlsPolicy,
+    ) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).map_err(Error::WriteFileError)?;
+        let acme = Self {
+            config,
+            domains,
+            dir,
+            tls_connector,
+            dialer,
+            policy,
+            issued: Mutex::default(),
+            tokens: Mutex::default(),
+            validations: Mutex::default(),
+        };
+        for domain in &acme.domains {
+            if let Err(e) = acme.load(domain) {
+                info!(?e, "No stored certificate for {}", domain);
+            }
+        }
+
+        Ok(acme)
+    }
+
+    pub(crate) fn server_config(&self, host: &str) -> Option<Arc<ServerConfig>> {
+        self.issued
+            .lock()
+            .unwrap()
+            .get(host)
+            .map(|issued| issued.config.clone())
+    }
+
+    pub(crate) fn validation(&self, host: &str) -> Option<Arc<ServerConfig>> {
+        self.validations.lock().unwrap().get(host).cloned()
+    }
+
+    // The answer to an HTTP-01 challenge, which the CA asks for over plain HTTP.
+    pub(crate) fn respond(&self, flow: &FlowRequest) -> Option<Response<Body>> {
+        let token = flow.uri.path().strip_prefix(CHALLENGE_PATH)?;
+        let key_authorization = self.tokens.lock().unwrap().get(token).cloned()?;
+
+        let mut response = Response::new(Body::from(key_authorization.clone()));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(key_authorization.len()));
+        Some(response)
+    }
+
+    pub(crate) fn spawn(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                for domain in &self.domains {
+                    if !self.needs_renewal(domain) {
+                        continue;
+                    }
+                    match self.issue(domain).await {
+                        Ok(()) => info!("Certificate for {} issued", domain),
+                        Err(e) => error!(?e, "Fail to issue certificate for {}", domain),
+                    }
+                }
+                tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    fn needs_renewal(&self, domain: &str) -> bool {
+        let renew_at = ASN1Time::now().timestamp() + self.config.renew_before.as_secs() as i64;
+
+        self.issued
+            .lock()
+            .unwrap()
+            .get(domain)
+            .is_none_or(|issued| issued.not_after <= renew_at)
+    }
+
+    fn paths(&self, domain: &str) -> (PathBuf, PathBuf) {
+        (
+            self.dir.join(format!("{}.crt", domain)),
+            self.dir.join(format!("{}.key", domain)),
+        )
+    }
+
+    fn load(&self, domain: &str) -> Result<(), Error> {
+        let (cert, key) = self.paths(domain);
+        let config = reverse::load_server_config(&cert, &key, &self.policy)?;
+        let pem = fs::read(&cert).map_err(Error::ReadFileError)?;
+        let invalid = || Error::AcmeError(format!("Invalid stored certificate for {}", domain));
+        let (_, pem) = parse_x509_pem(&pem).map_err(|_| invalid())?;
+        let not_after = pem
+            .parse_x509()
+            .map_err(|_| invalid())?
+            .validity()
+            .not_after
+            .timestamp();
+
+        self.issued.lock().unwrap().insert(
+            domain.to_string(),
+            Issued {
+                config: Arc::new(config),
+                not_after,
+            },
+        );
+        Ok(())
+    }
+
+    fn account_key(&self) -> Result<EcdsaKeyPair, Error> {
+        let path = self.dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &SystemRandom::new(),
+                )
+                .map_err(|_| Error::AcmeError("Fail to generate account key".to_string()))?;
+                ca::write_private(&path, pkcs8.as_ref()).map_err(Error::WriteFileError)?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+            .map_err(|_| Error::AcmeError("Invalid account key".to_string()))
+    }
+
+    async fn issue(&self, domain: &str) -> Result<(), Error> {
+        let mut client = AcmeClient::connect(self).await?;
+
+        let identifiers = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let new_order = client.directory.new_order.clone();
+        let (headers, order) = client.post::<Order>(&new_order, Some(identifiers)).await?;
+        let order_url = Self::location(&headers)?;
+
+        for url in &order.authorizations {
+            self.authorize(&mut client, domain, url).await?;
+        }
+
+        let key = Certificate::from_params(Self::csr_params(domain))?;
+        let csr = key.serialize_request_der()?;
+        client
+            .post::<Order>(&order.finalize, Some(json!({ "csr": b64(&csr) })))
+            .await?;
+        let order = client
+            .poll::<Order, _>(&order_url, |order| match order.status.as_str() {
+                "valid" => Some(Ok(())),
+                "invalid" => Some(Err(order
+                    .error
+                    .take()
+                    .map(Problem::into_error)
+                    .unwrap_or_else(|| Error::AcmeError("Order is invalid".to_string())))),
+                _ => None,
+            })
+            .await?;
+        let url = order
+            .certificate
+            .ok_or_else(|| Error::AcmeError("Order without certificate".to_string()))?;
+        let (_, chain) = client.request(&url, None).await?;
+
+        let (cert, key_path) = self.paths(domain);
+        let key_pem = key.serialize_private_key_pem();
+        ca::write_pair(&cert, &chain, &key_path, key_pem.as_bytes())
+            .map_err(Error::WriteFileError)?;
+
+        self.load(domain)
+    }
+
+    async fn authorize(
+        &self,
+        client: &mut AcmeClient<'_>,
+        domain: &str,
+        url: &str,
+    ) -> Result<(), Error> {
+        let (_, authorization) = client.post::<Authorization>(url, None).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let kind = self.config.challenge.name();
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == kind)
+            .ok_or_else(|| Error::AcmeError(format!("No {} challenge offered", kind)))?;
+        let token = challenge
+            .token
+            .ok_or_else(|| Error::AcmeError("Challenge without token".to_string()))?;
+        let key_authorization = format!("{}.{}", token, client.thumbprint);
+
+        match self.config.challenge {
+            ChallengeType::Http01 => {
+                self.tokens
+                    .lock()
+                    .unwrap()
+                    .insert(token.clone(), key_authorization);
+            }
+            ChallengeType::TlsAlpn01 => {
+                let config = Self::validation_config(domain, &key_authorization)?;
+                self.validations
+                    .lock()
+                    .unwrap()
+                    .insert(domain.to_string(), Arc::new(config));
+            }
+        }
+
+        let result = async {
+            client
+                .post::<Value>(&challenge.url, Some(json!({})))
+                .await?;
+            client
+                .poll::<Authorization, _>(url, |authorization| {
+                    match authorization.status.as_str() {
+                        "valid" => Some(Ok(())),
+                        "invalid" => Some(Err(authorization
+                            .challenges
+                            .iter_mut()
+                            .find_map(|challenge| challenge.error.take())
+                            .map(Problem::into_error)
+                            .unwrap_or_else(|| {
+                                Error::AcmeError(format!("Authorization for {} failed", domain))
+                            }))),
+                        _ => None,
+                    }
+                })
+                .await
+        }
+        .await;
+
+        self.tokens.lock().unwrap().remove(&token);
+        self.validations.lock().unwrap().remove(domain);
+        result.map(|_| ())
+    }
+
+    // A self-signed certificate carrying the key authorization digest (RFC 8737).
+    fn validation_config(domain: &str, key_authorization: &str) -> Result<ServerConfig, Error> {
+        let mut params = CertificateParams::new(vec![domain.to_string()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+            digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+        )];
+        let cert = Certificate::from_params(params)?;
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der()?)],
+                PrivateKey(cert.serialize_private_key_der()),
+            )?;
+        config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+
+        Ok(config)
+    }
+
+    fn csr_params(domain: &str) -> CertificateParams {
+        let mut params = CertificateParams::new(vec![domain.to_string()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        let mut d_name = DistinguishedName::new();
+        d_name.push(DnType::CommonName, domain);
+        params.distinguished_name = d_name;
+
+        params
+    }
+
+    fn location(headers: &HeaderMap) -> Result<String, Error> {
+        headers
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| Error::AcmeError("Response without location".to_string()))
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// An account session with the CA, sending JWS signed requests (RFC 8555).
+struct AcmeClient<'a> {
+    acme: &'a Acme,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    directory: Directory,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> AcmeClient<'a> {
+    async fn connect(acme: &'a Acme) -> Result<AcmeClient<'a>, Error> {
+        let key = acme.account_key()?;
+        let public = key.public_key().as_ref();
+        let (x, y) = (b64(&public[1..33]), b64(&public[33..65]));
+        // The thumbprint hashes the members in lexicographic order, without whitespace.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let thumbprint = b64(digest(&SHA256, canonical.as_bytes()).as_ref());
+
+        let (_, directory) = Self::send(acme, Method::GET, &acme.config.directory, None).await?;
+        let directory: Directory = serde_json::from_slice(&directory)
+            .map_err(|_| Error::AcmeError("Invalid directory".to_string()))?;
+
+        let mut client = Self {
+            acme,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            directory,
+            kid: None,
+            nonce: None,
+        };
+        let contact = acme
+            .config
+            .contact
+            .iter()
+            .map(|contact| format!("mailto:{}", contact.trim_start_matches("mailto:")))
+            .collect::<Vec<_>>();
+        let account = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let new_account = client.directory.new_account.clone();
+        let (headers, _) = client.post::<Value>(&new_account, Some(account)).await?;
+        client.kid = Some(Acme::location(&headers)?);
+
+        Ok(client)
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<(HeaderMap, T), Error> {
+        let (headers, body) = self.request(url, payload).await?;
+        let body = serde_json::from_slice(&body)
+            .map_err(|_| Error::AcmeError(format!("Invalid response from {}", url)))?;
+
+        Ok((headers, body))
+    }
+
+    // Polls `url` until `done` settles on the resource.
+    async fn poll<T, F>(&mut self, url: &str, mut done: F) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&mut T) -> Option<Result<(), Error>>,
+    {
+        for _ in 0..MAX_POLLS {
+            let (_, mut resource) = self.post::<T>(url, None).await?;
+            if let Some(result) = done(&mut resource) {
+                return result.map(|_| resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        Err(Error::AcmeError(format!("Gave up waiting for {}", url)))
+    }
+
+    // A signed POST, or a POST-as-GET without a payload; retried once on a stale nonce.
+    async fn request(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<(HeaderMap, Vec<u8>), Error> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign(url, payload.as_ref(), &nonce)?;
+            let (response, body) = Self::send(self.acme, Method::POST, url, Some(body)).await?;
+            self.nonce = Self::replay_nonce(response.headers());
+
+            if response.status().is_success() {
+                return Ok((response.headers().clone(), body));
+            }
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or(Problem {
+                kind: String::new(),
+                detail: format!("{} from {}", response.status(), url),
+            });
+            if problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(problem.into_error());
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, Error> {
+        let (response, _) =
+            Self::send(self.acme, Method::HEAD, &self.directory.new_nonce, None).await?;
+
+        Self::replay_nonce(response.headers())
+            .ok_or_else(|| Error::AcmeError("No nonce from the CA".to_string()))
+    }
+
+    fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+        headers
+            .get("replay-nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_string)
+    }
+
+    fn sign(&self, url: &str, payload: Option<&Value>, nonce: &str) -> Result<Vec<u8>, Error> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|payload| b64(payload.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| Error::AcmeError("Fail to sign request".to_string()))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        })
+        .to_string()
+        .into_bytes())
+    }
+
+    async fn send(
+        acme: &Acme,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<(Response<()>, Vec<u8>), Error> {
+        let uri =
+            Uri::try_from(url).map_err(|_| Error::AcmeError(format!("Invalid URL {}", url)))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| Error::AcmeError(format!("Invalid URL {}", url)))?
+            .to_string();
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(HOST, uri.authority().map_or(host.as_str(), |a| a.as_str()))
+            .header(USER_AGENT, "yaler");
+        if body.is_some() {
+            builder = builder.header(CONTENT_TYPE, "application/jose+json");
+        }
+        let mut req = builder
+            .body(body.map(Body::from).unwrap_or_else(Body::empty))
+            .map_err(|_| Error::AcmeError(format!("Invalid URL {}", url)))?;
+
+        let remote = acme.dialer.dial(&host, port).await?;
+        if !https {
+            return Self::exchange(remote, false, req).await;
+        }
+        let server_name = ServerName::try_from(host.as_str())
+            .map_err(|_| Error::AcmeError(format!("Invalid URL {}", url)))?;
+        let remote = acme
+            .tls_connector
+            .connect(server_name, remote)
+            .await
+            .map_err(Error::TlsConnectError)?;
+        let http2 = remote.get_ref().1.alpn_protocol() == Some(ALPN_H2);
+        if http2 {
+            req.headers_mut().remove(HOST);
+            *req.uri_mut() = uri;
+        }
+
+        Self::exchange(remote, http2, req).await
+    }
+
+    async fn exchange<S>(
+        stream: S,
+        http2: bool,
+        req: Request<Body>,
+    ) -> Result<(Response<()>, Vec<u8>), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(http2)
+            .handshake(stream)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!(?e);
+            }
+        });
+        let response = sender
+            .send_request(req)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(Error::HttpRequestError)?;
+
+        Ok((Response::from_parts(parts, ()), body.to_vec()))
+    }
+}
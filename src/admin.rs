@@ -0,0 +1,171 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use http::header::{AUTHORIZATION, CONTENT_TYPE, ORIGIN};
+use http::{Method, Request, Response, StatusCode};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::auth::constant_time_eq;
+use crate::config::Mode;
+use crate::error::Error;
+use crate::listen::{BoundListener, ListenAddr};
+use crate::server::Context;
+
+pub(crate) async fn serve(
+    addr: &ListenAddr,
+    token: Option<String>,
+    context: Arc<Context>,
+) -> Result<(), Error> {
+    let token: Option<Arc<str>> = token.map(Into::into);
+    let listener = BoundListener::bind(addr, Mode::Http, &context.tcp, false).await?;
+    let incoming = accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let response = match authorized(&req, token.as_deref()) {
+                    Ok(()) => handle(&req, &context),
+                    Err(code) => status(code),
+                };
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = hyper::Server::builder(incoming).serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!(?e, "Admin listener stopped");
+        }
+    });
+
+    Ok(())
+}
+
+// No browser has business here, and a page elsewhere could otherwise reach a local listener.
+fn authorized(req: &Request<Body>, token: Option<&str>) -> Result<(), StatusCode> {
+    if req.headers().contains_key(ORIGIN) {
+        warn!(origin = ?req.headers().get(ORIGIN), "Admin request from a browser refused");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if constant_time_eq(presented.trim().as_bytes(), token.as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn handle(req: &Request<Body>, context: &Context) -> Response<Body> {
+    let path = req.uri().path();
+
+    match (req.method(), path) {
+        (&Method::GET, "/connections") => connections(context),
+        (&Method::GET, "/certs") => {
+            let mut hosts = context.acceptors.hosts();
+            hosts.sort();
+            respond(StatusCode::OK, json!(hosts))
+        }
+        (&Method::DELETE, "/certs") => {
+            let flushed = context.acceptors.clear();
+            info!(flushed, "Certificate cache flushed");
+            respond(StatusCode::OK, json!({ "flushed": flushed }))
+        }
+        (&Method::DELETE, _) if path.starts_with("/certs/") => {
+            let host = &path["/certs/".len()..];
+            if host.is_empty() {
+                return status(StatusCode::NOT_FOUND);
+            }
+            // Unless asked to, leaves stay in the on-disk store and may be loaded again.
+            let store = req
+                .uri()
+                .query()
+                .is_some_and(|query| query.split('&').any(|param| param == "store=true"));
+
+            let revoked = context.acceptors.revoke(host, store);
+            info!(%host, revoked, store, "Certificate revoked");
+            respond(
+                StatusCode::OK,
+                json!({ "host": host, "revoked": revoked, "store": store }),
+            )
+        }
+        (&Method::GET, "/bypass") => respond(StatusCode::OK, json!(context.policy.overrides())),
+        (method, _) if path.starts_with("/bypass/") => {
+            let host = &path["/bypass/".len()..];
+            let bypass = match *method {
+                Method::PUT => true,
+                Method::DELETE => false,
+                _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+            };
+            if host.is_empty() {
+                return status(StatusCode::NOT_FOUND);
+            }
+
+            context.policy.set_bypass(host, bypass);
+            info!(%host, bypass, "Bypass changed");
+            respond(StatusCode::OK, json!({ "host": host, "bypass": bypass }))
+        }
+        (&Method::POST, "/shutdown") => {
+            info!("Shutdown requested");
+            context.shutdown.notify_one();
+            status(StatusCode::ACCEPTED)
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn connections(context: &Context) -> Response<Body> {
+    let mut connections = context.connections.list();
+    connections.sort_by_key(|info| info.started);
+
+    let connections: Vec<Value> = connections
+        .into_iter()
+        .map(|info| {
+            json!({
+                "id": info.id.to_string(),
+                "peer": info.peer.to_string(),
+                "target": info.target,
+                "started": OffsetDateTime::from(info.started).format(&Rfc3339).unwrap_or_default(),
+                "elapsed": info.started.elapsed().unwrap_or_default().as_secs_f64(),
+            })
+        })
+        .collect();
+
+    respond(StatusCode::OK, json!(connections))
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use async_trait::async_trait;
+use http::header::PROXY_AUTHORIZATION;
+use http::HeaderMap;
+
+use crate::error::Error;
+
+#[async_trait]
+pub trait Credentials: Send + Sync {
+    async fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StaticCredentials {
+    users: HashMap<String, String>,
+}
+
+impl StaticCredentials {
+    pub fn new<I>(users: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        Self {
+            users: users.into_iter().collect(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadFileError)?;
+
+        Ok(Self::new(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(|line| line.split_once(':'))
+                .map(|(username, password)| (username.to_string(), password.to_string())),
+        ))
+    }
+}
+
+#[async_trait]
+impl Credentials for StaticCredentials {
+    async fn verify(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+    }
+}
+
+pub(crate) async fn authorize(credentials: &dyn Credentials, headers: &HeaderMap) -> bool {
+    match basic_credentials(headers) {
+        Some((username, password)) => credentials.verify(&username, &password).await,
+        None => false,
+    }
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
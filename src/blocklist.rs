@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::header::CONTENT_LENGTH;
+use http::{HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::intercept::{Interceptor, RequestAction};
+
+// Names hosts files map to themselves rather than to block anything.
+const HOSTS_BUILTINS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    // 403 from the proxy.
+    #[default]
+    Forbidden,
+    // 200 with no body, which pages tend to take more quietly than an error.
+    Empty,
+    // The client connection is reset.
+    Reset,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    // Hosts files (`0.0.0.0 ads.example.com`), adblock filter lists (`||ads.example.com^`,
+    // `@@||ok.example.com^`) or plain lists with a domain per line.
+    pub files: Vec<PathBuf>,
+    // Blocked with their subdomains, like `||domain^`.
+    pub domains: Vec<String>,
+    pub action: BlockAction,
+}
+
+#[derive(Default)]
+struct Inner {
+    action: BlockAction,
+    exact: HashSet<String>,
+    // Blocked along with every subdomain.
+    domains: HashSet<String>,
+    allowed: HashSet<String>,
+}
+
+#[derive(Clone)]
+pub struct Blocklist(Arc<Inner>);
+
+impl Blocklist {
+    pub fn load(config: &BlocklistConfig) -> Result<Self, Error> {
+        let mut inner = Inner {
+            action: config.action,
+            ..Inner::default()
+        };
+        for domain in &config.domains {
+            inner.domains.insert(Self::normalize(domain));
+        }
+        for path in &config.files {
+            let content = std::fs::read_to_string(path).map_err(Error::ReadFileError)?;
+            let before = inner.exact.len() + inner.domains.len();
+            for line in content.lines() {
+                inner.parse_line(line);
+            }
+            info!(
+                path = %path.display(),
+                entries = inner.exact.len() + inner.domains.len() - before,
+                "Blocklist loaded"
+            );
+        }
+
+        Ok(Self(Arc::new(inner)))
+    }
+
+    pub fn action(&self) -> BlockAction {
+        self.0.action
+    }
+
+    pub fn is_blocked(&self, host: &str) -> bool {
+        let host = Self::normalize(host);
+        let mut suffixes = std::iter::successors(Some(host.as_str()), |name| {
+            name.split_once('.').map(|(_, parent)| parent)
+        });
+        if suffixes.clone().any(|name| self.0.allowed.contains(name)) {
+            return false;
+        }
+
+        self.0.exact.contains(&host) || suffixes.any(|name| self.0.domains.contains(name))
+    }
+
+    // The answer for a blocked request; None when the connection should be reset instead.
+    pub(crate) fn response(&self) -> Option<Response<Body>> {
+        let status = match self.0.action {
+            BlockAction::Forbidden => StatusCode::FORBIDDEN,
+            BlockAction::Empty => StatusCode::OK,
+            BlockAction::Reset => return None,
+        };
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(0));
+
+        Some(response)
+    }
+
+    fn normalize(host: &str) -> String {
+        host.trim().trim_end_matches('.').to_ascii_lowercase()
+    }
+}
+
+impl Inner {
+    fn parse_line(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', '!', '[']) {
+            return;
+        }
+
+        // Adblock syntax; only whole-domain rules without options map onto hosts.
+        if let Some(rule) = line.strip_prefix("@@||") {
+            if let Some(domain) = Self::adblock_domain(rule) {
+                self.allowed.insert(domain);
+            }
+            return;
+        }
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = Self::adblock_domain(rule) {
+                self.domains.insert(domain);
+            }
+            return;
+        }
+
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            // `address name [name...]`
+            (Some(address), Some(name)) if address.parse::<std::net::IpAddr>().is_ok() => {
+                for name in std::iter::once(name).chain(fields) {
+                    let name = Blocklist::normalize(name);
+                    if !HOSTS_BUILTINS.contains(&name.as_str()) {
+                        self.exact.insert(name);
+                    }
+                }
+            }
+            (Some(name), None) if Self::is_domain(name) => {
+                self.exact.insert(Blocklist::normalize(name));
+            }
+            _ => {}
+        }
+    }
+
+    fn adblock_domain(rule: &str) -> Option<String> {
+        let domain = rule.strip_suffix('^').or_else(|| rule.strip_suffix("^|"))?;
+        Self::is_domain(domain).then(|| Blocklist::normalize(domain))
+    }
+
+    fn is_domain(name: &str) -> bool {
+        name.contains('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    }
+}
+
+#[async_trait]
+impl Interceptor for Blocklist {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        match flow.uri.host() {
+            Some(host) if self.is_blocked(host) => {
+                debug!(%host, "blocked");
+                match self.response() {
+                    Some(response) => RequestAction::Respond(response),
+                    None => RequestAction::Block,
+                }
+            }
+            _ => RequestAction::Forward(req),
+        }
+    }
+}
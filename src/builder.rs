@@ -0,0 +1,857 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Client;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{KeyLog, RootCertStore};
+use tokio::sync::Notify;
+use tracing::{enabled, Level};
+
+use crate::acceptor::{AcceptorMap, CertCacheConfig, LeafParams};
+use crate::access_log::AccessLog;
+use crate::acl::{Acl, AclConfig};
+use crate::acme::{Acme, AcmeConfig};
+use crate::admin;
+use crate::auth::{Credentials, StaticCredentials};
+use crate::blocklist::BlocklistConfig;
+use crate::ca::CertificateAuthority;
+use crate::capture::FlowCapture;
+use crate::config::{Config, DnsConfig, ListenerConfig, ListenerTls, Mode};
+use crate::dialer::{self, Dialer, DialerConnector, DirectDialer, RouteDialer};
+use crate::error::Error;
+use crate::fault::{FaultConfig, Faults};
+use crate::flow::Flows;
+use crate::forwarded::ForwardedConfig;
+use crate::grpc::{Descriptors, GrpcConfig};
+use crate::har::HarRecorder;
+use crate::http::DEFAULT_VIA;
+use crate::intercept::{Interceptor, Interceptors};
+use crate::keylog::{KeyLogFile, SSLKEYLOGFILE};
+use crate::limit::{ConnectionLimiter, RateLimitConfig, RateLimiter, ResourceLimits};
+use crate::listen::{AcceptorsConfig, ListenAddr};
+use crate::metrics::Metrics;
+use crate::pac::{Pac, PacConfig};
+use crate::plugin::{PluginConfig, Plugins};
+use crate::policy::{HostPattern, Policy};
+use crate::portal::CaPortal;
+use crate::redact::Redactor;
+use crate::reload::{Live, Slot, Snapshot};
+use crate::resolver::{DnsProtocol, DnsResolver, Resolver, StaticResolver};
+use crate::reverse::{read_certs, VirtualHostConfig, VirtualHosts};
+use crate::rules::RuleConfig;
+use crate::script::Script;
+use crate::server::{Context, Server};
+use crate::shaping::{Shaper, ShapingConfig};
+use crate::storage::{FlowStore, StorageConfig};
+use crate::tcp::TcpTuning;
+use crate::timeout::Timeouts;
+use crate::tls_policy::TlsPolicy;
+use crate::upstream::UpstreamProxy;
+use crate::upstream_tls::{UpstreamConnectors, UpstreamTlsConfig, UpstreamVerifier};
+use crate::vcr::{Vcr, VcrConfig};
+use crate::web::WebUi;
+
+const FLOW_CHANNEL_CAPACITY: usize = 1024;
+
+pub struct ServerBuilder {
+    listen: ListenAddr,
+    mode: Mode,
+    listen_tls: Option<ListenerTls>,
+    listeners: Vec<ListenerConfig>,
+    acceptors: AcceptorsConfig,
+    virtual_hosts: Vec<VirtualHostConfig>,
+    acme: Option<AcmeConfig>,
+    ca: Option<(String, String)>,
+    cert_store: Option<PathBuf>,
+    ca_domain: Option<String>,
+    leaf_params: LeafParams,
+    cert_cache: CertCacheConfig,
+    client_ca: Option<PathBuf>,
+    ca_chain: Option<PathBuf>,
+    tls_policy: TlsPolicy,
+    root_store: Option<RootCertStore>,
+    upstream_tls: UpstreamTlsConfig,
+    timeouts: Timeouts,
+    tcp: TcpTuning,
+    max_requests: Option<usize>,
+    via: Option<String>,
+    flow_id_header: bool,
+    forwarded: ForwardedConfig,
+    interceptors: Interceptors,
+    bypass: Vec<String>,
+    pac: Option<PacConfig>,
+    blocklist: Option<BlocklistConfig>,
+    acl: AclConfig,
+    rate_limit: RateLimitConfig,
+    limits: ResourceLimits,
+    shaping: Vec<ShapingConfig>,
+    faults: Vec<FaultConfig>,
+    credentials: Option<Arc<dyn Credentials>>,
+    upstream_proxy: Option<UpstreamProxy>,
+    dialer: Option<Arc<dyn Dialer>>,
+    resolver: Option<Arc<dyn Resolver>>,
+    access_log: Option<AccessLog>,
+    key_log_file: Option<PathBuf>,
+    metrics_listen: Option<SocketAddr>,
+    admin_listen: Option<ListenAddr>,
+    admin_token: Option<String>,
+    web_listen: Option<SocketAddr>,
+    capture: Option<FlowCapture>,
+    storage: Option<StorageConfig>,
+    vcr: Option<VcrConfig>,
+    rules: Vec<RuleConfig>,
+    script: Option<PathBuf>,
+    plugins: Option<PluginConfig>,
+    grpc: GrpcConfig,
+    redactor: Redactor,
+}
+
+impl ServerBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            listen: ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 5333))),
+            mode: Mode::default(),
+            listen_tls: None,
+            listeners: Vec::new(),
+            acceptors: AcceptorsConfig::default(),
+            virtual_hosts: Vec::new(),
+            acme: None,
+            ca: None,
+            cert_store: None,
+            ca_domain: None,
+            leaf_params: LeafParams::default(),
+            cert_cache: CertCacheConfig::default(),
+            client_ca: None,
+            ca_chain: None,
+            tls_policy: TlsPolicy::default(),
+            root_store: None,
+            upstream_tls: UpstreamTlsConfig::default(),
+            timeouts: Timeouts::default(),
+            tcp: TcpTuning::default(),
+            max_requests: None,
+            via: Some(DEFAULT_VIA.to_string()),
+            flow_id_header: false,
+            forwarded: ForwardedConfig::default(),
+            interceptors: Interceptors::default(),
+            bypass: Vec::new(),
+            pac: None,
+            blocklist: None,
+            acl: AclConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: ResourceLimits::default(),
+            shaping: Vec::new(),
+            faults: Vec::new(),
+            credentials: None,
+            upstream_proxy: None,
+            dialer: None,
+            resolver: None,
+            access_log: None,
+            key_log_file: std::env::var_os(SSLKEYLOGFILE).map(PathBuf::from),
+            metrics_listen: None,
+            admin_listen: None,
+            admin_token: None,
+            web_listen: None,
+            capture: None,
+            storage: None,
+            vcr: None,
+            rules: Vec::new(),
+            script: None,
+            plugins: None,
+            grpc: GrpcConfig::default(),
+            redactor: Redactor::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        let ca = CertificateAuthority::load_or_generate(
+            config.ca_cert.as_deref(),
+            config.ca_key.as_deref(),
+        )?;
+
+        let mut builder = Self::new();
+        if let Some(path) = &config.users_file {
+            builder = builder.credentials(StaticCredentials::load(path)?);
+        } else if !config.users.is_empty() {
+            builder = builder.credentials(StaticCredentials::new(config.users.clone()));
+        }
+        let resolver = Self::resolver_from_config(&config.dns)?;
+        builder.resolver = Some(resolver.clone());
+
+        let (router, upstream_proxy) = Self::dialer_from_config(config, Some(resolver))?;
+        if let Some(proxy) = upstream_proxy {
+            builder = builder.upstream_proxy(proxy);
+        }
+        builder = builder.dialer(router);
+        let redactor = Redactor::new(&config.redact)?;
+        if let Some(har) = &config.har {
+            builder = builder.interceptor(HarRecorder::new(har.clone()).redactor(redactor.clone()));
+        }
+
+        Ok(builder
+            .listen(config.listen.clone())
+            .mode(config.mode)
+            .listen_tls(config.listen_tls.clone())
+            .listeners(config.listeners.clone())
+            .acceptors(config.acceptors)
+            .virtual_hosts(config.virtual_hosts.clone())
+            .acme(config.acme.clone())
+            .ca(ca.cert, ca.key)
+            .ca_chain(config.ca_chain.clone())
+            .cert_store(config.cert_store.clone())
+            .ca_domain(config.ca_domain.clone())
+            .leaf_params(config.leaf.clone())
+            .cert_cache(config.cert_cache.clone())
+            .client_ca(config.client_ca.clone())
+            .tls_policy(config.tls.clone())
+            .upstream_tls(config.upstream_tls.clone())
+            .timeouts(config.timeouts)
+            .tcp(config.tcp)
+            .max_requests_per_connection(config.max_requests_per_connection)
+            .via(config.via.clone())
+            .stealth(config.stealth)
+            .flow_id_header(config.flow_id_header)
+            .forwarded(config.forwarded)
+            .bypass(config.bypass.iter().cloned())
+            .pac(config.pac.clone())
+            .blocklist(config.blocklist.clone())
+            .acl(config.acl.clone())
+            .rate_limit(config.rate_limit)
+            .limits(config.limits)
+            .shaping(config.shaping.clone())
+            .faults(config.faults.clone())
+            .access_log(config.access_log.clone())
+            .key_log_file(config.key_log_file.clone())
+            .metrics_listen(config.metrics_listen)
+            .admin_listen(config.admin_listen.clone())
+            .admin_token(config.admin_token.clone())
+            .web_listen(config.web_listen)
+            .storage(config.storage.clone())
+            .vcr(config.vcr.clone())
+            .rules(config.rules.clone())
+            .script(config.script.clone())
+            .plugins(config.plugins.clone())
+            .grpc(config.grpc.clone())
+            .redactor(redactor))
+    }
+
+    // The dialer for `upstream_proxy` and `upstream_routes`, and the upstream proxy itself when
+    // it is an HTTP one that plain requests are forwarded to.
+    pub(crate) fn dialer_from_config(
+        config: &Config,
+        resolver: Option<Arc<dyn Resolver>>,
+    ) -> Result<(RouteDialer, Option<UpstreamProxy>), Error> {
+        let mut direct = DirectDialer::new().tcp(config.tcp);
+        if let Some(resolver) = resolver {
+            direct = direct.resolver(resolver);
+        }
+        if let Some(delay) = config.happy_eyeballs_delay {
+            direct = direct.fallback_delay(delay);
+        }
+        if let Some(bind) = &config.outbound_bind {
+            direct = direct.bind(bind.clone());
+        }
+        let mut upstream_proxy = None;
+        let fallback = match &config.upstream_proxy {
+            Some(url) if url.starts_with("http://") => {
+                let proxy = UpstreamProxy::parse(url)?.tcp(config.tcp);
+                upstream_proxy = Some(proxy.clone());
+                Arc::new(proxy)
+            }
+            Some(url) => dialer::from_url(url, &direct)?,
+            None => Arc::new(direct.clone()) as Arc<dyn Dialer>,
+        };
+        let mut router = RouteDialer::new(fallback);
+        for route in &config.upstream_routes {
+            let dialer = match &route.bind {
+                Some(_) if route.proxy != "direct" => {
+                    return Err(Error::InvalidConfigError(
+                        "Only direct upstream routes bind outbound connections",
+                    ))
+                }
+                Some(bind) => Arc::new(direct.clone().bind(bind.clone())),
+                None => dialer::from_url(&route.proxy, &direct)?,
+            };
+            for host in &route.hosts {
+                router = router.route(HostPattern::new(host)?, dialer.clone());
+            }
+        }
+
+        Ok((router, upstream_proxy))
+    }
+
+    pub fn listen(mut self, addr: ListenAddr) -> Self {
+        self.listen = addr;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn listen_tls(mut self, tls: Option<ListenerTls>) -> Self {
+        self.listen_tls = tls;
+        self
+    }
+
+    // Listeners besides the one of `listen` and `mode`.
+    pub fn listeners(mut self, listeners: Vec<ListenerConfig>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    pub fn acceptors(mut self, acceptors: AcceptorsConfig) -> Self {
+        self.acceptors = acceptors;
+        self
+    }
+
+    pub fn virtual_hosts(mut self, virtual_hosts: Vec<VirtualHostConfig>) -> Self {
+        self.virtual_hosts = virtual_hosts;
+        self
+    }
+
+    pub fn acme(mut self, acme: Option<AcmeConfig>) -> Self {
+        self.acme = acme;
+        self
+    }
+
+    pub fn ca(mut self, cert: String, key: String) -> Self {
+        self.ca = Some((cert, key));
+        self
+    }
+
+    pub fn ca_chain(mut self, path: Option<PathBuf>) -> Self {
+        self.ca_chain = path;
+        self
+    }
+
+    pub fn cert_store(mut self, dir: Option<PathBuf>) -> Self {
+        self.cert_store = dir;
+        self
+    }
+
+    pub fn ca_domain(mut self, domain: Option<String>) -> Self {
+        self.ca_domain = domain;
+        self
+    }
+
+    pub fn leaf_params(mut self, params: LeafParams) -> Self {
+        self.leaf_params = params;
+        self
+    }
+
+    pub fn cert_cache(mut self, cache: CertCacheConfig) -> Self {
+        self.cert_cache = cache;
+        self
+    }
+
+    pub fn client_ca(mut self, path: Option<PathBuf>) -> Self {
+        self.client_ca = path;
+        self
+    }
+
+    pub fn tls_policy(mut self, policy: TlsPolicy) -> Self {
+        self.tls_policy = policy;
+        self
+    }
+
+    pub fn root_store(mut self, root_store: RootCertStore) -> Self {
+        self.root_store = Some(root_store);
+        self
+    }
+
+    pub fn upstream_tls(mut self, upstream_tls: UpstreamTlsConfig) -> Self {
+        self.upstream_tls = upstream_tls;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // For client connections, and the direct dialer made when no dialer is given; dialers
+    // passed in keep their own.
+    pub fn tcp(mut self, tcp: TcpTuning) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = Some(timeout);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = Some(timeout);
+        self
+    }
+
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.header = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.keep_alive = Some(timeout);
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max: Option<usize>) -> Self {
+        self.max_requests = max;
+        self
+    }
+
+    pub fn via(mut self, pseudonym: String) -> Self {
+        self.via = Some(pseudonym);
+        self
+    }
+
+    // No `Via` header on proxied messages.
+    pub fn stealth(mut self, stealth: bool) -> Self {
+        if stealth {
+            self.via = None;
+        }
+        self
+    }
+
+    // Adds `X-Yaler-Flow-Id` to requests sent upstream and responses sent back.
+    pub fn flow_id_header(mut self, enabled: bool) -> Self {
+        self.flow_id_header = enabled;
+        self
+    }
+
+    pub fn forwarded(mut self, forwarded: ForwardedConfig) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+
+    pub fn bypass<I>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.bypass.extend(patterns);
+        self
+    }
+
+    pub fn pac(mut self, pac: Option<PacConfig>) -> Self {
+        self.pac = pac;
+        self
+    }
+
+    pub fn blocklist(mut self, blocklist: Option<BlocklistConfig>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    pub fn acl(mut self, acl: AclConfig) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn shaping(mut self, shaping: Vec<ShapingConfig>) -> Self {
+        self.shaping = shaping;
+        self
+    }
+
+    pub fn faults(mut self, faults: Vec<FaultConfig>) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    pub fn credentials<C>(mut self, credentials: C) -> Self
+    where
+        C: Credentials + 'static,
+    {
+        self.credentials = Some(Arc::new(credentials));
+        self
+    }
+
+    pub fn upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.upstream_proxy = Some(proxy);
+        self
+    }
+
+    pub fn dialer<D>(mut self, dialer: D) -> Self
+    where
+        D: Dialer + 'static,
+    {
+        self.dialer = Some(Arc::new(dialer));
+        self
+    }
+
+    pub fn resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Resolver + 'static,
+    {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    pub fn access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    pub fn key_log_file(mut self, path: Option<PathBuf>) -> Self {
+        if path.is_some() {
+            self.key_log_file = path;
+        }
+        self
+    }
+
+    pub fn metrics_listen(mut self, addr: Option<SocketAddr>) -> Self {
+        self.metrics_listen = addr;
+        self
+    }
+
+    pub fn admin_listen(mut self, addr: Option<ListenAddr>) -> Self {
+        self.admin_listen = addr;
+        self
+    }
+
+    pub fn admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+
+    pub fn web_listen(mut self, addr: Option<SocketAddr>) -> Self {
+        self.web_listen = addr;
+        self
+    }
+
+    pub fn capture(mut self, capture: FlowCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn storage(mut self, storage: Option<StorageConfig>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn vcr(mut self, vcr: Option<VcrConfig>) -> Self {
+        self.vcr = vcr;
+        self
+    }
+
+    pub fn rules(mut self, rules: Vec<RuleConfig>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    pub fn script(mut self, script: Option<PathBuf>) -> Self {
+        self.script = script;
+        self
+    }
+
+    pub fn plugins(mut self, plugins: Option<PluginConfig>) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn grpc(mut self, grpc: GrpcConfig) -> Self {
+        self.grpc = grpc;
+        self
+    }
+
+    // Masks what the flow log, access log, captures, storage and recorders made here keep.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    pub fn interceptor<I>(mut self, interceptor: I) -> Self
+    where
+        I: Interceptor + 'static,
+    {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    pub async fn build(mut self) -> Result<Server, Error> {
+        let (cert, key) = self.ca.ok_or(Error::MissingCaError)?;
+        let ca_portal = match self.ca_domain {
+            Some(domain) => Some(CaPortal::new(domain, cert.clone())?),
+            None => None,
+        };
+        let metrics = Arc::new(Metrics::new()?);
+        if let Some(addr) = self.metrics_listen {
+            metrics.clone().serve(addr).await?;
+        }
+
+        let mut acceptors = AcceptorMap::new(cert, key)?
+            .with_params(self.leaf_params)
+            .with_cache(self.cert_cache)
+            .with_policy(self.tls_policy.clone())?
+            .with_metrics(metrics.clone());
+        if let Some(path) = &self.ca_chain {
+            acceptors = acceptors.with_chain(read_certs(path)?);
+        }
+        if let Some(dir) = &self.cert_store {
+            acceptors = acceptors.with_store(dir.clone())?;
+        }
+        let key_log = match &self.key_log_file {
+            Some(path) => Some(Arc::new(KeyLogFile::open(path)?) as Arc<dyn KeyLog>),
+            None => None,
+        };
+        if let Some(key_log) = &key_log {
+            acceptors = acceptors.with_key_log(key_log.clone());
+        }
+        if let Some(path) = &self.client_ca {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(&read_certs(path)?);
+            if added == 0 {
+                return Err(Error::InvalidConfigError("No CA certificate in client_ca"));
+            }
+            acceptors = acceptors.with_client_auth(AllowAnyAuthenticatedClient::new(roots));
+        }
+
+        let root_store = match self.root_store {
+            Some(root_store) => root_store,
+            None => self.upstream_tls.root_store()?,
+        };
+        let verifier = UpstreamVerifier::new(root_store, &self.upstream_tls)?;
+        let tls_connectors = Arc::new(UpstreamConnectors::new(
+            verifier,
+            &self.upstream_tls,
+            key_log,
+        )?);
+
+        let dialer = match (self.dialer, &self.upstream_proxy) {
+            (Some(dialer), _) => dialer,
+            (None, Some(proxy)) => Arc::new(proxy.clone()),
+            (None, None) => {
+                let mut direct = DirectDialer::new().tcp(self.tcp);
+                if let Some(resolver) = self.resolver.clone() {
+                    direct = direct.resolver(resolver);
+                }
+                Arc::new(direct)
+            }
+        };
+        let mut snapshot = Snapshot::new(
+            &self.bypass,
+            self.blocklist.as_ref(),
+            &self.rules,
+            self.upstream_proxy.clone(),
+            dialer,
+        )?;
+        snapshot.pac = self
+            .pac
+            .as_ref()
+            .map(|pac| {
+                let secure = self.listen_tls.is_some();
+                Pac::new(pac, &self.listen, self.mode, secure, &self.bypass)
+            })
+            .transpose()?;
+        let live = Live::new(snapshot);
+
+        // Ahead of the other built-in interceptors, so blocked requests reach none of them.
+        self.interceptors
+            .push(Arc::new(Slot::Blocklist(live.clone())));
+        let shaper = Shaper::new(&self.shaping)?;
+        if !shaper.is_empty() {
+            self.interceptors.push(Arc::new(shaper.clone()));
+        }
+        // Bodies reach the flow log through the capture, so debug logging needs one too.
+        let log_bodies = enabled!(target: "yaler::flow", Level::DEBUG);
+        if (self.web_listen.is_some() || log_bodies) && self.capture.is_none() {
+            self.capture = Some(FlowCapture::new());
+        }
+        self.capture = self
+            .capture
+            .map(|capture| capture.redactor(self.redactor.clone()));
+        if let Some(capture) = &self.capture {
+            self.interceptors.push(Arc::new(capture.clone()));
+        }
+        let store = self.storage.map(FlowStore::open).transpose()?;
+        let store = store.map(|store| store.redactor(self.redactor.clone()));
+        if let Some(store) = store.as_ref().filter(|store| store.keeps_bodies()) {
+            self.interceptors.push(Arc::new(store.clone()));
+        }
+        self.interceptors.push(Arc::new(Slot::Rules(live.clone())));
+        if let Some(path) = self.script {
+            let script = Script::load(path)?;
+            script.watch();
+            self.interceptors.push(Arc::new(script));
+        }
+        if let Some(plugins) = self.plugins.map(Plugins::load).transpose()? {
+            if !plugins.is_empty() {
+                self.interceptors.push(Arc::new(plugins));
+            }
+        }
+        if !self.faults.is_empty() {
+            self.interceptors.push(Arc::new(Faults::new(&self.faults)?));
+        }
+        // Last, so everything else has seen the request before a recording answers it.
+        let vcr = self.vcr.map(Vcr::new).transpose()?;
+        let offline = vcr.as_ref().is_some_and(Vcr::is_replaying);
+        if let Some(vcr) = vcr {
+            self.interceptors.push(Arc::new(vcr));
+        }
+        // Routes whatever is left to a backend, so interceptors see the public URI.
+        let mut virtual_hosts = VirtualHosts::new(&self.virtual_hosts, &self.tls_policy)?;
+        if self.mode == Mode::Reverse
+            || self
+                .listeners
+                .iter()
+                .any(|listener| listener.mode == Mode::Reverse)
+        {
+            if virtual_hosts.is_empty() {
+                return Err(Error::InvalidConfigError(
+                    "Reverse mode needs virtual_hosts",
+                ));
+            }
+            let domains = virtual_hosts.acme_domains();
+            if !domains.is_empty() {
+                let config = self.acme.ok_or(Error::InvalidConfigError(
+                    "ACME virtual hosts need an acme section",
+                ))?;
+                // Issued certificates live next to the generated leaves.
+                let dir = self
+                    .cert_store
+                    .unwrap_or_else(CertificateAuthority::default_dir)
+                    .join("acme");
+                let acme = Arc::new(Acme::new(
+                    config,
+                    domains,
+                    dir,
+                    tls_connectors.default(),
+                    Arc::new(live.clone()),
+                    self.tls_policy.clone(),
+                )?);
+                acme.clone().spawn();
+                virtual_hosts = virtual_hosts.with_acme(acme);
+            }
+            self.interceptors.push(Arc::new(virtual_hosts.clone()));
+        }
+
+        let flows = Flows::new(FLOW_CHANNEL_CAPACITY, self.redactor.clone());
+        if let Some(capture) = &self.capture {
+            capture.spawn(flows.subscribe());
+        }
+        if let Some(store) = &store {
+            store.spawn(flows.subscribe());
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.spawn(flows.subscribe()).await?;
+        }
+
+        let mut http_client = Client::builder();
+        if let Some(idle) = self.timeouts.pool_idle {
+            http_client.pool_idle_timeout(idle);
+        }
+        if let Some(max) = self.limits.max_idle_upstream_connections {
+            http_client.pool_max_idle_per_host(max);
+        }
+        let http_client = http_client.build(DialerConnector {
+            dialer: Arc::new(live.clone()),
+            tls: tls_connectors.clone(),
+            handshake: self.timeouts.handshake,
+        });
+
+        let context = Context {
+            acceptors,
+            http_client,
+            tls_connectors,
+            resolver: self.resolver,
+            live,
+            flows,
+            redactor: self.redactor,
+            interceptors: self.interceptors,
+            policy: Policy::default(),
+            acl: Acl::new(&self.acl)?,
+            rate_limit: RateLimiter::new(&self.rate_limit),
+            limits: self.limits,
+            connection_limit: ConnectionLimiter::new(&self.limits),
+            shaper,
+            virtual_hosts,
+            credentials: self.credentials,
+            timeouts: self.timeouts,
+            tcp: self.tcp,
+            max_requests: self.max_requests,
+            via: self.via,
+            flow_id_header: self.flow_id_header,
+            forwarded: self.forwarded,
+            grpc: Descriptors::load(&self.grpc)?,
+            ca_portal,
+            metrics,
+            connections: Arc::default(),
+            shutdown: Notify::new(),
+            offline,
+        };
+
+        let mut listeners = vec![ListenerConfig {
+            tls: self.listen_tls,
+            ..ListenerConfig::new(self.listen, self.mode)
+        }];
+        listeners.extend(self.listeners);
+        let server = Server::bind(listeners, &self.tls_policy, self.acceptors, context).await?;
+        if let Some(addr) = self.admin_listen {
+            admin::serve(&addr, self.admin_token, server.context().clone()).await?;
+        }
+        if let (Some(addr), Some(capture)) = (self.web_listen, self.capture) {
+            WebUi::new(capture)
+                .serve(addr, server.context().clone())
+                .await?;
+        }
+
+        Ok(server)
+    }
+
+    fn resolver_from_config(config: &DnsConfig) -> Result<Arc<dyn Resolver>, Error> {
+        if config.protocol != DnsProtocol::System && config.servers.is_empty() {
+            return Err(Error::InvalidConfigError("dns.servers is required"));
+        }
+        let server_name = || {
+            config
+                .server_name
+                .clone()
+                .ok_or(Error::InvalidConfigError("dns.server_name is required"))
+        };
+
+        let dns = match config.protocol {
+            DnsProtocol::System => DnsResolver::system_with_cache(config.cache_size)?,
+            DnsProtocol::Tls => {
+                DnsResolver::tls(&config.servers, server_name()?, config.cache_size)?
+            }
+            DnsProtocol::Https => {
+                DnsResolver::https(&config.servers, server_name()?, config.cache_size)?
+            }
+        };
+        let mut resolver: Arc<dyn Resolver> = Arc::new(dns);
+
+        if !config.hosts.is_empty() {
+            let mut hosts = StaticResolver::new(resolver);
+            for (pattern, addrs) in &config.hosts {
+                hosts = hosts.host(HostPattern::new(pattern)?, addrs.clone());
+            }
+            resolver = Arc::new(hosts);
+        }
+
+        Ok(resolver)
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa,
+    KeyUsagePurpose,
+};
+use tracing::warn;
+
+use crate::error::Error;
+
+const CA_VALIDITY: Duration = Duration::from_secs(3600 * 24 * 3650);
+const CA_CERT_FILE: &str = "root.crt";
+const CA_KEY_FILE: &str = "key.pem";
+// Looked at first when no CA is configured.
+const LOCAL_CA_DIR: &str = "cert";
+
+pub struct CertificateAuthority {
+    pub cert: String,
+    pub key: String,
+}
+
+impl CertificateAuthority {
+    pub fn generate() -> Result<Self, Error> {
+        let mut params = CertificateParams::default();
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        params.not_before = time::OffsetDateTime::now_utc();
+        params.not_after = time::OffsetDateTime::now_utc().add(CA_VALIDITY);
+
+        let mut d_name = DistinguishedName::new();
+        d_name.push(DnType::OrganizationName, "Yaler");
+        d_name.push(DnType::CommonName, "Yaler Root CA");
+        params.distinguished_name = d_name;
+
+        let cert = Certificate::from_params(params)?;
+
+        Ok(Self {
+            cert: cert.serialize_pem()?,
+            key: cert.serialize_private_key_pem(),
+        })
+    }
+
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            cert: fs::read_to_string(cert).map_err(Error::ReadFileError)?,
+            key: fs::read_to_string(key).map_err(Error::ReadFileError)?,
+        })
+    }
+
+    // Paths given are loaded as they are. Without any, falls back to the CA under the config
+    // directory, creating it on first run.
+    pub fn load_or_generate(cert: Option<&Path>, key: Option<&Path>) -> Result<Self, Error> {
+        let (local_cert, local_key) = Self::paths_in(Path::new(LOCAL_CA_DIR));
+        if cert.is_some() || key.is_some() {
+            return Self::load(cert.unwrap_or(&local_cert), key.unwrap_or(&local_key));
+        }
+        if local_cert.exists() && local_key.exists() {
+            return Self::load(&local_cert, &local_key);
+        }
+
+        let (cert, key) = Self::default_paths();
+        if cert.exists() && key.exists() {
+            return Self::load(&cert, &key);
+        }
+
+        let ca = Self::generate()?;
+        ca.save(&cert, &key)?;
+        warn!(
+            "Generated a new CA at {}\n{}",
+            cert.display(),
+            Self::install_instructions(&cert)
+        );
+
+        Ok(ca)
+    }
+
+    pub fn save(&self, cert: &Path, key: &Path) -> Result<(), Error> {
+        for path in [cert, key] {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(Error::WriteFileError)?;
+            }
+        }
+
+        write_pair(cert, self.cert.as_bytes(), key, self.key.as_bytes())
+            .map_err(Error::WriteFileError)
+    }
+
+    pub fn default_dir() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("yaler")
+    }
+
+    pub fn default_paths() -> (PathBuf, PathBuf) {
+        Self::paths_in(&Self::default_dir())
+    }
+
+    pub fn paths_in(dir: &Path) -> (PathBuf, PathBuf) {
+        (dir.join(CA_CERT_FILE), dir.join(CA_KEY_FILE))
+    }
+
+    pub fn install_instructions(cert: &Path) -> String {
+        let cert = cert.display();
+
+        if cfg!(target_os = "macos") {
+            format!(
+                "Trust the CA with:\n  sudo security add-trusted-cert -d -r trustRoot -k /Library/Keychains/System.keychain {}",
+                cert
+            )
+        } else if cfg!(target_os = "windows") {
+            format!("Trust the CA with:\n  certutil -addstore -f ROOT {}", cert)
+        } else {
+            format!(
+                "Trust the CA with:\n  Debian/Ubuntu: sudo cp {0} /usr/local/share/ca-certificates/yaler.crt && sudo update-ca-certificates\n  Fedora/RHEL:   sudo cp {0} /etc/pki/ca-trust/source/anchors/yaler.crt && sudo update-ca-trust\nFirefox keeps its own store, import {0} under Settings > Certificates.",
+                cert
+            )
+        }
+    }
+}
+
+// Replaces a certificate and its key, neither of them left half written.
+pub(crate) fn write_pair(
+    cert: &Path,
+    cert_pem: &[u8],
+    key: &Path,
+    key_pem: &[u8],
+) -> io::Result<()> {
+    let tmp_cert = write_temp(cert, cert_pem, false)?;
+    let tmp_key = write_temp(key, key_pem, true)?;
+    fs::rename(tmp_key, key)?;
+    fs::rename(tmp_cert, cert)
+}
+
+// Replaces a file only its owner may read.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = write_temp(path, contents, true)?;
+    fs::rename(tmp, path)
+}
+
+// Writes `contents` beside `path`, to be renamed over it once everything that goes with it is
+// written too. Private files are readable by their owner alone from the start, never under the
+// umask first.
+fn write_temp(path: &Path, contents: &[u8], private: bool) -> io::Result<PathBuf> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // Left over from a crash, and in the way of create_new.
+    let _ = fs::remove_file(&tmp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    if private {
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    Ok(tmp)
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use http::{HeaderMap, Request, Response};
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, enabled, warn, Level};
+
+use crate::encoding;
+use crate::flow::{Direction, FlowEvent, FlowRequest, FlowResponse, FlowSummary};
+use crate::grpc::GrpcMessage;
+use crate::http as http_ext;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::redact::Redactor;
+use crate::view::BodyView;
+
+// Oldest flows are forgotten once this many are kept.
+const MAX_FLOWS: usize = 1000;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+// Of a streaming gRPC call, only the first messages are kept.
+const MAX_GRPC_MESSAGES: usize = 100;
+const UPDATE_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct CapturedFlow {
+    pub request: Option<FlowRequest>,
+    pub request_body: Option<Bytes>,
+    pub request_truncated: bool,
+    pub response: Option<FlowResponse>,
+    pub response_body: Option<Bytes>,
+    pub response_truncated: bool,
+    pub messages: usize,
+    pub grpc_messages: Vec<(Direction, GrpcMessage)>,
+    pub error: Option<String>,
+    pub summary: Option<FlowSummary>,
+}
+
+impl CapturedFlow {
+    // Rebuilds the request as the client sent it, ready to be sent again. Not when only part of
+    // its body was kept.
+    pub fn to_request(&self) -> Option<Request<Body>> {
+        let flow = self.request.as_ref().filter(|_| !self.request_truncated)?;
+
+        let mut req = Request::new(Body::from(self.request_body.clone().unwrap_or_default()));
+        *req.method_mut() = flow.method.clone();
+        *req.uri_mut() = flow.uri.clone();
+        *req.headers_mut() = flow.headers.clone();
+
+        Some(req)
+    }
+
+    // Bodies with their content encoding undone where it can be, for inspection.
+    pub fn decoded_request_body(&self) -> Option<Bytes> {
+        let headers = self.request.as_ref().map(|req| &req.headers);
+        self.request_body
+            .as_ref()
+            .map(|body| decoded(headers, body))
+    }
+
+    pub fn decoded_response_body(&self) -> Option<Bytes> {
+        let headers = self.response.as_ref().map(|res| &res.headers);
+        self.response_body
+            .as_ref()
+            .map(|body| decoded(headers, body))
+    }
+
+    pub fn request_view(&self) -> Option<BodyView> {
+        let headers = self.request.as_ref().map(|req| &req.headers);
+        self.decoded_request_body().map(|body| view(headers, &body))
+    }
+
+    pub fn response_view(&self) -> Option<BodyView> {
+        let headers = self.response.as_ref().map(|res| &res.headers);
+        self.decoded_response_body()
+            .map(|body| view(headers, &body))
+    }
+}
+
+// Truncated bodies rarely decode, and are kept as captured.
+fn decoded(headers: Option<&HeaderMap>, body: &Bytes) -> Bytes {
+    let decoded = headers.and_then(|headers| encoding::decode(headers, body));
+    decoded.map_or_else(|| body.clone(), Bytes::from)
+}
+
+// Bodies go to the flow log as they would be shown in the UI, at debug level.
+fn log_body(id: u64, direction: Direction, headers: &HeaderMap, body: &Bytes) {
+    if body.is_empty() || !enabled!(target: "yaler::flow", Level::DEBUG) {
+        return;
+    }
+    let view = view(Some(headers), &decoded(Some(headers), body));
+    debug!(target: "yaler::flow", id, ?direction, view = view.kind.name(), "\n{}", view.text);
+}
+
+fn view(headers: Option<&HeaderMap>, body: &[u8]) -> BodyView {
+    match headers {
+        Some(headers) => BodyView::new(headers, body),
+        None => BodyView::new(&HeaderMap::new(), body),
+    }
+}
+
+#[derive(Default)]
+struct Flows {
+    order: VecDeque<u64>,
+    map: HashMap<u64, CapturedFlow>,
+}
+
+impl Flows {
+    fn entry(&mut self, id: u64) -> &mut CapturedFlow {
+        if !self.map.contains_key(&id) {
+            self.order.push_back(id);
+            if self.order.len() > MAX_FLOWS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.map.remove(&oldest);
+                }
+            }
+        }
+
+        self.map.entry(id).or_default()
+    }
+}
+
+struct Inner {
+    flows: Mutex<Flows>,
+    updates: broadcast::Sender<u64>,
+}
+
+// Keeps the most recent flows with their bodies for inspection and resending.
+#[derive(Clone)]
+pub struct FlowCapture(Arc<Inner>, Redactor);
+
+impl FlowCapture {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(UPDATE_CHANNEL_CAPACITY);
+
+        Self(
+            Arc::new(Inner {
+                flows: Mutex::new(Flows::default()),
+                updates,
+            }),
+            Redactor::default(),
+        )
+    }
+
+    // Masks the bodies kept from here on. Events arrive already redacted.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.1 = redactor;
+        self
+    }
+
+    // Ids of flows as they change.
+    pub fn subscribe(&self) -> broadcast::Receiver<u64> {
+        self.0.updates.subscribe()
+    }
+
+    pub fn get(&self, id: u64) -> Option<CapturedFlow> {
+        self.0.flows.lock().unwrap().map.get(&id).cloned()
+    }
+
+    // Oldest first.
+    pub fn ids(&self) -> Vec<u64> {
+        self.0.flows.lock().unwrap().order.iter().copied().collect()
+    }
+
+    pub(crate) fn spawn(&self, events: broadcast::Receiver<FlowEvent>) {
+        tokio::spawn(self.clone().record(events));
+    }
+
+    async fn record(self, mut events: broadcast::Receiver<FlowEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Flow capture fell behind");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let id = {
+                let mut flows = self.0.flows.lock().unwrap();
+                match event {
+                    FlowEvent::Request(req) => {
+                        let id = req.id;
+                        flows.entry(id).request = Some(req);
+                        id
+                    }
+                    FlowEvent::Response(res) => {
+                        let id = res.id;
+                        flows.entry(id).response = Some(res);
+                        id
+                    }
+                    FlowEvent::WebSocketMessage { id, .. }
+                    | FlowEvent::ServerSentEvent { id, .. } => {
+                        flows.entry(id).messages += 1;
+                        id
+                    }
+                    FlowEvent::GrpcMessage {
+                        id,
+                        direction,
+                        message,
+                    } => {
+                        let flow = flows.entry(id);
+                        flow.messages += 1;
+                        if flow.grpc_messages.len() < MAX_GRPC_MESSAGES {
+                            flow.grpc_messages.push((direction, message));
+                        }
+                        id
+                    }
+                    FlowEvent::Error { id, error } => {
+                        flows.entry(id).error = Some(error);
+                        id
+                    }
+                    FlowEvent::Complete(summary) => {
+                        let id = summary.id;
+                        flows.entry(id).summary = Some(summary);
+                        id
+                    }
+                }
+            };
+
+            let _ = self.0.updates.send(id);
+        }
+    }
+}
+
+impl Default for FlowCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Interceptor for FlowCapture {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        if http_ext::is_stream(req.headers()) {
+            return RequestAction::Forward(req);
+        }
+        let (parts, body) = req.into_parts();
+        self.0.flows.lock().unwrap().entry(flow.id);
+
+        let capture = self.clone();
+        let id = flow.id;
+        let headers = parts.headers.clone();
+        let body = tee(body, MAX_BODY_SIZE, move |captured, size| {
+            let truncated = size > captured.len() as u64;
+            let captured = capture.1.body(&headers, captured);
+            log_body(id, Direction::Upstream, &headers, &captured);
+            if let Some(flow) = capture.0.flows.lock().unwrap().map.get_mut(&id) {
+                flow.request_body = Some(captured);
+                flow.request_truncated = truncated;
+            }
+            let _ = capture.0.updates.send(id);
+        });
+
+        RequestAction::Forward(Request::from_parts(parts, body))
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        let (parts, body) = res.into_parts();
+
+        let capture = self.clone();
+        let id = flow.id;
+        let headers = parts.headers.clone();
+        let body = tee(body, MAX_BODY_SIZE, move |captured, size| {
+            let truncated = size > captured.len() as u64;
+            let captured = capture.1.body(&headers, captured);
+            log_body(id, Direction::Downstream, &headers, &captured);
+            if let Some(flow) = capture.0.flows.lock().unwrap().map.get_mut(&id) {
+                flow.response_body = Some(captured);
+                flow.response_truncated = truncated;
+            }
+            let _ = capture.0.updates.send(id);
+        });
+
+        ResponseAction::Forward(Response::from_parts(parts, body))
+    }
+}
+
+// Copies up to `limit` bytes of a body aside while it streams on, so slow responses are not
+// held back. `done` gets them with the size of the whole body, and only runs if the body was
+// read to the end.
+pub(crate) fn tee<F>(mut body: Body, limit: usize, done: F) -> Body
+where
+    F: FnOnce(Bytes, u64) + Send + 'static,
+{
+    let (mut sender, tee) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut captured = Vec::new();
+        let mut size = 0;
+
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    sender.abort();
+                    return;
+                }
+            };
+
+            let room = limit.saturating_sub(captured.len());
+            size += chunk.len() as u64;
+            captured.extend_from_slice(&chunk[..chunk.len().min(room)]);
+
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+
+        done(Bytes::from(captured), size);
+    });
+
+    tee
+}
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use tracing::Level;
+
+use yaler::{Config, ExportFormat, ListenAddr, Mode};
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(long, env = "YALER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[clap(long, env = "YALER_LISTEN")]
+    pub listen: Option<ListenAddr>,
+
+    #[clap(long, env = "YALER_MODE")]
+    pub mode: Option<Mode>,
+
+    #[clap(long, env = "YALER_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+
+    #[clap(long, env = "YALER_CA_KEY")]
+    pub ca_key: Option<PathBuf>,
+
+    #[clap(long, env = "YALER_CERT_STORE")]
+    pub cert_store: Option<PathBuf>,
+
+    #[clap(long, env = "YALER_LOG_LEVEL", default_value = "info")]
+    pub log_level: Level,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    InitCa {
+        #[clap(long)]
+        dir: Option<PathBuf>,
+
+        #[clap(long)]
+        force: bool,
+    },
+    /// Run the proxy with an interactive flow viewer instead of logging to the terminal.
+    Tui,
+    /// Inspect flows persisted by the `storage` backend.
+    Flows {
+        #[clap(subcommand)]
+        command: FlowsCommand,
+    },
+    /// Send recorded flows again and report how the responses differ.
+    Replay(ReplayArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ReplayArgs {
+    /// Replay the entries of a HAR file.
+    #[clap(long, conflicts_with = "flow")]
+    pub har: Option<PathBuf>,
+
+    /// Only replay this HAR entry, counting from 0.
+    #[clap(long, requires = "har")]
+    pub entry: Option<usize>,
+
+    /// Replay a stored flow, as `SESSION:ID` from `flows query`.
+    #[clap(long, required_unless_present = "har")]
+    pub flow: Option<String>,
+
+    /// Database to read, defaults to `storage.path` from the config.
+    #[clap(long)]
+    pub db: Option<PathBuf>,
+
+    /// Set a request header, as `Name: value`.
+    #[clap(long = "header", short = 'H')]
+    pub headers: Vec<String>,
+
+    #[clap(long = "remove-header")]
+    pub remove_headers: Vec<String>,
+
+    /// Replace the request body.
+    #[clap(long, conflicts_with = "body-file")]
+    pub body: Option<String>,
+
+    #[clap(long)]
+    pub body_file: Option<PathBuf>,
+
+    /// Leave a response header out of the diff, e.g. `date`.
+    #[clap(long = "ignore-header")]
+    pub ignore_headers: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FlowsCommand {
+    Query {
+        /// Database to read, defaults to `storage.path` from the config.
+        #[clap(long)]
+        db: Option<PathBuf>,
+
+        /// Host name, `*` matches any characters.
+        #[clap(long)]
+        host: Option<String>,
+
+        #[clap(long)]
+        status: Option<u16>,
+
+        #[clap(long)]
+        method: Option<String>,
+
+        #[clap(long, default_value = "100")]
+        limit: u64,
+    },
+    /// Print a stored request as a command or `.http` file that sends it again.
+    Export {
+        /// Database to read, defaults to `storage.path` from the config.
+        #[clap(long)]
+        db: Option<PathBuf>,
+
+        /// The flow, as `SESSION:ID` from `flows query`.
+        #[clap(long)]
+        id: String,
+
+        /// `curl`, `httpie` or `http`.
+        #[clap(long, default_value = "curl")]
+        format: ExportFormat,
+    },
+    /// Store the HTTP flows of a mitmproxy dump file as a new session.
+    Import {
+        /// Database to write, defaults to `storage.path` from the config.
+        #[clap(long)]
+        db: Option<PathBuf>,
+
+        /// A file written by `mitmdump -w` or saved from mitmproxy.
+        file: PathBuf,
+    },
+    /// Write stored flows to a file mitmproxy can open.
+    Dump {
+        /// Database to read, defaults to `storage.path` from the config.
+        #[clap(long)]
+        db: Option<PathBuf>,
+
+        file: PathBuf,
+
+        /// Host name, `*` matches any characters.
+        #[clap(long)]
+        host: Option<String>,
+
+        #[clap(long)]
+        status: Option<u16>,
+
+        #[clap(long)]
+        method: Option<String>,
+
+        #[clap(long)]
+        limit: Option<u64>,
+    },
+}
+
+impl Args {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+        if let Some(mode) = self.mode {
+            config.mode = mode;
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            config.ca_cert = Some(ca_cert.clone());
+        }
+        if let Some(ca_key) = &self.ca_key {
+            config.ca_key = Some(ca_key.clone());
+        }
+        if let Some(cert_store) = &self.cert_store {
+            config.cert_store = Some(cert_store.clone());
+        }
+    }
+}
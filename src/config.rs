@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::acceptor::{CertCacheConfig, LeafParams};
+use crate::access_log::AccessLog;
+use crate::acl::AclConfig;
+use crate::acme::AcmeConfig;
+use crate::blocklist::BlocklistConfig;
+use crate::dialer::OutboundBind;
+use crate::error::Error;
+use crate::fault::FaultConfig;
+use crate::forwarded::ForwardedConfig;
+use crate::grpc::GrpcConfig;
+use crate::har::HarConfig;
+use crate::http::DEFAULT_VIA;
+use crate::limit::{RateLimitConfig, ResourceLimits};
+use crate::listen::{AcceptorsConfig, ListenAddr};
+use crate::pac::PacConfig;
+use crate::plugin::PluginConfig;
+use crate::redact::RedactionConfig;
+use crate::resolver::{DnsProtocol, DEFAULT_CACHE_SIZE};
+use crate::reverse::VirtualHostConfig;
+use crate::rules::RuleConfig;
+use crate::shaping::ShapingConfig;
+use crate::storage::StorageConfig;
+use crate::tcp::TcpTuning;
+use crate::telemetry::OtlpConfig;
+use crate::timeout::Timeouts;
+use crate::tls_policy::TlsPolicy;
+use crate::upstream_tls::UpstreamTlsConfig;
+use crate::vcr::VcrConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Http,
+    Socks5,
+    Transparent,
+    // Terminates TLS for `virtual_hosts` and routes them to their backends.
+    Reverse,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Mode::Http),
+            "socks5" => Ok(Mode::Socks5),
+            "transparent" => Ok(Mode::Transparent),
+            "reverse" => Ok(Mode::Reverse),
+            _ => Err(Error::BadRequestError("Unknown mode")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: ListenAddr,
+    pub mode: Mode,
+    // Serves the `listen` listener over TLS, as a secure web proxy.
+    pub listen_tls: Option<ListenerTls>,
+    // Bound next to `listen`, sharing its certificates, connections and interceptors.
+    pub listeners: Vec<ListenerConfig>,
+    pub acceptors: AcceptorsConfig,
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    pub acme: Option<AcmeConfig>,
+    // When neither is set, cert/root.crt and cert/key.pem, or else the CA under the config
+    // directory, made on first run. Paths set here have to exist.
+    pub ca_cert: Option<PathBuf>,
+    pub ca_key: Option<PathBuf>,
+    // PEM bundle of the CAs above `ca_cert` when that is an intermediate, shown after each leaf.
+    pub ca_chain: Option<PathBuf>,
+    pub cert_store: Option<PathBuf>,
+    pub ca_domain: Option<String>,
+    pub leaf: LeafParams,
+    pub cert_cache: CertCacheConfig,
+    // PEM bundle of CAs whose client certificates are required on intercepted TLS connections.
+    pub client_ca: Option<PathBuf>,
+    // Versions and cipher suites offered to clients; upstreams have theirs in `upstream_tls`.
+    pub tls: TlsPolicy,
+    pub timeouts: Timeouts,
+    pub max_requests_per_connection: Option<usize>,
+    // Pseudonym in the `Via` header added to proxied messages.
+    pub via: String,
+    // Adds no `Via` header.
+    pub stealth: bool,
+    // Adds `X-Yaler-Flow-Id` to requests sent upstream and responses sent back.
+    pub flow_id_header: bool,
+    pub forwarded: ForwardedConfig,
+    #[serde(with = "humantime_serde")]
+    pub happy_eyeballs_delay: Option<Duration>,
+    // Where direct connections to origins go out from; those to upstream proxies are left alone.
+    pub outbound_bind: Option<OutboundBind>,
+    pub tcp: TcpTuning,
+    pub dns: DnsConfig,
+    pub access_log: Option<AccessLog>,
+    pub har: Option<HarConfig>,
+    pub storage: Option<StorageConfig>,
+    pub vcr: Option<VcrConfig>,
+    pub rules: Vec<RuleConfig>,
+    // `.toml` files with `[[rules]]` of their own, applied after `rules` in file name order.
+    pub rules_dir: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub plugins: Option<PluginConfig>,
+    pub grpc: GrpcConfig,
+    pub redact: RedactionConfig,
+    pub key_log_file: Option<PathBuf>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub admin_listen: Option<ListenAddr>,
+    // When set, admin requests carry it as `Authorization: Bearer <token>`.
+    pub admin_token: Option<String>,
+    pub web_listen: Option<SocketAddr>,
+    pub otlp: Option<OtlpConfig>,
+    pub upstream_proxy: Option<String>,
+    pub upstream_routes: Vec<UpstreamRoute>,
+    pub upstream_tls: UpstreamTlsConfig,
+    pub bypass: Vec<String>,
+    pub pac: Option<PacConfig>,
+    pub blocklist: Option<BlocklistConfig>,
+    pub acl: AclConfig,
+    pub rate_limit: RateLimitConfig,
+    pub limits: ResourceLimits,
+    pub shaping: Vec<ShapingConfig>,
+    pub faults: Vec<FaultConfig>,
+    pub users: HashMap<String, String>,
+    pub users_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub listen: ListenAddr,
+    #[serde(default)]
+    pub mode: Mode,
+    // Asks clients for the configured `users`; off leaves this listener open to anyone the ACL
+    // lets in.
+    #[serde(default = "ListenerConfig::enabled")]
+    pub auth: bool,
+    // Intercepts TLS unless bypassed; off tunnels every host as it is.
+    #[serde(default = "ListenerConfig::enabled")]
+    pub intercept: bool,
+    // Only for http mode.
+    pub tls: Option<ListenerTls>,
+}
+
+// The PEM certificate chain and key a listener shows its clients.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ListenerConfig {
+    pub fn new(listen: ListenAddr, mode: Mode) -> Self {
+        Self {
+            listen,
+            mode,
+            auth: true,
+            intercept: true,
+            tls: None,
+        }
+    }
+
+    fn enabled() -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamRoute {
+    pub hosts: Vec<String>,
+    pub proxy: String,
+    // Only for direct routes, over `outbound_bind`.
+    #[serde(default)]
+    pub bind: Option<OutboundBind>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub protocol: DnsProtocol,
+    pub servers: Vec<IpAddr>,
+    pub server_name: Option<String>,
+    pub cache_size: usize,
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            protocol: DnsProtocol::default(),
+            servers: Vec::new(),
+            server_name: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadFileError)?;
+
+        let mut config: Config = toml::from_str(&content)?;
+        if let Some(dir) = &config.rules_dir {
+            for file in Self::rules_files(dir)? {
+                let content = std::fs::read_to_string(file).map_err(Error::ReadFileError)?;
+                let file: RulesFile = toml::from_str(&content)?;
+                config.rules.extend(file.rules);
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub(crate) fn rules_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut files = std::fs::read_dir(dir)
+            .map_err(Error::ReadFileError)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "toml")
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 5333))),
+            mode: Mode::default(),
+            listen_tls: None,
+            listeners: Vec::new(),
+            acceptors: AcceptorsConfig::default(),
+            virtual_hosts: Vec::new(),
+            acme: None,
+            ca_cert: None,
+            ca_key: None,
+            ca_chain: None,
+            cert_store: None,
+            ca_domain: None,
+            leaf: LeafParams::default(),
+            cert_cache: CertCacheConfig::default(),
+            client_ca: None,
+            tls: TlsPolicy::default(),
+            timeouts: Timeouts::default(),
+            max_requests_per_connection: None,
+            via: DEFAULT_VIA.to_string(),
+            stealth: false,
+            flow_id_header: false,
+            forwarded: ForwardedConfig::default(),
+            happy_eyeballs_delay: None,
+            outbound_bind: None,
+            tcp: TcpTuning::default(),
+            dns: DnsConfig::default(),
+            access_log: None,
+            har: None,
+            storage: None,
+            vcr: None,
+            rules: Vec::new(),
+            rules_dir: None,
+            script: None,
+            plugins: None,
+            grpc: GrpcConfig::default(),
+            redact: RedactionConfig::default(),
+            key_log_file: None,
+            metrics_listen: None,
+            admin_listen: None,
+            admin_token: None,
+            web_listen: None,
+            otlp: None,
+            upstream_proxy: None,
+            upstream_routes: Vec::new(),
+            upstream_tls: UpstreamTlsConfig::default(),
+            bypass: Vec::new(),
+            pac: None,
+            blocklist: None,
+            acl: AclConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: ResourceLimits::default(),
+            shaping: Vec::new(),
+            faults: Vec::new(),
+            users: HashMap::new(),
+            users_file: None,
+        }
+    }
+}
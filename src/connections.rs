@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::sync::Notify;
+use ulid::Ulid;
+
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: Ulid,
+    pub(crate) peer: SocketAddr,
+    pub(crate) target: Option<String>,
+    pub(crate) started: SystemTime,
+}
+
+// A client connection being served: where it comes from, and its own entry in the registry.
+// Addresses do not tell connections apart, as clients without one share made-up ones.
+#[derive(Clone, Copy)]
+pub(crate) struct Peer {
+    pub(crate) addr: SocketAddr,
+    pub(crate) connection: Ulid,
+}
+
+impl Peer {
+    pub(crate) fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+}
+
+// Logged as the address; spans carry the connection already.
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.addr, f)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.addr, f)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Connections {
+    active: Mutex<HashMap<Ulid, ConnectionInfo>>,
+    idle: Notify,
+}
+
+impl Connections {
+    pub(crate) fn register(self: &Arc<Self>, peer: SocketAddr) -> ConnectionGuard {
+        let id = Ulid::new();
+        self.active.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                id,
+                peer,
+                target: None,
+                started: SystemTime::now(),
+            },
+        );
+
+        ConnectionGuard {
+            connections: self.clone(),
+            peer: Peer {
+                addr: peer,
+                connection: id,
+            },
+        }
+    }
+
+    pub(crate) fn set_target(&self, peer: Peer, target: &str) {
+        if let Some(info) = self.active.lock().unwrap().get_mut(&peer.connection) {
+            info.target = Some(target.to_string());
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        self.active.lock().unwrap().values().cloned().collect()
+    }
+
+    pub(crate) async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.active.lock().unwrap().is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    pub(crate) peer: Peer,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.connections.active.lock().unwrap();
+        active.remove(&self.peer.connection);
+        if active.is_empty() {
+            self.connections.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_from_one_address_stay_apart() {
+        let connections = Arc::new(Connections::default());
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let first = connections.register(addr);
+        let second = connections.register(addr);
+        connections.set_target(first.peer, "first.test");
+        connections.set_target(second.peer, "second.test");
+
+        let mut targets: Vec<_> = connections
+            .list()
+            .into_iter()
+            .map(|info| info.target)
+            .collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            [
+                Some("first.test".to_string()),
+                Some("second.test".to_string())
+            ]
+        );
+
+        drop(first);
+        let left = connections.list();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, second.peer.connection);
+    }
+}
@@ -0,0 +1,603 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::uri::Scheme;
+use http::Uri;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use rustls::ServerName;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio_rustls::client::TlsStream;
+
+use crate::error::Error;
+use crate::http::{self as http_ext, ALPN_H2};
+use crate::policy::HostPattern;
+use crate::resolver::Resolver;
+use crate::tcp::TcpTuning;
+use crate::timeout::with_timeout;
+use crate::upstream::UpstreamProxy;
+use crate::upstream_tls::UpstreamConnectors;
+
+pub fn from_url(url: &str, direct: &DirectDialer) -> Result<Arc<dyn Dialer>, Error> {
+    if url == "direct" {
+        Ok(Arc::new(direct.clone()))
+    } else if url.starts_with("socks5://") || url.starts_with("socks5h://") {
+        Ok(Arc::new(Socks5Dialer::parse(url)?.tcp(direct.tcp)))
+    } else {
+        Ok(Arc::new(UpstreamProxy::parse(url)?.tcp(direct.tcp)))
+    }
+}
+
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error>;
+}
+
+// A connection to a remote as dialers hand it over: a socket, or one end of an in-memory pipe
+// for dialers that stand in for the network.
+#[derive(Debug)]
+pub enum RemoteStream {
+    Tcp(TcpStream),
+    Memory(DuplexStream),
+    // A socket some of whose bytes were already read while setting it up, such as what an
+    // upstream proxy sent along with its reply to CONNECT. They are read again first.
+    Prefixed(Vec<u8>, TcpStream),
+}
+
+impl From<TcpStream> for RemoteStream {
+    fn from(stream: TcpStream) -> Self {
+        RemoteStream::Tcp(stream)
+    }
+}
+
+impl From<DuplexStream> for RemoteStream {
+    fn from(stream: DuplexStream) -> Self {
+        RemoteStream::Memory(stream)
+    }
+}
+
+impl AsyncRead for RemoteStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            RemoteStream::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+            RemoteStream::Prefixed(prefix, _) if !prefix.is_empty() => {
+                let len = prefix.len().min(buf.remaining());
+                buf.put_slice(&prefix[..len]);
+                prefix.drain(..len);
+                Poll::Ready(Ok(()))
+            }
+            RemoteStream::Prefixed(_, stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RemoteStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RemoteStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            RemoteStream::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+            RemoteStream::Prefixed(_, stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            RemoteStream::Memory(stream) => Pin::new(stream).poll_flush(cx),
+            RemoteStream::Prefixed(_, stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            RemoteStream::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+            RemoteStream::Prefixed(_, stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// RFC 8305 recommends 250ms between connection attempts.
+const DEFAULT_FALLBACK_DELAY: Duration = Duration::from_millis(250);
+
+// The local end of direct connections, for hosts with more than one uplink: an address of the
+// host, a network interface, or both.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OutboundBind {
+    pub address: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+impl OutboundBind {
+    fn apply(&self, socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+        if let Some(address) = self.address {
+            socket.bind(&SocketAddr::new(address, 0).into())?;
+        }
+        if let Some(interface) = &self.interface {
+            bind_interface(socket, interface, addr)?;
+        }
+        Ok(())
+    }
+
+    // An address of one family cannot reach those of the other.
+    fn reaches(&self, addr: &SocketAddr) -> bool {
+        self.address
+            .is_none_or(|address| address.is_ipv4() == addr.is_ipv4())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_interface(socket: &Socket, interface: &str, _: SocketAddr) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(target_os = "macos")]
+fn bind_interface(socket: &Socket, interface: &str, addr: SocketAddr) -> io::Result<()> {
+    let index = nix::net::if_::if_nametoindex(interface).map_err(io::Error::from)?;
+    let index = std::num::NonZeroU32::new(index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown interface"))?;
+    if addr.is_ipv4() {
+        socket.bind_device_by_index_v4(Some(index))
+    } else {
+        socket.bind_device_by_index_v6(Some(index))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn bind_interface(_: &Socket, _: &str, _: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is not supported on this platform",
+    ))
+}
+
+#[derive(Clone)]
+pub struct DirectDialer {
+    fallback_delay: Duration,
+    resolver: Option<Arc<dyn Resolver>>,
+    bind: Option<OutboundBind>,
+    tcp: TcpTuning,
+}
+
+impl DirectDialer {
+    pub fn new() -> Self {
+        Self {
+            fallback_delay: DEFAULT_FALLBACK_DELAY,
+            resolver: None,
+            bind: None,
+            tcp: TcpTuning::default(),
+        }
+    }
+
+    pub fn tcp(mut self, tcp: TcpTuning) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub fn bind(mut self, bind: OutboundBind) -> Self {
+        self.bind = Some(bind);
+        self
+    }
+
+    pub fn fallback_delay(mut self, delay: Duration) -> Self {
+        self.fallback_delay = delay;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        match &self.resolver {
+            Some(resolver) => Ok(resolver
+                .resolve(host)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect()),
+            None => Ok(lookup_host((host, port))
+                .await
+                .map_err(Error::TcpConnectError)?
+                .collect()),
+        }
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        bind: Option<OutboundBind>,
+        tcp: TcpTuning,
+    ) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(bind) = bind {
+            bind.apply(&socket, addr)?;
+        }
+        tcp.apply(SockRef::from(&socket))?;
+        socket.set_nonblocking(true)?;
+
+        TcpSocket::from_std_stream(socket.into())
+            .connect(addr)
+            .await
+    }
+
+    fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+
+        let mut addrs = Vec::new();
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => return addrs,
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+}
+
+impl Default for DirectDialer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error> {
+        let mut addrs = self.lookup(host, port).await?;
+        if let Some(bind) = &self.bind {
+            addrs.retain(|addr| bind.reaches(addr));
+        }
+        let mut addrs = Self::interleave(addrs).into_iter();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut attempts = 0;
+        let mut last_error = None;
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                let tx = tx.clone();
+                let (bind, tcp) = (self.bind.clone(), self.tcp);
+                tokio::spawn(async move {
+                    let _ = tx.send(Self::connect(addr, bind, tcp).await);
+                });
+                attempts += 1;
+            } else if attempts == 0 {
+                return Err(Error::TcpConnectError(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "No address resolved")
+                })));
+            }
+
+            // Start the next attempt early if this one is still pending after the delay.
+            let result = if addrs.len() > 0 {
+                tokio::select! {
+                    result = rx.recv() => result,
+                    _ = sleep(self.fallback_delay) => continue,
+                }
+            } else {
+                rx.recv().await
+            };
+
+            match result {
+                Some(Ok(stream)) => return Ok(RemoteStream::Tcp(stream)),
+                Some(Err(e)) => {
+                    attempts -= 1;
+                    last_error = Some(e);
+                }
+                None => unreachable!("sender is held by the dialer"),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Socks5Dialer {
+    addr: String,
+    credentials: Option<(String, String)>,
+    tcp: TcpTuning,
+}
+
+impl Socks5Dialer {
+    pub fn new(addr: String, credentials: Option<(String, String)>) -> Self {
+        Self {
+            addr,
+            credentials,
+            tcp: TcpTuning::default(),
+        }
+    }
+
+    pub fn tcp(mut self, tcp: TcpTuning) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let uri = Uri::from_str(url).map_err(|_| Error::Socks5Error("Invalid socks5 url"))?;
+        let authority = uri
+            .authority()
+            .ok_or(Error::Socks5Error("Socks5 url without host"))?;
+
+        // Userinfo comes percent-encoded, so credentials may hold `:` or `@`.
+        let credentials = match authority
+            .as_str()
+            .rsplit_once('@')
+            .and_then(|(userinfo, _)| userinfo.split_once(':'))
+        {
+            Some((username, password)) => Some(
+                http_ext::percent_decode(username)
+                    .zip(http_ext::percent_decode(password))
+                    .ok_or(Error::Socks5Error("Invalid socks5 url"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self::new(
+            format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(1080)
+            ),
+            credentials,
+        ))
+    }
+
+    async fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+        let method = if self.credentials.is_some() {
+            0x02
+        } else {
+            0x00
+        };
+        Self::write(stream, &[0x05, 0x01, method]).await?;
+
+        let mut reply = [0u8; 2];
+        Self::read(stream, &mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(Error::Socks5Error("Unsupported authentication method"));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let too_long = |_| Error::InvalidConfigError("Socks5 credentials over 255 bytes");
+            let mut request = vec![0x01, u8::try_from(username.len()).map_err(too_long)?];
+            request.extend_from_slice(username.as_bytes());
+            request.push(u8::try_from(password.len()).map_err(too_long)?);
+            request.extend_from_slice(password.as_bytes());
+            Self::write(stream, &request).await?;
+
+            Self::read(stream, &mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(Error::Socks5Error("Authentication failed"));
+            }
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match IpAddr::from_str(host.trim_start_matches('[').trim_end_matches(']')) {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let len = u8::try_from(host.len())
+                    .map_err(|_| Error::Socks5Error("Host name too long"))?;
+                request.push(0x03);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        Self::write(stream, &request).await?;
+
+        let mut reply = [0u8; 4];
+        Self::read(stream, &mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(Error::Socks5Error("Connect request rejected"));
+        }
+
+        let bound = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0u8; 1];
+                Self::read(stream, &mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(Error::Socks5Error("Invalid bound address")),
+        };
+        let mut bound = vec![0u8; bound + 2];
+        Self::read(stream, &mut bound).await
+    }
+
+    async fn write(stream: &mut TcpStream, buf: &[u8]) -> Result<(), Error> {
+        stream.write_all(buf).await.map_err(Error::WriteStreamError)
+    }
+
+    async fn read(stream: &mut TcpStream, buf: &mut [u8]) -> Result<(), Error> {
+        stream
+            .read_exact(buf)
+            .await
+            .map(|_| ())
+            .map_err(Error::ReadStreamError)
+    }
+}
+
+#[async_trait]
+impl Dialer for Socks5Dialer {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(Error::TcpConnectError)?;
+        self.tcp
+            .apply(SockRef::from(&stream))
+            .map_err(Error::TcpConnectError)?;
+
+        self.handshake(&mut stream, host, port).await?;
+
+        Ok(RemoteStream::Tcp(stream))
+    }
+}
+
+pub struct RouteDialer {
+    routes: Vec<(HostPattern, Arc<dyn Dialer>)>,
+    fallback: Arc<dyn Dialer>,
+}
+
+impl RouteDialer {
+    pub fn new(fallback: Arc<dyn Dialer>) -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback,
+        }
+    }
+
+    pub fn route(mut self, pattern: HostPattern, dialer: Arc<dyn Dialer>) -> Self {
+        self.routes.push((pattern, dialer));
+        self
+    }
+}
+
+#[async_trait]
+impl Dialer for RouteDialer {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error> {
+        let dialer = self
+            .routes
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+            .map_or(&self.fallback, |(_, dialer)| dialer);
+
+        dialer.dial(host, port).await
+    }
+}
+
+// Connects the pooled client to origins, over TLS for https ones.
+#[derive(Clone)]
+pub(crate) struct DialerConnector {
+    pub(crate) dialer: Arc<dyn Dialer>,
+    pub(crate) tls: Arc<UpstreamConnectors>,
+    pub(crate) handshake: Option<Duration>,
+}
+
+impl Service<Uri> for DialerConnector {
+    type Response = OriginStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<OriginStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .map(http_ext::strip_brackets)
+                .ok_or(Error::BadRequestError("Request without host"))?;
+            let https = uri.scheme() == Some(&Scheme::HTTPS);
+            let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+            let stream = connector.dialer.dial(host, port).await?;
+            if !https {
+                return Ok(OriginStream::Plain(stream));
+            }
+            let server_name = ServerName::try_from(host)
+                .map_err(|_| Error::BadRequestError("Invalid server name"))?;
+            let stream = with_timeout(connector.handshake, async {
+                connector
+                    .tls
+                    .get(host)
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(Error::TlsConnectError)
+            })
+            .await?;
+
+            Ok(OriginStream::Tls(Box::new(stream)))
+        })
+    }
+}
+
+pub(crate) enum OriginStream {
+    Plain(RemoteStream),
+    Tls(Box<TlsStream<RemoteStream>>),
+}
+
+impl Connection for OriginStream {
+    fn connected(&self) -> Connected {
+        match self {
+            OriginStream::Tls(stream) if stream.get_ref().1.alpn_protocol() == Some(ALPN_H2) => {
+                Connected::new().negotiated_h2()
+            }
+            _ => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for OriginStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OriginStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            OriginStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for OriginStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            OriginStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            OriginStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OriginStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            OriginStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OriginStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            OriginStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
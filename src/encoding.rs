@@ -0,0 +1,110 @@
+use std::io::{Read, Write};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use http::header::CONTENT_ENCODING;
+use http::HeaderMap;
+
+// Decoding stops here, so a small body cannot expand without bound.
+const MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+// Content codings in the order they were applied, leaving out `identity`.
+fn codings(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect()
+}
+
+pub(crate) fn is_encoded(headers: &HeaderMap) -> bool {
+    !codings(headers).is_empty()
+}
+
+// Undoes the content codings the headers name. None for codings that cannot be undone here, or
+// a body that does not decode.
+pub(crate) fn decode(headers: &HeaderMap, body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = body.to_vec();
+    for coding in codings(headers).iter().rev() {
+        decoded = decode_one(coding, &decoded)?;
+    }
+
+    Some(decoded)
+}
+
+fn decode_one(coding: &str, body: &[u8]) -> Option<Vec<u8>> {
+    let read = |reader: &mut dyn Read| {
+        let mut decoded = Vec::new();
+        reader
+            .take(MAX_DECODED_SIZE + 1)
+            .read_to_end(&mut decoded)
+            .ok()
+            .filter(|_| decoded.len() as u64 <= MAX_DECODED_SIZE)
+            .map(|_| decoded)
+    };
+
+    match coding {
+        "gzip" | "x-gzip" => read(&mut GzDecoder::new(body)),
+        // Some servers send raw deflate streams without the zlib wrapper.
+        "deflate" => {
+            read(&mut ZlibDecoder::new(body)).or_else(|| read(&mut DeflateDecoder::new(body)))
+        }
+        "br" => read(&mut brotli::Decompressor::new(body, BROTLI_BUFFER_SIZE)),
+        "zstd" => read(&mut zstd::stream::read::Decoder::new(body).ok()?),
+        _ => None,
+    }
+}
+
+// Applies the content codings the headers name to a plaintext body that was changed. When one
+// cannot be applied here, the body goes as identity and the header is removed.
+pub(crate) fn encode(headers: &mut HeaderMap, body: Vec<u8>) -> Vec<u8> {
+    let mut encoded = body.clone();
+    for coding in codings(headers) {
+        encoded = match encode_one(&coding, &encoded) {
+            Some(encoded) => encoded,
+            None => {
+                headers.remove(CONTENT_ENCODING);
+                return body;
+            }
+        };
+    }
+
+    encoded
+}
+
+fn encode_one(coding: &str, body: &[u8]) -> Option<Vec<u8>> {
+    match coding {
+        "gzip" | "x-gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).ok()?;
+            encoder.finish().ok()
+        }
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).ok()?;
+            encoder.finish().ok()
+        }
+        "br" => {
+            let mut encoded = Vec::new();
+            let mut encoder = brotli::CompressorWriter::new(
+                &mut encoded,
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            );
+            encoder.write_all(body).ok()?;
+            drop(encoder);
+            Some(encoded)
+        }
+        "zstd" => zstd::stream::encode_all(body, ZSTD_LEVEL).ok(),
+        _ => None,
+    }
+}
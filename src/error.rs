@@ -1,3 +1,4 @@
+use http::StatusCode;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,12 +9,27 @@ pub enum Error {
     #[error("Fail to accept client with tcp")]
     TcpAcceptError(std::io::Error),
 
+    #[error("Fail to recover original destination")]
+    OriginalDstError(std::io::Error),
+
+    #[error("Fail to resolve host")]
+    ResolveError(trust_dns_resolver::error::ResolveError),
+
     #[error("Fail to connect remote with tcp")]
     TcpConnectError(std::io::Error),
 
     #[error("Fail to accept client with tls")]
     TlsAcceptError(std::io::Error),
 
+    #[error("Upstream proxy refused connection with {0}")]
+    UpstreamProxyError(StatusCode),
+
+    #[error("Proxy refused tunnel with {0}")]
+    TunnelRefusedError(StatusCode),
+
+    #[error("Socks5 error: {0}")]
+    Socks5Error(&'static str),
+
     #[error("Fail to connect remote with tls")]
     TlsConnectError(std::io::Error),
 
@@ -29,6 +45,124 @@ pub enum Error {
     #[error("Stream returned error")]
     WriteStreamError(std::io::Error),
 
+    #[error("Fail to relay tunnel")]
+    RelayError(std::io::Error),
+
     #[error("Fail to parse http")]
-    HttpParseError(#[from] pext::FromUtf8Err),
+    HttpParseError(#[from] httparse::Error),
+
+    #[error("Invalid http request: {0}")]
+    BadRequestError(&'static str),
+
+    #[error("Fail to request upstream")]
+    HttpRequestError(hyper::Error),
+
+    #[error("Upstream is not contacted while offline")]
+    OfflineError,
+
+    #[error("Proxy authentication required")]
+    ProxyAuthRequiredError,
+
+    #[error("Blocked by interceptor")]
+    FlowBlockedError,
+
+    #[error("Host is on the blocklist")]
+    HostBlockedError,
+
+    #[error("Access denied")]
+    AccessDeniedError,
+
+    #[error("Too many connections")]
+    TooManyConnectionsError,
+
+    #[error("Request was not received in time")]
+    RequestTimeoutError,
+
+    #[error("Request header is too large")]
+    HeaderTooLargeError,
+
+    #[error("Request body is too large")]
+    PayloadTooLargeError,
+
+    #[error("No virtual host for the requested name")]
+    UnknownHostError,
+
+    #[error("ACME: {0}")]
+    AcmeError(String),
+
+    #[error("Fail to build tls config")]
+    TlsConfigError(#[from] rustls::Error),
+
+    #[error("Fail to read file")]
+    ReadFileError(std::io::Error),
+
+    #[error("Fail to write file")]
+    WriteFileError(std::io::Error),
+
+    #[error("Invalid host pattern")]
+    PatternError(regex::Error),
+
+    #[error("Fail to register metrics")]
+    MetricsError(prometheus::Error),
+
+    #[error("Fail to set up trace export")]
+    TelemetryError(opentelemetry::trace::TraceError),
+
+    #[error("Fail to parse HAR")]
+    HarParseError(serde_json::Error),
+
+    #[error("Fail to parse mitmproxy dump: {0}")]
+    DumpParseError(&'static str),
+
+    #[error("Fail to access flow storage")]
+    StorageError(rusqlite::Error),
+
+    #[error("Fail to load script")]
+    ScriptError(Box<rhai::EvalAltResult>),
+
+    #[error("Fail to load plugin")]
+    PluginError(wasmtime::Error),
+
+    #[error("Fail to drive the terminal")]
+    TerminalError(std::io::Error),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfigError(&'static str),
+
+    #[error("Fail to parse config")]
+    ConfigError(#[from] toml::de::Error),
+
+    #[error("CA certificate and key are not configured")]
+    MissingCaError,
+
+    #[error("Fail to load certificate")]
+    CertificateError(#[from] rcgen::RcgenError),
+
+    #[error("Timed out after {0:?}")]
+    TimeoutError(std::time::Duration),
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::BadHttpError(_)
+            | Error::ReadUntilError(_)
+            | Error::HttpParseError(_)
+            | Error::BadRequestError(_) => StatusCode::BAD_REQUEST,
+            Error::TcpConnectError(_)
+            | Error::ResolveError(_)
+            | Error::TlsConnectError(_)
+            | Error::HttpRequestError(_)
+            | Error::OfflineError => StatusCode::BAD_GATEWAY,
+            Error::TimeoutError(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::ProxyAuthRequiredError => StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            Error::HostBlockedError | Error::AccessDeniedError => StatusCode::FORBIDDEN,
+            Error::RequestTimeoutError => StatusCode::REQUEST_TIMEOUT,
+            Error::TooManyConnectionsError => StatusCode::SERVICE_UNAVAILABLE,
+            Error::HeaderTooLargeError => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Error::PayloadTooLargeError => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnknownHostError => StatusCode::MISDIRECTED_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
@@ -0,0 +1,180 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use http::header::{ACCEPT_ENCODING, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use http::{HeaderName, Method};
+
+use crate::error::Error;
+use crate::replay::RecordedFlow;
+
+const PROXY_CONNECTION: &str = "proxy-connection";
+
+// Ways to send a recorded request again from outside the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Curl,
+    Httpie,
+    // A `.http` file, as the REST clients of editors read them.
+    Http,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "curl" => Ok(ExportFormat::Curl),
+            "httpie" => Ok(ExportFormat::Httpie),
+            "http" => Ok(ExportFormat::Http),
+            _ => Err(Error::BadRequestError("Unknown export format")),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn render(&self, flow: &RecordedFlow) -> String {
+        match self {
+            ExportFormat::Curl => curl(flow),
+            ExportFormat::Httpie => httpie(flow),
+            ExportFormat::Http => http_file(flow),
+        }
+    }
+}
+
+// Headers the tools set themselves from the URL and body, or that only concern the hop to the
+// proxy.
+fn is_exported(flow: &RecordedFlow, name: &HeaderName) -> bool {
+    let derived = [CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING];
+    if derived.contains(name) || name.as_str() == PROXY_CONNECTION {
+        return false;
+    }
+    if name == HOST {
+        let host = flow.headers.get(HOST).and_then(|value| value.to_str().ok());
+        return host != flow.uri.authority().map(|authority| authority.as_str());
+    }
+
+    true
+}
+
+fn headers(flow: &RecordedFlow) -> impl Iterator<Item = (&str, String)> {
+    flow.headers
+        .iter()
+        .filter(move |(name, _)| is_exported(flow, name))
+        .map(|(name, value)| {
+            (
+                name.as_str(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// Binary bodies are piped in, spelled out as octal escapes for `printf`. A leading `-` is
+// escaped too, so it is not taken for an option.
+fn printf(body: &[u8]) -> String {
+    let escaped: String = body
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| match byte {
+            b'%' => "%%".to_string(),
+            b'\\' => r"\\".to_string(),
+            b'-' if i == 0 => format!("\\{:03o}", byte),
+            b' '..=b'~' if byte != b'\'' => (byte as char).to_string(),
+            _ => format!("\\{:03o}", byte),
+        })
+        .collect();
+
+    format!("printf '{}' | ", escaped)
+}
+
+fn curl(flow: &RecordedFlow) -> String {
+    let text = std::str::from_utf8(&flow.body).ok();
+
+    let mut command = String::new();
+    if text.is_none() {
+        command.push_str(&printf(&flow.body));
+    }
+    command.push_str("curl");
+    match flow.method {
+        Method::HEAD => command.push_str(" --head"),
+        Method::GET if flow.body.is_empty() => {}
+        Method::POST if !flow.body.is_empty() => {}
+        ref method => {
+            let _ = write!(command, " -X {}", method);
+        }
+    }
+    let _ = write!(command, " {}", quote(&flow.uri.to_string()));
+    for (name, value) in headers(flow) {
+        let _ = write!(
+            command,
+            " \\\n  -H {}",
+            quote(&format!("{}: {}", name, value))
+        );
+    }
+    if flow.headers.contains_key(ACCEPT_ENCODING) {
+        command.push_str(" \\\n  --compressed");
+    }
+    match text {
+        _ if flow.body.is_empty() => {}
+        Some(text) => {
+            let _ = write!(command, " \\\n  --data-raw {}", quote(text));
+        }
+        None => command.push_str(" \\\n  --data-binary @-"),
+    }
+
+    command
+}
+
+fn httpie(flow: &RecordedFlow) -> String {
+    let text = std::str::from_utf8(&flow.body).ok();
+
+    let mut command = String::new();
+    if text.is_none() {
+        command.push_str(&printf(&flow.body));
+    }
+    let _ = write!(
+        command,
+        "http {} {}",
+        flow.method,
+        quote(&flow.uri.to_string())
+    );
+    for (name, value) in headers(flow) {
+        // `Name;` is how HTTPie sends a header with an empty value.
+        let item = if value.is_empty() {
+            format!("{};", name)
+        } else {
+            format!("{}:{}", name, value)
+        };
+        let _ = write!(command, " \\\n  {}", quote(&item));
+    }
+    if let Some(text) = text.filter(|text| !text.is_empty()) {
+        let _ = write!(command, " \\\n  --raw {}", quote(text));
+    }
+
+    command
+}
+
+fn http_file(flow: &RecordedFlow) -> String {
+    let mut file = format!("{} {} HTTP/1.1\n", flow.method, flow.uri);
+    for (name, value) in headers(flow) {
+        let _ = writeln!(file, "{}: {}", name, value);
+    }
+    match std::str::from_utf8(&flow.body) {
+        _ if flow.body.is_empty() => {}
+        Ok(text) => {
+            let _ = write!(file, "\n{}\n", text);
+        }
+        Err(_) => {
+            let _ = write!(
+                file,
+                "\n# A binary body of {} bytes is left out.\n",
+                flow.body.len()
+            );
+        }
+    }
+
+    file
+}
@@ -0,0 +1,196 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::header::CONTENT_LENGTH;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use hyper::body::HttpBody;
+use hyper::Body;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::policy::{self, HostPattern};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FaultKind {
+    // An error response from the proxy, 503 unless `status` says otherwise.
+    #[default]
+    Error,
+    // The connection is dropped without an answer.
+    Reset,
+    // The request waits for `delay` before it is forwarded.
+    Stall,
+    // The response body breaks off after `bytes`, keeping its original length.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    pub host: Option<String>,
+    // Glob on the path, without the query.
+    pub path: Option<String>,
+    pub method: Option<String>,
+    pub kind: FaultKind,
+    // Share of matching requests that fail, from 0 to 1; all of them by default.
+    pub probability: Option<f64>,
+    pub status: Option<u16>,
+    #[serde(with = "humantime_serde")]
+    pub delay: Option<Duration>,
+    pub bytes: u64,
+}
+
+struct Fault {
+    host: Option<HostPattern>,
+    path: Option<Regex>,
+    method: Option<Method>,
+    kind: FaultKind,
+    probability: f64,
+    status: StatusCode,
+    delay: Duration,
+    bytes: u64,
+}
+
+impl Fault {
+    fn new(config: &FaultConfig) -> Result<Self, Error> {
+        let probability = config.probability.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(Error::InvalidConfigError(
+                "Fault probability must be between 0 and 1",
+            ));
+        }
+        if config.kind == FaultKind::Stall && config.delay.is_none() {
+            return Err(Error::InvalidConfigError("Stall fault needs a delay"));
+        }
+        let status = StatusCode::from_u16(config.status.unwrap_or(503))
+            .map_err(|_| Error::InvalidConfigError("Invalid status in fault"))?;
+
+        Ok(Self {
+            host: config.host.as_deref().map(HostPattern::new).transpose()?,
+            path: config
+                .path
+                .as_deref()
+                .map(|glob| Regex::new(&policy::glob_to_regex(glob)))
+                .transpose()
+                .map_err(Error::PatternError)?,
+            method: config
+                .method
+                .as_deref()
+                .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+                .transpose()
+                .map_err(|_| Error::InvalidConfigError("Invalid method in fault"))?,
+            kind: config.kind,
+            probability,
+            status,
+            delay: config.delay.unwrap_or_default(),
+            bytes: config.bytes,
+        })
+    }
+
+    fn matches(&self, flow: &FlowRequest) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|method| method == flow.method)
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|host| flow.uri.host().is_some_and(|name| host.matches(name)))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| path.is_match(flow.uri.path()))
+    }
+
+    fn strikes(&self) -> bool {
+        if self.probability >= 1.0 {
+            return true;
+        }
+        let random = RandomState::new().build_hasher().finish();
+
+        (random as f64 / u64::MAX as f64) < self.probability
+    }
+
+    fn truncate(&self, mut body: Body) -> Body {
+        let mut remaining = self.bytes;
+        let (mut sender, truncated) = Body::channel();
+
+        tokio::spawn(async move {
+            while remaining > 0 {
+                let mut chunk = match body.data().await {
+                    Some(Ok(chunk)) => chunk,
+                    _ => break,
+                };
+                chunk.truncate(remaining.min(chunk.len() as u64) as usize);
+                remaining -= chunk.len() as u64;
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            sender.abort();
+        });
+
+        truncated
+    }
+}
+
+// Chaos rules for testing how clients cope with failing servers. The first rule matching a
+// request decides, and it strikes with its probability.
+#[derive(Clone)]
+pub struct Faults(Arc<Vec<Fault>>);
+
+impl Faults {
+    pub fn new(configs: &[FaultConfig]) -> Result<Self, Error> {
+        let faults = configs.iter().map(Fault::new).collect::<Result<_, _>>()?;
+
+        Ok(Self(Arc::new(faults)))
+    }
+
+    fn find(&self, flow: &FlowRequest) -> Option<&Fault> {
+        self.0.iter().find(|fault| fault.matches(flow))
+    }
+}
+
+#[async_trait]
+impl Interceptor for Faults {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        let fault = match self.find(flow) {
+            Some(fault) if fault.kind != FaultKind::Truncate && fault.strikes() => fault,
+            _ => return RequestAction::Forward(req),
+        };
+        debug!(id = flow.id, kind = ?fault.kind, "fault injected");
+
+        match fault.kind {
+            FaultKind::Error => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = fault.status;
+                response
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(0));
+                RequestAction::Respond(response)
+            }
+            FaultKind::Reset => RequestAction::Block,
+            FaultKind::Stall => {
+                tokio::time::sleep(fault.delay).await;
+                RequestAction::Forward(req)
+            }
+            FaultKind::Truncate => RequestAction::Forward(req),
+        }
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        let fault = match self.find(flow) {
+            Some(fault) if fault.kind == FaultKind::Truncate && fault.strikes() => fault,
+            _ => return ResponseAction::Forward(res),
+        };
+        debug!(id = flow.id, bytes = fault.bytes, "response truncated");
+
+        ResponseAction::Forward(res.map(|body| fault.truncate(body)))
+    }
+}
@@ -0,0 +1,237 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use http::{HeaderMap, Method, StatusCode, Uri, Version};
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::Body;
+use rustls::ProtocolVersion;
+use tokio::sync::broadcast;
+use ulid::Ulid;
+
+use tracing::info;
+
+use crate::grpc::GrpcMessage;
+use crate::redact::Redactor;
+use crate::sse::ServerSentEvent;
+use crate::websocket::WebSocketMessage;
+
+#[derive(Debug, Clone)]
+pub struct FlowRequest {
+    pub id: u64,
+    // Unique across runs and processes, for correlating records kept elsewhere.
+    pub ulid: Ulid,
+    // Of the client connection the request came in on; none for requests sent again.
+    pub connection: Option<Ulid>,
+    pub client: SocketAddr,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowResponse {
+    pub id: u64,
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowSummary {
+    pub id: u64,
+    pub ulid: Ulid,
+    pub client: SocketAddr,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub status: Option<StatusCode>,
+    pub tls_version: Option<ProtocolVersion>,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub started: SystemTime,
+    pub duration: Duration,
+}
+
+impl FlowSummary {
+    pub(crate) fn new(flow: &FlowRequest, tls_version: Option<ProtocolVersion>) -> Self {
+        Self {
+            id: flow.id,
+            ulid: flow.ulid,
+            client: flow.client,
+            method: flow.method.clone(),
+            uri: flow.uri.clone(),
+            version: flow.version,
+            status: None,
+            tls_version,
+            request_bytes: 0,
+            response_bytes: 0,
+            started: SystemTime::now(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> Self {
+        self.duration = self.started.elapsed().unwrap_or_default();
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upstream,
+    Downstream,
+}
+
+#[derive(Debug, Clone)]
+pub enum FlowEvent {
+    Request(FlowRequest),
+    Response(FlowResponse),
+    WebSocketMessage {
+        id: u64,
+        direction: Direction,
+        message: WebSocketMessage,
+    },
+    ServerSentEvent {
+        id: u64,
+        event: ServerSentEvent,
+    },
+    GrpcMessage {
+        id: u64,
+        direction: Direction,
+        message: GrpcMessage,
+    },
+    Error {
+        id: u64,
+        error: String,
+    },
+    Complete(FlowSummary),
+}
+
+pub(crate) struct Flows {
+    sender: broadcast::Sender<FlowEvent>,
+    next_id: AtomicU64,
+    redactor: Redactor,
+}
+
+impl Flows {
+    pub(crate) fn new(capacity: usize, redactor: Redactor) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self {
+            sender,
+            next_id: AtomicU64::new(1),
+            redactor,
+        }
+    }
+
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<FlowEvent> {
+        self.sender.subscribe()
+    }
+
+    // Events are redacted before they are logged or anything subscribed sees them.
+    pub(crate) fn emit(&self, event: FlowEvent) {
+        let event = self.redactor.event(event);
+        match &event {
+            FlowEvent::Request(req) => {
+                info!(id = req.id, flow = %req.ulid, client = %req.client, method = %req.method, uri = %req.uri)
+            }
+            FlowEvent::Response(res) => info!(id = res.id, status = %res.status),
+            FlowEvent::WebSocketMessage { id, direction, .. } => info!(id, ?direction),
+            FlowEvent::ServerSentEvent { id, event } => info!(id, event = ?event.event),
+            FlowEvent::GrpcMessage {
+                id,
+                direction,
+                message,
+            } => info!(id, ?direction, method = %message.method, size = message.size),
+            FlowEvent::Error { id, error } => info!(id, %error),
+            FlowEvent::Complete(summary) => {
+                info!(id = summary.id, flow = %summary.ulid, duration = ?summary.duration, bytes = summary.response_bytes)
+            }
+        }
+
+        let _ = self.sender.send(event);
+    }
+}
+
+type OnEnd = Box<dyn FnOnce(u64) + Send>;
+
+// Counts the bytes of a streamed body and reports the total once it ends or is dropped.
+pub(crate) struct MeteredBody {
+    inner: Body,
+    bytes: u64,
+    on_end: Option<OnEnd>,
+}
+
+impl MeteredBody {
+    pub(crate) fn new(inner: Body) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            on_end: None,
+        }
+    }
+
+    pub(crate) fn on_end<F>(mut self, on_end: F) -> Self
+    where
+        F: FnOnce(u64) + Send + 'static,
+    {
+        self.on_end = Some(Box::new(on_end));
+        self
+    }
+
+    fn finish(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(self.bytes);
+        }
+    }
+}
+
+impl HttpBody for MeteredBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+
+        let polled = Pin::new(&mut this.inner).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(data))) => this.bytes += data.len() as u64,
+            Poll::Ready(_) => this.finish(),
+            Poll::Pending => {}
+        }
+
+        polled
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+
+use http::header::{HeaderName, FORWARDED};
+use http::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+
+use crate::flow::FlowRequest;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+// Tells upstreams who the client is, for deployments that need the real address behind the proxy.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct ForwardedConfig {
+    pub x_forwarded_for: bool,
+    // RFC 7239 `Forwarded`, with the client address, the host and the scheme.
+    pub forwarded: bool,
+    // Drops what clients send in these headers instead of appending to it, so they cannot
+    // claim to come from somewhere else.
+    pub strip_incoming: bool,
+}
+
+impl ForwardedConfig {
+    pub(crate) fn apply(&self, flow: &FlowRequest, headers: &mut HeaderMap) {
+        if self.strip_incoming {
+            headers.remove(&X_FORWARDED_FOR);
+            headers.remove(FORWARDED);
+        }
+        let client = flow.client.ip().to_canonical();
+
+        if self.x_forwarded_for {
+            let forwarded_for = headers
+                .get_all(&X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .chain(std::iter::once(client.to_string().as_str()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                headers.insert(X_FORWARDED_FOR, value);
+            }
+        }
+
+        if self.forwarded {
+            let mut element = format!("for={}", Self::node(client));
+            if let Some(host) = flow.uri.authority() {
+                element.push_str(&format!(";host=\"{}\"", host));
+            }
+            if let Some(scheme) = flow.uri.scheme_str() {
+                element.push_str(&format!(";proto={}", scheme));
+            }
+            if let Ok(value) = HeaderValue::from_str(&element) {
+                headers.append(FORWARDED, value);
+            }
+        }
+    }
+
+    // IPv6 addresses are bracketed and quoted, as `:` is not allowed in a bare token.
+    fn node(address: IpAddr) -> String {
+        match address {
+            IpAddr::V4(address) => address.to_string(),
+            IpAddr::V6(address) => format!("\"[{}]\"", address),
+        }
+    }
+}
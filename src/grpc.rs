@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use flate2::read::GzDecoder;
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use hyper::body::HttpBody;
+use hyper::Body;
+use prost::encoding::{decode_key, decode_varint, WireType};
+use prost::Message;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tracing::Instrument;
+
+use crate::error::Error;
+use crate::flow::{Direction, FlowEvent, FlowRequest};
+use crate::server::Context;
+
+const GRPC_ENCODING: &str = "grpc-encoding";
+// Messages are length-prefixed by a compression flag and a big-endian u32.
+const PREFIX_LEN: usize = 5;
+// Larger messages are counted but not decoded.
+const MAX_DECODED_SIZE: usize = 4 * 1024 * 1024;
+const MAX_DEPTH: usize = 64;
+
+// Field types and labels of `google.protobuf.FieldDescriptorProto`.
+const TYPE_DOUBLE: i32 = 1;
+const TYPE_FLOAT: i32 = 2;
+const TYPE_INT64: i32 = 3;
+const TYPE_UINT64: i32 = 4;
+const TYPE_INT32: i32 = 5;
+const TYPE_FIXED64: i32 = 6;
+const TYPE_FIXED32: i32 = 7;
+const TYPE_BOOL: i32 = 8;
+const TYPE_STRING: i32 = 9;
+const TYPE_MESSAGE: i32 = 11;
+const TYPE_UINT32: i32 = 13;
+const TYPE_ENUM: i32 = 14;
+const TYPE_SFIXED32: i32 = 15;
+const TYPE_SFIXED64: i32 = 16;
+const TYPE_SINT32: i32 = 17;
+const TYPE_SINT64: i32 = 18;
+const LABEL_REPEATED: i32 = 3;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GrpcConfig {
+    // Binary `FileDescriptorSet`s, as written by `protoc --include_imports --descriptor_set_out`,
+    // to decode the messages of the services they describe.
+    pub descriptor_sets: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcMessage {
+    // `/package.Service/Method`
+    pub method: String,
+    pub compressed: bool,
+    // As framed, so compressed messages count their compressed size.
+    pub size: usize,
+    // Fields by name, for methods of a configured descriptor set.
+    pub decoded: Option<Value>,
+}
+
+// The subset of `descriptor.proto` needed to decode messages.
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorSet {
+    #[prost(message, repeated, tag = "1")]
+    file: Vec<FileDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorProto {
+    #[prost(string, optional, tag = "2")]
+    package: Option<String>,
+    #[prost(message, repeated, tag = "4")]
+    message_type: Vec<DescriptorProto>,
+    #[prost(message, repeated, tag = "5")]
+    enum_type: Vec<EnumDescriptorProto>,
+    #[prost(message, repeated, tag = "6")]
+    service: Vec<ServiceDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    field: Vec<FieldDescriptorProto>,
+    #[prost(message, repeated, tag = "3")]
+    nested_type: Vec<DescriptorProto>,
+    #[prost(message, repeated, tag = "4")]
+    enum_type: Vec<EnumDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(int32, optional, tag = "3")]
+    number: Option<i32>,
+    #[prost(int32, optional, tag = "4")]
+    label: Option<i32>,
+    #[prost(int32, optional, tag = "5")]
+    kind: Option<i32>,
+    #[prost(string, optional, tag = "6")]
+    type_name: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EnumDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    value: Vec<EnumValueDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EnumValueDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(int32, optional, tag = "2")]
+    number: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServiceDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    method: Vec<MethodDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MethodDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    input_type: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    output_type: Option<String>,
+}
+
+struct Field {
+    name: String,
+    kind: i32,
+    repeated: bool,
+    type_name: String,
+}
+
+// Messages, enums and methods of the configured descriptor sets, by fully qualified name.
+#[derive(Default)]
+pub(crate) struct Descriptors {
+    messages: HashMap<String, HashMap<u32, Field>>,
+    enums: HashMap<String, HashMap<i32, String>>,
+    // Input and output message types.
+    methods: HashMap<String, (String, String)>,
+}
+
+impl Descriptors {
+    pub(crate) fn load(config: &GrpcConfig) -> Result<Self, Error> {
+        let mut descriptors = Self::default();
+
+        for path in &config.descriptor_sets {
+            let bytes = std::fs::read(path).map_err(Error::ReadFileError)?;
+            let set = FileDescriptorSet::decode(bytes.as_slice())
+                .map_err(|_| Error::InvalidConfigError("Invalid gRPC descriptor set"))?;
+
+            for file in set.file {
+                // Type names are written with a leading dot, paths without.
+                let scope = file
+                    .package
+                    .as_ref()
+                    .map(|package| format!(".{}", package))
+                    .unwrap_or_default();
+                descriptors.add_messages(&scope, file.message_type);
+                descriptors.add_enums(&scope, file.enum_type);
+
+                for service in file.service {
+                    let service_name = format!("{}.{}", scope, service.name());
+                    for method in service.method {
+                        let path = format!("/{}/{}", &service_name[1..], method.name());
+                        let types = (
+                            method.input_type().to_string(),
+                            method.output_type().to_string(),
+                        );
+                        descriptors.methods.insert(path, types);
+                    }
+                }
+            }
+        }
+
+        Ok(descriptors)
+    }
+
+    fn add_messages(&mut self, scope: &str, messages: Vec<DescriptorProto>) {
+        for message in messages {
+            let name = format!("{}.{}", scope, message.name());
+            let fields = message
+                .field
+                .iter()
+                .map(|field| {
+                    let number = field.number() as u32;
+                    let field = Field {
+                        name: field.name().to_string(),
+                        kind: field.kind(),
+                        repeated: field.label() == LABEL_REPEATED,
+                        type_name: field.type_name().to_string(),
+                    };
+                    (number, field)
+                })
+                .collect();
+            self.messages.insert(name.clone(), fields);
+            self.add_enums(&name, message.enum_type);
+            self.add_messages(&name, message.nested_type);
+        }
+    }
+
+    fn add_enums(&mut self, scope: &str, enums: Vec<EnumDescriptorProto>) {
+        for descriptor in enums {
+            let values = descriptor
+                .value
+                .iter()
+                .map(|value| (value.number(), value.name().to_string()))
+                .collect();
+            self.enums
+                .insert(format!("{}.{}", scope, descriptor.name()), values);
+        }
+    }
+
+    fn decodes(&self, method: &str) -> bool {
+        self.methods.contains_key(method)
+    }
+
+    fn decode(&self, method: &str, direction: Direction, message: &[u8]) -> Option<Value> {
+        let (input, output) = self.methods.get(method)?;
+        let type_name = match direction {
+            Direction::Upstream => input,
+            Direction::Downstream => output,
+        };
+
+        self.message(type_name, message, 0)
+    }
+
+    // None when the bytes are not a valid encoding of any message.
+    fn message(&self, type_name: &str, mut buf: &[u8], depth: usize) -> Option<Value> {
+        let fields = self.messages.get(type_name)?;
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let mut map = Map::new();
+        while !buf.is_empty() {
+            let (number, wire_type) = decode_key(&mut buf).ok()?;
+            let field = fields.get(&number);
+            let kind = field.map(|field| field.kind);
+
+            let values = match wire_type {
+                WireType::Varint => vec![self.varint(field, decode_varint(&mut buf).ok()?)],
+                WireType::SixtyFourBit => vec![fixed64(kind, take(&mut buf, 8)?)],
+                WireType::ThirtyTwoBit => vec![fixed32(kind, take(&mut buf, 4)?)],
+                WireType::LengthDelimited => {
+                    let len = usize::try_from(decode_varint(&mut buf).ok()?).ok()?;
+                    self.length_delimited(field, take(&mut buf, len)?, depth)?
+                }
+                WireType::StartGroup | WireType::EndGroup => return None,
+            };
+
+            match field {
+                Some(field) if field.repeated => {
+                    let entry = map
+                        .entry(field.name.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()));
+                    if let Value::Array(array) = entry {
+                        array.extend(values);
+                    }
+                }
+                // Of a scalar sent more than once, the last one wins.
+                _ => {
+                    let name = field.map_or_else(|| number.to_string(), |field| field.name.clone());
+                    if let Some(value) = values.into_iter().last() {
+                        map.insert(name, value);
+                    }
+                }
+            }
+        }
+
+        Some(Value::Object(map))
+    }
+
+    fn varint(&self, field: Option<&Field>, value: u64) -> Value {
+        let field = match field {
+            Some(field) => field,
+            None => return json!(value),
+        };
+
+        match field.kind {
+            TYPE_INT32 => json!(value as i32),
+            TYPE_INT64 => json!(value as i64),
+            TYPE_SINT32 => json!(((value >> 1) as i32) ^ -((value & 1) as i32)),
+            TYPE_SINT64 => json!(((value >> 1) as i64) ^ -((value & 1) as i64)),
+            TYPE_BOOL => json!(value != 0),
+            TYPE_ENUM => self
+                .enums
+                .get(&field.type_name)
+                .and_then(|values| values.get(&(value as i32)))
+                .map_or_else(|| json!(value as i32), |name| json!(name)),
+            _ => json!(value),
+        }
+    }
+
+    fn length_delimited(
+        &self,
+        field: Option<&Field>,
+        mut bytes: &[u8],
+        depth: usize,
+    ) -> Option<Vec<Value>> {
+        let field = match field {
+            Some(field) => field,
+            None => return Some(vec![json!(base64::encode(bytes))]),
+        };
+
+        let values = match field.kind {
+            TYPE_STRING => vec![json!(String::from_utf8_lossy(bytes))],
+            // A nested message that does not decode is shown as bytes.
+            TYPE_MESSAGE => vec![self
+                .message(&field.type_name, bytes, depth + 1)
+                .unwrap_or_else(|| json!(base64::encode(bytes)))],
+            // Packed repeated scalars.
+            TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => {
+                let mut values = Vec::new();
+                while !bytes.is_empty() {
+                    values.push(fixed64(Some(field.kind), take(&mut bytes, 8)?));
+                }
+                values
+            }
+            TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => {
+                let mut values = Vec::new();
+                while !bytes.is_empty() {
+                    values.push(fixed32(Some(field.kind), take(&mut bytes, 4)?));
+                }
+                values
+            }
+            TYPE_INT32 | TYPE_INT64 | TYPE_UINT32 | TYPE_UINT64 | TYPE_SINT32 | TYPE_SINT64
+            | TYPE_BOOL | TYPE_ENUM => {
+                let mut values = Vec::new();
+                while !bytes.is_empty() {
+                    values.push(self.varint(Some(field), decode_varint(&mut bytes).ok()?));
+                }
+                values
+            }
+            _ => vec![json!(base64::encode(bytes))],
+        };
+
+        Some(values)
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+
+    Some(head)
+}
+
+fn fixed64(kind: Option<i32>, bytes: &[u8]) -> Value {
+    let value = u64::from_le_bytes(bytes.try_into().unwrap_or_default());
+    match kind {
+        Some(TYPE_DOUBLE) => json!(f64::from_bits(value)),
+        Some(TYPE_SFIXED64) => json!(value as i64),
+        _ => json!(value),
+    }
+}
+
+fn fixed32(kind: Option<i32>, bytes: &[u8]) -> Value {
+    let value = u32::from_le_bytes(bytes.try_into().unwrap_or_default());
+    match kind {
+        Some(TYPE_FLOAT) => json!(f32::from_bits(value)),
+        Some(TYPE_SFIXED32) => json!(value as i32),
+        _ => json!(value),
+    }
+}
+
+// gRPC-Web frames its trailers into the body, so it is not taken for gRPC.
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .is_some_and(|value| value == "application/grpc" || value.starts_with("application/grpc+"))
+}
+
+struct Frame {
+    compressed: bool,
+    size: usize,
+    // Only kept when it is to be decoded.
+    data: Option<Vec<u8>>,
+}
+
+// Splits a gRPC body into its length-prefixed messages, however it is chunked.
+struct Deframer {
+    keep: bool,
+    prefix: Vec<u8>,
+    size: usize,
+    remaining: usize,
+    data: Vec<u8>,
+}
+
+impl Deframer {
+    fn new(keep: bool) -> Self {
+        Self {
+            keep,
+            prefix: Vec::with_capacity(PREFIX_LEN),
+            size: 0,
+            remaining: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            if self.prefix.len() < PREFIX_LEN {
+                let len = bytes.len().min(PREFIX_LEN - self.prefix.len());
+                self.prefix.extend_from_slice(&bytes[..len]);
+                bytes = &bytes[len..];
+                if self.prefix.len() < PREFIX_LEN {
+                    break;
+                }
+                let size = [
+                    self.prefix[1],
+                    self.prefix[2],
+                    self.prefix[3],
+                    self.prefix[4],
+                ];
+                self.size = u32::from_be_bytes(size) as usize;
+                self.remaining = self.size;
+            }
+
+            let len = bytes.len().min(self.remaining);
+            if self.keep && self.size <= MAX_DECODED_SIZE {
+                self.data.extend_from_slice(&bytes[..len]);
+            }
+            bytes = &bytes[len..];
+            self.remaining -= len;
+
+            if self.remaining == 0 {
+                let data = mem::take(&mut self.data);
+                frames.push(Frame {
+                    compressed: self.prefix[0] & 1 == 1,
+                    size: self.size,
+                    data: (self.keep && self.size <= MAX_DECODED_SIZE).then_some(data),
+                });
+                self.prefix.clear();
+            }
+        }
+
+        frames
+    }
+}
+
+fn decompress(encoding: Option<&str>, data: &[u8]) -> Option<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match encoding {
+        Some("gzip") => GzDecoder::new(data)
+            .take(MAX_DECODED_SIZE as u64)
+            .read_to_end(&mut decompressed)
+            .ok()?,
+        _ => return None,
+    };
+
+    Some(decompressed)
+}
+
+// Reports each message of a gRPC body as a flow event while it streams through. `headers` are
+// those sent along with the body, naming its compression.
+pub(crate) fn observe(
+    mut body: Body,
+    direction: Direction,
+    headers: &HeaderMap,
+    flow: &FlowRequest,
+    context: Arc<Context>,
+) -> Body {
+    let (mut sender, observed) = Body::channel();
+    let id = flow.id;
+    let method = flow.uri.path().to_string();
+    let encoding = headers
+        .get(GRPC_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+
+    tokio::spawn(
+        async move {
+            let mut deframer = Deframer::new(context.grpc.decodes(&method));
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        sender.abort();
+                        return;
+                    }
+                };
+                let frames = deframer.push(&chunk);
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+
+                for frame in frames {
+                    let decoded = frame.data.and_then(|data| {
+                        let data = if frame.compressed {
+                            decompress(encoding.as_deref(), &data)?
+                        } else {
+                            data
+                        };
+                        context.grpc.decode(&method, direction, &data)
+                    });
+                    let message = GrpcMessage {
+                        method: method.clone(),
+                        compressed: frame.compressed,
+                        size: frame.size,
+                        decoded,
+                    };
+                    context.flows.emit(FlowEvent::GrpcMessage {
+                        id,
+                        direction,
+                        message,
+                    });
+                }
+            }
+            if let Ok(Some(trailers)) = body.trailers().await {
+                let _ = sender.send_trailers(trailers).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    observed
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use http::{HeaderMap, Request, Response, Uri, Version};
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::error;
+use ulid::Ulid;
+
+use crate::capture;
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::http as http_ext;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::redact::Redactor;
+
+// Of larger bodies, only the start is kept; `max_body_size` may cut the text shorter still.
+pub(crate) const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+// Requests that never see a response (blocked, failed upstream) are forgotten after this.
+const PENDING_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HarFlush {
+    #[default]
+    Entry,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HarConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    #[serde(default)]
+    pub flush: HarFlush,
+}
+
+struct Pending {
+    flow: Ulid,
+    started: SystemTime,
+    instant: Instant,
+    request: Value,
+    headers: HeaderMap,
+    // Filled in once the request body has been sent: what was kept of it and its whole size.
+    body: Arc<Mutex<Option<(Bytes, u64)>>>,
+}
+
+#[derive(Clone)]
+pub struct HarRecorder(Arc<Inner>, Redactor);
+
+struct Inner {
+    config: HarConfig,
+    pending: Mutex<HashMap<u64, Pending>>,
+    entries: Mutex<Vec<Value>>,
+}
+
+impl HarRecorder {
+    pub fn new(config: HarConfig) -> Self {
+        Self(
+            Arc::new(Inner {
+                config,
+                pending: Mutex::new(HashMap::new()),
+                entries: Mutex::new(Vec::new()),
+            }),
+            Redactor::default(),
+        )
+    }
+
+    // Masks what is recorded from here on.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.1 = redactor;
+        self
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        self.0.save()
+    }
+
+    fn headers(headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                json!({
+                    "name": name.as_str(),
+                    "value": String::from_utf8_lossy(value.as_bytes()),
+                })
+            })
+            .collect()
+    }
+
+    fn query_string(uri: &Uri) -> Vec<Value> {
+        uri.query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                json!({ "name": name, "value": value })
+            })
+            .collect()
+    }
+
+    fn record(&self, pending: Pending, wait: Duration, receive: Duration, response: Value) {
+        let mut request = pending.request;
+        match pending.body.lock().unwrap().take() {
+            Some((body, size)) => {
+                request["bodySize"] = json!(size);
+                if size > 0 {
+                    request["postData"] = self.content(&pending.headers, &body, size);
+                }
+            }
+            None => request["bodySize"] = json!(-1),
+        }
+
+        let entry = json!({
+            "_flowId": pending.flow.to_string(),
+            "startedDateTime": OffsetDateTime::from(pending.started).format(&Rfc3339).unwrap_or_default(),
+            "time": (wait + receive).as_secs_f64() * 1000.0,
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": {
+                "send": 0,
+                "wait": wait.as_secs_f64() * 1000.0,
+                "receive": receive.as_secs_f64() * 1000.0,
+            },
+        });
+        self.0.entries.lock().unwrap().push(entry);
+
+        if self.0.config.flush == HarFlush::Entry {
+            if let Err(e) = self.0.save() {
+                error!(?e, "Fail to save HAR");
+            }
+        }
+    }
+
+    // `size` is that of the whole body, of which `body` may be only the start.
+    fn content(&self, headers: &HeaderMap, body: &Bytes, size: u64) -> Value {
+        let mime_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        // The text is kept decoded, with what the content encoding saved as `compression`. Only
+        // a whole body can be decoded.
+        let whole = body.len() as u64 == size;
+        let body = &self.1.body(headers, body.clone());
+        let decoded = encoding::decode(headers, body).filter(|_| whole);
+        let (body, compression, size) = match &decoded {
+            Some(decoded) => (
+                decoded.as_slice(),
+                decoded.len() as i64 - body.len() as i64,
+                decoded.len() as u64,
+            ),
+            None => (body.as_ref(), 0, size),
+        };
+
+        let limit = self.0.config.max_body_size.unwrap_or(usize::MAX);
+        let truncated = !whole || body.len() > limit;
+        let body = &body[..body.len().min(limit)];
+
+        let mut content = match std::str::from_utf8(body) {
+            Ok(text) => json!({ "mimeType": mime_type, "text": text }),
+            Err(_) => json!({
+                "mimeType": mime_type,
+                "text": base64::encode(body),
+                "encoding": "base64",
+            }),
+        };
+        content["size"] = json!(size);
+        if compression > 0 {
+            content["compression"] = json!(compression);
+        }
+        if truncated {
+            content["comment"] = json!("truncated");
+        }
+
+        content
+    }
+
+    fn version(version: Version) -> String {
+        match version {
+            Version::HTTP_09 => "HTTP/0.9",
+            Version::HTTP_10 => "HTTP/1.0",
+            Version::HTTP_2 => "HTTP/2",
+            Version::HTTP_3 => "HTTP/3",
+            _ => "HTTP/1.1",
+        }
+        .to_string()
+    }
+}
+
+impl Inner {
+    fn save(&self) -> Result<(), Error> {
+        let entries = self.entries.lock().unwrap().clone();
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "yaler", "version": env!("CARGO_PKG_VERSION") },
+                "entries": entries,
+            }
+        });
+
+        Self::write(&self.config.path, &har.to_string())
+    }
+
+    // Write through a temporary file so readers never see a half-written archive.
+    fn write(path: &Path, content: &str) -> Result<(), Error> {
+        let tmp = path.with_extension("har.tmp");
+        std::fs::write(&tmp, content).map_err(Error::WriteFileError)?;
+        std::fs::rename(&tmp, path).map_err(Error::WriteFileError)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.config.flush == HarFlush::Shutdown {
+            if let Err(e) = self.save() {
+                error!(?e, "Fail to save HAR");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for HarRecorder {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        let (parts, body) = req.into_parts();
+        // Streamed request bodies are left out, as the upstream may answer before they end.
+        let captured = Arc::new(Mutex::new(None));
+        let body = if http_ext::is_stream(&parts.headers) {
+            body
+        } else {
+            let captured = captured.clone();
+            capture::tee(body, MAX_BODY_SIZE, move |body, size| {
+                *captured.lock().unwrap() = Some((body, size));
+            })
+        };
+
+        let uri = self.1.uri(&flow.uri);
+        let request = json!({
+            "method": parts.method.as_str(),
+            "url": uri.to_string(),
+            "httpVersion": Self::version(parts.version),
+            "cookies": [],
+            "headers": Self::headers(&self.1.headers(&parts.headers)),
+            "queryString": Self::query_string(&uri),
+            "headersSize": -1,
+        });
+
+        let mut pending = self.0.pending.lock().unwrap();
+        pending.retain(|_, pending| pending.instant.elapsed() < PENDING_TTL);
+        pending.insert(
+            flow.id,
+            Pending {
+                flow: flow.ulid,
+                started: SystemTime::now(),
+                instant: Instant::now(),
+                request,
+                headers: parts.headers.clone(),
+                body: captured,
+            },
+        );
+        drop(pending);
+
+        RequestAction::Forward(Request::from_parts(parts, body))
+    }
+
+    // The body streams on to the client and the entry is recorded once it has ended, so event
+    // streams and long polls are not held back.
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        let waited = Instant::now();
+        let pending = match self.0.pending.lock().unwrap().remove(&flow.id) {
+            Some(pending) => pending,
+            None => return ResponseAction::Forward(res),
+        };
+        let wait = waited.duration_since(pending.instant);
+
+        let (parts, body) = res.into_parts();
+        let redacted = self.1.headers(&parts.headers);
+        let mut response = json!({
+            "status": parts.status.as_u16(),
+            "statusText": parts.status.canonical_reason().unwrap_or_default(),
+            "httpVersion": Self::version(parts.version),
+            "cookies": [],
+            "headers": Self::headers(&redacted),
+            "redirectURL": redacted.get(http::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default(),
+            "headersSize": -1,
+        });
+        let headers = parts.headers.clone();
+        let recorder = self.clone();
+        let body = capture::tee(body, MAX_BODY_SIZE, move |body, size| {
+            let receive = waited.elapsed();
+            response["content"] = recorder.content(&headers, &body, size);
+            response["bodySize"] = json!(size);
+            recorder.record(pending, wait, receive, response);
+        });
+
+        ResponseAction::Forward(Response::from_parts(parts, body))
+    }
+}
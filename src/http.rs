@@ -1,36 +1,1069 @@
+use std::future::poll_fn;
+use std::io;
+
 use async_trait::async_trait;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, BufStream},
-    net::TcpStream,
+use bytes::BytesMut;
+use http::header::HeaderName;
+use http::header::{
+    CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, EXPECT, HOST, PROXY_AUTHENTICATE, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE, VIA,
 };
+use http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri, Version};
+use hyper::body::{Bytes, HttpBody, Sender};
+use hyper::Body;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use ulid::Ulid;
 
 use crate::error::Error;
+use crate::grpc;
+use crate::pool::BUFFERS;
+use crate::sse;
+
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const FLOW_ID: HeaderName = HeaderName::from_static("x-yaler-flow-id");
+
+// Headers about the connection a message travels on rather than the message itself.
+const HOP_BY_HOP: [HeaderName; 7] = [
+    CONNECTION,
+    PROXY_CONNECTION,
+    KEEP_ALIVE,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+pub(crate) const DEFAULT_VIA: &str = "yaler";
+
+// More than this many header fields in a head is answered as too large.
+const MAX_HEADERS: usize = 128;
+// For a chunk-size line with its extensions, and for all trailers of a chunked body together.
+const MAX_CHUNK_LINE_SIZE: usize = 8 * 1024;
 
 #[async_trait]
 pub trait ReadHttpExt {
-    async fn read_until_header_end(&mut self, vec: &mut Vec<u8>) -> Result<usize, Error>;
+    // None when the stream ends before a request starts. Body bytes that came along with the
+    // head stay buffered for whoever reads on.
+    async fn read_request_head(
+        &mut self,
+        max: Option<usize>,
+    ) -> Result<Option<Request<Vec<u8>>>, Error>;
+
+    // The status of a response head, like the one a proxy answers CONNECT with.
+    async fn read_response_status(&mut self, max: Option<usize>) -> Result<StatusCode, Error>;
 }
 
 #[async_trait]
-impl ReadHttpExt for BufStream<TcpStream> {
-    async fn read_until_header_end(&mut self, vec: &mut Vec<u8>) -> Result<usize, Error> {
-        loop {
-            let mut buf = Vec::new();
-            self.read_until(b'\r', &mut buf)
-                .await
-                .map_err(|e| Error::ReadUntilError(e))?;
+impl<T> ReadHttpExt for T
+where
+    T: AsyncBufRead + Unpin + Send,
+{
+    async fn read_request_head(
+        &mut self,
+        max: Option<usize>,
+    ) -> Result<Option<Request<Vec<u8>>>, Error> {
+        read_head(self, max, |buf| {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut head = httparse::Request::new(&mut headers);
+            let len = match head.parse(buf).map_err(parse_error)? {
+                httparse::Status::Complete(len) => len,
+                httparse::Status::Partial => return Ok(None),
+            };
 
-            let mut check = [0u8; 3];
-            self.read_exact(&mut check)
-                .await
-                .map_err(|e| Error::BadHttpError(e))?;
+            let mut request = Request::builder()
+                .method(head.method.unwrap_or_default())
+                .uri(head.path.unwrap_or_default())
+                .version(version(head.version));
+            for header in head.headers.iter() {
+                request = request.header(header.name, header.value);
+            }
+            let request = request
+                .body(Vec::new())
+                .map_err(|_| Error::BadRequestError("Invalid request head"))?;
+
+            Ok(Some((len, request)))
+        })
+        .await
+    }
+
+    async fn read_response_status(&mut self, max: Option<usize>) -> Result<StatusCode, Error> {
+        let status = read_head(self, max, |buf| {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut head = httparse::Response::new(&mut headers);
+            match head.parse(buf).map_err(parse_error)? {
+                httparse::Status::Complete(len) => Ok(Some((len, head.code.unwrap_or_default()))),
+                httparse::Status::Partial => Ok(None),
+            }
+        })
+        .await?
+        .ok_or(Error::BadHttpError(io::ErrorKind::UnexpectedEof.into()))?;
+
+        StatusCode::from_u16(status).map_err(|_| Error::BadRequestError("Invalid response status"))
+    }
+}
+
+// Reads into a buffer of the pool until `parse` finds a whole head there, then consumes just
+// that much. Both CRLF and bare LF line endings pass.
+async fn read_head<R, T, F>(
+    reader: &mut R,
+    max: Option<usize>,
+    parse: F,
+) -> Result<Option<T>, Error>
+where
+    R: AsyncBufRead + Unpin + Send,
+    F: Fn(&[u8]) -> Result<Option<(usize, T)>, Error>,
+{
+    let mut buf = BUFFERS.get();
+
+    loop {
+        let available = reader.fill_buf().await.map_err(Error::ReadUntilError)?;
+        if available.is_empty() {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::BadHttpError(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let before = buf.len();
+        let take = max.map_or(available.len(), |max| available.len().min(max + 1 - before));
+        buf.extend_from_slice(&available[..take]);
+        if let Some((len, head)) = parse(&buf)? {
+            reader.consume(len - before);
+            return Ok(Some(head));
+        }
+        reader.consume(take);
+
+        if max.is_some_and(|max| buf.len() > max) {
+            return Err(Error::HeaderTooLargeError);
+        }
+    }
+}
+
+fn parse_error(e: httparse::Error) -> Error {
+    match e {
+        httparse::Error::TooManyHeaders => Error::HeaderTooLargeError,
+        e => Error::HttpParseError(e),
+    }
+}
+
+fn version(minor: Option<u8>) -> Version {
+    match minor {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    }
+}
+
+pub(crate) const ALPN_H2: &[u8] = b"h2";
+pub(crate) const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+pub(crate) const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+pub(crate) fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+// Bodies that may never end, or only once the other side has answered, must be passed on as
+// they stream rather than read in full.
+pub(crate) fn is_stream(headers: &HeaderMap) -> bool {
+    sse::is_event_stream(headers) || grpc::is_grpc(headers)
+}
+
+pub(crate) fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// HTTP/1.0 clients cannot ask for `100 Continue`, whatever they send.
+pub(crate) fn expects_continue(version: Version, headers: &HeaderMap) -> bool {
+    version >= Version::HTTP_11
+        && headers
+            .get(EXPECT)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+// Waits until the receiving side first asks for the body; false when it is dropped instead.
+pub(crate) async fn body_wanted(sender: &mut Sender) -> bool {
+    // The channel holds a single chunk, so there is room again once the empty one is taken.
+    if sender.send_data(Bytes::new()).await.is_err() {
+        return false;
+    }
+
+    poll_fn(|cx| sender.poll_ready(cx)).await.is_ok()
+}
+
+// How a request body is framed on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyFraming {
+    Length(u64),
+    Chunked,
+}
+
+// Decided by the headers alone as RFC 7230 section 3.3.3 has it, whatever the method: chunked
+// coding wins over Content-Length, any other coding leaves the length unknown, and repeated
+// Content-Length values must agree. Without either header there is no body.
+pub(crate) fn body_framing(headers: &HeaderMap, max: Option<usize>) -> Result<BodyFraming, Error> {
+    if headers.contains_key(TRANSFER_ENCODING) {
+        return match is_chunked(headers) {
+            true => Ok(BodyFraming::Chunked),
+            false => Err(Error::BadRequestError("Unsupported Transfer-Encoding")),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all(CONTENT_LENGTH) {
+        let values = value
+            .to_str()
+            .map_err(|_| Error::BadRequestError("Invalid Content-Length"))?;
+        for value in values.split(',').map(str::trim) {
+            let value = Some(value)
+                .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|value| value.parse().ok())
+                .ok_or(Error::BadRequestError("Invalid Content-Length"))?;
+            if length.is_some_and(|length| length != value) {
+                return Err(Error::BadRequestError("Conflicting Content-Length"));
+            }
+            length = Some(value);
+        }
+    }
+    let length = length.unwrap_or(0);
+    if max.is_some_and(|max| length > max as u64) {
+        return Err(Error::PayloadTooLargeError);
+    }
+
+    Ok(BodyFraming::Length(length))
+}
+
+// Passes a request body on through `sender` as it arrives, without its chunked framing, and
+// returns its size. Once nobody receives it any more the rest is still read and dropped, so the
+// next request on the connection starts in the right place.
+pub(crate) async fn stream_body<R>(
+    framing: BodyFraming,
+    reader: &mut R,
+    sender: Sender,
+    max: Option<usize>,
+) -> Result<u64, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut sender = Some(sender);
+    let result = match framing {
+        BodyFraming::Length(length) => pass_body(reader, length, &mut sender).await.map(|_| length),
+        BodyFraming::Chunked => pass_chunked_body(reader, &mut sender, max).await,
+    };
+    if result.is_err() {
+        // Otherwise the upstream would take what came so far as the whole body.
+        if let Some(sender) = sender {
+            sender.abort();
+        }
+    }
+
+    result
+}
+
+async fn pass_body<R>(
+    reader: &mut R,
+    mut remaining: u64,
+    sender: &mut Option<Sender>,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    while remaining > 0 {
+        let available = reader.fill_buf().await.map_err(Error::BadHttpError)?;
+        if available.is_empty() {
+            return Err(Error::BadHttpError(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let len = remaining.min(available.len() as u64) as usize;
+        let chunk = Bytes::copy_from_slice(&available[..len]);
+        reader.consume(len);
+        remaining -= len as u64;
+
+        if let Some(channel) = sender.as_mut() {
+            if channel.send_data(chunk).await.is_err() {
+                *sender = None;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn pass_chunked_body<R>(
+    reader: &mut R,
+    sender: &mut Option<Sender>,
+    max: Option<usize>,
+) -> Result<u64, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut size: u64 = 0;
+
+    loop {
+        let line = read_chunk_line(reader, MAX_CHUNK_LINE_SIZE).await?;
+        let chunk_size = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .map(str::trim)
+            // Hex digits only; from_str_radix would take a sign too.
+            .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|size| u64::from_str_radix(size, 16).ok())
+            .ok_or(Error::BadRequestError("Invalid chunk size"))?;
+
+        if chunk_size == 0 {
+            break;
+        }
+        size = size
+            .checked_add(chunk_size)
+            .ok_or(Error::PayloadTooLargeError)?;
+        if max.is_some_and(|max| size > max as u64) {
+            return Err(Error::PayloadTooLargeError);
+        }
+
+        pass_body(reader, chunk_size, sender).await?;
+
+        let mut crlf = [0u8; 2];
+        reader
+            .read_exact(&mut crlf)
+            .await
+            .map_err(Error::BadHttpError)?;
+        if crlf != *b"\r\n" {
+            return Err(Error::BadRequestError("Invalid chunk terminator"));
+        }
+    }
+
+    let mut left = MAX_CHUNK_LINE_SIZE;
+    loop {
+        let trailer = read_chunk_line(reader, left).await?;
+        left -= trailer.len();
+
+        if trailer == b"\r\n" || trailer == b"\n" {
+            break Ok(size);
+        }
+    }
+}
+
+// A whole line of at most `max` bytes, its line feed included.
+async fn read_chunk_line<R>(reader: &mut R, max: usize) -> Result<Vec<u8>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let len = reader
+        .take(max as u64)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(Error::ReadUntilError)?;
+    if line.ends_with(b"\n") {
+        return Ok(line);
+    }
+    if len == max {
+        return Err(Error::BadRequestError("Chunk line too long"));
+    }
+
+    // The body ended before it said it would.
+    Err(Error::BadHttpError(io::ErrorKind::UnexpectedEof.into()))
+}
+
+pub(crate) fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    let mut buf = format!("{:x}\r\n", data.len()).into_bytes();
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+
+    buf
+}
+
+// The chunk that ends a chunked body, with any trailer fields.
+pub(crate) fn encode_last_chunk(trailers: Option<&HeaderMap>) -> Vec<u8> {
+    let mut buf = b"0\r\n".to_vec();
+    for (name, value) in trailers.into_iter().flatten() {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"\r\n");
+
+    buf
+}
+
+// How a response body is framed on the wire to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResponseFraming {
+    // Answers to HEAD and 1xx, 204 and 304 responses, which never have one.
+    Empty,
+    Length(u64),
+    Chunked,
+    // Ended by closing the connection, when the length is unknown and chunks are not understood.
+    Close,
+}
+
+// Settles how a response goes to a client speaking `version` and makes its headers say so.
+// Whatever coding the body came in was already undone, so only `exact`, the size of the body
+// when known up front, or else a valid Content-Length is trusted.
+pub(crate) fn response_framing(
+    version: Version,
+    is_head: bool,
+    status: StatusCode,
+    headers: &mut HeaderMap,
+    exact: Option<u64>,
+) -> ResponseFraming {
+    headers.remove(TRANSFER_ENCODING);
+    // These keep the length the body of a GET would have had.
+    if is_head || status == StatusCode::NOT_MODIFIED {
+        return ResponseFraming::Empty;
+    }
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        headers.remove(CONTENT_LENGTH);
+        return ResponseFraming::Empty;
+    }
+
+    if let Some(length) = exact.or_else(|| content_length(headers)) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        return ResponseFraming::Length(length);
+    }
+    headers.remove(CONTENT_LENGTH);
+    if version >= Version::HTTP_11 {
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        ResponseFraming::Chunked
+    } else {
+        ResponseFraming::Close
+    }
+}
+
+// None for a malformed escape or bytes that are not UTF-8.
+pub(crate) fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+pub(crate) fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+pub(crate) fn format_host(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+pub(crate) fn join_host_port(host: &str, port: u16) -> String {
+    format!("{}:{}", format_host(host), port)
+}
+
+// A handshake record of any TLS version, so other protocols starting with 0x16 are not
+// mistaken for one.
+pub(crate) fn is_tls_handshake(preface: &[u8]) -> bool {
+    preface.first() == Some(&0x16) && preface.get(1).is_none_or(|major| *major == 0x03)
+}
+
+pub(crate) fn is_http1_request(preface: &[u8]) -> bool {
+    const METHODS: [&[u8]; 8] = [
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"DELETE ",
+        b"HEAD ",
+        b"OPTIONS ",
+        b"PATCH ",
+        b"TRACE ",
+    ];
+
+    METHODS
+        .iter()
+        .any(|method| preface.starts_with(method) || method.starts_with(preface))
+}
+
+pub(crate) fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+pub(crate) fn wants_keep_alive(version: Version, headers: &HeaderMap) -> bool {
+    let tokens = headers
+        .get_all(CONNECTION)
+        .iter()
+        .chain(headers.get_all(PROXY_CONNECTION).iter())
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    if tokens.iter().any(|token| token == "close") {
+        false
+    } else if tokens.iter().any(|token| token == "keep-alive") {
+        true
+    } else {
+        version >= Version::HTTP_11
+    }
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+// Removes the hop-by-hop headers, and any others the Connection header names, before a message
+// is passed on. `TE: trailers` is end to end in practice, as gRPC depends on it; an upgrade is
+// kept when `upgrade` says it is being passed on too.
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let named = connection_tokens(headers)
+        .filter_map(|token| HeaderName::from_bytes(token.as_bytes()).ok())
+        .collect::<Vec<_>>();
+    let trailers = accepts_trailers(headers);
+    let upgrade = headers.get(UPGRADE).cloned().filter(|_| upgrade);
+
+    for name in HOP_BY_HOP.iter().chain(&named) {
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert(UPGRADE, upgrade);
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+pub(crate) fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .any(|value| value.as_bytes().eq_ignore_ascii_case(b"trailers"))
+}
+
+pub(crate) fn wants_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE)
+        && connection_tokens(headers).any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
 
-            vec.append(&mut buf);
-            vec.append(&mut check.to_vec());
+// A body read up to a size, for interceptors that have to see all of it.
+pub(crate) enum BoundedBody {
+    Full(Bytes),
+    // Past the size: what was read, then the rest as it arrives.
+    TooLarge(Body),
+}
 
-            if check == [b'\n', b'\r', b'\n'] {
-                break Ok(vec.len());
+pub(crate) async fn read_body(mut body: Body, max: usize) -> Result<BoundedBody, hyper::Error> {
+    let mut read = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        read.extend_from_slice(&chunk?);
+        if read.len() > max {
+            return Ok(BoundedBody::TooLarge(prepend(read.freeze(), body)));
+        }
+    }
+
+    Ok(BoundedBody::Full(read.freeze()))
+}
+
+fn prepend(first: Bytes, mut rest: Body) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        if sender.send_data(first).await.is_err() {
+            return;
+        }
+        while let Some(chunk) = rest.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    sender.abort();
+                    return;
+                }
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
             }
         }
+        if let Ok(Some(trailers)) = rest.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+
+    body
+}
+
+// Just the path and query of a target, whatever form it came in.
+pub(crate) fn origin_form(uri: &Uri) -> Uri {
+    uri.path_and_query()
+        .and_then(|path| Uri::try_from(path.as_str()).ok())
+        .unwrap_or_else(|| Uri::from_static("/"))
+}
+
+// An absolute-form target names the host itself, which HTTP/1.0 clients may not send otherwise,
+// and wins over any Host sent along as RFC 7230 section 5.4 has it.
+pub(crate) fn host_from_target<B>(req: &mut Request<B>) {
+    if req.method() == Method::CONNECT {
+        return;
+    }
+    if let Some(host) = req
+        .uri()
+        .authority()
+        .and_then(|host| HeaderValue::from_str(host.as_str()).ok())
+    {
+        req.headers_mut().insert(HOST, host);
+    }
+}
+
+pub(crate) fn append_via(version: Version, headers: &mut HeaderMap, pseudonym: &str) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", protocol, pseudonym)) {
+        headers.append(VIA, value);
+    }
+}
+
+pub(crate) fn set_flow_id(headers: &mut HeaderMap, flow: Ulid) {
+    if let Ok(value) = HeaderValue::from_str(&flow.to_string()) {
+        headers.insert(FLOW_ID, value);
+    }
+}
+
+// For a body replaced as a whole, whatever framing it had before.
+pub(crate) fn set_content_length(headers: &mut HeaderMap, length: usize) {
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+}
+
+// Points a request at `uri`. On the flow's own origin it stays in origin form on the connection
+// it came in on; any other origin makes it absolute, so it is sent there instead.
+pub(crate) fn retarget<B>(origin: &Uri, req: &mut Request<B>, uri: Uri) {
+    let same_origin = (uri.scheme(), uri.authority()) == (origin.scheme(), origin.authority());
+    if same_origin && req.uri().authority().is_none() {
+        if let Some(path_and_query) = uri.path_and_query() {
+            if let Ok(uri) = Uri::try_from(path_and_query.as_str()) {
+                *req.uri_mut() = uri;
+            }
+        }
+        return;
+    }
+
+    if let Some(host) = uri
+        .authority()
+        .and_then(|host| HeaderValue::from_str(host.as_str()).ok())
+    {
+        req.headers_mut().insert(HOST, host);
+    }
+    *req.uri_mut() = uri;
+}
+
+pub(crate) fn encode_response_head(
+    version: Version,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Vec<u8> {
+    let mut buf = format!(
+        "{:?} {} {}\r\n",
+        version,
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+
+    for (name, value) in headers {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"\r\n");
+
+    buf
+}
+
+pub(crate) fn error_response(status: StatusCode) -> Vec<u8> {
+    let body = status.canonical_reason().unwrap_or_default();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        headers.insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"yaler\""),
+        );
+    }
+
+    let mut buf = encode_response_head(Version::HTTP_11, status, &headers);
+    buf.extend_from_slice(body.as_bytes());
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(fields: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    async fn chunked(body: &[u8], max: Option<usize>) -> Result<u64, Error> {
+        let mut reader = body;
+        pass_chunked_body(&mut reader, &mut None, max).await
+    }
+
+    #[tokio::test]
+    async fn reads_request_heads() {
+        let mut reader = &b"POST http://a.test/x HTTP/1.1\r\nHost: a.test\r\n\r\nbody"[..];
+        let req = reader.read_request_head(None).await.unwrap().unwrap();
+        assert_eq!(req.method(), Method::POST);
+        assert_eq!(req.uri(), "http://a.test/x");
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert_eq!(req.headers()[HOST], "a.test");
+        // The body stays for whoever reads on.
+        assert_eq!(reader, b"body");
+
+        let mut reader = &b"GET / HTTP/1.0\nHost: a.test\n\n"[..];
+        let req = reader.read_request_head(None).await.unwrap().unwrap();
+        assert_eq!(req.version(), Version::HTTP_10);
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_request_heads_to_their_end() {
+        let mut reader = &b""[..];
+        assert!(reader.read_request_head(None).await.unwrap().is_none());
+
+        let mut reader = &b"GET / HTTP/1.1\r\nHost: a.te"[..];
+        assert!(matches!(
+            reader.read_request_head(None).await,
+            Err(Error::BadHttpError(_))
+        ));
+
+        let mut reader = &b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n"[..];
+        assert!(matches!(
+            reader.read_request_head(Some(16)).await,
+            Err(Error::HeaderTooLargeError)
+        ));
+
+        let mut reader = &b"GET / HTTP/1.1\r\nHost a.test\r\n\r\n"[..];
+        assert!(matches!(
+            reader.read_request_head(None).await,
+            Err(Error::HttpParseError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_response_status() {
+        let mut reader = &b"HTTP/1.1 200 Connection established\r\n\r\nhello"[..];
+        assert_eq!(
+            reader.read_response_status(Some(1024)).await.unwrap(),
+            StatusCode::OK
+        );
+        assert_eq!(reader, b"hello");
+
+        let mut reader = &b"HTTP/1.1 407 Proxy Authentication Required\r\n"[..];
+        assert!(matches!(
+            reader.read_response_status(None).await,
+            Err(Error::BadHttpError(_))
+        ));
+
+        let mut reader = &b"HTTP/1.1 200 OK\r\nX-Padding: aaaaaaaaaaaaaaaa\r\n\r\n"[..];
+        assert!(matches!(
+            reader.read_response_status(Some(24)).await,
+            Err(Error::HeaderTooLargeError)
+        ));
+    }
+
+    #[test]
+    fn frames_bodies_by_their_headers() {
+        let framing = |fields| body_framing(&headers(fields), Some(100));
+
+        assert_eq!(framing(&[]).unwrap(), BodyFraming::Length(0));
+        assert_eq!(
+            framing(&[("content-length", "5")]).unwrap(),
+            BodyFraming::Length(5)
+        );
+        assert_eq!(
+            framing(&[("content-length", "5, 5")]).unwrap(),
+            BodyFraming::Length(5)
+        );
+        assert_eq!(
+            framing(&[("content-length", "5"), ("content-length", "5")]).unwrap(),
+            BodyFraming::Length(5)
+        );
+        assert_eq!(
+            framing(&[("transfer-encoding", "gzip, chunked")]).unwrap(),
+            BodyFraming::Chunked
+        );
+        // Chunked coding wins over a Content-Length that comes with it.
+        assert_eq!(
+            framing(&[("transfer-encoding", "chunked"), ("content-length", "5")]).unwrap(),
+            BodyFraming::Chunked
+        );
+    }
+
+    #[test]
+    fn refuses_ambiguous_body_framing() {
+        let framing = |fields| body_framing(&headers(fields), Some(100));
+
+        for fields in [
+            &[("content-length", "5"), ("content-length", "6")][..],
+            &[("content-length", "5, 6")],
+            &[("content-length", "+5")],
+            &[("content-length", "-1")],
+            &[("content-length", "")],
+            &[("content-length", "0x5")],
+            &[("transfer-encoding", "gzip")],
+            &[
+                ("transfer-encoding", "chunked, gzip"),
+                ("content-length", "5"),
+            ],
+        ] {
+            assert!(
+                matches!(framing(fields), Err(Error::BadRequestError(_))),
+                "{:?}",
+                fields
+            );
+        }
+        assert!(matches!(
+            framing(&[("content-length", "101")]),
+            Err(Error::PayloadTooLargeError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn passes_chunked_bodies() {
+        assert_eq!(chunked(b"5\r\nhello\r\n0\r\n\r\n", None).await.unwrap(), 5);
+        assert_eq!(
+            chunked(b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", None)
+                .await
+                .unwrap(),
+            11
+        );
+        assert_eq!(
+            chunked(b"5\nhello\r\n0\nExpires: never\r\nX-Sum: 1\n\n", None)
+                .await
+                .unwrap(),
+            5
+        );
+
+        // What follows the body is left for the next request.
+        let mut reader = &b"0\r\n\r\nGET"[..];
+        pass_chunked_body(&mut reader, &mut None, None)
+            .await
+            .unwrap();
+        assert_eq!(reader, b"GET");
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_chunk_sizes() {
+        for body in [
+            &b"+5\r\nhello\r\n0\r\n\r\n"[..],
+            b"-5\r\nhello\r\n0\r\n\r\n",
+            b"\r\nhello\r\n0\r\n\r\n",
+            b"0x5\r\nhello\r\n0\r\n\r\n",
+            // More than a u64 holds.
+            b"10000000000000000\r\n",
+        ] {
+            assert!(
+                matches!(chunked(body, None).await, Err(Error::BadRequestError(_))),
+                "{:?}",
+                String::from_utf8_lossy(body)
+            );
+        }
+
+        assert!(matches!(
+            chunked(b"5\r\nhelloX\n0\r\n\r\n", None).await,
+            Err(Error::BadRequestError(_))
+        ));
+        assert!(matches!(
+            chunked(&[b'1'; MAX_CHUNK_LINE_SIZE + 1], None).await,
+            Err(Error::BadRequestError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_chunked_bodies() {
+        assert!(matches!(
+            chunked(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", Some(10)).await,
+            Err(Error::PayloadTooLargeError)
+        ));
+        // Refused on its size line, before any of the data comes.
+        assert!(matches!(
+            chunked(b"ffffffffffffffff\r\n", Some(10)).await,
+            Err(Error::PayloadTooLargeError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_chunked_bodies_cut_short() {
+        for body in [
+            &b""[..],
+            b"5\r\nhel",
+            b"5\r\nhello",
+            b"5\r\nhello\r\n",
+            b"5\r\nhello\r\n0\r\n",
+            b"5\r\nhello\r\n0\r\nX-Sum: 1\r\n",
+            b"5\r\nhello\r\n0\r\nX-Sum: 1",
+        ] {
+            assert!(
+                matches!(chunked(body, None).await, Err(Error::BadHttpError(_))),
+                "{:?}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[test]
+    fn frames_responses_without_a_body() {
+        let mut fields = headers(&[("content-length", "5"), ("transfer-encoding", "chunked")]);
+        assert_eq!(
+            response_framing(Version::HTTP_11, true, StatusCode::OK, &mut fields, None),
+            ResponseFraming::Empty
+        );
+        // HEAD answers keep the length a GET would have had.
+        assert_eq!(fields[CONTENT_LENGTH], "5");
+        assert!(!fields.contains_key(TRANSFER_ENCODING));
+
+        let mut fields = headers(&[("content-length", "5")]);
+        assert_eq!(
+            response_framing(
+                Version::HTTP_11,
+                false,
+                StatusCode::NOT_MODIFIED,
+                &mut fields,
+                None
+            ),
+            ResponseFraming::Empty
+        );
+        assert_eq!(fields[CONTENT_LENGTH], "5");
+
+        let mut fields = headers(&[("content-length", "5")]);
+        assert_eq!(
+            response_framing(
+                Version::HTTP_11,
+                false,
+                StatusCode::NO_CONTENT,
+                &mut fields,
+                None
+            ),
+            ResponseFraming::Empty
+        );
+        assert!(!fields.contains_key(CONTENT_LENGTH));
+    }
+
+    #[test]
+    fn frames_responses_with_a_body() {
+        // The exact size beats what the headers claimed.
+        let mut fields = headers(&[("content-length", "5"), ("transfer-encoding", "chunked")]);
+        assert_eq!(
+            response_framing(
+                Version::HTTP_11,
+                false,
+                StatusCode::OK,
+                &mut fields,
+                Some(7)
+            ),
+            ResponseFraming::Length(7)
+        );
+        assert_eq!(fields[CONTENT_LENGTH], "7");
+        assert!(!fields.contains_key(TRANSFER_ENCODING));
+
+        let mut fields = headers(&[("content-length", "5")]);
+        assert_eq!(
+            response_framing(Version::HTTP_10, false, StatusCode::OK, &mut fields, None),
+            ResponseFraming::Length(5)
+        );
+
+        let mut fields = headers(&[("content-length", "five")]);
+        assert_eq!(
+            response_framing(Version::HTTP_11, false, StatusCode::OK, &mut fields, None),
+            ResponseFraming::Chunked
+        );
+        assert!(!fields.contains_key(CONTENT_LENGTH));
+        assert_eq!(fields[TRANSFER_ENCODING], "chunked");
+
+        let mut fields = headers(&[("transfer-encoding", "chunked")]);
+        assert_eq!(
+            response_framing(Version::HTTP_10, false, StatusCode::OK, &mut fields, None),
+            ResponseFraming::Close
+        );
+        assert!(!fields.contains_key(TRANSFER_ENCODING));
+    }
+
+    #[test]
+    fn strips_ipv6_brackets() {
+        assert_eq!(strip_brackets("[::1]"), "::1");
+        assert_eq!(strip_brackets("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(strip_brackets("::1"), "::1");
+        assert_eq!(strip_brackets("example.com"), "example.com");
+        assert_eq!(strip_brackets("[::1"), "[::1");
+    }
+
+    #[test]
+    fn formats_ipv6_hosts_in_brackets() {
+        assert_eq!(format_host("::1"), "[::1]");
+        assert_eq!(format_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(format_host("example.com"), "example.com");
+        assert_eq!(
+            format_host(strip_brackets("[2001:db8::1]")),
+            "[2001:db8::1]"
+        );
+    }
+
+    #[test]
+    fn joins_hosts_and_ports() {
+        assert_eq!(join_host_port("::1", 443), "[::1]:443");
+        assert_eq!(join_host_port("2001:db8::1", 8080), "[2001:db8::1]:8080");
+        assert_eq!(join_host_port("127.0.0.1", 80), "127.0.0.1:80");
+        assert_eq!(join_host_port("example.com", 443), "example.com:443");
+        assert_eq!(
+            join_host_port(strip_brackets("[::1]"), 443)
+                .parse::<std::net::SocketAddr>()
+                .ok(),
+            Some(std::net::SocketAddr::from((
+                [0u16, 0, 0, 0, 0, 0, 0, 1],
+                443
+            )))
+        );
     }
 }
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::{Request, Response};
+use hyper::Body;
+
+use crate::flow::{Direction, FlowRequest};
+use crate::sse::ServerSentEvent;
+use crate::websocket::WebSocketMessage;
+
+pub enum RequestAction {
+    Forward(Request<Body>),
+    Respond(Response<Body>),
+    Block,
+}
+
+pub enum ResponseAction {
+    Forward(Response<Body>),
+    Block,
+}
+
+pub enum MessageAction {
+    Forward(WebSocketMessage),
+    Drop,
+}
+
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    async fn on_request(&self, _flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        RequestAction::Forward(req)
+    }
+
+    async fn on_response(&self, _flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        ResponseAction::Forward(res)
+    }
+
+    // Called once a message has arrived in full, before any of it is passed on.
+    async fn on_websocket_message(
+        &self,
+        _flow: &FlowRequest,
+        _direction: Direction,
+        message: WebSocketMessage,
+    ) -> MessageAction {
+        MessageAction::Forward(message)
+    }
+
+    // Called for each event of a `text/event-stream` response, once it has been passed on.
+    async fn on_server_sent_event(&self, _flow: &FlowRequest, _event: &ServerSentEvent) {}
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct Interceptors(Vec<Arc<dyn Interceptor>>);
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.0.push(interceptor);
+    }
+
+    pub(crate) async fn on_request(
+        &self,
+        flow: &FlowRequest,
+        mut req: Request<Body>,
+    ) -> RequestAction {
+        for interceptor in &self.0 {
+            match interceptor.on_request(flow, req).await {
+                RequestAction::Forward(next) => req = next,
+                action => return action,
+            }
+        }
+
+        RequestAction::Forward(req)
+    }
+
+    pub(crate) async fn on_response(
+        &self,
+        flow: &FlowRequest,
+        mut res: Response<Body>,
+    ) -> ResponseAction {
+        for interceptor in self.0.iter().rev() {
+            match interceptor.on_response(flow, res).await {
+                ResponseAction::Forward(next) => res = next,
+                action => return action,
+            }
+        }
+
+        ResponseAction::Forward(res)
+    }
+
+    pub(crate) async fn on_websocket_message(
+        &self,
+        flow: &FlowRequest,
+        direction: Direction,
+        mut message: WebSocketMessage,
+    ) -> MessageAction {
+        for interceptor in &self.0 {
+            match interceptor
+                .on_websocket_message(flow, direction, message)
+                .await
+            {
+                MessageAction::Forward(next) => message = next,
+                MessageAction::Drop => return MessageAction::Drop,
+            }
+        }
+
+        MessageAction::Forward(message)
+    }
+
+    pub(crate) async fn on_server_sent_event(&self, flow: &FlowRequest, event: &ServerSentEvent) {
+        for interceptor in &self.0 {
+            interceptor.on_server_sent_event(flow, event).await;
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rustls::KeyLog;
+use tracing::warn;
+
+use crate::error::Error;
+
+pub(crate) const SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+
+// Writes secrets in the NSS key log format understood by Wireshark.
+pub(crate) struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::WriteFileError)?;
+
+        Ok(Self(Mutex::new(file)))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!(
+            "{} {} {}\n",
+            label,
+            Self::hex(client_random),
+            Self::hex(secret)
+        );
+
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            warn!(?e, "Fail to write key log");
+        }
+    }
+}
@@ -0,0 +1,109 @@
+mod acceptor;
+mod access_log;
+mod acl;
+mod acme;
+mod admin;
+mod auth;
+mod blocklist;
+mod builder;
+mod ca;
+mod capture;
+mod config;
+mod connections;
+mod dialer;
+mod encoding;
+mod error;
+mod export;
+mod fault;
+mod flow;
+mod forwarded;
+mod grpc;
+mod har;
+mod http;
+mod intercept;
+mod keylog;
+mod limit;
+mod listen;
+mod metrics;
+mod mitmproxy;
+mod pac;
+mod plugin;
+mod policy;
+mod pool;
+mod portal;
+mod redact;
+mod reload;
+mod replay;
+mod resolver;
+mod reverse;
+mod rules;
+mod script;
+mod server;
+mod shaping;
+mod sni;
+mod socks;
+mod sse;
+mod storage;
+mod tcp;
+mod telemetry;
+mod testing;
+mod timeout;
+mod tls_policy;
+mod transparent;
+mod tunnel;
+mod upstream;
+mod upstream_tls;
+mod vcr;
+mod view;
+mod web;
+mod websocket;
+
+pub use acceptor::{CertCacheConfig, CertStrategy, KeyAlgorithm, LeafParams};
+pub use access_log::{AccessLog, AccessLogFormat};
+pub use acl::{AclConfig, AclRule};
+pub use acme::{AcmeConfig, ChallengeType, LETS_ENCRYPT};
+pub use auth::{Credentials, StaticCredentials};
+pub use blocklist::{BlockAction, Blocklist, BlocklistConfig};
+pub use builder::ServerBuilder;
+pub use ca::CertificateAuthority;
+pub use capture::{CapturedFlow, FlowCapture};
+pub use config::{Config, DnsConfig, ListenerConfig, ListenerTls, Mode, UpstreamRoute};
+pub use dialer::{Dialer, DirectDialer, OutboundBind, RemoteStream, RouteDialer, Socks5Dialer};
+pub use error::Error;
+pub use export::ExportFormat;
+pub use fault::{FaultConfig, FaultKind, Faults};
+pub use flow::{Direction, FlowEvent, FlowRequest, FlowResponse, FlowSummary};
+pub use forwarded::ForwardedConfig;
+pub use grpc::{GrpcConfig, GrpcMessage};
+pub use har::{HarConfig, HarFlush, HarRecorder};
+pub use intercept::{Interceptor, MessageAction, RequestAction, ResponseAction};
+pub use limit::{Rate, RateLimitConfig, ResourceLimits};
+pub use listen::{AcceptorsConfig, ListenAddr, Transport};
+pub use pac::PacConfig;
+pub use plugin::{PluginConfig, Plugins};
+pub use policy::HostPattern;
+pub use redact::{RedactionConfig, Redactor};
+pub use replay::{
+    BodyChange, HeaderChange, Overrides, RecordedFlow, RecordedResponse, Replayed, ResponseDiff,
+};
+pub use resolver::{DnsProtocol, DnsResolver, Resolver, StaticResolver};
+pub use reverse::{VirtualHostConfig, VirtualHosts};
+pub use rules::{BodyReplace, HeaderEdits, RuleConfig, Rules};
+pub use script::Script;
+pub use server::Server;
+pub use shaping::{NetworkProfile, Shaper, ShapingConfig};
+pub use sse::ServerSentEvent;
+pub use storage::{FlowQuery, FlowStore, StorageConfig, StoredFlow};
+pub use tcp::TcpTuning;
+pub use telemetry::{OtlpConfig, Telemetry};
+pub use testing::{FakeUpstream, ReceivedRequest, TestProxy};
+pub use timeout::Timeouts;
+pub use tls_policy::{TlsPolicy, TlsVersion};
+pub use tunnel::Transferred;
+pub use upstream::UpstreamProxy;
+pub use upstream_tls::{
+    ClientCertRule, RootSource, UpstreamTlsConfig, Verification, VerificationRule,
+};
+pub use vcr::{Vcr, VcrConfig, VcrMode};
+pub use view::{BodyKind, BodyView};
+pub use websocket::WebSocketMessage;
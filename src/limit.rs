@@ -0,0 +1,371 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+
+// Per-client buckets that have refilled completely are dropped once this many are tracked.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Rate {
+    // Per second: connections, or bytes for bandwidth.
+    pub rate: f64,
+    // How far above `rate` a quiet client may briefly go; one second's worth by default.
+    pub burst: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub connections: Option<Rate>,
+    pub client_connections: Option<Rate>,
+    // Bytes relayed through tunnels, both directions together.
+    pub bandwidth: Option<Rate>,
+    pub client_bandwidth: Option<Rate>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    // Connections served at once; more are answered with 503 and closed.
+    pub max_connections: Option<usize>,
+    pub max_client_connections: Option<usize>,
+    // Bytes of a request line and headers, answered with 431 beyond it.
+    pub max_header_size: Option<usize>,
+    // Bytes per second a request head has to keep up after its first second, answered with
+    // 408 when it falls behind.
+    pub min_header_rate: Option<f64>,
+    // Bytes of a request body, answered with 413 beyond it. Bodies stream upstream as they
+    // come, so a declared length is checked up front and a chunked one is counted on the way.
+    pub max_body_size: Option<usize>,
+    // Idle connections pooled for each upstream origin.
+    pub max_idle_upstream_connections: Option<usize>,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_client_connections: None,
+            max_header_size: Some(DEFAULT_MAX_HEADER_SIZE),
+            min_header_rate: None,
+            max_body_size: None,
+            max_idle_upstream_connections: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn new(rate: &Rate) -> Self {
+        let burst = rate.burst.unwrap_or(rate.rate).max(1.0);
+        Self {
+            rate: rate.rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    // Takes `amount` even when it runs the bucket into debt, and says how long until it is
+    // paid back.
+    pub(crate) fn charge(&mut self, now: Instant, amount: f64) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.burst
+    }
+}
+
+#[derive(Debug)]
+struct ClientBuckets {
+    rate: Rate,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl ClientBuckets {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn with<T>(&self, client: IpAddr, now: Instant, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+
+        f(buckets
+            .entry(client)
+            .or_insert_with(|| Bucket::new(&self.rate)))
+    }
+}
+
+// Token buckets for new connections and relayed bytes, globally and per client address.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    connections: Option<Mutex<Bucket>>,
+    client_connections: Option<ClientBuckets>,
+    bandwidth: Option<Mutex<Bucket>>,
+    client_bandwidth: Option<ClientBuckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            connections: config.connections.as_ref().map(Bucket::new).map(Mutex::new),
+            client_connections: config.client_connections.map(ClientBuckets::new),
+            bandwidth: config.bandwidth.as_ref().map(Bucket::new).map(Mutex::new),
+            client_bandwidth: config.client_bandwidth.map(ClientBuckets::new),
+        }
+    }
+
+    // Whether a new connection from `client` may be served; a refused one costs nothing.
+    pub(crate) fn admit(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut global = self
+            .connections
+            .as_ref()
+            .map(|bucket| bucket.lock().unwrap());
+        if let Some(bucket) = global.as_mut() {
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+        if let Some(buckets) = &self.client_connections {
+            let admitted = buckets.with(client, now, |bucket| {
+                bucket.refill(now);
+                let admitted = bucket.tokens >= 1.0;
+                if admitted {
+                    bucket.tokens -= 1.0;
+                }
+                admitted
+            });
+            if !admitted {
+                return false;
+            }
+        }
+        if let Some(bucket) = global.as_mut() {
+            bucket.tokens -= 1.0;
+        }
+
+        true
+    }
+
+    // Whether streams passed to `throttle` are slowed down at all.
+    pub(crate) fn limits_bandwidth(&self) -> bool {
+        self.bandwidth.is_some() || self.client_bandwidth.is_some()
+    }
+
+    pub(crate) fn throttle<S>(&self, client: IpAddr, stream: S) -> Throttled<'_, S> {
+        Throttled {
+            inner: stream,
+            limiter: self,
+            client,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    fn charge(&self, client: IpAddr, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let global = self
+            .bandwidth
+            .as_ref()
+            .map(|bucket| bucket.lock().unwrap().charge(now, bytes as f64))
+            .unwrap_or_default();
+        let own = self
+            .client_bandwidth
+            .as_ref()
+            .map(|buckets| buckets.with(client, now, |bucket| bucket.charge(now, bytes as f64)))
+            .unwrap_or_default();
+
+        global.max(own)
+    }
+}
+
+// Holds off the next read or write until the bytes of the previous one fit the bandwidth
+// limits, so both directions of a client stream are paced.
+pub(crate) struct Throttled<'a, S> {
+    inner: S,
+    limiter: &'a RateLimiter,
+    client: IpAddr,
+    // Apart, as the two halves of a split stream are polled from different tasks.
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<'_, S> {
+    fn wait(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(sleep) = delay.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        Poll::Ready(())
+    }
+
+    fn charge(&self, bytes: usize) -> Option<Pin<Box<Sleep>>> {
+        let wait = self.limiter.charge(self.client, bytes);
+        (!wait.is_zero()).then(|| Box::pin(tokio::time::sleep(wait)))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(Self::wait(&mut self.read_delay, cx));
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.read_delay = self.charge(buf.filled().len() - filled);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for Throttled<'_, S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(Self::wait(&mut this.read_delay, cx));
+        Pin::new(&mut this.inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.read_delay = self.charge(amt);
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Self::wait(&mut self.write_delay, cx));
+
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.write_delay = self.charge(written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+type ClientCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+// Caps on connections served at once, in total and per client address.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionLimiter {
+    total: Option<Arc<Semaphore>>,
+    per_client: Option<usize>,
+    clients: ClientCounts,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(limits: &ResourceLimits) -> Self {
+        Self {
+            total: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            per_client: limits.max_client_connections,
+            clients: ClientCounts::default(),
+        }
+    }
+
+    // A permit held for as long as the connection is served; None when a cap is reached.
+    pub(crate) fn acquire(&self, client: IpAddr) -> Option<ConnectionPermit> {
+        let total = match &self.total {
+            Some(total) => Some(total.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        let max = match self.per_client {
+            Some(max) => max,
+            None => {
+                return Some(ConnectionPermit {
+                    _total: total,
+                    client: None,
+                })
+            }
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.entry(client).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionPermit {
+            _total: total,
+            client: Some((self.clients.clone(), client)),
+        })
+    }
+}
+
+pub(crate) struct ConnectionPermit {
+    _total: Option<OwnedSemaphorePermit>,
+    client: Option<(ClientCounts, IpAddr)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((clients, client)) = &self.client {
+            if let Entry::Occupied(mut count) = clients.lock().unwrap().entry(*client) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+        }
+    }
+}
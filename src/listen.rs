@@ -0,0 +1,301 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::server::TlsStream;
+
+use crate::config::Mode;
+use crate::error::Error;
+use crate::tcp::TcpTuning;
+use crate::transparent;
+
+const UNIX_PREFIX: &str = "unix:";
+
+// Where a listener binds: `host:port`, or `unix:PATH` for a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(Error::BadRequestError("Unix socket path is empty")),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| Error::BadRequestError("Invalid listen address")),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+// The loops accepting connections. With more than one, each binds a SO_REUSEPORT socket of its
+// own for every TCP listener and the system spreads connections among them; Unix listeners are
+// left to the first.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct AcceptorsConfig {
+    pub count: usize,
+    // Gives each a thread and runtime of its own, which the connections it accepts are served
+    // on, rather than running them all as tasks of the shared runtime.
+    pub pin: bool,
+}
+
+impl Default for AcceptorsConfig {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            pin: false,
+        }
+    }
+}
+
+pub(crate) enum BoundListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl BoundListener {
+    // With `reuse_port`, TCP listeners can be bound more than once to the same address.
+    pub(crate) async fn bind(
+        addr: &ListenAddr,
+        mode: Mode,
+        tcp: &TcpTuning,
+        reuse_port: bool,
+    ) -> Result<Self, Error> {
+        match addr {
+            ListenAddr::Tcp(addr) if mode == Mode::Transparent => Ok(BoundListener::Tcp(
+                transparent::bind(*addr, tcp, reuse_port)?,
+            )),
+            ListenAddr::Tcp(addr) => {
+                let socket = Socket::new(
+                    Domain::for_address(*addr),
+                    Type::STREAM,
+                    Some(Protocol::TCP),
+                )
+                .map_err(Error::TcpBindError)?;
+                tcp.listen(socket, *addr, reuse_port)
+                    .map(BoundListener::Tcp)
+                    .map_err(Error::TcpBindError)
+            }
+            ListenAddr::Unix(_) if mode == Mode::Transparent => Err(Error::InvalidConfigError(
+                "Transparent mode needs a TCP listener",
+            )),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // A socket left behind by an earlier run would fail the bind.
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    std::fs::remove_file(path).map_err(Error::TcpBindError)?;
+                }
+                UnixListener::bind(path)
+                    .map(BoundListener::Unix)
+                    .map_err(Error::TcpBindError)
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(Error::InvalidConfigError(
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    // Moves the listener to the runtime this is called in, whose reactor then drives it and the
+    // connections it accepts.
+    pub(crate) fn rebind(self) -> io::Result<Self> {
+        match self {
+            BoundListener::Tcp(listener) => {
+                TcpListener::from_std(listener.into_std()?).map(BoundListener::Tcp)
+            }
+            #[cfg(unix)]
+            BoundListener::Unix(listener) => {
+                UnixListener::from_std(listener.into_std()?).map(BoundListener::Unix)
+            }
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            BoundListener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            BoundListener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or_else(|| "".as_ref());
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+
+    pub(crate) fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(ClientStream, SocketAddr)>> {
+        match self {
+            BoundListener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, peer)| (ClientStream::Tcp(stream), peer)),
+            #[cfg(unix)]
+            BoundListener::Unix(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| (ClientStream::Unix(stream), local_peer())),
+        }
+    }
+}
+
+// For clients without an address of their own, like those of Unix sockets. Each gets a made-up
+// loopback one, so they count as local; the port only tells them apart in logs, and repeats.
+pub(crate) fn local_peer() -> SocketAddr {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+    SocketAddr::from((
+        Ipv4Addr::LOCALHOST,
+        NEXT_PORT.fetch_add(1, Ordering::Relaxed),
+    ))
+}
+
+// What client connections are served over, whatever carries them.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    // Makes closing the connection reset it, where the transport has a way to.
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The socket the transport is, when it is a bare TCP one.
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    // A zero linger makes closing send RST instead of FIN.
+    fn reset(&self) -> io::Result<()> {
+        socket2::SockRef::from(self).set_linger(Some(Duration::ZERO))
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {}
+
+impl Transport for DuplexStream {}
+
+impl<S: Transport> Transport for TlsStream<S> {
+    fn reset(&self) -> io::Result<()> {
+        self.get_ref().0.reset()
+    }
+}
+
+impl Transport for ClientStream {
+    fn reset(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.reset(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.reset(),
+        }
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            ClientStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => None,
+        }
+    }
+}
+
+// A client connection, whichever kind of listener it came to.
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
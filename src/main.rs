@@ -1,34 +1,314 @@
-mod acceptor;
-mod error;
-mod http;
-mod server;
+mod cli;
+mod tui;
 
-use crate::server::Server;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use acceptor::AcceptorMap;
-use std::sync::{Arc, Mutex};
+use clap::Parser;
+use http::header::{HeaderName, HeaderValue};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+
+use yaler::{
+    CertificateAuthority, Config, Error, FlowCapture, FlowQuery, FlowStore, ListenAddr, Overrides,
+    RecordedFlow, Redactor, ResponseDiff, ServerBuilder, Telemetry,
+};
+
+use crate::cli::{Args, Command, FlowsCommand, ReplayArgs};
+
+const DEFAULT_CONFIG: &str = "yaler.toml";
 
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt().init();
-
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-
-    let acceptor = AcceptorMap::new(
-        include_str!("../cert/root.crt").to_string(),
-        include_str!("../cert/key.pem").to_string(),
-    );
-
-    let server = Server::bind("127.0.0.1:5333", root_store, Arc::new(Mutex::new(acceptor)))
-        .await
-        .unwrap();
-
-    server.run().await.unwrap();
+async fn main() -> Result<(), Error> {
+    let args = Args::parse();
+
+    if let Some(Command::InitCa { dir, force }) = &args.command {
+        tracing_subscriber::fmt()
+            .with_max_level(args.log_level)
+            .init();
+        return init_ca(dir.as_deref(), *force);
+    }
+
+    let path = args
+        .config
+        .clone()
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG)).filter(|path| path.exists()));
+    let mut config = match &path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    args.apply(&mut config);
+
+    if let Some(Command::Flows { command }) = &args.command {
+        return flows(command, &config);
+    }
+
+    // Held until return so buffered spans are exported on shutdown.
+    let telemetry = config.otlp.as_ref().map(Telemetry::init).transpose()?;
+    let tui = matches!(args.command, Some(Command::Tui));
+    // Replay prints its report to stdout, which log lines would interleave with.
+    let quiet = tui || matches!(args.command, Some(Command::Replay(_)));
+    tracing_subscriber::registry()
+        .with(LevelFilter::from_level(args.log_level))
+        .with((!quiet).then(tracing_subscriber::fmt::layer))
+        .with(telemetry.as_ref().map(Telemetry::layer))
+        .init();
+
+    if tui {
+        let capture = FlowCapture::new();
+        let server = ServerBuilder::from_config(&config)?
+            .capture(capture.clone())
+            .build()
+            .await?;
+        let server = Arc::new(server);
+
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+        if let Some(path) = path {
+            let watching = server.clone();
+            tokio::spawn(async move {
+                watching
+                    .watch_config(&path, |config| args.apply(config))
+                    .await
+            });
+        }
+
+        let handle = tokio::runtime::Handle::current();
+        return tokio::task::block_in_place(|| tui::run(server, capture, handle))
+            .map_err(Error::TerminalError);
+    }
+
+    if let Some(Command::Replay(replay_args)) = &args.command {
+        return replay(replay_args, &config).await;
+    }
+
+    let server = ServerBuilder::from_config(&config)?.build().await?;
+
+    let watch = async {
+        match &path {
+            Some(path) => server.watch_config(path, |config| args.apply(config)).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // Returning drops the server so recorders can flush what they buffered.
+    tokio::select! {
+        result = server.run() => result,
+        _ = watch => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+fn flows(command: &FlowsCommand, config: &Config) -> Result<(), Error> {
+    match command {
+        FlowsCommand::Query {
+            db,
+            host,
+            status,
+            method,
+            limit,
+        } => {
+            let path = storage_path(db.as_ref(), config)?;
+            let query = FlowQuery {
+                host: host.clone(),
+                status: *status,
+                method: method.clone(),
+                since: None,
+                limit: Some(*limit),
+            };
+
+            for flow in FlowStore::query(&path, &query)? {
+                let started = OffsetDateTime::from(flow.started)
+                    .format(&Rfc3339)
+                    .unwrap_or_default();
+                println!(
+                    "{}:{} {} {} {} {} {} {} {}",
+                    flow.session,
+                    flow.id,
+                    flow.ulid.as_deref().unwrap_or("-"),
+                    started,
+                    flow.method.as_deref().unwrap_or("-"),
+                    flow.url.as_deref().unwrap_or("-"),
+                    flow.status
+                        .map(|status| status.to_string())
+                        .or(flow.error.map(|_| "ERR".to_string()))
+                        .unwrap_or_else(|| "-".to_string()),
+                    flow.response_bytes.unwrap_or_default(),
+                    flow.duration
+                        .map(|duration| format!("{}ms", duration.as_millis()))
+                        .unwrap_or_else(|| "-".to_string()),
+                );
+            }
+        }
+        FlowsCommand::Export { db, id, format } => {
+            let (session, id) = parse_flow_id(id)?;
+            let path = storage_path(db.as_ref(), config)?;
+            let flow = FlowStore::load(&path, session, id)?
+                .ok_or(Error::InvalidConfigError("No such stored flow"))?;
+            // Only the start of the body was stored, which would not make the same request.
+            if flow.truncated {
+                return Err(Error::PayloadTooLargeError);
+            }
+            println!("{}", format.render(&flow));
+        }
+        FlowsCommand::Import { db, file } => {
+            let path = storage_path(db.as_ref(), config)?;
+            let flows = RecordedFlow::from_mitmproxy(file)?;
+            let session = FlowStore::import(&path, &flows, &Redactor::new(&config.redact)?)?;
+            println!("Imported {} flows as session {}", flows.len(), session);
+        }
+        FlowsCommand::Dump {
+            db,
+            file,
+            host,
+            status,
+            method,
+            limit,
+        } => {
+            let path = storage_path(db.as_ref(), config)?;
+            let query = FlowQuery {
+                host: host.clone(),
+                status: *status,
+                method: method.clone(),
+                since: None,
+                limit: *limit,
+            };
+
+            // Oldest first, as mitmproxy wrote them.
+            let mut flows = Vec::new();
+            for stored in FlowStore::query(&path, &query)?.iter().rev() {
+                // Rows of requests still in flight when the proxy stopped may lack a method or url.
+                match FlowStore::load(&path, stored.session, stored.id) {
+                    Ok(Some(flow)) => flows.push(flow),
+                    Ok(None) | Err(Error::BadRequestError(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            RecordedFlow::write_mitmproxy(file, &flows)?;
+            println!("Wrote {} flows to {}", flows.len(), file.display());
+        }
+    }
+
+    Ok(())
+}
+
+// `SESSION:ID`, as `flows query` prints them.
+fn parse_flow_id(flow: &str) -> Result<(u64, u64), Error> {
+    flow.split_once(':')
+        .and_then(|(session, id)| Some((session.parse().ok()?, id.parse().ok()?)))
+        .ok_or(Error::InvalidConfigError("Flows are given as SESSION:ID"))
+}
+
+async fn replay(args: &ReplayArgs, config: &Config) -> Result<(), Error> {
+    let flows = match (&args.har, &args.flow) {
+        (Some(har), _) => {
+            let flows = RecordedFlow::from_har(har)?;
+            match args.entry {
+                Some(entry) => vec![flows
+                    .into_iter()
+                    .nth(entry)
+                    .ok_or(Error::InvalidConfigError("No such HAR entry"))?],
+                None => flows,
+            }
+        }
+        (None, Some(flow)) => {
+            let (session, id) = parse_flow_id(flow)?;
+            let path = storage_path(args.db.as_ref(), config)?;
+            let flow = FlowStore::load(&path, session, id)?
+                .ok_or(Error::InvalidConfigError("No such stored flow"))?;
+            vec![flow]
+        }
+        (None, None) => return Err(Error::InvalidConfigError("--har or --flow is required")),
+    };
+
+    let mut overrides = Overrides::new();
+    for header in &args.headers {
+        let (name, value) = header
+            .split_once(':')
+            .and_then(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.trim().as_bytes()).ok()?,
+                    HeaderValue::from_str(value.trim()).ok()?,
+                ))
+            })
+            .ok_or(Error::InvalidConfigError("--header must be `Name: value`"))?;
+        overrides = overrides.header(name, value);
+    }
+    for name in &args.remove_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| Error::InvalidConfigError("Invalid header name"))?;
+        overrides = overrides.remove_header(name);
+    }
+    if let Some(body) = &args.body {
+        overrides = overrides.body(body.clone());
+    }
+    if let Some(path) = &args.body_file {
+        overrides = overrides.body(std::fs::read(path).map_err(Error::ReadFileError)?);
+    }
+    let ignore = args
+        .ignore_headers
+        .iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidConfigError("Invalid header name"))?;
+
+    // Stay off the ports and recordings of a proxy that may be running with the same config.
+    let config = Config {
+        listen: ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0))),
+        listeners: Vec::new(),
+        metrics_listen: None,
+        admin_listen: None,
+        web_listen: None,
+        har: None,
+        storage: None,
+        ..config.clone()
+    };
+    let server = ServerBuilder::from_config(&config)?.build().await?;
+
+    for flow in &flows {
+        println!("{} {}", flow.method, flow.uri);
+        match server.replay(flow, &overrides).await {
+            Ok(replayed) => match &flow.response {
+                Some(original) => print!(
+                    "{}",
+                    ResponseDiff::ignoring(original, &replayed.response, &ignore)
+                ),
+                None => println!("{} (no recorded response)", replayed.response.status),
+            },
+            Err(e) => println!("failed: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn storage_path(db: Option<&PathBuf>, config: &Config) -> Result<PathBuf, Error> {
+    db.cloned()
+        .or_else(|| config.storage.as_ref().map(|storage| storage.path.clone()))
+        .ok_or(Error::InvalidConfigError(
+            "storage.path or --db is required",
+        ))
+}
+
+fn init_ca(dir: Option<&Path>, force: bool) -> Result<(), Error> {
+    let (cert, key) = match dir {
+        Some(dir) => CertificateAuthority::paths_in(dir),
+        None => CertificateAuthority::default_paths(),
+    };
+
+    if cert.exists() && !force {
+        println!(
+            "CA already exists at {}, pass --force to replace it",
+            cert.display()
+        );
+    } else {
+        CertificateAuthority::generate()?.save(&cert, &key)?;
+        println!("CA written to {} and {}", cert.display(), key.display());
+    }
+    println!("{}", CertificateAuthority::install_instructions(&cert));
+
+    Ok(())
 }
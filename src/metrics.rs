@@ -0,0 +1,186 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use http::header::CONTENT_TYPE;
+use http::{Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use tracing::error;
+
+use crate::error::Error;
+use crate::flow::Direction;
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    active_connections: IntGauge,
+    tunnels_opened: IntCounter,
+    bytes_relayed: IntCounterVec,
+    cert_cache_hits: IntCounter,
+    cert_cache_misses: IntCounter,
+    cert_cache_evictions: IntCounter,
+    tls_handshake_failures: IntCounterVec,
+    upstream_connect_seconds: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Result<Self, Error> {
+        let registry =
+            Registry::new_custom(Some("yaler".to_string()), None).map_err(Error::MetricsError)?;
+
+        let metrics = Self {
+            active_connections: IntGauge::new("active_connections", "Open client connections")
+                .map_err(Error::MetricsError)?,
+            tunnels_opened: IntCounter::new("tunnels_opened_total", "Opaque tunnels relayed")
+                .map_err(Error::MetricsError)?,
+            bytes_relayed: IntCounterVec::new(
+                Opts::new(
+                    "bytes_relayed_total",
+                    "Bytes relayed between client and upstream",
+                ),
+                &["direction"],
+            )
+            .map_err(Error::MetricsError)?,
+            cert_cache_hits: IntCounter::new(
+                "cert_cache_hits_total",
+                "Leaf certificate cache hits",
+            )
+            .map_err(Error::MetricsError)?,
+            cert_cache_misses: IntCounter::new(
+                "cert_cache_misses_total",
+                "Leaf certificate cache misses",
+            )
+            .map_err(Error::MetricsError)?,
+            cert_cache_evictions: IntCounter::new(
+                "cert_cache_evictions_total",
+                "Leaf certificates evicted to keep the cache under its size cap",
+            )
+            .map_err(Error::MetricsError)?,
+            tls_handshake_failures: IntCounterVec::new(
+                Opts::new("tls_handshake_failures_total", "Failed TLS handshakes"),
+                &["side"],
+            )
+            .map_err(Error::MetricsError)?,
+            upstream_connect_seconds: Histogram::with_opts(HistogramOpts::new(
+                "upstream_connect_seconds",
+                "Time to establish upstream connections",
+            ))
+            .map_err(Error::MetricsError)?,
+            registry,
+        };
+
+        metrics.register(Box::new(metrics.active_connections.clone()))?;
+        metrics.register(Box::new(metrics.tunnels_opened.clone()))?;
+        metrics.register(Box::new(metrics.bytes_relayed.clone()))?;
+        metrics.register(Box::new(metrics.cert_cache_hits.clone()))?;
+        metrics.register(Box::new(metrics.cert_cache_misses.clone()))?;
+        metrics.register(Box::new(metrics.cert_cache_evictions.clone()))?;
+        metrics.register(Box::new(metrics.tls_handshake_failures.clone()))?;
+        metrics.register(Box::new(metrics.upstream_connect_seconds.clone()))?;
+
+        Ok(metrics)
+    }
+
+    fn register(&self, collector: Box<dyn prometheus::core::Collector>) -> Result<(), Error> {
+        self.registry
+            .register(collector)
+            .map_err(Error::MetricsError)
+    }
+
+    pub(crate) fn connection(self: &Arc<Self>) -> ActiveConnection {
+        self.active_connections.inc();
+        ActiveConnection(self.clone())
+    }
+
+    pub(crate) fn tunnel_opened(&self) {
+        self.tunnels_opened.inc();
+    }
+
+    pub(crate) fn relayed(&self, direction: Direction, bytes: u64) {
+        let label = match direction {
+            Direction::Upstream => "upstream",
+            Direction::Downstream => "downstream",
+        };
+        self.bytes_relayed.with_label_values(&[label]).inc_by(bytes);
+    }
+
+    pub(crate) fn cert_cache(&self, hit: bool) {
+        if hit {
+            self.cert_cache_hits.inc();
+        } else {
+            self.cert_cache_misses.inc();
+        }
+    }
+
+    pub(crate) fn cert_cache_evicted(&self) {
+        self.cert_cache_evictions.inc();
+    }
+
+    pub(crate) fn tls_handshake_failed(&self, side: &str) {
+        self.tls_handshake_failures.with_label_values(&[side]).inc();
+    }
+
+    pub(crate) fn upstream_connected(&self, seconds: f64) {
+        self.upstream_connect_seconds.observe(seconds);
+    }
+
+    fn render(&self) -> Response<Body> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+
+        match encoder.encode(&self.registry.gather(), &mut buf) {
+            Ok(()) => Response::builder()
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buf))
+                .unwrap(),
+            Err(e) => {
+                error!(?e, "Fail to encode metrics");
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }
+        }
+    }
+
+    pub(crate) async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<(), Error> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let response = match req.uri().path() {
+                        "/metrics" => metrics.render(),
+                        _ => {
+                            let mut response = Response::new(Body::empty());
+                            *response.status_mut() = StatusCode::NOT_FOUND;
+                            response
+                        }
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = hyper::Server::try_bind(&addr)
+            .map_err(Error::HttpRequestError)?
+            .serve(make_service);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(?e, "Metrics listener stopped");
+            }
+        });
+
+        Ok(())
+    }
+}
+
+pub(crate) struct ActiveConnection(Arc<Metrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.dec();
+    }
+}
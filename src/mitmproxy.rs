@@ -0,0 +1,487 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use hyper::body::Bytes;
+use ulid::Ulid;
+
+use crate::error::Error;
+use crate::replay::{RecordedFlow, RecordedResponse};
+
+// The flow format of mitmproxy 9. Later releases upgrade it when loading.
+const FLOW_FORMAT_VERSION: i64 = 18;
+// Lengths longer than this many digits are not tnetstrings.
+const MAX_LENGTH_DIGITS: usize = 12;
+// Flows nest a handful of levels deep. Far more is a crafted dump out to exhaust the stack.
+const MAX_DEPTH: usize = 64;
+
+// mitmproxy dumps are tnetstrings, one flow after another.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bytes(Vec<u8>),
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    List(Vec<Value>),
+    Dict(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    // Older formats kept text as bytes, newer ones as strings.
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            Value::String(text) => Some(text.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> Option<String> {
+        self.bytes()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Value::Int(int) => Some(*int),
+            _ => None,
+        }
+    }
+
+    fn float(&self) -> Option<f64> {
+        match self {
+            Value::Float(float) => Some(*float),
+            Value::Int(int) => Some(*int as f64),
+            _ => None,
+        }
+    }
+}
+
+// `depth` counts the lists and dicts the value is in.
+fn parse(input: &[u8], depth: usize) -> Result<(Value, &[u8]), Error> {
+    if depth > MAX_DEPTH {
+        return Err(Error::DumpParseError("Too deeply nested"));
+    }
+    let colon = input
+        .iter()
+        .take(MAX_LENGTH_DIGITS + 1)
+        .position(|&byte| byte == b':')
+        .ok_or(Error::DumpParseError("Missing length"))?;
+    let length: usize = std::str::from_utf8(&input[..colon])
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or(Error::DumpParseError("Invalid length"))?;
+    let rest = &input[colon + 1..];
+    if rest.len() <= length {
+        return Err(Error::DumpParseError("Truncated value"));
+    }
+    let (data, kind, rest) = (&rest[..length], rest[length], &rest[length + 1..]);
+    let text = || std::str::from_utf8(data).map_err(|_| Error::DumpParseError("Invalid text"));
+
+    let value = match kind {
+        b',' => Value::Bytes(data.to_vec()),
+        b';' => Value::String(text()?.to_string()),
+        b'#' => Value::Int(
+            text()?
+                .parse()
+                .map_err(|_| Error::DumpParseError("Invalid integer"))?,
+        ),
+        b'^' => Value::Float(
+            text()?
+                .parse()
+                .map_err(|_| Error::DumpParseError("Invalid float"))?,
+        ),
+        b'!' => Value::Bool(data == b"true"),
+        b'~' => Value::Null,
+        b']' => {
+            let mut items = Vec::new();
+            let mut data = data;
+            while !data.is_empty() {
+                let (item, rest) = parse(data, depth + 1)?;
+                items.push(item);
+                data = rest;
+            }
+            Value::List(items)
+        }
+        b'}' => {
+            let mut fields = Vec::new();
+            let mut data = data;
+            while !data.is_empty() {
+                let (key, rest) = parse(data, depth + 1)?;
+                let key = key.text().ok_or(Error::DumpParseError("Invalid key"))?;
+                let (value, rest) = parse(rest, depth + 1)?;
+                fields.push((key, value));
+                data = rest;
+            }
+            Value::Dict(fields)
+        }
+        _ => return Err(Error::DumpParseError("Unknown type")),
+    };
+
+    Ok((value, rest))
+}
+
+fn write(value: &Value, out: &mut Vec<u8>) {
+    let (data, kind) = match value {
+        Value::Bytes(bytes) => (bytes.clone(), b','),
+        Value::String(text) => (text.clone().into_bytes(), b';'),
+        Value::Int(int) => (int.to_string().into_bytes(), b'#'),
+        Value::Float(float) => (format!("{:?}", float).into_bytes(), b'^'),
+        Value::Bool(bool) => (bool.to_string().into_bytes(), b'!'),
+        Value::Null => (Vec::new(), b'~'),
+        Value::List(items) => {
+            let mut data = Vec::new();
+            for item in items {
+                write(item, &mut data);
+            }
+            (data, b']')
+        }
+        Value::Dict(fields) => {
+            let mut data = Vec::new();
+            for (key, value) in fields {
+                write(&Value::String(key.clone()), &mut data);
+                write(value, &mut data);
+            }
+            (data, b'}')
+        }
+    };
+
+    out.extend_from_slice(data.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(&data);
+    out.push(kind);
+}
+
+fn dict(fields: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn string(text: &str) -> Value {
+    Value::String(text.to_string())
+}
+
+fn bytes(bytes: &[u8]) -> Value {
+    Value::Bytes(bytes.to_vec())
+}
+
+impl RecordedFlow {
+    // Flows other than HTTP ones, like TCP or DNS, and CONNECT requests are left out.
+    pub fn from_mitmproxy<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, Error> {
+        let content = std::fs::read(path).map_err(Error::ReadFileError)?;
+
+        let mut flows = Vec::new();
+        let mut rest = content.as_slice();
+        while !rest.is_empty() {
+            let (state, next) = parse(rest, 0)?;
+            flows.extend(read_flow(&state)?);
+            rest = next;
+        }
+
+        Ok(flows)
+    }
+
+    pub fn write_mitmproxy<P: AsRef<Path>>(path: P, flows: &[Self]) -> Result<(), Error> {
+        let mut content = Vec::new();
+        for flow in flows {
+            write(&flow_state(flow), &mut content);
+        }
+
+        std::fs::write(path, content).map_err(Error::WriteFileError)
+    }
+}
+
+fn read_flow(state: &Value) -> Result<Option<RecordedFlow>, Error> {
+    let kind = state.get("type").and_then(Value::text);
+    if kind.is_some_and(|kind| kind != "http") {
+        return Ok(None);
+    }
+    let request = state
+        .get("request")
+        .ok_or(Error::DumpParseError("Flow has no request"))?;
+
+    let method = request
+        .get("method")
+        .and_then(Value::bytes)
+        .and_then(|method| Method::from_bytes(method).ok())
+        .ok_or(Error::DumpParseError("Invalid method"))?;
+    if method == Method::CONNECT {
+        return Ok(None);
+    }
+    let response = match state.get("response") {
+        Some(response @ Value::Dict(_)) => Some(RecordedResponse {
+            status: response
+                .get("status_code")
+                .and_then(Value::int)
+                .and_then(|status| u16::try_from(status).ok())
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .ok_or(Error::DumpParseError("Invalid status"))?,
+            headers: read_headers(response)?,
+            // Content is missing when mitmproxy streamed the body through.
+            body: response
+                .get("content")
+                .and_then(Value::bytes)
+                .map(Bytes::copy_from_slice),
+            truncated: false,
+        }),
+        _ => None,
+    };
+
+    Ok(Some(RecordedFlow {
+        method,
+        uri: read_uri(request)?,
+        headers: read_headers(request)?,
+        body: request
+            .get("content")
+            .and_then(Value::bytes)
+            .map(Bytes::copy_from_slice)
+            .unwrap_or_default(),
+        truncated: false,
+        started: request
+            .get("timestamp_start")
+            .and_then(Value::float)
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(|since| UNIX_EPOCH + since),
+        response,
+    }))
+}
+
+fn read_uri(request: &Value) -> Result<Uri, Error> {
+    let field = |name| request.get(name).and_then(Value::text);
+    let scheme = field("scheme").ok_or(Error::DumpParseError("Request has no scheme"))?;
+    let host = field("host").ok_or(Error::DumpParseError("Request has no host"))?;
+    let port = request.get("port").and_then(Value::int);
+    let path = field("path").unwrap_or_default();
+
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    let default_port = if scheme == "https" { 443 } else { 80 };
+    let authority = match port {
+        Some(port) if port != default_port => format!("{}:{}", host, port),
+        _ => host,
+    };
+
+    format!("{}://{}{}", scheme, authority, path)
+        .parse()
+        .map_err(|_| Error::DumpParseError("Invalid url"))
+}
+
+fn read_headers(message: &Value) -> Result<HeaderMap, Error> {
+    let fields = match message.get("headers") {
+        Some(Value::List(fields)) => fields.as_slice(),
+        _ => &[],
+    };
+
+    let mut headers = HeaderMap::new();
+    for field in fields {
+        let (name, value) = match field {
+            Value::List(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+            _ => return Err(Error::DumpParseError("Invalid header")),
+        };
+        let name = name
+            .bytes()
+            .and_then(|name| HeaderName::from_bytes(name).ok())
+            .ok_or(Error::DumpParseError("Invalid header name"))?;
+        let value = value
+            .bytes()
+            .and_then(|value| HeaderValue::from_bytes(value).ok())
+            .ok_or(Error::DumpParseError("Invalid header value"))?;
+        headers.append(name, value);
+    }
+
+    Ok(headers)
+}
+
+fn write_headers(headers: &HeaderMap) -> Value {
+    Value::List(
+        headers
+            .iter()
+            .map(|(name, value)| {
+                Value::List(vec![
+                    bytes(name.as_str().as_bytes()),
+                    bytes(value.as_bytes()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+// mitmproxy ids are UUIDs.
+fn uuid() -> String {
+    let hex = format!("{:032x}", Ulid::new().0);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Recorded flows keep no connection details, so the ones mitmproxy requires are filled from the
+// URL or left unspecified.
+fn connection(address: Option<Value>, tls: bool, sni: Value, timestamp: f64) -> Value {
+    let unspecified = || Value::List(vec![string("0.0.0.0"), Value::Int(0)]);
+    let (peername, sockname) = match address {
+        Some(_) => (Value::Null, Value::Null),
+        None => (unspecified(), unspecified()),
+    };
+    let mut fields = vec![
+        ("id", string(&uuid())),
+        ("peername", peername),
+        ("sockname", sockname),
+        ("error", Value::Null),
+        ("tls", Value::Bool(tls)),
+        ("certificate_list", Value::List(Vec::new())),
+        ("alpn", Value::Null),
+        ("alpn_offers", Value::List(Vec::new())),
+        ("cipher", Value::Null),
+        ("cipher_list", Value::List(Vec::new())),
+        ("tls_version", Value::Null),
+        ("sni", sni),
+        ("timestamp_start", Value::Float(timestamp)),
+        ("timestamp_end", Value::Float(timestamp)),
+        ("timestamp_tls_setup", Value::Null),
+        // Closed, on both ends.
+        ("state", Value::Int(0)),
+    ];
+    match address {
+        Some(address) => fields.extend([
+            ("address", address),
+            ("timestamp_tcp_setup", Value::Null),
+            ("via", Value::Null),
+        ]),
+        None => fields.push(("mitmcert", Value::Null)),
+    }
+
+    dict(fields)
+}
+
+fn flow_state(flow: &RecordedFlow) -> Value {
+    let timestamp = flow
+        .started
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let scheme = flow.uri.scheme_str().unwrap_or("http");
+    let host = flow.uri.host().unwrap_or_default();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = flow
+        .uri
+        .port_u16()
+        .unwrap_or(if scheme == "https" { 443 } else { 80 });
+    let tls = scheme == "https";
+
+    let request = dict(vec![
+        ("http_version", bytes(b"HTTP/1.1")),
+        ("headers", write_headers(&flow.headers)),
+        ("content", bytes(&flow.body)),
+        ("trailers", Value::Null),
+        ("timestamp_start", Value::Float(timestamp)),
+        ("timestamp_end", Value::Float(timestamp)),
+        ("host", string(host)),
+        ("port", Value::Int(port.into())),
+        ("method", bytes(flow.method.as_str().as_bytes())),
+        ("scheme", bytes(scheme.as_bytes())),
+        (
+            "authority",
+            bytes(
+                flow.uri
+                    .authority()
+                    .map_or("", |authority| authority.as_str())
+                    .as_bytes(),
+            ),
+        ),
+        (
+            "path",
+            bytes(
+                flow.uri
+                    .path_and_query()
+                    .map_or("/", |path| path.as_str())
+                    .as_bytes(),
+            ),
+        ),
+    ]);
+    let response = match &flow.response {
+        Some(response) => dict(vec![
+            ("http_version", bytes(b"HTTP/1.1")),
+            ("headers", write_headers(&response.headers)),
+            (
+                "content",
+                response
+                    .body
+                    .as_ref()
+                    .map_or(Value::Null, |body| bytes(body)),
+            ),
+            ("trailers", Value::Null),
+            ("timestamp_start", Value::Float(timestamp)),
+            ("timestamp_end", Value::Float(timestamp)),
+            ("status_code", Value::Int(response.status.as_u16().into())),
+            (
+                "reason",
+                bytes(
+                    response
+                        .status
+                        .canonical_reason()
+                        .unwrap_or_default()
+                        .as_bytes(),
+                ),
+            ),
+        ]),
+        None => Value::Null,
+    };
+    // No SNI is sent for addresses.
+    let sni = if tls && host.parse::<IpAddr>().is_err() {
+        string(host)
+    } else {
+        Value::Null
+    };
+    let address = Value::List(vec![string(host), Value::Int(port.into())]);
+
+    dict(vec![
+        ("version", Value::Int(FLOW_FORMAT_VERSION)),
+        ("type", string("http")),
+        ("id", string(&uuid())),
+        ("error", Value::Null),
+        ("client_conn", connection(None, tls, sni.clone(), timestamp)),
+        (
+            "server_conn",
+            connection(Some(address), tls, sni, timestamp),
+        ),
+        ("intercepted", Value::Bool(false)),
+        ("is_replay", Value::Null),
+        ("marked", string("")),
+        ("metadata", Value::Dict(Vec::new())),
+        ("comment", string("")),
+        ("timestamp_created", Value::Float(timestamp)),
+        ("request", request),
+        ("response", response),
+        ("websocket", Value::Null),
+    ])
+}
@@ -0,0 +1,146 @@
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use http::header::{CONNECTION, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, StatusCode, Version};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::config::Mode;
+use crate::error::Error;
+use crate::http::encode_response_head;
+use crate::listen::ListenAddr;
+
+// Where WPAD clients look for the file, served next to the configured path.
+const WPAD_PATH: &str = "/wpad.dat";
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PacConfig {
+    // Served to plain requests for it on the listener, like `http://127.0.0.1:5333/proxy.pac`.
+    pub path: String,
+    // Also written here on startup.
+    pub file: Option<PathBuf>,
+    // `host:port` browsers should use; the one they fetched the file from by default.
+    pub address: Option<String>,
+}
+
+impl Default for PacConfig {
+    fn default() -> Self {
+        Self {
+            path: "/proxy.pac".to_string(),
+            file: None,
+            address: None,
+        }
+    }
+}
+
+// A proxy auto-config file pointing browsers at the proxy, except for bypassed hosts which
+// they reach directly.
+pub(crate) struct Pac {
+    path: String,
+    address: Option<String>,
+    listen: SocketAddr,
+    mode: Mode,
+    // The listener speaks TLS.
+    secure: bool,
+    bypass: Vec<String>,
+}
+
+impl Pac {
+    pub(crate) fn new(
+        config: &PacConfig,
+        listen: &ListenAddr,
+        mode: Mode,
+        secure: bool,
+        bypass: &[String],
+    ) -> Result<Self, Error> {
+        if !matches!(mode, Mode::Http | Mode::Socks5) {
+            return Err(Error::InvalidConfigError("PAC needs http or socks5 mode"));
+        }
+        let listen = match listen {
+            ListenAddr::Tcp(addr) => *addr,
+            ListenAddr::Unix(_) => {
+                return Err(Error::InvalidConfigError("PAC needs a TCP listener"))
+            }
+        };
+        let pac = Self {
+            path: config.path.clone(),
+            address: config.address.clone(),
+            listen,
+            mode,
+            secure,
+            // Regexes have no equivalent in PAC, so those hosts stay on the proxy.
+            bypass: bypass
+                .iter()
+                .filter(|pattern| !pattern.starts_with("regex:"))
+                .cloned()
+                .collect(),
+        };
+
+        if let Some(file) = &config.file {
+            let mut listen = listen;
+            if listen.ip().is_unspecified() {
+                listen.set_ip([127, 0, 0, 1].into());
+            }
+            let address = pac.address.clone().unwrap_or_else(|| listen.to_string());
+            fs::write(file, pac.script(&address)).map_err(Error::WriteFileError)?;
+            info!("PAC file written to {}", file.display());
+        }
+
+        Ok(pac)
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        path == self.path || path == WPAD_PATH
+    }
+
+    // A complete response; `host` is the Host header the client fetched it with.
+    pub(crate) fn response(&self, host: Option<&str>) -> Vec<u8> {
+        let address = self
+            .address
+            .clone()
+            .or_else(|| {
+                host.filter(|host| {
+                    host.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c))
+                })
+                .map(str::to_string)
+            })
+            .unwrap_or_else(|| self.listen.to_string());
+        let body = self.script(&address);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(PAC_CONTENT_TYPE));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+
+        let mut buf = encode_response_head(Version::HTTP_11, StatusCode::OK, &headers);
+        buf.extend_from_slice(body.as_bytes());
+        buf
+    }
+
+    fn script(&self, address: &str) -> String {
+        let proxy = match self.mode {
+            Mode::Socks5 => format!("SOCKS5 {0}; SOCKS {0}", address),
+            _ if self.secure => format!("HTTPS {}", address),
+            _ => format!("PROXY {}", address),
+        };
+
+        let mut script = String::from("function FindProxyForURL(url, host) {\n");
+        for pattern in &self.bypass {
+            let pattern = pattern.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                script,
+                "    if (shExpMatch(host, \"{}\")) return \"DIRECT\";",
+                pattern
+            );
+        }
+        let _ = writeln!(script, "    return \"{}\";\n}}", proxy);
+
+        script
+    }
+}
@@ -0,0 +1,465 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use wasmtime::{
+    Caller, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::http as http_ext;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+
+// Larger bodies are left streaming and never shown to plugins.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+const ON_REQUEST: &str = "on_request";
+const ON_RESPONSE: &str = "on_response";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PluginConfig {
+    // Every `*.wasm` (or `*.wat`) file in it is loaded, in file name order.
+    pub dir: PathBuf,
+    // Instructions a plugin may spend on one call before it is stopped.
+    pub fuel: u64,
+    pub max_memory: usize,
+    // Lets plugins read and replace bodies, decoded from their content encoding; without it they
+    // only see the request line and headers.
+    pub bodies: bool,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("plugins"),
+            fuel: 10_000_000,
+            max_memory: 16 * 1024 * 1024,
+            bodies: false,
+        }
+    }
+}
+
+// What a plugin sees, as JSON in its memory. Bodies are base64.
+#[derive(Serialize)]
+struct RequestView<'a> {
+    id: u64,
+    ulid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<String>,
+    client: String,
+    method: &'a str,
+    url: String,
+    headers: Vec<(&'a str, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ResponseView<'a> {
+    request: RequestView<'a>,
+    status: u16,
+    headers: Vec<(&'a str, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+// What a plugin hands back. Fields left out are kept as they were.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Action {
+    block: bool,
+    respond: Option<Reply>,
+    method: Option<String>,
+    url: Option<String>,
+    status: Option<u16>,
+    headers: Option<Vec<(String, String)>>,
+    body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    #[serde(default = "Reply::default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl Reply {
+    fn default_status() -> u16 {
+        200
+    }
+}
+
+struct State {
+    plugin: Arc<str>,
+    limits: StoreLimits,
+}
+
+struct Plugin {
+    name: Arc<str>,
+    instance: InstancePre<State>,
+    on_request: bool,
+    on_response: bool,
+}
+
+struct Inner {
+    config: PluginConfig,
+    engine: Engine,
+    plugins: Vec<Plugin>,
+}
+
+// WebAssembly interceptors. A plugin is a module exporting `memory`, `alloc(len) -> ptr` and
+// either of `on_request(ptr, len) -> i64` and `on_response(ptr, len) -> i64`. The hooks get a
+// JSON view of the flow and return 0 to leave it alone, or `ptr << 32 | len` of a JSON action
+// such as `{"block": true}`, `{"respond": {"status": 403}}` or `{"headers": [["x-a", "1"]]}`.
+//
+// Every call runs in a fresh instance with its own fuel and memory limits. The only import a
+// plugin can link against is `yaler.log(ptr, len)`: no files, sockets, clocks or environment.
+#[derive(Clone)]
+pub struct Plugins(Arc<Inner>);
+
+impl Plugins {
+    pub fn load(config: PluginConfig) -> Result<Self, Error> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config).map_err(Error::PluginError)?;
+
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("yaler", "log", Self::log)
+            .map_err(Error::PluginError)?;
+
+        let mut paths = std::fs::read_dir(&config.dir)
+            .map_err(Error::ReadFileError)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "wasm" || extension == "wat")
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let plugins = paths
+            .iter()
+            .map(|path| Self::compile(&engine, &linker, path))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self(Arc::new(Inner {
+            config,
+            engine,
+            plugins,
+        })))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.plugins.is_empty()
+    }
+
+    fn compile(engine: &Engine, linker: &Linker<State>, path: &Path) -> Result<Plugin, Error> {
+        let module = Module::from_file(engine, path).map_err(Error::PluginError)?;
+        let instance = linker
+            .instantiate_pre(&module)
+            .map_err(Error::PluginError)?;
+        let name: Arc<str> = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default()
+            .into();
+
+        let plugin = Plugin {
+            on_request: module.get_export(ON_REQUEST).is_some(),
+            on_response: module.get_export(ON_RESPONSE).is_some(),
+            name,
+            instance,
+        };
+        if module.get_export("memory").is_none() || module.get_export("alloc").is_none() {
+            return Err(Error::InvalidConfigError(
+                "Plugins must export `memory` and `alloc`",
+            ));
+        }
+        info!(plugin = %plugin.name, plugin.on_request, plugin.on_response, "Plugin loaded");
+
+        Ok(plugin)
+    }
+
+    fn log(mut caller: Caller<'_, State>, ptr: i32, len: i32) {
+        let memory = match caller
+            .get_export("memory")
+            .and_then(|export| export.into_memory())
+        {
+            Some(memory) => memory,
+            None => return,
+        };
+        let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+        if let Some(text) = memory.data(&caller).get(ptr..ptr.saturating_add(len)) {
+            info!(
+                target: "yaler::plugin",
+                plugin = %caller.data().plugin,
+                "{}",
+                String::from_utf8_lossy(text)
+            );
+        }
+    }
+
+    fn call(&self, plugin: &Plugin, hook: &str, input: &[u8]) -> wasmtime::Result<Option<Action>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.0.config.max_memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(
+            &self.0.engine,
+            State {
+                plugin: plugin.name.clone(),
+                limits,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.0.config.fuel)?;
+
+        let instance = plugin.instance.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("`memory` is not a memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let hook = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let packed = hook.call(&mut store, (ptr, len))? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let output = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| wasmtime::Error::msg("action is out of bounds"))?;
+
+        Ok(Some(serde_json::from_slice(output)?))
+    }
+
+    // A plugin that fails is logged and skipped, leaving the flow as it was.
+    fn run<T: Serialize>(&self, plugin: &Plugin, hook: &str, view: &T) -> Option<Action> {
+        let input = serde_json::to_vec(view).ok()?;
+        self.call(plugin, hook, &input).unwrap_or_else(|e| {
+            warn!(plugin = %plugin.name, hook, error = %e, "Plugin failed");
+            None
+        })
+    }
+
+    async fn read_body(
+        &self,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Option<Bytes>, Body), Error> {
+        if !self.0.config.bodies
+            || http_ext::is_stream(headers)
+            || http_ext::content_length(headers).unwrap_or_default() > MAX_BODY_SIZE
+        {
+            return Ok((None, body));
+        }
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        // A body in an encoding that cannot be undone is left out like a large one.
+        let decoded = encoding::decode(headers, &body).map(Bytes::from);
+
+        Ok((decoded, Body::from(body)))
+    }
+
+    fn request_view<'a>(
+        flow: &'a FlowRequest,
+        headers: &'a HeaderMap,
+        body: Option<&Bytes>,
+    ) -> RequestView<'a> {
+        RequestView {
+            id: flow.id,
+            ulid: flow.ulid.to_string(),
+            connection: flow.connection.map(|id| id.to_string()),
+            client: flow.client.to_string(),
+            method: flow.method.as_str(),
+            url: flow.uri.to_string(),
+            headers: Self::header_view(headers),
+            body: body.map(base64::encode),
+        }
+    }
+
+    fn header_view(headers: &HeaderMap) -> Vec<(&str, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn headers(name: &str, pairs: Vec<(String, String)>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (key, value) in pairs {
+            match (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                (Ok(key), Ok(value)) => {
+                    headers.append(key, value);
+                }
+                _ => warn!(plugin = name, header = %key, "Plugin set an invalid header"),
+            }
+        }
+        headers
+    }
+
+    fn body(name: &str, body: &str) -> Option<Bytes> {
+        match base64::decode(body) {
+            Ok(body) => Some(Bytes::from(body)),
+            Err(_) => {
+                warn!(plugin = name, "Plugin set a body that is not base64");
+                None
+            }
+        }
+    }
+
+    fn reply(plugin: &Plugin, reply: Reply) -> Response<Body> {
+        let mut headers = Self::headers(&plugin.name, reply.headers);
+        let body = reply
+            .body
+            .and_then(|body| Self::body(&plugin.name, &body))
+            .unwrap_or_default();
+        let body = encoding::encode(&mut headers, body.to_vec());
+        http_ext::set_content_length(&mut headers, body.len());
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = StatusCode::from_u16(reply.status).unwrap_or(StatusCode::OK);
+        *response.headers_mut() = headers;
+        response
+    }
+}
+
+#[async_trait]
+impl Interceptor for Plugins {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        if !self.0.plugins.iter().any(|plugin| plugin.on_request) {
+            return RequestAction::Forward(req);
+        }
+
+        let (mut parts, body) = req.into_parts();
+        let (mut current, mut body) = match self.read_body(&parts.headers, body).await {
+            Ok(body) => body,
+            Err(_) => return RequestAction::Block,
+        };
+        let mut url = None;
+        for plugin in self.0.plugins.iter().filter(|plugin| plugin.on_request) {
+            let view = Self::request_view(flow, &parts.headers, current.as_ref());
+            let action = match self.run(plugin, ON_REQUEST, &view) {
+                Some(action) => action,
+                None => continue,
+            };
+
+            if action.block {
+                debug!(plugin = %plugin.name, uri = %flow.uri, "Plugin blocked request");
+                return RequestAction::Block;
+            }
+            if let Some(reply) = action.respond {
+                debug!(plugin = %plugin.name, uri = %flow.uri, "Plugin answered request");
+                return RequestAction::Respond(Self::reply(plugin, reply));
+            }
+            if let Some(method) = action
+                .method
+                .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            {
+                parts.method = method;
+            }
+            if let Some(headers) = action.headers {
+                parts.headers = Self::headers(&plugin.name, headers);
+            }
+            if let Some(changed) = action
+                .body
+                .filter(|_| current.is_some())
+                .and_then(|changed| Self::body(&plugin.name, &changed))
+            {
+                let encoded = encoding::encode(&mut parts.headers, changed.to_vec());
+                http_ext::set_content_length(&mut parts.headers, encoded.len());
+                body = Body::from(encoded);
+                current = Some(changed);
+            }
+            url = action.url.or(url);
+        }
+
+        let mut req = Request::from_parts(parts, body);
+        if let Some(url) = url {
+            match Uri::try_from(url.as_str()) {
+                Ok(uri) if uri != flow.uri => http_ext::retarget(&flow.uri, &mut req, uri),
+                Ok(_) => {}
+                Err(_) => warn!(url, "Plugin set an invalid url"),
+            }
+        }
+
+        RequestAction::Forward(req)
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        if !self.0.plugins.iter().any(|plugin| plugin.on_response) {
+            return ResponseAction::Forward(res);
+        }
+
+        let (mut parts, body) = res.into_parts();
+        let (mut current, mut body) = match self.read_body(&parts.headers, body).await {
+            Ok(body) => body,
+            Err(_) => return ResponseAction::Block,
+        };
+        for plugin in self.0.plugins.iter().filter(|plugin| plugin.on_response) {
+            let view = ResponseView {
+                request: Self::request_view(flow, &flow.headers, None),
+                status: parts.status.as_u16(),
+                headers: Self::header_view(&parts.headers),
+                body: current.as_ref().map(base64::encode),
+            };
+            let action = match self.run(plugin, ON_RESPONSE, &view) {
+                Some(action) => action,
+                None => continue,
+            };
+
+            if action.block {
+                debug!(plugin = %plugin.name, uri = %flow.uri, "Plugin blocked response");
+                return ResponseAction::Block;
+            }
+            if let Some(status) = action
+                .status
+                .and_then(|status| StatusCode::from_u16(status).ok())
+            {
+                parts.status = status;
+            }
+            if let Some(headers) = action.headers {
+                parts.headers = Self::headers(&plugin.name, headers);
+            }
+            if let Some(changed) = action
+                .body
+                .filter(|_| current.is_some())
+                .and_then(|changed| Self::body(&plugin.name, &changed))
+            {
+                let encoded = encoding::encode(&mut parts.headers, changed.to_vec());
+                http_ext::set_content_length(&mut parts.headers, encoded.len());
+                body = Body::from(encoded);
+                current = Some(changed);
+            }
+        }
+
+        ResponseAction::Forward(Response::from_parts(parts, body))
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::error::Error;
+
+const REGEX_PREFIX: &str = "regex:";
+
+#[derive(Debug, Clone)]
+pub struct HostPattern(Regex);
+
+impl HostPattern {
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        let source = match pattern.strip_prefix(REGEX_PREFIX) {
+            Some(regex) => regex.to_string(),
+            None => glob_to_regex(pattern),
+        };
+
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(true)
+            .build()
+            .map_err(Error::PatternError)?;
+
+        Ok(Self(regex))
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.0.is_match(host)
+    }
+}
+
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+
+    regex
+}
+
+// Bypass decisions set at runtime through the admin API. They win over the configured patterns,
+// and outlive config reloads.
+#[derive(Debug, Default)]
+pub(crate) struct Policy {
+    overrides: RwLock<HashMap<String, bool>>,
+}
+
+impl Policy {
+    pub(crate) fn should_bypass(&self, host: &str, bypass: &[HostPattern]) -> bool {
+        match self
+            .overrides
+            .read()
+            .unwrap()
+            .get(&host.to_ascii_lowercase())
+        {
+            Some(bypass) => *bypass,
+            None => bypass.iter().any(|pattern| pattern.matches(host)),
+        }
+    }
+
+    pub(crate) fn set_bypass(&self, host: &str, bypass: bool) {
+        self.overrides
+            .write()
+            .unwrap()
+            .insert(host.to_ascii_lowercase(), bypass);
+    }
+
+    pub(crate) fn overrides(&self) -> HashMap<String, bool> {
+        self.overrides.read().unwrap().clone()
+    }
+}
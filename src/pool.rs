@@ -0,0 +1,76 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+// Enough for most request heads, and what a relay reads at a time.
+pub(crate) const BUFFER_SIZE: usize = 16 * 1024;
+// Buffers that grew past this, for an unusually large head, are freed rather than kept.
+const MAX_KEPT_SIZE: usize = 4 * BUFFER_SIZE;
+// Idle buffers kept at most, about 16 MiB of them.
+const MAX_IDLE: usize = 1024;
+
+// Buffers handed back once their reader is done with them, for the next connection to reuse.
+pub(crate) static BUFFERS: BufferPool = BufferPool::new();
+
+pub(crate) struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    const fn new() -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    // An empty buffer with room for at least `BUFFER_SIZE` bytes.
+    pub(crate) fn get(&'static self) -> Buffer {
+        let bytes = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(BUFFER_SIZE));
+
+        Buffer { bytes, pool: self }
+    }
+
+    fn put(&self, mut bytes: BytesMut) {
+        if !(BUFFER_SIZE..=MAX_KEPT_SIZE).contains(&bytes.capacity()) {
+            return;
+        }
+        bytes.clear();
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(bytes);
+        }
+    }
+}
+
+// A buffer of the pool, which takes it back when dropped.
+pub(crate) struct Buffer {
+    bytes: BytesMut,
+    pool: &'static BufferPool,
+}
+
+impl Deref for Buffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.bytes
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.bytes));
+    }
+}
@@ -0,0 +1,134 @@
+use http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderValue, Response, StatusCode};
+use hyper::Body;
+use rcgen::RcgenError;
+use x509_parser::pem::parse_x509_pem;
+
+use crate::error::Error;
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Yaler CA</title></head>
+<body>
+<h1>Install the Yaler CA certificate</h1>
+<ul>
+<li><a href="/cert.pem">PEM</a> (Linux, Firefox)</li>
+<li><a href="/cert.crt">DER</a> (Windows, Android)</li>
+<li><a href="/cert.mobileconfig">Configuration profile</a> (iOS, macOS)</li>
+</ul>
+</body>
+</html>
+"#;
+
+pub(crate) struct CaPortal {
+    domain: String,
+    pem: String,
+    der: Vec<u8>,
+}
+
+impl CaPortal {
+    pub(crate) fn new(domain: String, pem: String) -> Result<Self, Error> {
+        let (_, parsed) =
+            parse_x509_pem(pem.as_bytes()).map_err(|_| RcgenError::CouldNotParseCertificate)?;
+
+        Ok(Self {
+            domain,
+            der: parsed.contents,
+            pem,
+        })
+    }
+
+    pub(crate) fn matches(&self, host: Option<&str>) -> bool {
+        host.is_some_and(|host| host.eq_ignore_ascii_case(&self.domain))
+    }
+
+    pub(crate) fn respond(&self, path: &str) -> Response<Body> {
+        match path {
+            "/" => Self::response("text/html; charset=utf-8", None, INDEX.as_bytes().to_vec()),
+            "/cert.pem" => Self::response(
+                "application/x-x509-ca-cert",
+                Some("yaler-ca.pem"),
+                self.pem.as_bytes().to_vec(),
+            ),
+            "/cert.crt" => Self::response(
+                "application/x-x509-ca-cert",
+                Some("yaler-ca.crt"),
+                self.der.clone(),
+            ),
+            "/cert.mobileconfig" => Self::response(
+                "application/x-apple-aspen-config",
+                Some("yaler-ca.mobileconfig"),
+                self.mobileconfig().into_bytes(),
+            ),
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(0));
+                response
+            }
+        }
+    }
+
+    fn response(
+        content_type: &'static str,
+        filename: Option<&str>,
+        body: Vec<u8>,
+    ) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, body.len());
+        if let Some(filename) = filename {
+            response = response.header(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            );
+        }
+
+        response.body(Body::from(body)).unwrap()
+    }
+
+    fn mobileconfig(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>PayloadCertificateFileName</key>
+            <string>yaler-ca.crt</string>
+            <key>PayloadContent</key>
+            <data>{}</data>
+            <key>PayloadDisplayName</key>
+            <string>Yaler Root CA</string>
+            <key>PayloadIdentifier</key>
+            <string>com.yaler.ca.certificate</string>
+            <key>PayloadType</key>
+            <string>com.apple.security.root</string>
+            <key>PayloadUUID</key>
+            <string>5B2A6A3C-3A55-4B8E-9A59-2B1F0C7F6E11</string>
+            <key>PayloadVersion</key>
+            <integer>1</integer>
+        </dict>
+    </array>
+    <key>PayloadDisplayName</key>
+    <string>Yaler</string>
+    <key>PayloadIdentifier</key>
+    <string>com.yaler.ca</string>
+    <key>PayloadType</key>
+    <string>Configuration</string>
+    <key>PayloadUUID</key>
+    <string>0E8A4B77-8C1D-4F1B-B3E4-6A0D7F2C9D35</string>
+    <key>PayloadVersion</key>
+    <integer>1</integer>
+</dict>
+</plist>
+"#,
+            base64::encode(&self.der)
+        )
+    }
+}
@@ -0,0 +1,323 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use http::header::{HeaderName, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, Uri};
+use hyper::body::Bytes;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::FlowEvent;
+use crate::grpc::GrpcMessage;
+use crate::sse::ServerSentEvent;
+use crate::websocket::WebSocketMessage;
+
+// Kept to letters so masked URLs still parse.
+const MASK: &str = "REDACTED";
+
+// What is masked before flows are logged, stored or exported.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    // Header names like `authorization`, whose values are masked whole.
+    pub headers: Vec<String>,
+    // Paths into JSON bodies and messages like `user.password` or `$.items.*.token`. `*` matches
+    // any field or element, and a field name applies to every element of an array.
+    pub json_paths: Vec<String>,
+    // Regexes over URLs, header values, text bodies and messages. Only the first capture group
+    // is masked when there is one, the whole match otherwise.
+    pub patterns: Vec<String>,
+}
+
+#[derive(Default)]
+struct Rules {
+    headers: Vec<HeaderName>,
+    json_paths: Vec<Vec<String>>,
+    patterns: Vec<Regex>,
+}
+
+#[derive(Clone, Default)]
+pub struct Redactor(Arc<Rules>);
+
+impl Redactor {
+    pub fn new(config: &RedactionConfig) -> Result<Self, Error> {
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| HeaderName::try_from(name.as_str()))
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::InvalidConfigError("Invalid header name to redact"))?;
+        let json_paths = config
+            .json_paths
+            .iter()
+            .map(|path| {
+                let path = path.strip_prefix('$').unwrap_or(path);
+                path.split('.')
+                    .filter(|segment| !segment.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|path| !path.is_empty())
+            .collect();
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::InvalidConfigError("Invalid redaction pattern"))?;
+
+        Ok(Self(Arc::new(Rules {
+            headers,
+            json_paths,
+            patterns,
+        })))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.headers.is_empty() && self.0.json_paths.is_empty() && self.0.patterns.is_empty()
+    }
+
+    pub(crate) fn event(&self, event: FlowEvent) -> FlowEvent {
+        if self.is_empty() {
+            return event;
+        }
+
+        match event {
+            FlowEvent::Request(mut req) => {
+                req.uri = self.uri(&req.uri);
+                req.headers = self.headers(&req.headers);
+                FlowEvent::Request(req)
+            }
+            FlowEvent::Response(mut res) => {
+                res.headers = self.headers(&res.headers);
+                FlowEvent::Response(res)
+            }
+            FlowEvent::WebSocketMessage {
+                id,
+                direction,
+                message,
+            } => FlowEvent::WebSocketMessage {
+                id,
+                direction,
+                message: match message {
+                    WebSocketMessage::Text(text) => {
+                        WebSocketMessage::Text(self.text(&text).into_owned())
+                    }
+                    binary => binary,
+                },
+            },
+            FlowEvent::ServerSentEvent { id, event } => FlowEvent::ServerSentEvent {
+                id,
+                event: ServerSentEvent {
+                    data: self.text(&event.data).into_owned(),
+                    ..event
+                },
+            },
+            FlowEvent::GrpcMessage {
+                id,
+                direction,
+                message,
+            } => FlowEvent::GrpcMessage {
+                id,
+                direction,
+                message: GrpcMessage {
+                    decoded: message.decoded.map(|mut decoded| {
+                        self.json(&mut decoded);
+                        decoded
+                    }),
+                    ..message
+                },
+            },
+            FlowEvent::Error { id, error } => FlowEvent::Error {
+                id,
+                error: self.mask(&error).into_owned(),
+            },
+            FlowEvent::Complete(mut summary) => {
+                summary.uri = self.uri(&summary.uri);
+                FlowEvent::Complete(summary)
+            }
+        }
+    }
+
+    pub(crate) fn headers(&self, headers: &HeaderMap) -> HeaderMap {
+        let mut redacted = headers.clone();
+        for (name, value) in redacted.iter_mut() {
+            if self.0.headers.contains(name) {
+                *value = HeaderValue::from_static(MASK);
+                continue;
+            }
+            if let Ok(text) = value.to_str() {
+                if let Cow::Owned(masked) = self.mask(text) {
+                    *value = HeaderValue::try_from(masked)
+                        .unwrap_or_else(|_| HeaderValue::from_static(MASK));
+                }
+            }
+        }
+
+        redacted
+    }
+
+    pub(crate) fn uri(&self, uri: &Uri) -> Uri {
+        let text = uri.to_string();
+        match self.mask(&text) {
+            Cow::Borrowed(_) => uri.clone(),
+            // A URL the mask broke keeps only where it went.
+            Cow::Owned(masked) => masked.parse().unwrap_or_else(|_| {
+                let mut parts = uri.clone().into_parts();
+                parts.path_and_query = None;
+                Uri::from_parts(parts).unwrap_or_else(|_| Uri::from_static("/"))
+            }),
+        }
+    }
+
+    // Bodies are looked into with their content encoding undone, and encoded again once masked.
+    // Ones that cannot be decoded might hold anything and are masked whole. Ones that are not
+    // text are kept as they are.
+    pub(crate) fn body(&self, headers: &HeaderMap, body: Bytes) -> Bytes {
+        if self.is_empty() || body.is_empty() {
+            return body;
+        }
+        let decoded = if encoding::is_encoded(headers) {
+            match encoding::decode(headers, &body) {
+                Some(decoded) => Cow::Owned(decoded),
+                None => return Bytes::from_static(MASK.as_bytes()),
+            }
+        } else {
+            Cow::Borrowed(body.as_ref())
+        };
+        let text = match std::str::from_utf8(&decoded) {
+            Ok(text) => text,
+            Err(_) => return body,
+        };
+
+        let is_json = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_none_or(|value| {
+                let value = value.trim().to_ascii_lowercase();
+                value == "application/json" || value.ends_with("+json")
+            });
+        let redacted = if is_json {
+            self.text(text)
+        } else {
+            self.mask(text)
+        };
+        match redacted {
+            Cow::Borrowed(_) => body,
+            Cow::Owned(redacted) => Bytes::from(encoding::encode(
+                &mut headers.clone(),
+                redacted.into_bytes(),
+            )),
+        }
+    }
+
+    // Text that parses as JSON has its paths masked too.
+    pub(crate) fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.0.json_paths.is_empty() {
+            return self.mask(text);
+        }
+        let mut value = match serde_json::from_str::<Value>(text) {
+            Ok(value @ (Value::Object(_) | Value::Array(_))) => value,
+            _ => return self.mask(text),
+        };
+
+        if self.json(&mut value) {
+            Cow::Owned(value.to_string())
+        } else {
+            self.mask(text)
+        }
+    }
+
+    // Masks values at the configured paths, then patterns in the strings left. True if anything
+    // was masked.
+    pub(crate) fn json(&self, value: &mut Value) -> bool {
+        let mut masked = false;
+        for path in &self.0.json_paths {
+            masked |= mask_path(value, path);
+        }
+        if !self.0.patterns.is_empty() {
+            masked |= self.mask_strings(value);
+        }
+
+        masked
+    }
+
+    fn mask_strings(&self, value: &mut Value) -> bool {
+        match value {
+            Value::String(text) => match self.mask(text) {
+                Cow::Owned(masked) => {
+                    *text = masked;
+                    true
+                }
+                Cow::Borrowed(_) => false,
+            },
+            Value::Array(values) => values
+                .iter_mut()
+                .fold(false, |masked, value| self.mask_strings(value) | masked),
+            Value::Object(fields) => fields
+                .values_mut()
+                .fold(false, |masked, value| self.mask_strings(value) | masked),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn mask<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.0.patterns {
+            if let Cow::Owned(masked) = pattern.replace_all(&text, mask_match) {
+                text = Cow::Owned(masked);
+            }
+        }
+
+        text
+    }
+}
+
+fn mask_match(captures: &Captures) -> String {
+    let whole = &captures[0];
+    let start = captures.get(0).map_or(0, |whole| whole.start());
+    match captures.get(1) {
+        Some(group) => format!(
+            "{}{}{}",
+            &whole[..group.start() - start],
+            MASK,
+            &whole[group.end() - start..]
+        ),
+        None => MASK.to_string(),
+    }
+}
+
+fn mask_path(value: &mut Value, path: &[String]) -> bool {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            *value = Value::String(MASK.to_string());
+            return true;
+        }
+    };
+
+    match value {
+        Value::Object(fields) if segment == "*" => fields
+            .values_mut()
+            .fold(false, |masked, value| mask_path(value, rest) | masked),
+        Value::Object(fields) => fields
+            .get_mut(segment)
+            .is_some_and(|value| mask_path(value, rest)),
+        Value::Array(values) if segment == "*" => values
+            .iter_mut()
+            .fold(false, |masked, value| mask_path(value, rest) | masked),
+        Value::Array(values) => match segment.parse::<usize>() {
+            Ok(index) => values
+                .get_mut(index)
+                .is_some_and(|value| mask_path(value, rest)),
+            Err(_) => values
+                .iter_mut()
+                .fold(false, |masked, value| mask_path(value, path) | masked),
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::blocklist::{Blocklist, BlocklistConfig};
+use crate::builder::ServerBuilder;
+use crate::config::Config;
+use crate::dialer::{Dialer, RemoteStream};
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::pac::Pac;
+use crate::policy::HostPattern;
+use crate::rules::{RuleConfig, Rules};
+use crate::server::Server;
+use crate::upstream::UpstreamProxy;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// The parts of the config a running proxy applies again when it changes. Each decision reads
+// the snapshot current at the time, so a reload takes effect from the next request or
+// connection on, while tunnels already open carry on as they were.
+pub(crate) struct Snapshot {
+    pub(crate) bypass: Vec<HostPattern>,
+    // Made from the bypass patterns, so written again with them.
+    pub(crate) pac: Option<Pac>,
+    pub(crate) blocklist: Option<Blocklist>,
+    pub(crate) rules: Option<Rules>,
+    // Plain requests are forwarded to it rather than tunneled through it.
+    pub(crate) upstream_proxy: Option<UpstreamProxy>,
+    pub(crate) dialer: Arc<dyn Dialer>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        bypass: &[String],
+        blocklist: Option<&BlocklistConfig>,
+        rules: &[RuleConfig],
+        upstream_proxy: Option<UpstreamProxy>,
+        dialer: Arc<dyn Dialer>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            bypass: bypass
+                .iter()
+                .map(|pattern| HostPattern::new(pattern))
+                .collect::<Result<_, _>>()?,
+            pac: None,
+            blocklist: blocklist.map(Blocklist::load).transpose()?,
+            rules: (!rules.is_empty()).then(|| Rules::new(rules)).transpose()?,
+            upstream_proxy,
+            dialer,
+        })
+    }
+}
+
+// The current snapshot, replaced whole on reload.
+#[derive(Clone)]
+pub(crate) struct Live(Arc<RwLock<Arc<Snapshot>>>);
+
+impl Live {
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(snapshot))))
+    }
+
+    pub(crate) fn load(&self) -> Arc<Snapshot> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn store(&self, snapshot: Snapshot) {
+        *self.0.write().unwrap() = Arc::new(snapshot);
+    }
+}
+
+// Dials through the upstream proxy of the current snapshot, for clients that keep the dialer
+// they were made with.
+#[async_trait]
+impl Dialer for Live {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error> {
+        let dialer = self.load().dialer.clone();
+        dialer.dial(host, port).await
+    }
+}
+
+// Holds the place of the blocklist or the rules in the interceptor chain, and runs the ones of
+// the current snapshot.
+pub(crate) enum Slot {
+    Blocklist(Live),
+    Rules(Live),
+}
+
+impl Slot {
+    fn current(&self) -> Option<Arc<dyn Interceptor>> {
+        match self {
+            Slot::Blocklist(live) => live
+                .load()
+                .blocklist
+                .clone()
+                .map(|blocklist| Arc::new(blocklist) as Arc<dyn Interceptor>),
+            Slot::Rules(live) => live
+                .load()
+                .rules
+                .clone()
+                .map(|rules| Arc::new(rules) as Arc<dyn Interceptor>),
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for Slot {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        match self.current() {
+            Some(interceptor) => interceptor.on_request(flow, req).await,
+            None => RequestAction::Forward(req),
+        }
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        match self.current() {
+            Some(interceptor) => interceptor.on_response(flow, res).await,
+            None => ResponseAction::Forward(res),
+        }
+    }
+}
+
+impl Server {
+    // Applies the bypass patterns, PAC file, blocklist, rules, upstream proxy and upstream
+    // routes of `config`, all at once or not at all. The rest of it, like listeners, TLS or DNS
+    // settings, only applies on restart.
+    pub fn reload(&self, config: &Config) -> Result<(), Error> {
+        let context = self.context();
+        let (dialer, upstream_proxy) =
+            ServerBuilder::dialer_from_config(config, context.resolver.clone())?;
+        let mut snapshot = Snapshot::new(
+            &config.bypass,
+            config.blocklist.as_ref(),
+            &config.rules,
+            upstream_proxy,
+            Arc::new(dialer),
+        )?;
+        snapshot.pac = match &config.pac {
+            Some(pac) => {
+                let listener = self.listener();
+                Some(Pac::new(
+                    pac,
+                    &self.local_addrs()?[0],
+                    listener.mode,
+                    listener.tls.is_some(),
+                    &config.bypass,
+                )?)
+            }
+            None => None,
+        };
+
+        context.live.store(snapshot);
+        Ok(())
+    }
+
+    // Reloads the config at `path` whenever it or a file in its `rules_dir` changes, and on
+    // SIGHUP. `adjust` applies what overrides the file, like command line flags. A config that
+    // fails to load or apply leaves the running one in place.
+    pub async fn watch_config<F>(&self, path: &Path, adjust: F)
+    where
+        F: Fn(&mut Config),
+    {
+        let mut hangups = hangups();
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut rules_dir = Config::load(path).ok().and_then(|config| config.rules_dir);
+        let mut seen = fingerprint(path, rules_dir.as_deref());
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if fingerprint(path, rules_dir.as_deref()) == seen {
+                        continue;
+                    }
+                }
+                Some(()) = hangups.recv() => {}
+            }
+
+            let result = Config::load(path).and_then(|mut config| {
+                adjust(&mut config);
+                rules_dir = config.rules_dir.clone();
+                self.reload(&config)
+            });
+            // The rules directory may have moved with the config.
+            seen = fingerprint(path, rules_dir.as_deref());
+            match result {
+                Ok(()) => info!(path = %path.display(), "Config reloaded"),
+                Err(e) => warn!(path = %path.display(), %e, "Fail to reload config"),
+            }
+        }
+    }
+}
+
+// When the config and each file of the rules directory last changed.
+fn fingerprint(path: &Path, rules_dir: Option<&Path>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+
+    let mut files = vec![(path.to_path_buf(), modified(path))];
+    if let Some(dir) = rules_dir {
+        files.extend(
+            Config::rules_files(dir)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|file| {
+                    let modified = modified(&file);
+                    (file, modified)
+                }),
+        );
+    }
+
+    files
+}
+
+// SIGHUPs, on platforms that have them.
+fn hangups() -> mpsc::Receiver<()> {
+    let (sender, receiver) = mpsc::channel(1);
+    #[cfg(unix)]
+    if let Ok(mut signal) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        tokio::spawn(async move {
+            while signal.recv().await.is_some() {
+                if sender.send(()).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    receiver
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use http::header::{HeaderName, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Request, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::Deserialize;
+use similar::TextDiff;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct RecordedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // None when the recording kept no body.
+    pub body: Option<Bytes>,
+    // Only the start of the body was kept.
+    pub truncated: bool,
+}
+
+// A request as it was captured, with the response it got back then.
+#[derive(Debug, Clone)]
+pub struct RecordedFlow {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    // Only the start of the request body was kept.
+    pub truncated: bool,
+    // When the request was captured, for recordings that say.
+    pub started: Option<SystemTime>,
+    pub response: Option<RecordedResponse>,
+}
+
+impl RecordedFlow {
+    pub fn from_har<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadFileError)?;
+        let har: Har = serde_json::from_str(&content).map_err(Error::HarParseError)?;
+
+        har.log
+            .entries
+            .into_iter()
+            .map(HarEntry::into_flow)
+            .collect()
+    }
+
+    // Fails for a body that was only kept in part, unless the overrides replace it.
+    pub fn to_request(&self, overrides: &Overrides) -> Result<Request<Body>, Error> {
+        if self.truncated && overrides.body.is_none() {
+            return Err(Error::PayloadTooLargeError);
+        }
+
+        let mut headers = self.headers.clone();
+        for (name, value) in &overrides.headers {
+            match value {
+                Some(value) => headers.insert(name.clone(), value.clone()),
+                None => headers.remove(name),
+            };
+        }
+        let body = overrides.body.clone().unwrap_or_else(|| self.body.clone());
+        // Recordings may keep a body redacted or decoded, so the length it came with is no
+        // longer its own. It goes out whole, with the length of what is sent.
+        headers.remove(TRANSFER_ENCODING);
+        if !body.is_empty() || headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+
+        let mut req = Request::new(Body::from(body));
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.headers_mut() = headers;
+
+        Ok(req)
+    }
+}
+
+// Changes applied to a recorded request before it is sent again.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    body: Option<Bytes>,
+}
+
+impl Overrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, Some(value)));
+        self
+    }
+
+    pub fn remove_header(mut self, name: HeaderName) -> Self {
+        self.headers.push((name, None));
+        self
+    }
+
+    pub fn body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Replayed {
+    pub id: u64,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderChange {
+    Added(HeaderName, String),
+    Removed(HeaderName, String),
+    Changed(HeaderName, String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyChange {
+    // The original body was not recorded, so nothing can be compared.
+    Unknown,
+    Text(String),
+    Binary(usize, usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResponseDiff {
+    pub status: Option<(StatusCode, StatusCode)>,
+    pub headers: Vec<HeaderChange>,
+    pub body: Option<BodyChange>,
+}
+
+impl ResponseDiff {
+    pub fn new(original: &RecordedResponse, replayed: &RecordedResponse) -> Self {
+        Self::ignoring(original, replayed, &[])
+    }
+
+    // Headers in `ignore` are expected to differ between runs (`date`, `set-cookie`, ...).
+    pub fn ignoring(
+        original: &RecordedResponse,
+        replayed: &RecordedResponse,
+        ignore: &[HeaderName],
+    ) -> Self {
+        let status =
+            (original.status != replayed.status).then_some((original.status, replayed.status));
+
+        let names: BTreeSet<&str> = original
+            .headers
+            .keys()
+            .chain(replayed.headers.keys())
+            .filter(|name| !ignore.contains(name))
+            .map(HeaderName::as_str)
+            .collect();
+        let headers = names
+            .into_iter()
+            .filter_map(|name| {
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                match (
+                    Self::joined(&original.headers, &name),
+                    Self::joined(&replayed.headers, &name),
+                ) {
+                    (Some(old), Some(new)) if old != new => {
+                        Some(HeaderChange::Changed(name, old, new))
+                    }
+                    (Some(old), None) => Some(HeaderChange::Removed(name, old)),
+                    (None, Some(new)) => Some(HeaderChange::Added(name, new)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let body = match (&original.body, &replayed.body) {
+            (Some(old), Some(new)) if old == new => None,
+            (Some(old), Some(new)) => {
+                Some(match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+                    (Ok(old), Ok(new)) => BodyChange::Text(
+                        TextDiff::from_lines(old, new)
+                            .unified_diff()
+                            .header("original", "replayed")
+                            .to_string(),
+                    ),
+                    _ => BodyChange::Binary(old.len(), new.len()),
+                })
+            }
+            _ => Some(BodyChange::Unknown),
+        };
+
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none() && self.headers.is_empty() && self.body.is_none()
+    }
+
+    fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+        let values: Vec<_> = headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+}
+
+impl fmt::Display for ResponseDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "identical");
+        }
+        if let Some((old, new)) = self.status {
+            writeln!(f, "status: {} -> {}", old, new)?;
+        }
+        for change in &self.headers {
+            match change {
+                HeaderChange::Added(name, value) => writeln!(f, "+ {}: {}", name, value)?,
+                HeaderChange::Removed(name, value) => writeln!(f, "- {}: {}", name, value)?,
+                HeaderChange::Changed(name, old, new) => {
+                    writeln!(f, "~ {}: {} -> {}", name, old, new)?
+                }
+            }
+        }
+        match &self.body {
+            Some(BodyChange::Unknown) => writeln!(f, "body: not recorded"),
+            Some(BodyChange::Text(diff)) => write!(f, "{}", diff),
+            Some(BodyChange::Binary(old, new)) => {
+                writeln!(f, "body: {} bytes -> {} bytes (binary)", old, new)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+struct HarEntry {
+    request: HarRequest,
+    response: Option<HarResponse>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<HarHeader>,
+    post_data: Option<HarContent>,
+}
+
+#[derive(Deserialize)]
+struct HarResponse {
+    status: u16,
+    #[serde(default)]
+    headers: Vec<HarHeader>,
+    content: Option<HarContent>,
+}
+
+#[derive(Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct HarContent {
+    text: Option<String>,
+    encoding: Option<String>,
+    comment: Option<String>,
+}
+
+impl HarEntry {
+    fn into_flow(self) -> Result<RecordedFlow, Error> {
+        let request = self.request;
+        let method = Method::from_bytes(request.method.as_bytes())
+            .map_err(|_| Error::BadRequestError("Invalid method in HAR"))?;
+        let uri = Uri::try_from(request.url.as_str())
+            .map_err(|_| Error::BadRequestError("Invalid url in HAR"))?;
+
+        // Browsers record a status of 0 for requests that never got an answer.
+        let response = match self.response.filter(|res| res.status != 0) {
+            Some(res) => Some(RecordedResponse {
+                status: StatusCode::from_u16(res.status)
+                    .map_err(|_| Error::BadRequestError("Invalid status in HAR"))?,
+                headers: Self::headers(&res.headers)?,
+                truncated: res.content.as_ref().is_some_and(HarContent::truncated),
+                body: match res.content {
+                    Some(content) if content.text.is_some() => Some(content.into_bytes()?),
+                    _ => None,
+                },
+            }),
+            None => None,
+        };
+
+        Ok(RecordedFlow {
+            method,
+            uri,
+            headers: Self::headers(&request.headers)?,
+            truncated: request
+                .post_data
+                .as_ref()
+                .is_some_and(HarContent::truncated),
+            body: match request.post_data {
+                Some(content) => content.into_bytes()?,
+                None => Bytes::new(),
+            },
+            started: None,
+            response,
+        })
+    }
+
+    fn headers(headers: &[HarHeader]) -> Result<HeaderMap, Error> {
+        let mut map = HeaderMap::new();
+        // HTTP/2 pseudo headers are part of the request line, not headers.
+        for header in headers
+            .iter()
+            .filter(|header| !header.name.starts_with(':'))
+        {
+            let name = HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|_| Error::BadRequestError("Invalid header name in HAR"))?;
+            let value = HeaderValue::from_str(&header.value)
+                .map_err(|_| Error::BadRequestError("Invalid header value in HAR"))?;
+            map.append(name, value);
+        }
+
+        Ok(map)
+    }
+}
+
+impl HarContent {
+    // As the recorder marks bodies it kept only the start of.
+    fn truncated(&self) -> bool {
+        self.comment.as_deref() == Some("truncated")
+    }
+
+    fn into_bytes(self) -> Result<Bytes, Error> {
+        let text = self.text.unwrap_or_default();
+        match self.encoding.as_deref() {
+            Some("base64") => base64::decode(&text)
+                .map(Bytes::from)
+                .map_err(|_| Error::BadRequestError("Invalid base64 body in HAR")),
+            _ => Ok(Bytes::from(text)),
+        }
+    }
+}
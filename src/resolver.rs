@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::error::Error;
+use crate::policy::HostPattern;
+
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 1024;
+const DNS_OVER_TLS_PORT: u16 = 853;
+const DNS_OVER_HTTPS_PORT: u16 = 443;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    #[default]
+    System,
+    Tls,
+    Https,
+}
+
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error>;
+}
+
+// Answers are cached by trust-dns for as long as their TTL allows.
+pub struct DnsResolver(TokioAsyncResolver);
+
+impl DnsResolver {
+    pub fn system() -> Result<Self, Error> {
+        Self::system_with_cache(DEFAULT_CACHE_SIZE)
+    }
+
+    pub fn system_with_cache(cache_size: usize) -> Result<Self, Error> {
+        let (config, opts) = trust_dns_resolver::system_conf::read_system_conf()
+            .ok()
+            .unwrap_or_default();
+
+        Self::with_config(config, opts, cache_size)
+    }
+
+    pub fn tls(servers: &[IpAddr], server_name: String, cache_size: usize) -> Result<Self, Error> {
+        let group =
+            NameServerConfigGroup::from_ips_tls(servers, DNS_OVER_TLS_PORT, server_name, true);
+
+        Self::with_config(
+            ResolverConfig::from_parts(None, Vec::new(), group),
+            ResolverOpts::default(),
+            cache_size,
+        )
+    }
+
+    pub fn https(
+        servers: &[IpAddr],
+        server_name: String,
+        cache_size: usize,
+    ) -> Result<Self, Error> {
+        let group =
+            NameServerConfigGroup::from_ips_https(servers, DNS_OVER_HTTPS_PORT, server_name, true);
+
+        Self::with_config(
+            ResolverConfig::from_parts(None, Vec::new(), group),
+            ResolverOpts::default(),
+            cache_size,
+        )
+    }
+
+    fn with_config(
+        config: ResolverConfig,
+        mut opts: ResolverOpts,
+        cache_size: usize,
+    ) -> Result<Self, Error> {
+        opts.cache_size = cache_size;
+
+        Ok(Self(
+            TokioAsyncResolver::tokio(config, opts).map_err(Error::ResolveError)?,
+        ))
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let lookup = self.0.lookup_ip(host).await.map_err(Error::ResolveError)?;
+
+        Ok(lookup.iter().collect())
+    }
+}
+
+pub struct StaticResolver {
+    hosts: Vec<(HostPattern, Vec<IpAddr>)>,
+    fallback: Arc<dyn Resolver>,
+}
+
+impl StaticResolver {
+    pub fn new(fallback: Arc<dyn Resolver>) -> Self {
+        Self {
+            hosts: Vec::new(),
+            fallback,
+        }
+    }
+
+    pub fn host(mut self, pattern: HostPattern, addrs: Vec<IpAddr>) -> Self {
+        self.hosts.push((pattern, addrs));
+        self
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        match self.hosts.iter().find(|(pattern, _)| pattern.matches(host)) {
+            Some((_, addrs)) => Ok(addrs.clone()),
+            None => self.fallback.resolve(host).await,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::header::CONTENT_LENGTH;
+use http::uri::{Authority, Scheme};
+use http::{HeaderValue, Request, Response, Uri};
+use hyper::Body;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+
+use crate::acceptor::AcceptorMap;
+use crate::acme::Acme;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::http::ALPN_HTTP1;
+use crate::intercept::{Interceptor, RequestAction};
+use crate::policy::HostPattern;
+use crate::tls_policy::TlsPolicy;
+
+// A site served in reverse mode.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct VirtualHostConfig {
+    // Names routed to this site; empty makes it the default for names nothing else claims.
+    pub hosts: Vec<String>,
+    // Origin requests are sent to, like `http://127.0.0.1:8080`.
+    pub backend: String,
+    // PEM certificate chain and key shown to clients, instead of a leaf from the CA.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // Gets certificates for `hosts` from the CA in the `acme` section.
+    pub acme: bool,
+}
+
+struct VirtualHost {
+    hosts: Vec<HostPattern>,
+    names: Vec<String>,
+    scheme: Scheme,
+    authority: Authority,
+    tls: Option<Arc<ServerConfig>>,
+    acme: bool,
+}
+
+impl VirtualHost {
+    fn new(config: &VirtualHostConfig, policy: &TlsPolicy) -> Result<Self, Error> {
+        let backend = Uri::try_from(config.backend.as_str())
+            .map_err(|_| Error::InvalidConfigError("Invalid backend in virtual host"))?;
+        let (scheme, authority) = match (backend.scheme(), backend.authority()) {
+            (Some(scheme), Some(authority))
+                if *scheme == Scheme::HTTP || *scheme == Scheme::HTTPS =>
+            {
+                (scheme.clone(), authority.clone())
+            }
+            _ => {
+                return Err(Error::InvalidConfigError(
+                    "Virtual host backend must be an http or https origin",
+                ))
+            }
+        };
+        if config.acme
+            && (config.cert.is_some()
+                || config.hosts.is_empty()
+                || config.hosts.iter().any(|host| host.contains('*')))
+        {
+            return Err(Error::InvalidConfigError(
+                "ACME virtual host needs plain host names and no cert",
+            ));
+        }
+        let tls = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => Some(Arc::new(load_server_config(cert, key, policy)?)),
+            (None, None) => None,
+            _ => {
+                return Err(Error::InvalidConfigError(
+                    "Virtual host needs both cert and key",
+                ))
+            }
+        };
+
+        Ok(Self {
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| HostPattern::new(host))
+                .collect::<Result<_, _>>()?,
+            names: config.hosts.clone(),
+            scheme,
+            authority,
+            tls,
+            acme: config.acme,
+        })
+    }
+}
+
+// A TLS config for a PEM certificate chain and key, offering HTTP/1.1.
+pub(crate) fn load_server_config(
+    cert: &Path,
+    key: &Path,
+    policy: &TlsPolicy,
+) -> Result<ServerConfig, Error> {
+    let (chain, key) = read_cert_and_key(cert, key)?;
+    let mut config = policy
+        .server_builder()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+    Ok(config)
+}
+
+pub(crate) fn read_cert_and_key(
+    cert: &Path,
+    key: &Path,
+) -> Result<(Vec<Certificate>, PrivateKey), Error> {
+    let invalid = || Error::InvalidConfigError("Invalid PEM certificate or key");
+    let chain = read_certs(cert)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if chain.is_empty() {
+        return Err(invalid());
+    }
+    let key = read_pem(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(invalid)?;
+
+    Ok((chain, PrivateKey(key)))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, Error> {
+    let file = File::open(path).map_err(Error::ReadFileError)?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(Error::ReadFileError)
+}
+
+// DER of the certificates in a PEM file, skipping anything else in it.
+pub(crate) fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
+    Ok(read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(der),
+            _ => None,
+        })
+        .collect())
+}
+
+// Sites of reverse mode, routed by name. As an interceptor it points each request at the
+// backend of its site, after every other interceptor has seen it with its public URI.
+#[derive(Clone, Default)]
+pub struct VirtualHosts {
+    sites: Arc<Vec<VirtualHost>>,
+    acme: Option<Arc<Acme>>,
+}
+
+impl VirtualHosts {
+    pub fn new(configs: &[VirtualHostConfig], policy: &TlsPolicy) -> Result<Self, Error> {
+        let sites = configs
+            .iter()
+            .map(|config| VirtualHost::new(config, policy))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            sites: Arc::new(sites),
+            acme: None,
+        })
+    }
+
+    pub(crate) fn with_acme(mut self, acme: Arc<Acme>) -> Self {
+        self.acme = Some(acme);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+
+    pub(crate) fn acme_domains(&self) -> Vec<String> {
+        self.sites
+            .iter()
+            .filter(|site| site.acme)
+            .flat_map(|site| site.names.iter().cloned())
+            .collect()
+    }
+
+    pub(crate) fn acme(&self) -> Option<&Acme> {
+        self.acme.as_deref()
+    }
+
+    // A site naming the host wins over a default one, whatever their order.
+    fn find(&self, host: Option<&str>) -> Option<&VirtualHost> {
+        host.and_then(|host| {
+            self.sites
+                .iter()
+                .find(|site| site.hosts.iter().any(|pattern| pattern.matches(host)))
+        })
+        .or_else(|| self.sites.iter().find(|site| site.hosts.is_empty()))
+    }
+
+    // What a TLS client asking for `host` is accepted with; only HTTP/1.1 is offered.
+    pub(crate) async fn server_config(
+        &self,
+        host: Option<&str>,
+        acceptors: &AcceptorMap,
+    ) -> Result<Arc<ServerConfig>, Error> {
+        let site = self.find(host).ok_or(Error::UnknownHostError)?;
+        if let Some(tls) = &site.tls {
+            return Ok(tls.clone());
+        }
+        let host = host.ok_or(Error::BadRequestError("Request without server name"))?;
+        // Until the CA has issued one, clients get a leaf from the internal CA.
+        if let Some(issued) = site
+            .acme
+            .then(|| self.acme.as_ref()?.server_config(host))
+            .flatten()
+        {
+            return Ok(issued);
+        }
+        let mut config = (*acceptors.get(host.to_string()).await?).clone();
+        config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+        Ok(Arc::new(config))
+    }
+}
+
+#[async_trait]
+impl Interceptor for VirtualHosts {
+    async fn on_request(&self, flow: &FlowRequest, mut req: Request<Body>) -> RequestAction {
+        if let Some(response) = self.acme.as_ref().and_then(|acme| acme.respond(flow)) {
+            return RequestAction::Respond(response);
+        }
+        // Already sent elsewhere by another interceptor; clients only ever get to name a path.
+        if req.uri().authority().is_some() {
+            return RequestAction::Forward(req);
+        }
+        let site = match self.find(flow.uri.host()) {
+            Some(site) => site,
+            None => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = Error::UnknownHostError.status_code();
+                response
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(0));
+                return RequestAction::Respond(response);
+            }
+        };
+
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = Some(site.scheme.clone());
+        parts.authority = Some(site.authority.clone());
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+
+        RequestAction::Forward(req)
+    }
+}
@@ -0,0 +1,484 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::header::{
+    HeaderName, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING,
+};
+use http::uri::PathAndQuery;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use hyper::Body;
+use regex::bytes::Regex as BytesRegex;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::http::{self as http_ext, BoundedBody};
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::policy::{self, HostPattern};
+
+// Larger responses are passed on untouched rather than buffered for body rewrites.
+const MAX_REWRITE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub host: Option<String>,
+    // Glob on the path, without the query.
+    pub path: Option<String>,
+    pub method: Option<String>,
+    // A file served as is, or a directory the rest of the path is looked up in.
+    pub map_local: Option<PathBuf>,
+    // Another origin such as `http://localhost:8080`. A path on it replaces the literal start
+    // of the path glob.
+    pub map_remote: Option<String>,
+    // Keep sending the original Host header to the new origin.
+    pub preserve_host: bool,
+    pub request_headers: HeaderEdits,
+    pub response_headers: HeaderEdits,
+    // Glob on the response media type such as `text/*`; body rewrites apply to any without it.
+    pub content_type: Option<String>,
+    pub body_replace: Vec<BodyReplace>,
+}
+
+// `replacement` may refer to capture groups (`$1`, `${name}`) and to the flow with
+// `{{host}}`, `{{path}}`, `{{method}}` and `{{id}}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BodyReplace {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HeaderEdits {
+    pub remove: Vec<String>,
+    // Replaces every value the header had.
+    pub set: HashMap<String, String>,
+    // Kept alongside values the header already has.
+    pub add: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Edits {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    add: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Edits {
+    fn new(config: &HeaderEdits) -> Result<Self, Error> {
+        let name = |name: &String| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::InvalidConfigError("Invalid header name in rule"))
+        };
+        let pair = |(key, value): (&String, &String)| {
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| Error::InvalidConfigError("Invalid header value in rule"))?;
+            Ok((name(key)?, value))
+        };
+
+        Ok(Self {
+            remove: config.remove.iter().map(name).collect::<Result<_, _>>()?,
+            set: config.set.iter().map(pair).collect::<Result<_, Error>>()?,
+            add: config.add.iter().map(pair).collect::<Result<_, Error>>()?,
+        })
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        for (name, value) in &self.add {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+struct Rule {
+    host: Option<HostPattern>,
+    path: Option<Regex>,
+    // Literal start of the path glob, up to its last `/`; stripped before mapping into a directory.
+    prefix: String,
+    method: Option<Method>,
+    map_local: Option<PathBuf>,
+    map_remote: Option<Uri>,
+    preserve_host: bool,
+    request_headers: Edits,
+    response_headers: Edits,
+    content_type: Option<Regex>,
+    body_replace: Vec<(BytesRegex, String)>,
+}
+
+impl Rule {
+    fn new(config: &RuleConfig) -> Result<Self, Error> {
+        let path = match &config.path {
+            Some(glob) => {
+                Some(Regex::new(&policy::glob_to_regex(glob)).map_err(Error::PatternError)?)
+            }
+            None => None,
+        };
+        let literal = config.path.as_deref().map_or("", |glob| {
+            &glob[..glob.find(['*', '?']).unwrap_or(glob.len())]
+        });
+        let prefix = literal[..literal.rfind('/').map_or(0, |i| i + 1)].to_string();
+        let method = match &config.method {
+            Some(method) => Some(
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| Error::InvalidConfigError("Invalid method in rule"))?,
+            ),
+            None => None,
+        };
+
+        let map_remote = match &config.map_remote {
+            Some(url) => {
+                let uri = Uri::try_from(url.as_str())
+                    .map_err(|_| Error::InvalidConfigError("Invalid map_remote url"))?;
+                if uri.scheme().is_none() || uri.authority().is_none() {
+                    return Err(Error::InvalidConfigError(
+                        "map_remote needs a scheme and a host",
+                    ));
+                }
+                Some(uri)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            host: config.host.as_deref().map(HostPattern::new).transpose()?,
+            path,
+            prefix,
+            method,
+            map_local: config.map_local.clone(),
+            map_remote,
+            preserve_host: config.preserve_host,
+            request_headers: Edits::new(&config.request_headers)?,
+            response_headers: Edits::new(&config.response_headers)?,
+            content_type: config
+                .content_type
+                .as_deref()
+                .map(|glob| {
+                    RegexBuilder::new(&policy::glob_to_regex(glob))
+                        .case_insensitive(true)
+                        .build()
+                })
+                .transpose()
+                .map_err(Error::PatternError)?,
+            body_replace: config
+                .body_replace
+                .iter()
+                .map(|replace| {
+                    let pattern = BytesRegex::new(&replace.pattern).map_err(Error::PatternError)?;
+                    Ok((pattern, replace.replacement.clone()))
+                })
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    fn matches(&self, flow: &FlowRequest) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|method| method == flow.method)
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|host| flow.uri.host().is_some_and(|name| host.matches(name)))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| path.is_match(flow.uri.path()))
+    }
+
+    fn rewrites_body(&self, headers: &HeaderMap) -> bool {
+        if self.body_replace.is_empty() {
+            return false;
+        }
+
+        let media_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .trim();
+        self.content_type
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(media_type))
+    }
+
+    fn replace_body(&self, flow: &FlowRequest, body: &mut Vec<u8>) {
+        for (pattern, replacement) in &self.body_replace {
+            let replacement = Self::expand(replacement, flow);
+            if let Cow::Owned(replaced) = pattern.replace_all(body, replacement.as_bytes()) {
+                *body = replaced;
+            }
+        }
+    }
+
+    fn expand(template: &str, flow: &FlowRequest) -> String {
+        // Flow values are literal text, not references to capture groups.
+        let literal = |value: &str| value.replace('$', "$$");
+
+        template
+            .replace("{{host}}", &literal(flow.uri.host().unwrap_or_default()))
+            .replace("{{path}}", &literal(flow.uri.path()))
+            .replace("{{method}}", flow.method.as_str())
+            .replace("{{id}}", &flow.id.to_string())
+    }
+
+    fn map_remote(&self, target: &Uri, flow: &FlowRequest, req: &mut Request<Body>) {
+        let path = flow.uri.path();
+        let path = match target.path().trim_end_matches('/') {
+            "" => path.to_string(),
+            base => {
+                let rest = path.strip_prefix(self.prefix.as_str()).unwrap_or(path);
+                format!("{}/{}", base, rest.trim_start_matches('/'))
+            }
+        };
+        let path_and_query = match flow.uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+
+        let mut parts = target.clone().into_parts();
+        parts.path_and_query = PathAndQuery::try_from(path_and_query).ok();
+        let uri = match Uri::from_parts(parts) {
+            Ok(uri) => uri,
+            Err(_) => return,
+        };
+
+        let host = if self.preserve_host {
+            flow.uri.authority()
+        } else {
+            uri.authority()
+        };
+        if let Some(host) = host.and_then(|host| HeaderValue::from_str(host.as_str()).ok()) {
+            req.headers_mut().insert(HOST, host);
+        }
+
+        debug!(from = %flow.uri, to = %uri, "map remote");
+        *req.uri_mut() = uri;
+    }
+}
+
+// Declarative request and response rewrites, applied in the order they are configured.
+#[derive(Clone)]
+pub struct Rules(Arc<Vec<Rule>>);
+
+impl Rules {
+    pub fn new(configs: &[RuleConfig]) -> Result<Self, Error> {
+        let rules = configs.iter().map(Rule::new).collect::<Result<_, _>>()?;
+
+        Ok(Self(Arc::new(rules)))
+    }
+
+    async fn map_local(rule: &Rule, root: &Path, flow: &FlowRequest) -> Response<Body> {
+        let path = flow.uri.path();
+        let rest = path.strip_prefix(rule.prefix.as_str()).unwrap_or(path);
+        let rest = match Self::relative_path(rest) {
+            Some(rest) => rest,
+            None => return Self::status(StatusCode::FORBIDDEN),
+        };
+
+        let mut file = match tokio::fs::metadata(root).await {
+            Ok(metadata) if metadata.is_dir() => root.join(rest),
+            Ok(_) => root.to_path_buf(),
+            Err(e) => {
+                warn!(?e, root = %root.display(), "Fail to map local");
+                return Self::status(StatusCode::NOT_FOUND);
+            }
+        };
+        if tokio::fs::metadata(&file).await.is_ok_and(|m| m.is_dir()) {
+            let index = file.join("index.html");
+            if !tokio::fs::metadata(&index).await.is_ok_and(|m| m.is_file()) {
+                return Self::listing(&file, path).await;
+            }
+            file = index;
+        }
+
+        debug!(uri = %flow.uri, file = %file.display(), "map local");
+        match tokio::fs::read(&file).await {
+            Ok(content) => {
+                let mut response = Response::new(Body::from(content));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(Self::content_type(&file)),
+                );
+                response
+            }
+            Err(_) => Self::status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn listing(dir: &Path, path: &str) -> Response<Body> {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(_) => return Self::status(StatusCode::NOT_FOUND),
+        };
+
+        let mut names = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+                name.push('/');
+            }
+            names.push(name);
+        }
+        names.sort();
+
+        let base = if path.ends_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/", path)
+        };
+        let mut html = format!(
+            "<!DOCTYPE html>\n<title>{0}</title>\n<h1>{0}</h1>\n<ul>\n",
+            Self::escape(path)
+        );
+        for name in names {
+            html.push_str(&format!(
+                "<li><a href=\"{}{}\">{}</a></li>\n",
+                Self::escape(&base),
+                Self::escape(&name),
+                Self::escape(&name)
+            ));
+        }
+        html.push_str("</ul>\n");
+
+        let mut response = Response::new(Body::from(html));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        response
+    }
+
+    // Keeps the request inside the mapped directory.
+    fn relative_path(path: &str) -> Option<PathBuf> {
+        let decoded = http_ext::percent_decode(path)?;
+        let mut relative = PathBuf::new();
+        for component in Path::new(&decoded).components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        Some(relative)
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    fn content_type(file: &Path) -> &'static str {
+        let extension = file
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("html" | "htm") => "text/html; charset=utf-8",
+            Some("js" | "mjs") => "text/javascript; charset=utf-8",
+            Some("css") => "text/css; charset=utf-8",
+            Some("json" | "map") => "application/json",
+            Some("txt") => "text/plain; charset=utf-8",
+            Some("xml") => "application/xml",
+            Some("svg") => "image/svg+xml",
+            Some("png") => "image/png",
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            Some("ico") => "image/x-icon",
+            Some("woff") => "font/woff",
+            Some("woff2") => "font/woff2",
+            Some("wasm") => "application/wasm",
+            _ => "application/octet-stream",
+        }
+    }
+
+    fn status(status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    }
+}
+
+#[async_trait]
+impl Interceptor for Rules {
+    async fn on_request(&self, flow: &FlowRequest, mut req: Request<Body>) -> RequestAction {
+        for rule in self.0.iter().filter(|rule| rule.matches(flow)) {
+            if let Some(root) = &rule.map_local {
+                return RequestAction::Respond(Self::map_local(rule, root, flow).await);
+            }
+            if let Some(target) = &rule.map_remote {
+                rule.map_remote(target, flow, &mut req);
+            }
+            rule.request_headers.apply(req.headers_mut());
+        }
+
+        RequestAction::Forward(req)
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, mut res: Response<Body>) -> ResponseAction {
+        let mut rewrites = Vec::new();
+        for rule in self.0.iter().filter(|rule| rule.matches(flow)) {
+            rule.response_headers.apply(res.headers_mut());
+            if rule.rewrites_body(res.headers()) {
+                rewrites.push(rule);
+            }
+        }
+        if rewrites.is_empty()
+            || http_ext::is_stream(res.headers())
+            || http_ext::content_length(res.headers()).unwrap_or_default() > MAX_REWRITE_SIZE
+        {
+            return ResponseAction::Forward(res);
+        }
+
+        // Bodies without a length are only found too large once read that far.
+        let (mut parts, body) = res.into_parts();
+        let original = match http_ext::read_body(body, MAX_REWRITE_SIZE as usize).await {
+            Ok(BoundedBody::Full(body)) => body,
+            Ok(BoundedBody::TooLarge(body)) => {
+                return ResponseAction::Forward(Response::from_parts(parts, body))
+            }
+            Err(_) => return ResponseAction::Block,
+        };
+        let mut body = match encoding::decode(&parts.headers, &original) {
+            Some(body) => body,
+            None => {
+                let encoding = parts.headers.get(CONTENT_ENCODING);
+                debug!(?encoding, "Skip body rewrite of encoded response");
+                return ResponseAction::Forward(Response::from_parts(parts, Body::from(original)));
+            }
+        };
+
+        let decoded = body.clone();
+        for rule in rewrites {
+            rule.replace_body(flow, &mut body);
+        }
+        if body == decoded {
+            return ResponseAction::Forward(Response::from_parts(parts, Body::from(original)));
+        }
+
+        // Encoded again as it arrived, so the length is that of the encoded rewrite.
+        let body = encoding::encode(&mut parts.headers, body);
+        parts.headers.remove(TRANSFER_ENCODING);
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+        ResponseAction::Forward(Response::from_parts(parts, Body::from(body)))
+    }
+}
@@ -0,0 +1,469 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use tracing::{debug, info, warn};
+
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::{Direction, FlowRequest};
+use crate::http as http_ext;
+use crate::intercept::{Interceptor, MessageAction, RequestAction, ResponseAction};
+use crate::sse::ServerSentEvent;
+use crate::websocket::WebSocketMessage;
+
+// Keeps a runaway script from stalling the connection it runs for.
+const MAX_OPERATIONS: u64 = 1_000_000;
+// Larger bodies, and streams like event streams or gRPC, are passed on as they come; scripts
+// see `()` for them.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+const ON_REQUEST: &str = "on_request";
+const ON_RESPONSE: &str = "on_response";
+const ON_WEBSOCKET_MESSAGE: &str = "on_websocket_message";
+const ON_SERVER_SENT_EVENT: &str = "on_server_sent_event";
+
+struct Inner {
+    path: PathBuf,
+    engine: Engine,
+    ast: RwLock<(Arc<AST>, Option<SystemTime>)>,
+}
+
+// A Rhai script with `on_request()` and `on_response(req)` functions, called with `this` bound
+// to a map of the request or response:
+//
+//   request:  #{ id, client, method, url, host, path, headers, body }
+//   response: #{ status, headers, body }
+//
+// `method`, `url`, `status`, `headers` and `body` are written back. Header values are strings,
+// or arrays of strings for repeated headers; bodies are strings, or blobs when not UTF-8. They
+// are seen with any gzip, deflate, br or zstd content encoding undone, and a changed body is
+// encoded again as its `Content-Encoding` header says.
+// `on_request` may return a response map to answer without going upstream, and either hook
+// may return `false` to drop the flow.
+//
+// `on_websocket_message(req)` sees each WebSocket message of the flow, with `this` bound to
+// `#{ direction, message }`: `direction` is "upstream" or "downstream", and `message` a string
+// for text or a blob for binary, written back. Returning `false` drops the message.
+//
+// `on_server_sent_event(req)` sees each event of a `text/event-stream` response once it has
+// been passed on, with `this` bound to `#{ event, data, id, retry }`; absent fields are `()`.
+#[derive(Clone)]
+pub struct Script(Arc<Inner>);
+
+impl Script {
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| info!(target: "yaler::script", "{}", text));
+        engine.on_debug(|text, _, position| debug!(target: "yaler::script", %position, "{}", text));
+
+        let modified = Self::modified(&path);
+        let ast = engine
+            .compile_file(path.clone())
+            .map_err(Error::ScriptError)?;
+
+        Ok(Self(Arc::new(Inner {
+            path,
+            engine,
+            ast: RwLock::new((Arc::new(ast), modified)),
+        })))
+    }
+
+    // Recompiles the script whenever the file changes; a script that fails to compile leaves
+    // the previous one in place.
+    pub(crate) fn watch(&self) {
+        let inner = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                match Weak::upgrade(&inner) {
+                    Some(inner) => Self(inner).reload(),
+                    None => return,
+                }
+            }
+        });
+    }
+
+    fn reload(&self) {
+        let modified = Self::modified(&self.0.path);
+        if modified.is_none() || modified == self.0.ast.read().unwrap().1 {
+            return;
+        }
+
+        match self.0.engine.compile_file(self.0.path.clone()) {
+            Ok(ast) => {
+                info!(path = %self.0.path.display(), "Script reloaded");
+                *self.0.ast.write().unwrap() = (Arc::new(ast), modified);
+            }
+            Err(e) => {
+                warn!(path = %self.0.path.display(), %e, "Fail to reload script");
+                // Not retried until the file changes again.
+                self.0.ast.write().unwrap().1 = modified;
+            }
+        }
+    }
+
+    fn modified(path: &PathBuf) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn ast(&self, function: &str) -> Option<Arc<AST>> {
+        let ast = self.0.ast.read().unwrap().0.clone();
+        let defined = ast.iter_functions().any(|f| f.name == function);
+        defined.then_some(ast)
+    }
+
+    fn call(&self, ast: &AST, function: &str, this: &mut Dynamic, args: Vec<Dynamic>) -> Dynamic {
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+        let result = self.0.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            ast,
+            function,
+            args,
+        );
+
+        result.unwrap_or_else(|e| {
+            warn!(%e, function, "Script failed");
+            Dynamic::UNIT
+        })
+    }
+
+    async fn read_body(headers: &HeaderMap, body: Body) -> Result<(Option<Bytes>, Body), Error> {
+        if http_ext::is_stream(headers)
+            || http_ext::content_length(headers).unwrap_or_default() > MAX_BODY_SIZE
+        {
+            return Ok((None, body));
+        }
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        // A body in an encoding that cannot be undone is left out like a large one.
+        let decoded = encoding::decode(headers, &body).map(Bytes::from);
+
+        Ok((decoded, Body::from(body)))
+    }
+
+    fn request_map(flow: &FlowRequest, headers: &HeaderMap, body: Option<&Bytes>) -> Map {
+        let mut map = Map::new();
+        map.insert("id".into(), Dynamic::from_int(flow.id as i64));
+        map.insert("ulid".into(), flow.ulid.to_string().into());
+        map.insert(
+            "connection".into(),
+            flow.connection
+                .map_or(Dynamic::UNIT, |id| id.to_string().into()),
+        );
+        map.insert("client".into(), flow.client.to_string().into());
+        map.insert("method".into(), flow.method.to_string().into());
+        map.insert("url".into(), flow.uri.to_string().into());
+        map.insert(
+            "host".into(),
+            flow.uri.host().unwrap_or_default().to_string().into(),
+        );
+        map.insert("path".into(), flow.uri.path().to_string().into());
+        map.insert("headers".into(), Self::headers_to_map(headers).into());
+        map.insert("body".into(), Self::body_to_dynamic(body));
+        map
+    }
+
+    fn response_map(status: StatusCode, headers: &HeaderMap, body: Option<&Bytes>) -> Map {
+        let mut map = Map::new();
+        map.insert("status".into(), Dynamic::from_int(status.as_u16() as i64));
+        map.insert("headers".into(), Self::headers_to_map(headers).into());
+        map.insert("body".into(), Self::body_to_dynamic(body));
+        map
+    }
+
+    fn headers_to_map(headers: &HeaderMap) -> Map {
+        let mut map = Map::new();
+        for name in headers.keys() {
+            let mut values: Array = headers
+                .get_all(name)
+                .iter()
+                .map(|value| {
+                    String::from_utf8_lossy(value.as_bytes())
+                        .into_owned()
+                        .into()
+                })
+                .collect();
+            let value = match values.len() {
+                1 => values.remove(0),
+                _ => values.into(),
+            };
+            map.insert(name.as_str().into(), value);
+        }
+        map
+    }
+
+    fn headers_from_map(map: Map) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in map {
+            let name = match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(name) => name,
+                Err(_) => {
+                    warn!(%name, "Script set an invalid header name");
+                    continue;
+                }
+            };
+            let values = match value.is_array() {
+                true => value.into_array().unwrap_or_default(),
+                false => vec![value],
+            };
+            for value in values {
+                match HeaderValue::from_str(&value.to_string()) {
+                    Ok(value) => {
+                        headers.append(name.clone(), value);
+                    }
+                    Err(_) => warn!(%name, "Script set an invalid header value"),
+                }
+            }
+        }
+        headers
+    }
+
+    fn body_to_dynamic(body: Option<&Bytes>) -> Dynamic {
+        match body {
+            Some(body) => match std::str::from_utf8(body) {
+                Ok(text) => text.to_string().into(),
+                Err(_) => Dynamic::from_blob(body.to_vec()),
+            },
+            None => Dynamic::UNIT,
+        }
+    }
+
+    // None when the script left the body alone.
+    fn body_from_dynamic(value: Dynamic, original: Option<&Bytes>) -> Option<Bytes> {
+        let body = if value.is_unit() {
+            return None;
+        } else if value.is_blob() {
+            Bytes::from(value.into_blob().unwrap_or_default())
+        } else {
+            Bytes::from(value.to_string())
+        };
+
+        (original != Some(&body)).then_some(body)
+    }
+
+    fn take(map: &mut Map, key: &str) -> Dynamic {
+        map.remove(key).unwrap_or(Dynamic::UNIT)
+    }
+
+    fn response_from_map(mut map: Map) -> Response<Body> {
+        let status = map
+            .get("status")
+            .and_then(|status| status.as_int().ok())
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::OK);
+        let mut headers = match Self::take(&mut map, "headers").try_cast::<Map>() {
+            Some(headers) => Self::headers_from_map(headers),
+            None => HeaderMap::new(),
+        };
+        let body = Self::body_from_dynamic(Self::take(&mut map, "body"), None).unwrap_or_default();
+        let body = encoding::encode(&mut headers, body.to_vec());
+        http_ext::set_content_length(&mut headers, body.len());
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        response
+    }
+
+    fn apply_url(flow: &FlowRequest, req: &mut Request<Body>, url: &str) {
+        if flow.uri == url {
+            return;
+        }
+        let uri = match Uri::try_from(url) {
+            Ok(uri) => uri,
+            Err(_) => {
+                warn!(url, "Script set an invalid url");
+                return;
+            }
+        };
+        http_ext::retarget(&flow.uri, req, uri);
+    }
+}
+
+#[async_trait]
+impl Interceptor for Script {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        let ast = match self.ast(ON_REQUEST) {
+            Some(ast) => ast,
+            None => return RequestAction::Forward(req),
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let (original, body) = match Self::read_body(&parts.headers, body).await {
+            Ok(body) => body,
+            Err(_) => return RequestAction::Block,
+        };
+        let mut this: Dynamic = Self::request_map(flow, &parts.headers, original.as_ref()).into();
+        let result = self.call(&ast, ON_REQUEST, &mut this, Vec::new());
+
+        if result.as_bool() == Ok(false) {
+            debug!(uri = %flow.uri, "Script blocked request");
+            return RequestAction::Block;
+        }
+        if let Some(response) = result.try_cast::<Map>() {
+            debug!(uri = %flow.uri, "Script answered request");
+            return RequestAction::Respond(Self::response_from_map(response));
+        }
+
+        let mut map = match this.try_cast::<Map>() {
+            Some(map) => map,
+            None => return RequestAction::Forward(Request::from_parts(parts, body)),
+        };
+        if let Some(method) = map
+            .get("method")
+            .and_then(|method| Method::from_bytes(method.to_string().as_bytes()).ok())
+        {
+            parts.method = method;
+        }
+        if let Some(headers) = Self::take(&mut map, "headers").try_cast::<Map>() {
+            parts.headers = Self::headers_from_map(headers);
+        }
+        let body = match Self::body_from_dynamic(Self::take(&mut map, "body"), original.as_ref()) {
+            Some(changed) => {
+                let changed = encoding::encode(&mut parts.headers, changed.to_vec());
+                http_ext::set_content_length(&mut parts.headers, changed.len());
+                Body::from(changed)
+            }
+            None => body,
+        };
+
+        let mut req = Request::from_parts(parts, body);
+        if let Some(url) = map.get("url") {
+            Self::apply_url(flow, &mut req, &url.to_string());
+        }
+
+        RequestAction::Forward(req)
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        let ast = match self.ast(ON_RESPONSE) {
+            Some(ast) => ast,
+            None => return ResponseAction::Forward(res),
+        };
+
+        let (mut parts, body) = res.into_parts();
+        let (original, body) = match Self::read_body(&parts.headers, body).await {
+            Ok(body) => body,
+            Err(_) => return ResponseAction::Block,
+        };
+        let req = Self::request_map(flow, &flow.headers, None);
+        let mut this: Dynamic =
+            Self::response_map(parts.status, &parts.headers, original.as_ref()).into();
+        let result = self.call(&ast, ON_RESPONSE, &mut this, vec![req.into()]);
+
+        if result.as_bool() == Ok(false) {
+            debug!(uri = %flow.uri, "Script blocked response");
+            return ResponseAction::Block;
+        }
+
+        let mut map = match this.try_cast::<Map>() {
+            Some(map) => map,
+            None => return ResponseAction::Forward(Response::from_parts(parts, body)),
+        };
+        if let Some(status) = map
+            .get("status")
+            .and_then(|status| status.as_int().ok())
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+        {
+            parts.status = status;
+        }
+        if let Some(headers) = Self::take(&mut map, "headers").try_cast::<Map>() {
+            parts.headers = Self::headers_from_map(headers);
+        }
+        let body = match Self::body_from_dynamic(Self::take(&mut map, "body"), original.as_ref()) {
+            Some(changed) => {
+                let changed = encoding::encode(&mut parts.headers, changed.to_vec());
+                http_ext::set_content_length(&mut parts.headers, changed.len());
+                Body::from(changed)
+            }
+            None => body,
+        };
+
+        ResponseAction::Forward(Response::from_parts(parts, body))
+    }
+
+    async fn on_websocket_message(
+        &self,
+        flow: &FlowRequest,
+        direction: Direction,
+        message: WebSocketMessage,
+    ) -> MessageAction {
+        let ast = match self.ast(ON_WEBSOCKET_MESSAGE) {
+            Some(ast) => ast,
+            None => return MessageAction::Forward(message),
+        };
+
+        let mut map = Map::new();
+        let direction = match direction {
+            Direction::Upstream => "upstream",
+            Direction::Downstream => "downstream",
+        };
+        map.insert("direction".into(), direction.into());
+        let value = match &message {
+            WebSocketMessage::Text(text) => text.clone().into(),
+            WebSocketMessage::Binary(data) => Dynamic::from_blob(data.clone()),
+        };
+        map.insert("message".into(), value);
+        let req = Self::request_map(flow, &flow.headers, None);
+        let mut this: Dynamic = map.into();
+        let result = self.call(&ast, ON_WEBSOCKET_MESSAGE, &mut this, vec![req.into()]);
+
+        if result.as_bool() == Ok(false) {
+            debug!(uri = %flow.uri, "Script dropped WebSocket message");
+            return MessageAction::Drop;
+        }
+
+        let value = match this.try_cast::<Map>() {
+            Some(mut map) => Self::take(&mut map, "message"),
+            None => return MessageAction::Forward(message),
+        };
+        let message = if value.is_unit() {
+            message
+        } else if value.is_blob() {
+            WebSocketMessage::Binary(value.into_blob().unwrap_or_default())
+        } else {
+            WebSocketMessage::Text(value.to_string())
+        };
+
+        MessageAction::Forward(message)
+    }
+
+    async fn on_server_sent_event(&self, flow: &FlowRequest, event: &ServerSentEvent) {
+        let ast = match self.ast(ON_SERVER_SENT_EVENT) {
+            Some(ast) => ast,
+            None => return,
+        };
+
+        let optional = |value: Option<Dynamic>| value.unwrap_or(Dynamic::UNIT);
+        let mut map = Map::new();
+        map.insert(
+            "event".into(),
+            optional(event.event.clone().map(Into::into)),
+        );
+        map.insert("data".into(), event.data.clone().into());
+        map.insert("id".into(), optional(event.id.clone().map(Into::into)));
+        map.insert(
+            "retry".into(),
+            optional(event.retry.map(|retry| Dynamic::from_int(retry as i64))),
+        );
+        let req = Self::request_map(flow, &flow.headers, None);
+        let mut this: Dynamic = map.into();
+        let _ = self.call(&ast, ON_SERVER_SENT_EVENT, &mut this, vec![req.into()]);
+    }
+}
@@ -1,230 +1,2117 @@
-use std::pin::Pin;
+use std::convert::Infallible;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use http::header::*;
-use http::{HeaderMap, Method, Request, Response, StatusCode};
-use hyper::{body::HttpBody, client, Body};
+use http::uri::{Authority, Scheme};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::body::Bytes;
+use hyper::client::conn::SendRequest;
+use hyper::client::Client;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{body::HttpBody, Body};
 
-use tokio::io::{split, AsyncReadExt, ReadHalf, WriteHalf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, watch, Mutex as AsyncMutex, Notify};
+use tokio::time::timeout;
 use tokio::{
     io::{AsyncWriteExt, BufStream},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    net::TcpStream,
 };
 
 use rustls::client::ServerName;
-use rustls::{ClientConfig, RootCertStore};
-use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use rustls::ProtocolVersion;
+use tokio_rustls::{TlsAcceptor, TlsStream};
 
-use pext::FromUtf8;
-use pext::IntoUtf8;
+use socket2::SockRef;
 
-use tracing::{error, info, instrument, warn};
+use tracing::{debug, error, field, info, instrument, Span};
+use ulid::Ulid;
 
 use crate::acceptor::AcceptorMap;
+use crate::acl::Acl;
+use crate::acme::ACME_TLS_ALPN;
+use crate::auth::{self, Credentials};
+use crate::blocklist::BlockAction;
+use crate::builder::ServerBuilder;
+use crate::config::{ListenerConfig, Mode};
+use crate::connections::{Connections, Peer};
+use crate::dialer::{Dialer, DialerConnector, RemoteStream};
 use crate::error::Error;
-use crate::http::ReadHttpExt;
+use crate::flow::{
+    Direction, FlowEvent, FlowRequest, FlowResponse, FlowSummary, Flows, MeteredBody,
+};
+use crate::forwarded::ForwardedConfig;
+use crate::grpc::{self, Descriptors};
+use crate::har;
+use crate::http::{self as http_ext, BodyFraming, BoundedBody, ReadHttpExt, ResponseFraming};
+use crate::intercept::{Interceptors, RequestAction, ResponseAction};
+use crate::limit::{ConnectionLimiter, ConnectionPermit, RateLimiter, ResourceLimits};
+use crate::listen::{AcceptorsConfig, BoundListener, ClientStream, ListenAddr, Transport};
+use crate::metrics::Metrics;
+use crate::policy::Policy;
+use crate::portal::CaPortal;
+use crate::redact::Redactor;
+use crate::reload::Live;
+use crate::replay::{Overrides, RecordedFlow, RecordedResponse, Replayed};
+use crate::resolver::Resolver;
+use crate::reverse::{self, VirtualHosts};
+use crate::shaping::{Shape, Shaper};
+use crate::sni::{self, ClientOffer};
+use crate::socks;
+use crate::sse;
+use crate::tcp::TcpTuning;
+use crate::telemetry;
+use crate::timeout::{with_timeout, MinRate, Timeouts, DEFAULT_SNIFF_TIMEOUT};
+use crate::tls_policy::TlsPolicy;
+use crate::transparent;
+use crate::tunnel;
+use crate::upstream::UpstreamProxy;
+use crate::upstream_tls::UpstreamConnectors;
+use crate::websocket;
+
+// How long a requested shutdown waits for in-flight connections.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+pub(crate) struct Context {
+    pub(crate) acceptors: AcceptorMap,
+    pub(crate) tls_connectors: Arc<UpstreamConnectors>,
+    pub(crate) http_client: Client<DialerConnector>,
+    // Kept to dial with when the upstream proxy is reloaded.
+    pub(crate) resolver: Option<Arc<dyn Resolver>>,
+    pub(crate) live: Live,
+    pub(crate) flows: Flows,
+    pub(crate) redactor: Redactor,
+    pub(crate) interceptors: Interceptors,
+    pub(crate) policy: Policy,
+    pub(crate) acl: Acl,
+    pub(crate) rate_limit: RateLimiter,
+    pub(crate) limits: ResourceLimits,
+    pub(crate) connection_limit: ConnectionLimiter,
+    pub(crate) shaper: Shaper,
+    pub(crate) virtual_hosts: VirtualHosts,
+    pub(crate) credentials: Option<Arc<dyn Credentials>>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) tcp: TcpTuning,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) via: Option<String>,
+    pub(crate) flow_id_header: bool,
+    pub(crate) forwarded: ForwardedConfig,
+    pub(crate) grpc: Descriptors,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) connections: Arc<Connections>,
+    pub(crate) shutdown: Notify,
+    pub(crate) ca_portal: Option<CaPortal>,
+    pub(crate) offline: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+    scheme: Scheme,
+    authority: Authority,
+    tls_version: Option<ProtocolVersion>,
+}
+
+enum Upstream<'a> {
+    Client(&'a Client<DialerConnector>),
+    Connection {
+        sender: SendRequest<Body>,
+        http2: bool,
+    },
+    Shared(Arc<AsyncMutex<SendRequest<Body>>>),
+    Proxy {
+        sender: SendRequest<Body>,
+        authorization: Option<HeaderValue>,
+    },
+    // Nothing to send to; interceptors have to answer every request.
+    Offline,
+    // Every request goes to the origin an interceptor points it at, as in reverse mode.
+    Routed,
+}
+
+impl Upstream<'_> {
+    fn is_http2(&self) -> bool {
+        match self {
+            Upstream::Client(_) | Upstream::Proxy { .. } | Upstream::Offline | Upstream::Routed => {
+                false
+            }
+            Upstream::Connection { http2, .. } => *http2,
+            Upstream::Shared(_) => true,
+        }
+    }
+
+    async fn send(&mut self, mut req: Request<Body>) -> Result<Response<Body>, Error> {
+        let response = match self {
+            Upstream::Client(client) => client.request(req),
+            Upstream::Proxy {
+                sender,
+                authorization,
+            } => {
+                if let Some(authorization) = authorization {
+                    req.headers_mut()
+                        .insert(PROXY_AUTHORIZATION, authorization.clone());
+                }
+                poll_fn(|cx| sender.poll_ready(cx))
+                    .await
+                    .map_err(Error::HttpRequestError)?;
+                return sender
+                    .send_request(req)
+                    .await
+                    .map_err(Error::HttpRequestError);
+            }
+            Upstream::Connection { sender, .. } => {
+                poll_fn(|cx| sender.poll_ready(cx))
+                    .await
+                    .map_err(Error::HttpRequestError)?;
+                return sender
+                    .send_request(req)
+                    .await
+                    .map_err(Error::HttpRequestError);
+            }
+            Upstream::Shared(sender) => {
+                let future = {
+                    let mut sender = sender.lock().await;
+                    poll_fn(|cx| sender.poll_ready(cx))
+                        .await
+                        .map_err(Error::HttpRequestError)?;
+                    sender.send_request(req)
+                };
+                return future.await.map_err(Error::HttpRequestError);
+            }
+            Upstream::Offline => return Err(Error::OfflineError),
+            Upstream::Routed => return Err(Error::UnknownHostError),
+        };
+
+        response.await.map_err(Error::HttpRequestError)
+    }
+}
 
 pub struct Server {
-    listener: TcpListener,
-    acceptors: Arc<Mutex<AcceptorMap>>,
-    tls_connector: Arc<TlsConnector>,
+    // The first is the one of `listen` and `mode`.
+    listeners: Arc<[Listener]>,
+    // The sockets of each acceptor, taken when the server runs.
+    acceptors: Mutex<Vec<Vec<(usize, BoundListener)>>>,
+    pin_acceptors: bool,
+    context: Arc<Context>,
+}
+
+struct Listener {
+    addr: ListenAddr,
+    config: Arc<ListenerConfig>,
+    tls: Option<TlsAcceptor>,
+}
+
+// A loop accepting connections on a socket of each listener, with the index of the one it is for.
+struct Acceptor {
+    sockets: Vec<(usize, BoundListener)>,
+    listeners: Arc<[Listener]>,
+    context: Arc<Context>,
+    stopped: watch::Receiver<()>,
+}
+
+impl Acceptor {
+    // Runs on a runtime of its own, which the sockets move to.
+    fn run_pinned(mut self) {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!(?e, "Fail to start acceptor runtime");
+                return;
+            }
+        };
+
+        runtime.block_on(async move {
+            let sockets = mem::take(&mut self.sockets);
+            for (index, listener) in sockets {
+                match listener.rebind() {
+                    Ok(listener) => self.sockets.push((index, listener)),
+                    Err(e) => error!(e = ?Error::TcpBindError(e)),
+                }
+            }
+            let context = self.context.clone();
+            self.run().await;
+
+            // Its connections are served here, so the runtime has to outlast them.
+            let _ = timeout(SHUTDOWN_GRACE, context.connections.wait_idle()).await;
+        });
+    }
+
+    async fn run(mut self) {
+        loop {
+            let (accepted, index) = tokio::select! {
+                accepted = Self::accept(&self.sockets) => accepted,
+                _ = self.stopped.changed() => break,
+            };
+            let Listener {
+                addr,
+                config: listener,
+                tls,
+            } = &self.listeners[index];
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(e = ?Error::TcpAcceptError(e));
+                    continue;
+                }
+            };
+            if let ClientStream::Tcp(stream) = &stream {
+                if let Err(e) = self.context.tcp.apply(SockRef::from(stream)) {
+                    debug!(%peer, ?e, "Fail to tune client socket");
+                }
+            }
+            if !self.context.rate_limit.admit(peer.ip()) {
+                debug!(%peer, "rate limited");
+                continue;
+            }
+            let permit = match self.context.connection_limit.acquire(peer.ip()) {
+                Some(permit) => permit,
+                None => {
+                    debug!(%peer, "connection limit reached");
+                    // Clients of TLS listeners would not understand a plain answer.
+                    if listener.mode == Mode::Http && tls.is_none() {
+                        tokio::spawn(Server::reject(stream, Error::TooManyConnectionsError));
+                    }
+                    continue;
+                }
+            };
+
+            let context = self.context.clone();
+            let listener = listener.clone();
+            match listener.mode {
+                Mode::Http => Server::spawn(
+                    permit,
+                    Server::handle_stream(stream, peer, tls.clone(), listener, context),
+                ),
+                Mode::Socks5 => Server::spawn(
+                    permit,
+                    Server::handle_socks(stream, peer, listener, context),
+                ),
+                Mode::Transparent => {
+                    // Transparent listeners are only bound on TCP.
+                    let (ClientStream::Tcp(stream), ListenAddr::Tcp(listen)) = (stream, addr)
+                    else {
+                        continue;
+                    };
+                    Server::spawn(
+                        permit,
+                        Server::handle_transparent(stream, peer, *listen, listener, context),
+                    )
+                }
+                Mode::Reverse => {
+                    Server::spawn(permit, Server::handle_reverse(stream, peer, context))
+                }
+            }
+        }
+    }
+
+    // The next connection on any of the sockets, with the index of the listener it came to.
+    async fn accept(
+        sockets: &[(usize, BoundListener)],
+    ) -> (io::Result<(ClientStream, SocketAddr)>, usize) {
+        poll_fn(|cx| {
+            for (index, listener) in sockets {
+                if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return Poll::Ready((accepted, *index));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
 }
 
 impl Server {
-    #[instrument(skip(acceptors))]
-    pub async fn bind<A>(
-        addr: A,
-        root_store: RootCertStore,
-        acceptors: Arc<Mutex<AcceptorMap>>,
-    ) -> Result<Self, Error>
-    where
-        A: ToSocketAddrs + std::fmt::Debug,
-    {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    #[instrument(skip(context))]
+    pub(crate) async fn bind(
+        configs: Vec<ListenerConfig>,
+        policy: &TlsPolicy,
+        acceptors: AcceptorsConfig,
+        context: Context,
+    ) -> Result<Self, Error> {
+        if acceptors.count == 0 {
+            return Err(Error::InvalidConfigError(
+                "acceptors.count must be at least 1",
+            ));
+        }
+        let reuse_port = acceptors.count > 1;
+
+        let mut listeners = Vec::with_capacity(configs.len());
+        let mut sockets: Vec<_> = (0..acceptors.count).map(|_| Vec::new()).collect();
+        for (index, config) in configs.into_iter().enumerate() {
+            let tls = match &config.tls {
+                Some(_) if config.mode != Mode::Http => {
+                    return Err(Error::InvalidConfigError("TLS listeners need http mode"));
+                }
+                Some(tls) => Some(TlsAcceptor::from(Arc::new(reverse::load_server_config(
+                    &tls.cert, &tls.key, policy,
+                )?))),
+                None => None,
+            };
+            let listener =
+                BoundListener::bind(&config.listen, config.mode, &context.tcp, reuse_port).await?;
+            // The others share the port the first was given, when it asked for any.
+            let addr = listener.local_addr().map_err(Error::TcpBindError)?;
+            sockets[0].push((index, listener));
+            if let ListenAddr::Tcp(_) = addr {
+                for acceptor in &mut sockets[1..] {
+                    let listener =
+                        BoundListener::bind(&addr, config.mode, &context.tcp, true).await?;
+                    acceptor.push((index, listener));
+                }
+            }
+            listeners.push(Listener {
+                addr,
+                config: Arc::new(config),
+                tls,
+            });
+        }
+
         Ok(Self {
-            listener: TcpListener::bind(addr)
-                .await
-                .map_err(|e| Error::TcpBindError(e))?,
-            acceptors,
-            tls_connector: Arc::new(TlsConnector::from(Arc::new(
-                ClientConfig::builder()
-                    .with_safe_defaults()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth(),
-            ))),
+            listeners: listeners.into(),
+            acceptors: Mutex::new(sockets),
+            pin_acceptors: acceptors.pin,
+            context: Arc::new(context),
         })
     }
 
+    // The address of the first listener, if it is on TCP.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self.local_addrs()?.swap_remove(0) {
+            ListenAddr::Tcp(addr) => Ok(addr),
+            ListenAddr::Unix(_) => Err(Error::InvalidConfigError(
+                "The first listener is on a Unix socket",
+            )),
+        }
+    }
+
+    pub fn local_addrs(&self) -> Result<Vec<ListenAddr>, Error> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(|listener| listener.addr.clone())
+            .collect())
+    }
+
+    pub(crate) fn context(&self) -> &Arc<Context> {
+        &self.context
+    }
+
+    // The config of the first listener.
+    pub(crate) fn listener(&self) -> &ListenerConfig {
+        &self.listeners[0].config
+    }
+
+    // Sends a captured request again; returns the id of the new flow.
+    pub async fn resend(&self, req: Request<Body>) -> Result<u64, Error> {
+        let replayed = Self::resend_from(req, self.own_addr(), &self.context).await?;
+        Ok(replayed.id)
+    }
+
+    // Sends a recorded flow again through the same pipeline as live traffic.
+    pub async fn replay(
+        &self,
+        flow: &RecordedFlow,
+        overrides: &Overrides,
+    ) -> Result<Replayed, Error> {
+        Self::resend_from(flow.to_request(overrides)?, self.own_addr(), &self.context).await
+    }
+
+    // Where flows the proxy sends itself come from.
+    fn own_addr(&self) -> SocketAddr {
+        self.local_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FlowEvent> {
+        self.context.flows.subscribe()
+    }
+
+    // Serves a connection handed over rather than accepted, as the first listener would, until
+    // it closes. Rate and connection limits are left to whoever accepted it.
+    pub async fn serve_connection<S: Transport>(
+        &self,
+        stream: S,
+        peer: SocketAddr,
+    ) -> Result<(), Error> {
+        let Listener {
+            config: listener,
+            tls,
+            ..
+        } = &self.listeners[0];
+        let context = self.context.clone();
+        match listener.mode {
+            Mode::Http => {
+                Self::handle_stream(stream, peer, tls.clone(), listener.clone(), context).await
+            }
+            Mode::Socks5 => Self::handle_socks(stream, peer, listener.clone(), context).await,
+            Mode::Reverse => Self::handle_reverse(stream, peer, context).await,
+            Mode::Transparent => {
+                return Err(Error::InvalidConfigError(
+                    "Transparent mode needs TCP connections",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn run(&self) -> Result<(), Error> {
-        loop {
-            let (stream, _addr) = self
-                .listener
-                .accept()
-                .await
-                .map_err(|e| Error::TcpAcceptError(e))?;
+        let acceptors = mem::take(&mut *self.acceptors.lock().unwrap());
+        if acceptors.is_empty() {
+            return Err(Error::InvalidConfigError("The server is already running"));
+        }
 
-            let acceptors = self.acceptors.clone();
-            let connector = self.tls_connector.clone();
+        // Dropped when this returns or is cancelled, which stops the acceptors.
+        let (stop, stopped) = watch::channel(());
+        for sockets in acceptors {
+            let acceptor = Acceptor {
+                sockets,
+                listeners: self.listeners.clone(),
+                context: self.context.clone(),
+                stopped: stopped.clone(),
+            };
+            if self.pin_acceptors {
+                std::thread::spawn(move || acceptor.run_pinned());
+            } else {
+                tokio::spawn(acceptor.run());
+            }
+        }
+
+        self.context.shutdown.notified().await;
+        drop(stop);
 
-            tokio::spawn(Self::handle_stream(stream, acceptors, connector));
+        info!("Shutting down, waiting for open connections");
+        if timeout(SHUTDOWN_GRACE, self.context.connections.wait_idle())
+            .await
+            .is_err()
+        {
+            info!("Grace period over, dropping open connections");
         }
+
+        Ok(())
     }
 
-    async fn handle_stream(
+    fn spawn<F>(permit: ConnectionPermit, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            connection.await;
+            drop(permit);
+        });
+    }
+
+    async fn reject<S: Transport>(stream: S, e: Error) {
+        Self::write_error(&mut BufStream::new(stream), &e).await;
+    }
+
+    #[instrument(skip(stream, listener, context), fields(connection = field::Empty))]
+    async fn handle_transparent(
         stream: TcpStream,
-        acceptors: Arc<Mutex<AcceptorMap>>,
-        connector: Arc<TlsConnector>,
+        peer: SocketAddr,
+        listen: SocketAddr,
+        listener: Arc<ListenerConfig>,
+        context: Arc<Context>,
     ) {
+        let _connection = context.metrics.connection();
+        let tracked = context.connections.register(peer);
+        let peer = tracked.peer;
+        Span::current().record("connection", field::display(peer.connection));
+        if let Err(e) = Self::serve_transparent(stream, peer, listen, &listener, &context).await {
+            error!(%peer, ?e);
+        }
+    }
+
+    async fn serve_transparent(
+        stream: TcpStream,
+        peer: Peer,
+        listen: SocketAddr,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
+    ) -> Result<(), Error> {
+        let target = transparent::original_dst(&stream, listen)?;
+        if !context.acl.allows_client(peer.ip())
+            || !context
+                .acl
+                .allows(peer.ip(), &target.ip().to_string(), target.port())
+        {
+            return Err(Error::AccessDeniedError);
+        }
+
+        let stream = BufStream::new(stream);
+        let host = target.ip().to_string();
+        context.connections.set_target(peer, &host);
+
+        let remote = Self::dial(&host, target.port(), context).await?;
+
+        Self::handle_tunnel(host, target.port(), peer, listener, context, remote, stream).await
+    }
+
+    #[instrument(skip(stream, context), fields(connection = field::Empty))]
+    async fn handle_reverse<S: Transport>(stream: S, peer: SocketAddr, context: Arc<Context>) {
+        let _connection = context.metrics.connection();
+        let tracked = context.connections.register(peer);
+        let peer = tracked.peer;
+        Span::current().record("connection", field::display(peer.connection));
+        if let Err(e) = Self::serve_reverse(stream, peer, &context).await {
+            error!(%peer, ?e);
+        }
+    }
+
+    async fn serve_reverse<S: Transport>(
+        stream: S,
+        peer: Peer,
+        context: &Arc<Context>,
+    ) -> Result<(), Error> {
+        if !context.acl.allows_client(peer.ip()) {
+            return Err(Error::AccessDeniedError);
+        }
         let mut stream = BufStream::new(stream);
+        let preface = match with_timeout(context.timeouts.header, async {
+            stream.fill_buf().await.map_err(Error::ReadStreamError)
+        })
+        .await
+        {
+            Ok(preface) if !preface.is_empty() => preface,
+            Ok(_) | Err(Error::TimeoutError(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if !http_ext::is_tls_handshake(preface) {
+            return Self::route(stream, Scheme::HTTP, None, None, peer, context).await;
+        }
+        let server_name = sni::server_name(preface);
+        // A TLS-ALPN-01 challenge is answered by the handshake alone.
+        let validation = context
+            .virtual_hosts
+            .acme()
+            .filter(|_| sni::offers_protocol(preface, ACME_TLS_ALPN))
+            .zip(server_name.as_deref())
+            .and_then(|(acme, host)| acme.validation(host));
+        let answers_challenge = validation.is_some();
+        let server_config = match validation {
+            Some(validation) => validation,
+            None => {
+                context
+                    .virtual_hosts
+                    .server_config(server_name.as_deref(), &context.acceptors)
+                    .await?
+            }
+        };
+        let stream = with_timeout(context.timeouts.handshake, async {
+            TlsAcceptor::from(server_config)
+                .accept(stream)
+                .await
+                .map_err(|e| {
+                    context.metrics.tls_handshake_failed("client");
+                    Error::TlsAcceptError(e)
+                })
+        })
+        .await?;
+        if answers_challenge {
+            return Ok(());
+        }
+        let tls_version = stream.get_ref().1.protocol_version();
+        let stream = BufStream::new(TlsStream::Server(stream));
 
-        let mut buf = Vec::new();
-        stream.read_until_header_end(&mut buf).await.unwrap();
+        Self::route(
+            stream,
+            Scheme::HTTPS,
+            server_name,
+            tls_version,
+            peer,
+            context,
+        )
+        .await
+    }
 
-        let req = Request::from_utf8(&buf).unwrap();
+    // Like `intercept`, but every request names its own site in the Host header.
+    async fn route<S>(
+        mut stream: BufStream<S>,
+        scheme: Scheme,
+        server_name: Option<String>,
+        tls_version: Option<ProtocolVersion>,
+        peer: Peer,
+        context: &Context,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut upstream = Upstream::Routed;
+        let mut served = 0;
 
-        info!(?req);
+        loop {
+            let wait = if served == 0 {
+                context.timeouts.header
+            } else {
+                context.timeouts.keep_alive
+            };
+            let mut req = match with_timeout(wait, Self::read_request(&mut stream, context)).await {
+                Ok(Some(req)) => req,
+                Ok(None) | Err(Error::TimeoutError(_)) => return Ok(()),
+                Err(e) => {
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            };
+            // Only the virtual hosts pick where a request goes, never an origin in its target.
+            *req.uri_mut() = http_ext::origin_form(req.uri());
 
-        if req.method() == Method::CONNECT {
-            let host = req.uri().host().unwrap().to_string();
-            let acceptor = {
-                let mut map = acceptors.lock().unwrap();
+            let host = req
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(str::to_string)
+                .or_else(|| server_name.as_deref().map(http_ext::format_host));
+            let authority = match host.and_then(|host| Authority::try_from(host).ok()) {
+                Some(authority) => authority,
+                None => {
+                    let e = Error::BadRequestError("Request without host");
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            };
+            context.connections.set_target(peer, authority.host());
 
-                map.get(host.clone())
+            let target = Target {
+                scheme: scheme.clone(),
+                authority,
+                tls_version,
             };
+            let mut client = context.rate_limit.throttle(peer.ip(), &mut stream);
+            let keep_alive =
+                Self::exchange(req, &mut client, &mut upstream, Some(target), peer, context)
+                    .await?;
+            served += 1;
+
+            if !keep_alive || context.max_requests.is_some_and(|max| served >= max) {
+                return Ok(());
+            }
+        }
+    }
+
+    #[instrument(skip(stream, listener, context), fields(connection = field::Empty))]
+    async fn handle_socks<S: Transport>(
+        stream: S,
+        peer: SocketAddr,
+        listener: Arc<ListenerConfig>,
+        context: Arc<Context>,
+    ) {
+        let _connection = context.metrics.connection();
+        let tracked = context.connections.register(peer);
+        let peer = tracked.peer;
+        Span::current().record("connection", field::display(peer.connection));
+        if let Err(e) = Self::serve_socks(stream, peer, &listener, &context).await {
+            error!(%peer, ?e);
+        }
+    }
+
+    async fn serve_socks<S: Transport>(
+        mut stream: S,
+        peer: Peer,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
+    ) -> Result<(), Error> {
+        let credentials = context.credentials.as_deref().filter(|_| listener.auth);
+        // Like request headers, the greeting and request have to arrive in time.
+        let (host, port) = with_timeout(
+            context.timeouts.header,
+            socks::accept(&mut stream, credentials),
+        )
+        .await?;
+        context.connections.set_target(peer, &host);
+        if !context.acl.allows_client(peer.ip()) || !context.acl.allows(peer.ip(), &host, port) {
+            info!(%host, port, "denied");
+            return socks::reply(&mut stream, socks::NOT_ALLOWED).await;
+        }
+        if let Some(blocklist) = &context.live.load().blocklist {
+            if blocklist.is_blocked(&host) {
+                info!(%host, "blocked");
+                if blocklist.action() == BlockAction::Reset {
+                    return Self::reset(&stream);
+                }
+                return socks::reply(&mut stream, socks::NOT_ALLOWED).await;
+            }
+        }
+
+        let remote = match Self::dial(&host, port, context).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                let code = match e {
+                    Error::TcpConnectError(_) | Error::TimeoutError(_) => socks::HOST_UNREACHABLE,
+                    _ => socks::GENERAL_FAILURE,
+                };
+                let _ = socks::reply(&mut stream, code).await;
+                return Err(e);
+            }
+        };
+        socks::reply(&mut stream, socks::SUCCEEDED).await?;
 
-            let remote = Self::connect_to_remote(&req, &mut stream).await.unwrap();
+        Self::handle_tunnel(
+            host,
+            port,
+            peer,
+            listener,
+            context,
+            remote,
+            BufStream::new(stream),
+        )
+        .await
+    }
 
-            match Self::handle_https(host.clone(), connector, acceptor, remote, stream.into_inner()).await {
-                Ok(_) => return,
-                Err(e) => error!(?host, ?e),
+    async fn handle_tunnel<S: Transport>(
+        host: String,
+        port: u16,
+        peer: Peer,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
+        remote: RemoteStream,
+        mut stream: BufStream<S>,
+    ) -> Result<(), Error> {
+        let sniff = context.timeouts.sniff.unwrap_or(DEFAULT_SNIFF_TIMEOUT);
+        let preface = match with_timeout(Some(sniff), async {
+            stream.fill_buf().await.map_err(Error::ReadStreamError)
+        })
+        .await
+        {
+            Ok(preface) => preface,
+            Err(Error::TimeoutError(_)) => {
+                info!(%host, "client waits for the server, relaying");
+                return Self::pass(stream, remote, peer, &host, context).await;
             }
+            Err(e) => return Err(e),
+        };
+        let is_tls = http_ext::is_tls_handshake(preface);
+        let is_http1 = http_ext::is_http1_request(preface);
+        let dialed = http_ext::strip_brackets(&host).parse::<IpAddr>().ok();
+        // Trust the name the client actually asks for over the address it dialed.
+        let host = sni::server_name(preface).unwrap_or(host);
+        let offer = sni::client_offer(preface);
+        context.connections.set_target(peer, &host);
+
+        if !listener.intercept
+            || context
+                .policy
+                .should_bypass(&host, &context.live.load().bypass)
+        {
+            info!(%host, "bypass");
+            return Self::pass(stream, remote, peer, &host, context).await;
+        }
+        if is_tls && sni::offers_ech(preface) {
+            info!(%host, "encrypted client hello, relaying");
+            return Self::pass(stream, remote, peer, &host, context).await;
+        }
+
+        if is_tls {
+            Self::handle_https(host, dialed, offer, peer, context, remote, stream).await
+        } else if is_http1 {
+            let authority = Authority::try_from(http_ext::join_host_port(&host, port))
+                .map_err(|_| Error::BadRequestError("Invalid server name"))?;
+
+            let (sender, connection) = hyper::client::conn::handshake(remote)
+                .await
+                .map_err(Error::HttpRequestError)?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    error!(?e);
+                }
+            });
+
+            let upstream = Upstream::Connection {
+                sender,
+                http2: false,
+            };
+            let target = Target {
+                scheme: Scheme::HTTP,
+                authority,
+                tls_version: None,
+            };
+            Self::intercept(stream, upstream, target, peer, context).await
         } else {
-            Self::handle_http(req, stream).await;
+            info!(%host, "neither TLS nor HTTP, relaying");
+            Self::pass(stream, remote, peer, &host, context).await
         }
     }
 
-    async fn connect_to_remote(
+    #[instrument(skip(stream, tls, listener, context), fields(connection = field::Empty))]
+    async fn handle_stream<S: Transport>(
+        stream: S,
+        peer: SocketAddr,
+        tls: Option<TlsAcceptor>,
+        listener: Arc<ListenerConfig>,
+        context: Arc<Context>,
+    ) {
+        let _connection = context.metrics.connection();
+        let tracked = context.connections.register(peer);
+        let peer = tracked.peer;
+        Span::current().record("connection", field::display(peer.connection));
+        let acceptor = match tls {
+            Some(acceptor) => acceptor,
+            None => return Self::serve_stream(stream, peer, &listener, &context).await,
+        };
+
+        match with_timeout(context.timeouts.handshake, async {
+            acceptor.accept(stream).await.map_err(|e| {
+                context.metrics.tls_handshake_failed("client");
+                Error::TlsAcceptError(e)
+            })
+        })
+        .await
+        {
+            Ok(stream) => Self::serve_stream(stream, peer, &listener, &context).await,
+            Err(e) => error!(%peer, ?e),
+        }
+    }
+
+    async fn serve_stream<S: Transport>(
+        stream: S,
+        peer: Peer,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
+    ) {
+        let mut stream = BufStream::new(stream);
+
+        let req = match with_timeout(
+            context.timeouts.header,
+            Self::read_request(&mut stream, context),
+        )
+        .await
+        {
+            Ok(Some(req)) => req,
+            Ok(None) | Err(Error::TimeoutError(_)) => return,
+            Err(e) => {
+                Self::write_error(&mut stream, &e).await;
+                error!(%peer, ?e);
+                return;
+            }
+        };
+
+        info!(
+            method = %req.method(),
+            uri = %context.redactor.uri(req.uri()),
+            version = ?req.version(),
+            headers = ?context.redactor.headers(req.headers()),
+        );
+
+        if !context.acl.allows_client(peer.ip()) {
+            Self::write_error(&mut stream, &Error::AccessDeniedError).await;
+            info!(%peer, "denied");
+            return;
+        }
+        // Asked of the proxy itself rather than through it, and before credentials are set up.
+        let snapshot = context.live.load();
+        if let Some(pac) = snapshot
+            .pac
+            .as_ref()
+            .filter(|pac| req.uri().authority().is_none() && pac.matches(req.uri().path()))
+        {
+            let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+            let _ = stream.write_all(&pac.response(host)).await;
+            let _ = stream.flush().await;
+            return;
+        }
+        if let Err(e) = Self::authenticate(&req, &mut stream, listener, context).await {
+            error!(%peer, ?e);
+            return;
+        }
+
+        let result = if req.method() == Method::CONNECT {
+            Self::handle_connect(req, stream, peer, listener, context).await
+        } else {
+            Self::handle_http(req, stream, peer, listener, context).await
+        };
+
+        if let Err(e) = result {
+            error!(%peer, ?e);
+        }
+    }
+
+    async fn read_request<S>(
+        stream: &mut BufStream<S>,
+        context: &Context,
+    ) -> Result<Option<Request<Vec<u8>>>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        // The header timeout runs from the first byte, leaving idle waits to the caller.
+        if stream
+            .fill_buf()
+            .await
+            .map_err(Error::ReadUntilError)?
+            .is_empty()
+        {
+            return Ok(None);
+        }
+
+        let limits = &context.limits;
+        let mut reader = MinRate::new(&mut *stream, limits.min_header_rate);
+        let mut req = with_timeout(
+            context.timeouts.header,
+            reader.read_request_head(limits.max_header_size),
+        )
+        .await
+        .map_err(|e| match e {
+            Error::TimeoutError(_) => Error::RequestTimeoutError,
+            Error::ReadUntilError(e) | Error::BadHttpError(e)
+                if e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Error::RequestTimeoutError
+            }
+            e => e,
+        })?;
+        if let Some(req) = &mut req {
+            http_ext::host_from_target(req);
+        }
+
+        Ok(req)
+    }
+
+    async fn authenticate<S>(
         req: &Request<Vec<u8>>,
-        stream: &mut BufStream<TcpStream>,
-    ) -> Result<TcpStream, Error> {
-        let connection = TcpStream::connect(format!(
-            "{}:{}",
-            req.uri().host().unwrap(),
-            req.uri().port().unwrap()
-        ))
-        .await;
-
-        let status_code = if let Ok(_) = &connection {
-            StatusCode::OK
+        stream: &mut BufStream<S>,
+        listener: &ListenerConfig,
+        context: &Context,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let credentials = match &context.credentials {
+            Some(credentials) if listener.auth => credentials,
+            _ => return Ok(()),
+        };
+
+        if auth::authorize(credentials.as_ref(), req.headers()).await {
+            Ok(())
         } else {
-            StatusCode::INTERNAL_SERVER_ERROR
+            let e = Error::ProxyAuthRequiredError;
+            Self::write_error(stream, &e).await;
+            Err(e)
+        }
+    }
+
+    async fn write_error<S>(stream: &mut S, e: &Error)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let response = http_ext::error_response(e.status_code());
+
+        if stream.write_all(&response).await.is_ok() {
+            let _ = stream.flush().await;
+        }
+    }
+
+    async fn handle_connect<S: Transport>(
+        req: Request<Vec<u8>>,
+        mut stream: BufStream<S>,
+        peer: Peer,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
+    ) -> Result<(), Error> {
+        let host = match req.uri().host() {
+            Some(host) => http_ext::strip_brackets(host).to_string(),
+            None => {
+                let e = Error::BadRequestError("CONNECT without host");
+                Self::write_error(&mut stream, &e).await;
+                return Err(e);
+            }
         };
 
-        let response = Response::builder()
-            .version(req.version())
-            .status(status_code)
-            .body(Vec::new())
-            .unwrap();
+        if let Some(portal) = &context.ca_portal {
+            if portal.matches(Some(&host)) {
+                return Self::serve_portal(host, req.version(), stream, context).await;
+            }
+        }
+
+        let port = req.uri().port_u16().unwrap_or(443);
+        context.connections.set_target(peer, &host);
+        if !context.acl.allows_connect(peer.ip(), &host, port) {
+            info!(%host, port, "denied");
+            Self::write_error(&mut stream, &Error::AccessDeniedError).await;
+            return Ok(());
+        }
+        if let Some(blocklist) = &context.live.load().blocklist {
+            if blocklist.is_blocked(&host) {
+                info!(%host, "blocked");
+                return match blocklist.action() {
+                    BlockAction::Forbidden => {
+                        Self::write_error(&mut stream, &Error::HostBlockedError).await;
+                        Ok(())
+                    }
+                    // Answered by the blocklist interceptor once the tunnel is open.
+                    BlockAction::Empty => {
+                        Self::handle_offline(host, port, req.version(), peer, stream, context).await
+                    }
+                    BlockAction::Reset => Self::reset(stream.get_ref()),
+                };
+            }
+        }
+        if context.offline {
+            return Self::handle_offline(host, port, req.version(), peer, stream, context).await;
+        }
+        let remote = Self::connect_to_remote(&req, &host, port, &mut stream, context).await?;
+
+        Self::handle_tunnel(host, port, peer, listener, context, remote, stream).await
+    }
+
+    // The client sees a reset rather than the connection just closing.
+    fn reset<S: Transport>(stream: &S) -> Result<(), Error> {
+        stream.reset().map_err(Error::WriteStreamError)
+    }
 
+    async fn serve_portal<S: Transport>(
+        host: String,
+        version: Version,
+        mut stream: BufStream<S>,
+        context: &Arc<Context>,
+    ) -> Result<(), Error> {
         stream
-            .write_all(&response.into_utf8().unwrap())
+            .write_all(&http_ext::encode_response_head(
+                version,
+                StatusCode::OK,
+                &HeaderMap::new(),
+            ))
             .await
-            .unwrap();
-        stream.flush().await.unwrap();
+            .map_err(Error::WriteStreamError)?;
+        stream.flush().await.map_err(Error::WriteStreamError)?;
+
+        let mut server_config = (*context.acceptors.get(host).await?).clone();
+        server_config.alpn_protocols = vec![http_ext::ALPN_HTTP1.to_vec()];
+        let stream = with_timeout(context.timeouts.handshake, async {
+            TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .map_err(|e| {
+                    context.metrics.tls_handshake_failed("client");
+                    Error::TlsAcceptError(e)
+                })
+        })
+        .await?;
+
+        let context = context.clone();
+        let service = service_fn(move |req: Request<Body>| {
+            let context = context.clone();
+            async move {
+                let portal = context.ca_portal.as_ref().unwrap();
+                Ok::<_, Infallible>(portal.respond(req.uri().path()))
+            }
+        });
 
-        connection.map_err(Error::TcpConnectError)
+        Http::new()
+            .http1_only(true)
+            .serve_connection(stream, service)
+            .await
+            .map_err(Error::HttpRequestError)
     }
 
-    #[instrument(skip(connector, acceptor))]
-    async fn handle_https(
+    // Like a tunnel, but intercepted without ever dialing the remote.
+    async fn handle_offline<S: Transport>(
         host: String,
-        connector: Arc<TlsConnector>,
-        acceptor: Arc<TlsAcceptor>,
-        remote: TcpStream,
-        stream: TcpStream,
+        port: u16,
+        version: Version,
+        peer: Peer,
+        mut stream: BufStream<S>,
+        context: &Arc<Context>,
     ) -> Result<(), Error> {
-        let remote = connector
-            .connect(ServerName::try_from(host.as_str()).unwrap(), remote)
+        stream
+            .write_all(&http_ext::encode_response_head(
+                version,
+                StatusCode::OK,
+                &HeaderMap::new(),
+            ))
             .await
-            .map_err(Error::TlsConnectError)?;
-        let remote = TlsStream::Client(remote);
+            .map_err(Error::WriteStreamError)?;
+        stream.flush().await.map_err(Error::WriteStreamError)?;
 
-        let stream = acceptor
-            .accept(stream)
-            .await
-            .map_err(Error::TlsAcceptError)?;
-        let stream = TlsStream::Server(stream);
+        let preface = stream.fill_buf().await.map_err(Error::ReadStreamError)?;
+        let is_tls = http_ext::is_tls_handshake(preface);
+        let host = sni::server_name(preface).unwrap_or(host);
+        context.connections.set_target(peer, &host);
+
+        let (scheme, authority) = if is_tls {
+            (Scheme::HTTPS, http_ext::format_host(&host))
+        } else {
+            (Scheme::HTTP, http_ext::join_host_port(&host, port))
+        };
+        let authority = Authority::try_from(authority)
+            .map_err(|_| Error::BadRequestError("Invalid server name"))?;
 
-        let (remote_read, remote_write) = split(remote);
-        let (stream_read, stream_write) = split(stream);
+        if !is_tls {
+            let target = Target {
+                scheme,
+                authority,
+                tls_version: None,
+            };
+            return Self::intercept(stream, Upstream::Offline, target, peer, context).await;
+        }
 
-        let c_to_s = tokio::spawn(Self::link(stream_read, remote_write));
-        Self::link(remote_read, stream_write).await?;
-        c_to_s.await.unwrap()?;
+        let mut server_config = (*context.acceptors.get(host).await?).clone();
+        server_config.alpn_protocols = vec![http_ext::ALPN_HTTP1.to_vec()];
+        let stream = with_timeout(context.timeouts.handshake, async {
+            TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .map_err(|e| {
+                    context.metrics.tls_handshake_failed("client");
+                    Error::TlsAcceptError(e)
+                })
+        })
+        .await?;
+        let target = Target {
+            scheme,
+            authority,
+            tls_version: stream.get_ref().1.protocol_version(),
+        };
+        let stream = BufStream::new(TlsStream::Server(stream));
 
-        Ok(())
+        Self::intercept(stream, Upstream::Offline, target, peer, context).await
     }
 
-    #[instrument]
-    async fn handle_http(req: Request<Vec<u8>>, mut stream: BufStream<TcpStream>) {
-        let client = client::Client::new();
-        let (parts, empty) = req.into_parts();
+    async fn connect_to_remote<S: Transport>(
+        req: &Request<Vec<u8>>,
+        host: &str,
+        port: u16,
+        stream: &mut BufStream<S>,
+        context: &Context,
+    ) -> Result<RemoteStream, Error> {
+        let connection = Self::dial(host, port, context).await;
 
-        let body = if parts.method == &Method::POST {
-            Self::read_body(&parts.headers, &mut stream).await
-        } else {
-            empty
+        let response = match &connection {
+            Ok(_) => {
+                http_ext::encode_response_head(req.version(), StatusCode::OK, &HeaderMap::new())
+            }
+            Err(e) => http_ext::error_response(e.status_code()),
         };
-        let req = Request::from_parts(parts, Body::from(body));
-
-        let response = client.request(req).await.unwrap();
-        let (parts, mut body) = response.into_parts();
-        let response = Response::from_parts(parts, Vec::new());
 
         stream
-            .write_all(&response.into_utf8().unwrap())
+            .write_all(&response)
             .await
-            .unwrap();
-        stream.flush().await.unwrap();
+            .map_err(Error::WriteStreamError)?;
+        stream.flush().await.map_err(Error::WriteStreamError)?;
+
+        connection
+    }
+
+    #[instrument(skip(offer, context, stream))]
+    async fn handle_https<S>(
+        host: String,
+        dialed: Option<IpAddr>,
+        offer: Option<ClientOffer>,
+        peer: Peer,
+        context: &Arc<Context>,
+        remote: RemoteStream,
+        stream: S,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let server_name = ServerName::try_from(host.as_str())
+            .map_err(|_| Error::BadRequestError("Invalid server name"))?;
+        let connector = match &offer {
+            Some(offer) => context.tls_connectors.mirror(&host, offer),
+            None => context.tls_connectors.get(&host),
+        };
+        let remote = with_timeout(context.timeouts.handshake, async {
+            connector.connect(server_name, remote).await.map_err(|e| {
+                context.metrics.tls_handshake_failed("upstream");
+                Error::TlsConnectError(e)
+            })
+        })
+        .await?;
+        let upstream_h2 = remote.get_ref().1.alpn_protocol() == Some(http_ext::ALPN_H2);
+        let server_config = match remote.get_ref().1.peer_certificates() {
+            Some([upstream, ..]) => {
+                context
+                    .acceptors
+                    .get_like(host.clone(), dialed, &upstream.0)
+                    .await?
+            }
+            _ => context.acceptors.get_for(host.clone(), dialed).await?,
+        };
+        let mut remote = TlsStream::Client(remote);
+
+        let mut server_config = (*server_config).clone();
+        if !upstream_h2 {
+            server_config.alpn_protocols = vec![http_ext::ALPN_HTTP1.to_vec()];
+        }
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
 
-        while !body.is_end_stream() {
-            let mut pin_body = Pin::new(&mut body);
+        let stream = with_timeout(context.timeouts.handshake, async {
+            acceptor.accept(stream).await.map_err(|e| {
+                context.metrics.tls_handshake_failed("client");
+                Error::TlsAcceptError(e)
+            })
+        })
+        .await?;
+        let client_h2 = stream.get_ref().1.alpn_protocol() == Some(http_ext::ALPN_H2);
+        let tls_version = stream.get_ref().1.protocol_version();
+        let mut stream = BufStream::new(TlsStream::Server(stream));
 
-            if let Some(Ok(buf)) = pin_body.data().await {
-                let buf: Vec<_> = buf.to_vec();
-                stream.write_all(&buf).await.unwrap();
-                stream.flush().await.unwrap();
+        if !client_h2 {
+            let preface = stream.fill_buf().await.map_err(Error::ReadStreamError)?;
+            if !http_ext::is_http1_request(preface) {
+                return Self::relay(&mut stream, &mut remote, peer, &host, context).await;
             }
         }
+
+        let (sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(upstream_h2)
+            .handshake(remote)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!(?e);
+            }
+        });
+
+        let authority = Authority::try_from(http_ext::format_host(&host))
+            .map_err(|_| Error::BadRequestError("Invalid server name"))?;
+        let target = Target {
+            scheme: Scheme::HTTPS,
+            authority,
+            tls_version,
+        };
+
+        if client_h2 {
+            Self::intercept_h2(stream, sender, target, peer, context.clone()).await
+        } else {
+            let upstream = Upstream::Connection {
+                sender,
+                http2: upstream_h2,
+            };
+            Self::intercept(stream, upstream, target, peer, context).await
+        }
     }
 
-    async fn read_body(headers: &HeaderMap, stream: &mut BufStream<TcpStream>) -> Vec<u8> {
-        let content_length = headers.get(CONTENT_LENGTH).unwrap();
-        let content_length: usize = content_length.to_str().unwrap().parse().unwrap();
+    async fn intercept<S>(
+        mut stream: BufStream<S>,
+        mut upstream: Upstream<'_>,
+        target: Target,
+        peer: Peer,
+        context: &Context,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        loop {
+            let req = match with_timeout(
+                context.timeouts.keep_alive,
+                Self::read_request(&mut stream, context),
+            )
+            .await
+            {
+                Ok(Some(req)) => req,
+                Ok(None) | Err(Error::TimeoutError(_)) => return Ok(()),
+                Err(e) => {
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            };
 
-        let mut buf = Vec::with_capacity(content_length);
-        stream.read_exact(&mut buf[..content_length]).await.unwrap();
-        buf
+            let target = Some(target.clone());
+            let mut client = context.rate_limit.throttle(peer.ip(), &mut stream);
+            if !Self::exchange(req, &mut client, &mut upstream, target, peer, context).await? {
+                return Ok(());
+            }
+        }
     }
 
-    #[instrument]
-    async fn link(
-        mut from: ReadHalf<TlsStream<TcpStream>>,
-        mut to: WriteHalf<TlsStream<TcpStream>>,
+    async fn intercept_h2<S>(
+        stream: S,
+        sender: SendRequest<Body>,
+        target: Target,
+        peer: Peer,
+        context: Arc<Context>,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let sender = Arc::new(AsyncMutex::new(sender));
+        let limits = context.clone();
+        let stream = limits.rate_limit.throttle(peer.ip(), stream);
+
+        let service = service_fn(move |req| {
+            let upstream = Upstream::Shared(sender.clone());
+            let target = target.clone();
+            let context = context.clone();
+
+            async move {
+                Ok::<_, Infallible>(Self::exchange_h2(req, upstream, target, peer, context).await)
+            }
+        });
+
+        Http::new()
+            .http2_only(true)
+            .serve_connection(stream, service)
+            .await
+            .map_err(Error::HttpRequestError)
+    }
+
+    #[instrument(skip(req, upstream, context), fields(flow = field::Empty))]
+    async fn exchange_h2(
+        mut req: Request<Body>,
+        mut upstream: Upstream<'_>,
+        target: Target,
+        peer: Peer,
+        context: Arc<Context>,
+    ) -> Response<MeteredBody> {
+        let id = context.flows.next_id();
+
+        let flow = FlowRequest {
+            id,
+            ulid: Ulid::new(),
+            connection: Some(peer.connection),
+            client: peer.addr,
+            method: req.method().clone(),
+            uri: Self::absolute_uri(req.uri(), target.scheme, target.authority),
+            version: req.version(),
+            headers: req.headers().clone(),
+        };
+        Span::current().record("flow", field::display(flow.ulid));
+        context.flows.emit(FlowEvent::Request(flow.clone()));
+        *req.uri_mut() = flow.uri.clone();
+        if grpc::is_grpc(req.headers()) {
+            let body = mem::take(req.body_mut());
+            let body = grpc::observe(
+                body,
+                Direction::Upstream,
+                req.headers(),
+                &flow,
+                context.clone(),
+            );
+            *req.body_mut() = body;
+        }
+
+        let mut summary = FlowSummary::new(&flow, target.tls_version);
+        summary.request_bytes = http_ext::content_length(req.headers()).unwrap_or_default();
+
+        let (status, error) = match Self::forward(&flow, req, &mut upstream, &context).await {
+            Ok(Some(response)) => {
+                context.flows.emit(FlowEvent::Response(FlowResponse {
+                    id,
+                    status: response.status(),
+                    version: response.version(),
+                    headers: response.headers().clone(),
+                }));
+                summary.status = Some(response.status());
+
+                let (parts, mut body) = response.into_parts();
+                if let Some(parser) = sse::parser_for(&parts.headers) {
+                    body = sse::observe(body, parser, flow, context.clone());
+                } else if grpc::is_grpc(&parts.headers) {
+                    body = grpc::observe(
+                        body,
+                        Direction::Downstream,
+                        &parts.headers,
+                        &flow,
+                        context.clone(),
+                    );
+                }
+                let body = MeteredBody::new(body).on_end(move |bytes| {
+                    summary.response_bytes = bytes;
+                    Self::complete(summary, &context);
+                });
+                return Response::from_parts(parts, body);
+            }
+            Ok(None) => (StatusCode::FORBIDDEN, Error::FlowBlockedError.to_string()),
+            Err(e) => (e.status_code(), e.to_string()),
+        };
+
+        context.flows.emit(FlowEvent::Error { id, error });
+        summary.status = Some(status);
+        Self::complete(summary, &context);
+
+        let mut response = Response::new(MeteredBody::new(Body::empty()));
+        *response.status_mut() = status;
+        response
+    }
+
+    fn absolute_uri(uri: &Uri, scheme: Scheme, authority: Authority) -> Uri {
+        let mut parts = uri.clone().into_parts();
+        if parts.scheme.is_none() {
+            parts.scheme = Some(scheme);
+        }
+        if parts.authority.is_none() {
+            parts.authority = Some(authority);
+        }
+
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+
+    #[instrument(skip_all, fields(method = %flow.method, uri = %context.redactor.uri(&flow.uri)))]
+    async fn forward(
+        flow: &FlowRequest,
+        mut req: Request<Body>,
+        upstream: &mut Upstream<'_>,
+        context: &Context,
+    ) -> Result<Option<Response<Body>>, Error> {
+        if let Some(portal) = &context.ca_portal {
+            if portal.matches(flow.uri.host()) {
+                return Ok(Some(portal.respond(flow.uri.path())));
+            }
+        }
+
+        let upgrade = http_ext::wants_upgrade(req.headers());
+        http_ext::strip_hop_by_hop(req.headers_mut(), upgrade);
+        if let Some(via) = &context.via {
+            http_ext::append_via(req.version(), req.headers_mut(), via);
+        }
+        context.forwarded.apply(flow, req.headers_mut());
+        if context.flow_id_header {
+            http_ext::set_flow_id(req.headers_mut(), flow.ulid);
+        }
+
+        let response = match context.interceptors.on_request(flow, req).await {
+            RequestAction::Forward(mut req) => {
+                telemetry::propagate(&flow.headers, req.headers_mut());
+                // Interceptors point a request at another origin by giving it an absolute URI.
+                let redirected = req.uri().authority().is_some()
+                    && (matches!(upstream, Upstream::Routed)
+                        || (req.uri().scheme(), req.uri().authority())
+                            != (flow.uri.scheme(), flow.uri.authority()));
+                let mut response = if redirected {
+                    let mut upstream = Self::connect_origin(req.uri(), context).await?;
+                    Self::prepare(&mut req, &upstream)?;
+                    upstream.send(req).await?
+                } else {
+                    // h2 needs the full URI, with any path an interceptor changed.
+                    if let (true, Some(scheme), Some(authority)) =
+                        (upstream.is_http2(), flow.uri.scheme(), flow.uri.authority())
+                    {
+                        *req.uri_mut() =
+                            Self::absolute_uri(req.uri(), scheme.clone(), authority.clone());
+                    }
+                    upstream.send(req).await?
+                };
+                let upgraded = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+                // Transfer-Encoding overrides any Content-Length, and its coding is undone now.
+                if response.headers().contains_key(TRANSFER_ENCODING) {
+                    response.headers_mut().remove(CONTENT_LENGTH);
+                }
+                http_ext::strip_hop_by_hop(response.headers_mut(), upgraded);
+                if let Some(via) = &context.via {
+                    http_ext::append_via(response.version(), response.headers_mut(), via);
+                }
+                response
+            }
+            RequestAction::Respond(response) => response,
+            RequestAction::Block => return Ok(None),
+        };
+
+        match context.interceptors.on_response(flow, response).await {
+            ResponseAction::Forward(mut response) => {
+                if context.flow_id_header {
+                    http_ext::set_flow_id(response.headers_mut(), flow.ulid);
+                }
+                Ok(Some(response))
+            }
+            ResponseAction::Block => Ok(None),
+        }
+    }
+
+    async fn connect_upstream_proxy(
+        proxy: &UpstreamProxy,
+        context: &Context,
+    ) -> Result<Upstream<'static>, Error> {
+        let stream = with_timeout(context.timeouts.connect, proxy.connect()).await?;
+
+        let (sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(Error::HttpRequestError)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!(?e);
+            }
+        });
+
+        Ok(Upstream::Proxy {
+            sender,
+            authorization: proxy.authorization().cloned(),
+        })
+    }
+
+    async fn handle_http<S: Transport>(
+        mut req: Request<Vec<u8>>,
+        mut stream: BufStream<S>,
+        peer: Peer,
+        listener: &ListenerConfig,
+        context: &Arc<Context>,
     ) -> Result<(), Error> {
-        loop {
-            let mut buf = [0u8; 1024 * 10];
+        let mut upstream = match &context.live.load().upstream_proxy {
+            _ if context.offline => Upstream::Offline,
+            Some(proxy) => match Self::connect_upstream_proxy(proxy, context).await {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            },
+            None => Upstream::Client(&context.http_client),
+        };
+        let mut served = 0;
 
-            let len = from.read(&mut buf).await.map_err(Error::ReadStreamError)?;
+        loop {
+            // Only an absolute-form target says where to send it. The Host of an origin-form
+            // request names the proxy itself, and following it would loop back here.
+            let host = match req.uri().host() {
+                Some(host) => host,
+                None => {
+                    let e = Error::BadRequestError("Request without host");
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            };
+            context.connections.set_target(peer, host);
+            let port = req.uri().port_u16().unwrap_or(80);
+            if !context.acl.allows(peer.ip(), host, port) {
+                info!(%host, port, "denied");
+                Self::write_error(&mut stream, &Error::AccessDeniedError).await;
+                return Ok(());
+            }
+            let mut client = context.rate_limit.throttle(peer.ip(), &mut stream);
+            let keep_alive =
+                Self::exchange(req, &mut client, &mut upstream, None, peer, context).await?;
+            served += 1;
 
-            if len == 0 {
+            if !keep_alive || context.max_requests.is_some_and(|max| served >= max) {
                 return Ok(());
             }
 
-            to.write_all(&buf[..len])
+            req = match with_timeout(
+                context.timeouts.keep_alive,
+                Self::read_request(&mut stream, context),
+            )
+            .await
+            {
+                Ok(Some(req)) => req,
+                Ok(None) | Err(Error::TimeoutError(_)) => return Ok(()),
+                Err(e) => {
+                    Self::write_error(&mut stream, &e).await;
+                    return Err(e);
+                }
+            };
+
+            info!(
+                method = %req.method(),
+                uri = %context.redactor.uri(req.uri()),
+                version = ?req.version(),
+                headers = ?context.redactor.headers(req.headers()),
+            );
+
+            Self::authenticate(&req, &mut stream, listener, context).await?;
+
+            if req.method() == Method::CONNECT {
+                return Self::handle_connect(req, stream, peer, listener, context).await;
+            }
+        }
+    }
+
+    #[instrument(skip(req, stream, upstream, context), fields(flow = field::Empty))]
+    async fn exchange<S>(
+        req: Request<Vec<u8>>,
+        stream: &mut S,
+        upstream: &mut Upstream<'_>,
+        target: Option<Target>,
+        peer: Peer,
+        context: &Context,
+    ) -> Result<bool, Error>
+    where
+        S: AsyncBufRead + AsyncWrite + Unpin + Send,
+    {
+        let id = context.flows.next_id();
+        let (mut parts, _) = req.into_parts();
+        parts.headers.remove(PROXY_AUTHORIZATION);
+        let client_version = parts.version;
+        let client_keep_alive = http_ext::wants_keep_alive(parts.version, &parts.headers);
+        let is_head = parts.method == Method::HEAD;
+        let client_trailers = http_ext::accepts_trailers(&parts.headers);
+
+        let (uri, tls_version) = match target {
+            Some(target) => (
+                Self::absolute_uri(&parts.uri, target.scheme, target.authority),
+                target.tls_version,
+            ),
+            None => (parts.uri.clone(), None),
+        };
+        let flow = FlowRequest {
+            id,
+            ulid: Ulid::new(),
+            connection: Some(peer.connection),
+            client: peer.addr,
+            method: parts.method.clone(),
+            uri,
+            version: parts.version,
+            headers: parts.headers.clone(),
+        };
+        Span::current().record("flow", field::display(flow.ulid));
+        context.flows.emit(FlowEvent::Request(flow.clone()));
+        let mut summary = FlowSummary::new(&flow, tls_version);
+
+        let max_body_size = context.limits.max_body_size;
+        let framing = match http_ext::body_framing(&parts.headers, max_body_size) {
+            Ok(framing) => framing,
+            Err(e) => {
+                Self::fail(summary, stream, &e, context).await;
+                return Err(e);
+            }
+        };
+        match framing {
+            // The body goes on dechunked, so a length sent alongside would only mislead.
+            BodyFraming::Chunked => parts.headers.remove(CONTENT_LENGTH),
+            // Repeated values were checked to agree; one is enough upstream.
+            BodyFraming::Length(length) if parts.headers.contains_key(CONTENT_LENGTH) => {
+                parts.headers.insert(CONTENT_LENGTH, length.into())
+            }
+            BodyFraming::Length(_) => None,
+        };
+        let expects_continue = http_ext::expects_continue(parts.version, &parts.headers);
+        let (sender, body) = match framing {
+            BodyFraming::Length(0) => (None, Body::empty()),
+            _ => {
+                let (sender, body) = Body::channel();
+                (Some(sender), body)
+            }
+        };
+        // Sent on in the version the proxy speaks, whatever the client does (RFC 7230 section 2.6).
+        if parts.version == Version::HTTP_10 {
+            parts.version = Version::HTTP_11;
+        }
+        let req = Request::from_parts(parts, body);
+
+        // The body is read from the client while the request is already on its way upstream.
+        // None when a client waiting for `100 Continue` is answered before the body is wanted.
+        let streamed = async {
+            let mut sender = match sender {
+                Some(sender) => sender,
+                None => return Ok(Some(0)),
+            };
+            if expects_continue {
+                if !http_ext::body_wanted(&mut sender).await {
+                    return Ok(None);
+                }
+                stream
+                    .write_all(http_ext::CONTINUE)
+                    .await
+                    .map_err(Error::WriteStreamError)?;
+                stream.flush().await.map_err(Error::WriteStreamError)?;
+            }
+
+            http_ext::stream_body(framing, &mut *stream, sender, max_body_size)
+                .await
+                .map(Some)
+        };
+        let (forwarded, streamed) =
+            tokio::join!(Self::forward(&flow, req, upstream, context), streamed);
+        // The client may still send a body nobody asked for, so the connection cannot be reused.
+        let body_read = match streamed {
+            Ok(size) => {
+                summary.request_bytes = size.unwrap_or_default();
+                size.is_some()
+            }
+            Err(e) => {
+                Self::fail(summary, stream, &e, context).await;
+                return Err(e);
+            }
+        };
+
+        let mut response = match forwarded {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                context.flows.emit(FlowEvent::Error {
+                    id,
+                    error: Error::FlowBlockedError.to_string(),
+                });
+                Self::complete(summary, context);
+                return Ok(false);
+            }
+            Err(e) => {
+                Self::fail(summary, stream, &e, context).await;
+                return Err(e);
+            }
+        };
+        summary.status = Some(response.status());
+        let on_upgrade = (response.status() == StatusCode::SWITCHING_PROTOCOLS)
+            .then(|| hyper::upgrade::on(&mut response));
+        let (mut parts, mut body) = response.into_parts();
+        if parts.version == Version::HTTP_2 {
+            parts.version = Version::HTTP_11;
+        }
+        // Older clients are answered in their own version, without chunks or implied keep-alive.
+        if client_version == Version::HTTP_10 {
+            parts.version = Version::HTTP_10;
+        }
+
+        context.flows.emit(FlowEvent::Response(FlowResponse {
+            id,
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+        }));
+
+        if let Some(on_upgrade) = on_upgrade {
+            stream
+                .write_all(&http_ext::encode_response_head(
+                    parts.version,
+                    parts.status,
+                    &parts.headers,
+                ))
                 .await
                 .map_err(Error::WriteStreamError)?;
-            to.flush().await.map_err(Error::WriteStreamError)?;
+            stream.flush().await.map_err(Error::WriteStreamError)?;
+
+            let mut upgraded = on_upgrade.await.map_err(Error::HttpRequestError)?;
+            let result = if http_ext::is_websocket_upgrade(&parts.headers) {
+                websocket::relay(stream, upgraded, &flow, context).await
+            } else {
+                tunnel::relay(stream, &mut upgraded, context.timeouts.idle)
+                    .await
+                    .map(|transferred| {
+                        summary.request_bytes += transferred.upstream;
+                        summary.response_bytes = transferred.downstream;
+                    })
+            };
+            Self::complete(summary, context);
+
+            return result.map(|_| false);
+        }
+
+        let framing = http_ext::response_framing(
+            client_version,
+            is_head,
+            parts.status,
+            &mut parts.headers,
+            body.size_hint().exact(),
+        );
+        let mut keep_alive = client_keep_alive && framing != ResponseFraming::Close && body_read;
+
+        parts.headers.insert(
+            CONNECTION,
+            HeaderValue::from_static(if keep_alive { "keep-alive" } else { "close" }),
+        );
+
+        stream
+            .write_all(&http_ext::encode_response_head(
+                parts.version,
+                parts.status,
+                &parts.headers,
+            ))
+            .await
+            .map_err(Error::WriteStreamError)?;
+        stream.flush().await.map_err(Error::WriteStreamError)?;
+
+        if framing == ResponseFraming::Empty {
+            Self::complete(summary, context);
+            return Ok(keep_alive);
+        }
+
+        // Each chunk is flushed as it comes, so event streams and long polls are not held back.
+        let mut events = sse::parser_for(&parts.headers);
+        while let Some(buf) = body.data().await {
+            let mut buf = buf.map_err(Error::HttpRequestError)?;
+            if buf.is_empty() {
+                continue;
+            }
+
+            // Anything past the announced length would be taken for the next response.
+            let mut overran = false;
+            if let ResponseFraming::Length(length) = framing {
+                let left = length - summary.response_bytes;
+                if buf.len() as u64 > left {
+                    buf.truncate(left as usize);
+                    overran = true;
+                }
+            }
+            summary.response_bytes += buf.len() as u64;
+            if framing == ResponseFraming::Chunked {
+                stream.write_all(&http_ext::encode_chunk(&buf)).await
+            } else {
+                stream.write_all(&buf).await
+            }
+            .map_err(Error::WriteStreamError)?;
+            stream.flush().await.map_err(Error::WriteStreamError)?;
+
+            if let Some(parser) = &mut events {
+                sse::dispatch(parser.push(&buf), &flow, context).await;
+            }
+            if overran {
+                keep_alive = false;
+                break;
+            }
         }
+
+        match framing {
+            ResponseFraming::Chunked => {
+                let trailers = body
+                    .trailers()
+                    .await
+                    .map_err(Error::HttpRequestError)?
+                    .filter(|_| client_trailers);
+                stream
+                    .write_all(&http_ext::encode_last_chunk(trailers.as_ref()))
+                    .await
+                    .map_err(Error::WriteStreamError)?;
+                stream.flush().await.map_err(Error::WriteStreamError)?;
+            }
+            // A body that ended short can only be told apart by the connection closing.
+            ResponseFraming::Length(length) if summary.response_bytes < length => {
+                keep_alive = false;
+            }
+            _ => {}
+        }
+        Self::complete(summary, context);
+
+        Ok(keep_alive)
+    }
+
+    async fn fail<S>(mut summary: FlowSummary, stream: &mut S, e: &Error, context: &Context)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        context.flows.emit(FlowEvent::Error {
+            id: summary.id,
+            error: e.to_string(),
+        });
+        Self::write_error(stream, e).await;
+
+        summary.status = Some(e.status_code());
+        Self::complete(summary, context);
+    }
+
+    // Sends a request again as a new flow, through the same dialer and interceptors.
+    pub(crate) async fn resend_from(
+        mut req: Request<Body>,
+        peer: SocketAddr,
+        context: &Context,
+    ) -> Result<Replayed, Error> {
+        let uri = req.uri().clone();
+        let mut upstream = Self::connect_origin(&uri, context).await?;
+        Self::prepare(&mut req, &upstream)?;
+
+        let id = context.flows.next_id();
+        let flow = FlowRequest {
+            id,
+            ulid: Ulid::new(),
+            connection: None,
+            client: peer,
+            method: req.method().clone(),
+            uri,
+            version: req.version(),
+            headers: req.headers().clone(),
+        };
+        context.flows.emit(FlowEvent::Request(flow.clone()));
+
+        let mut summary = FlowSummary::new(&flow, None);
+        summary.request_bytes = http_ext::content_length(req.headers()).unwrap_or_default();
+
+        let error = match Self::forward(&flow, req, &mut upstream, context).await {
+            Ok(Some(response)) => {
+                context.flows.emit(FlowEvent::Response(FlowResponse {
+                    id,
+                    status: response.status(),
+                    version: response.version(),
+                    headers: response.headers().clone(),
+                }));
+                summary.status = Some(response.status());
+
+                let (parts, body) = response.into_parts();
+                let body = match http_ext::read_body(body, har::MAX_BODY_SIZE).await {
+                    Ok(BoundedBody::Full(body)) => Ok((body, false)),
+                    // What was read comes first; the rest is left unread.
+                    Ok(BoundedBody::TooLarge(mut body)) => match body.data().await {
+                        Some(Ok(mut read)) => {
+                            read.truncate(har::MAX_BODY_SIZE);
+                            Ok((read, true))
+                        }
+                        Some(Err(e)) => Err(e),
+                        None => Ok((Bytes::new(), true)),
+                    },
+                    Err(e) => Err(e),
+                };
+                match body {
+                    Ok((body, truncated)) => {
+                        summary.response_bytes = body.len() as u64;
+                        Self::complete(summary, context);
+                        return Ok(Replayed {
+                            id,
+                            response: RecordedResponse {
+                                status: parts.status,
+                                headers: parts.headers,
+                                body: Some(body),
+                                truncated,
+                            },
+                        });
+                    }
+                    Err(e) => Error::HttpRequestError(e),
+                }
+            }
+            Ok(None) => Error::FlowBlockedError,
+            Err(e) => e,
+        };
+
+        context.flows.emit(FlowEvent::Error {
+            id,
+            error: error.to_string(),
+        });
+        summary.status = Some(error.status_code());
+        Self::complete(summary, context);
+
+        Err(error)
+    }
+
+    // A fresh upstream for the origin of an absolute URI, rather than the one a client is
+    // pinned to.
+    async fn connect_origin<'a>(uri: &Uri, context: &'a Context) -> Result<Upstream<'a>, Error> {
+        if context.offline {
+            return Ok(Upstream::Offline);
+        }
+
+        // https origins go through the pool too, as they are dialed through the upstream
+        // proxy rather than sent to it.
+        match &context.live.load().upstream_proxy {
+            Some(proxy) if uri.scheme() != Some(&Scheme::HTTPS) => {
+                Self::connect_upstream_proxy(proxy, context).await
+            }
+            _ => Ok(Upstream::Client(&context.http_client)),
+        }
+    }
+
+    // Fits a request with an absolute URI to the upstream from `connect_origin`.
+    fn prepare(req: &mut Request<Body>, upstream: &Upstream) -> Result<(), Error> {
+        let uri = req.uri().clone();
+
+        if let Upstream::Connection { http2: false, .. } = upstream {
+            let path = uri.path_and_query().map_or("/", |path| path.as_str());
+            *req.uri_mut() =
+                Uri::try_from(path).map_err(|_| Error::BadRequestError("Invalid request path"))?;
+        }
+        *req.version_mut() = if upstream.is_http2() {
+            Version::HTTP_2
+        } else {
+            Version::HTTP_11
+        };
+        if !upstream.is_http2() && !req.headers().contains_key(HOST) {
+            let authority = HeaderValue::from_str(uri.authority().map_or("", |a| a.as_str()))
+                .map_err(|_| Error::BadRequestError("Invalid host"))?;
+            req.headers_mut().insert(HOST, authority);
+        }
+
+        Ok(())
+    }
+
+    fn complete(summary: FlowSummary, context: &Context) {
+        context
+            .metrics
+            .relayed(Direction::Upstream, summary.request_bytes);
+        context
+            .metrics
+            .relayed(Direction::Downstream, summary.response_bytes);
+        context.flows.emit(FlowEvent::Complete(summary.finish()));
+    }
+
+    async fn dial(host: &str, port: u16, context: &Context) -> Result<RemoteStream, Error> {
+        let start = Instant::now();
+        let remote = with_timeout(context.timeouts.connect, context.live.dial(host, port)).await?;
+        context
+            .metrics
+            .upstream_connected(start.elapsed().as_secs_f64());
+
+        Ok(remote)
+    }
+
+    async fn relay<C, S>(
+        client: &mut C,
+        server: &mut S,
+        peer: Peer,
+        host: &str,
+        context: &Context,
+    ) -> Result<(), Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+        S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    {
+        context.metrics.tunnel_opened();
+        let shape = context.shaper.find(host);
+        // Reads and writes on the client side are charged, covering both directions once.
+        let mut client = context
+            .rate_limit
+            .throttle(peer.ip(), Shape::stream(shape, true, client));
+        let mut server = Shape::stream(shape, false, server);
+        let transferred = tunnel::relay(&mut client, &mut server, context.timeouts.idle).await?;
+        Self::tunnel_closed(host, transferred, context);
+
+        Ok(())
+    }
+
+    // Relays a tunnel the proxy stays out of. Between bare TCP sockets on Linux, with nothing to
+    // pace it, the bytes do not leave the kernel.
+    async fn pass<S: Transport>(
+        mut stream: BufStream<S>,
+        mut remote: RemoteStream,
+        peer: Peer,
+        host: &str,
+        context: &Context,
+    ) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if context.shaper.find(host).is_none()
+            && !context.rate_limit.limits_bandwidth()
+            && stream.get_ref().tcp().is_some()
+            && matches!(remote, RemoteStream::Tcp(_))
+        {
+            stream.flush().await.map_err(Error::WriteStreamError)?;
+            // What was read ahead to sniff the protocol goes first.
+            let sniffed = poll_fn(|cx| {
+                let buffered = std::pin::Pin::new(&mut stream);
+                match tokio::io::AsyncBufRead::poll_fill_buf(buffered, cx) {
+                    Poll::Ready(buf) => Poll::Ready(buf.map(<[u8]>::to_vec)),
+                    Poll::Pending => Poll::Ready(Ok(Vec::new())),
+                }
+            })
+            .await
+            .map_err(Error::ReadStreamError)?;
+
+            if let (Some(client), RemoteStream::Tcp(server)) = (stream.get_ref().tcp(), &mut remote)
+            {
+                context.metrics.tunnel_opened();
+                server
+                    .write_all(&sniffed)
+                    .await
+                    .map_err(Error::RelayError)?;
+                let mut transferred = tunnel::splice(client, server, context.timeouts.idle).await?;
+                transferred.upstream += sniffed.len() as u64;
+                Self::tunnel_closed(host, transferred, context);
+                return Ok(());
+            }
+        }
+
+        Self::relay(&mut stream, &mut remote, peer, host, context).await
+    }
+
+    fn tunnel_closed(host: &str, transferred: tunnel::Transferred, context: &Context) {
+        info!(
+            %host,
+            upstream = transferred.upstream,
+            downstream = transferred.downstream,
+            "tunnel closed"
+        );
+        context
+            .metrics
+            .relayed(Direction::Upstream, transferred.upstream);
+        context
+            .metrics
+            .relayed(Direction::Downstream, transferred.downstream);
     }
 }
@@ -0,0 +1,305 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use http::{Request, Response};
+use hyper::body::HttpBody;
+use hyper::Body;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::limit::{Bucket, Rate};
+use crate::policy::HostPattern;
+
+// Bodies are paced in pieces no larger than this, so a big chunk does not arrive all at once.
+const PACING_CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkProfile {
+    #[serde(rename = "2g")]
+    Edge,
+    #[serde(rename = "3g")]
+    ThirdGeneration,
+    #[serde(rename = "4g")]
+    Lte,
+}
+
+impl NetworkProfile {
+    // Download and upload in bytes per second, and round trip latency.
+    fn parameters(self) -> (f64, f64, Duration) {
+        match self {
+            NetworkProfile::Edge => (30_000.0, 16_000.0, Duration::from_millis(650)),
+            NetworkProfile::ThirdGeneration => (200_000.0, 96_000.0, Duration::from_millis(300)),
+            NetworkProfile::Lte => (1_500_000.0, 500_000.0, Duration::from_millis(70)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ShapingConfig {
+    // Hosts this applies to; empty applies to every host.
+    pub hosts: Vec<String>,
+    // Defaults for the fields below, which override it.
+    pub profile: Option<NetworkProfile>,
+    // Bytes per second towards the client.
+    pub download: Option<f64>,
+    // Bytes per second towards the server.
+    pub upload: Option<f64>,
+    #[serde(with = "humantime_serde")]
+    pub latency: Option<Duration>,
+    // Up to this much is added to the latency at random.
+    #[serde(with = "humantime_serde")]
+    pub jitter: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Shape {
+    hosts: Vec<HostPattern>,
+    download: Option<f64>,
+    upload: Option<f64>,
+    latency: Duration,
+    jitter: Duration,
+}
+
+impl Shape {
+    fn new(config: &ShapingConfig) -> Result<Self, Error> {
+        let (download, upload, latency) = match config.profile {
+            Some(profile) => {
+                let (download, upload, latency) = profile.parameters();
+                (Some(download), Some(upload), Some(latency))
+            }
+            None => (None, None, None),
+        };
+
+        Ok(Self {
+            hosts: config
+                .hosts
+                .iter()
+                .map(|host| HostPattern::new(host))
+                .collect::<Result<_, _>>()?,
+            download: config.download.or(download),
+            upload: config.upload.or(upload),
+            latency: config.latency.or(latency).unwrap_or_default(),
+            jitter: config.jitter.unwrap_or_default(),
+        })
+    }
+
+    fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        let random = RandomState::new().build_hasher().finish();
+
+        self.latency + self.jitter.mul_f64(random as f64 / u64::MAX as f64)
+    }
+
+    fn bucket(rate: Option<f64>) -> Option<Bucket> {
+        // A tenth of a second's worth of burst keeps the pace smooth.
+        rate.map(|rate| {
+            Bucket::new(&Rate {
+                rate,
+                burst: Some(rate / 10.0),
+            })
+        })
+    }
+
+    // The stream read from the client carries uploads, the one read from the server downloads.
+    pub(crate) fn stream<S>(shape: Option<&Self>, upload: bool, stream: S) -> Shaped<S> {
+        Shaped {
+            inner: stream,
+            bucket: shape
+                .and_then(|shape| Self::bucket(if upload { shape.upload } else { shape.download })),
+            shape: shape.filter(|shape| !shape.latency.is_zero()).cloned(),
+            last: None,
+            held: None,
+            delay: None,
+        }
+    }
+
+    fn body(rate: Option<f64>, mut body: Body) -> Body {
+        let mut bucket = match Self::bucket(rate) {
+            Some(bucket) => bucket,
+            None => return body,
+        };
+        let (mut sender, paced) = Body::channel();
+
+        tokio::spawn(async move {
+            while let Some(chunk) = body.data().await {
+                let mut chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        sender.abort();
+                        return;
+                    }
+                };
+                while !chunk.is_empty() {
+                    let piece = chunk.split_to(chunk.len().min(PACING_CHUNK));
+                    let wait = bucket.charge(Instant::now(), piece.len() as f64);
+                    tokio::time::sleep(wait).await;
+                    if sender.send_data(piece).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            if let Ok(Some(trailers)) = body.trailers().await {
+                let _ = sender.send_trailers(trailers).await;
+            }
+        });
+
+        paced
+    }
+}
+
+// Simulated network conditions: throughput caps plus latency and jitter, per host.
+#[derive(Clone, Default)]
+pub struct Shaper(Arc<Vec<Shape>>);
+
+impl Shaper {
+    pub fn new(configs: &[ShapingConfig]) -> Result<Self, Error> {
+        let shapes = configs.iter().map(Shape::new).collect::<Result<_, _>>()?;
+
+        Ok(Self(Arc::new(shapes)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // The first shape whose hosts match.
+    pub(crate) fn find(&self, host: &str) -> Option<&Shape> {
+        self.0.iter().find(|shape| {
+            shape.hosts.is_empty() || shape.hosts.iter().any(|pattern| pattern.matches(host))
+        })
+    }
+
+    fn for_flow(&self, flow: &FlowRequest) -> Option<&Shape> {
+        flow.uri.host().and_then(|host| self.find(host))
+    }
+}
+
+#[async_trait]
+impl Interceptor for Shaper {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        let shape = match self.for_flow(flow) {
+            Some(shape) => shape,
+            None => return RequestAction::Forward(req),
+        };
+
+        RequestAction::Forward(req.map(|body| Shape::body(shape.upload, body)))
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        let shape = match self.for_flow(flow) {
+            Some(shape) => shape,
+            None => return ResponseAction::Forward(res),
+        };
+        tokio::time::sleep(shape.delay()).await;
+
+        ResponseAction::Forward(res.map(|body| Shape::body(shape.download, body)))
+    }
+}
+
+// One direction of a tunnel under a shape. Data that starts a new burst, after the stream has
+// been quiet for longer than the latency, is held back by the latency first; data within a
+// burst only waits on the throughput cap, so bulk transfers are not delayed chunk by chunk.
+pub(crate) struct Shaped<S> {
+    inner: S,
+    bucket: Option<Bucket>,
+    shape: Option<Shape>,
+    last: Option<Instant>,
+    held: Option<(Vec<u8>, usize)>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Shaped<S> {
+    fn passed(&mut self, read: usize) {
+        let now = Instant::now();
+        self.last = Some(now);
+        if let Some(bucket) = &mut self.bucket {
+            let wait = bucket.charge(now, read as f64);
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            if let Some((data, offset)) = self.held.as_mut() {
+                let read = buf.remaining().min(data.len() - *offset);
+                buf.put_slice(&data[*offset..*offset + read]);
+                *offset += read;
+                if *offset == data.len() {
+                    self.held = None;
+                }
+                self.passed(read);
+                return Poll::Ready(Ok(()));
+            }
+
+            let latency = self.shape.as_ref().map(|shape| shape.latency);
+            let starts_burst = latency
+                .is_some_and(|latency| self.last.is_none_or(|last| last.elapsed() > latency));
+            if !starts_burst {
+                let filled = buf.filled().len();
+                ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+                let read = buf.filled().len() - filled;
+                self.passed(read);
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut data = vec![0; buf.remaining()];
+            let mut held = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut held))?;
+            let read = held.filled().len();
+            if read == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            data.truncate(read);
+
+            let delay = self.shape.as_ref().map(Shape::delay).unwrap_or_default();
+            self.held = Some((data, 0));
+            self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
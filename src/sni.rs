@@ -0,0 +1,141 @@
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: u16 = 0x0000;
+const ALPN: u16 = 0x0010;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+const ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+const HOST_NAME: u8 = 0x00;
+const TLS13: u16 = 0x0304;
+
+// What a client offered in its ClientHello, for the upstream leg to offer the same.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientOffer {
+    // ALPN protocols, in the order of preference of the client.
+    pub(crate) protocols: Vec<Vec<u8>>,
+    pub(crate) tls13: bool,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.take(len).map(Reader)
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.take(len).map(Reader)
+    }
+}
+
+pub(crate) fn server_name(preface: &[u8]) -> Option<String> {
+    let mut names = extension(preface, SERVER_NAME)?.vec16()?;
+    while let Some(name_type) = names.u8() {
+        let name = names.vec16()?;
+        if name_type == HOST_NAME {
+            return std::str::from_utf8(name.0)
+                .ok()
+                .map(|name| name.to_ascii_lowercase());
+        }
+    }
+
+    None
+}
+
+pub(crate) fn offers_protocol(preface: &[u8], protocol: &[u8]) -> bool {
+    protocols(preface).iter().any(|offered| offered == protocol)
+}
+
+// With ECH the name in the clear is only a front for the real one, which a leaf could not be
+// made for.
+pub(crate) fn offers_ech(preface: &[u8]) -> bool {
+    extension(preface, ENCRYPTED_CLIENT_HELLO).is_some()
+}
+
+pub(crate) fn client_offer(preface: &[u8]) -> Option<ClientOffer> {
+    extensions(preface)?;
+    // TLS 1.3 is only announced in its own extension; the legacy version field says 1.2.
+    let tls13 = extension(preface, SUPPORTED_VERSIONS)
+        .and_then(|mut data| {
+            let mut versions = data.vec8()?;
+            let mut tls13 = false;
+            while let Some(version) = versions.u16() {
+                tls13 |= version == TLS13;
+            }
+            Some(tls13)
+        })
+        .unwrap_or(false);
+
+    Some(ClientOffer {
+        protocols: protocols(preface),
+        tls13,
+    })
+}
+
+fn protocols(preface: &[u8]) -> Vec<Vec<u8>> {
+    let mut offered = Vec::new();
+    if let Some(mut protocols) = extension(preface, ALPN).and_then(|mut data| data.vec16()) {
+        while let Some(protocol) = protocols.vec8() {
+            offered.push(protocol.0.to_vec());
+        }
+    }
+
+    offered
+}
+
+fn extension(preface: &[u8], wanted: u16) -> Option<Reader<'_>> {
+    let mut extensions = extensions(preface)?;
+    while let Some(kind) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if kind == wanted {
+            return Some(data);
+        }
+    }
+
+    None
+}
+
+fn extensions(preface: &[u8]) -> Option<Reader<'_>> {
+    let mut record = Reader(preface);
+    if record.u8()? != HANDSHAKE {
+        return None;
+    }
+    record.take(2)?;
+    let len = record.u16()? as usize;
+    // The ClientHello may span several records; only the first one is inspected.
+    let mut handshake = Reader(record.0.get(..len).unwrap_or(record.0));
+
+    if handshake.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    handshake.u24()?;
+    handshake.take(2 + 32)?;
+    handshake.vec8()?;
+    handshake.vec16()?;
+    handshake.vec8()?;
+
+    handshake.vec16()
+}
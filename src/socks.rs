@@ -0,0 +1,143 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::auth::Credentials;
+use crate::error::Error;
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+// The version of the username/password sub-negotiation, from RFC 1929.
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const AUTH_FAILED: u8 = 0x01;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const CONNECT: u8 = 0x01;
+
+pub(crate) const SUCCEEDED: u8 = 0x00;
+pub(crate) const GENERAL_FAILURE: u8 = 0x01;
+pub(crate) const NOT_ALLOWED: u8 = 0x02;
+pub(crate) const HOST_UNREACHABLE: u8 = 0x04;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+pub(crate) async fn accept<S>(
+    stream: &mut S,
+    credentials: Option<&dyn Credentials>,
+) -> Result<(String, u16), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    read(stream, &mut head).await?;
+    if head[0] != VERSION {
+        return Err(Error::Socks5Error("Unsupported version"));
+    }
+
+    let mut methods = vec![0u8; head[1] as usize];
+    read(stream, &mut methods).await?;
+
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTHENTICATION
+    };
+    if !methods.contains(&method) {
+        write(stream, &[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(Error::Socks5Error("No acceptable authentication method"));
+    }
+    write(stream, &[VERSION, method]).await?;
+
+    if let Some(credentials) = credentials {
+        let mut version = [0u8; 1];
+        read(stream, &mut version).await?;
+        if version[0] != AUTH_VERSION {
+            write(stream, &[AUTH_VERSION, AUTH_FAILED]).await?;
+            return Err(Error::Socks5Error("Unsupported authentication version"));
+        }
+        let username = read_string(stream).await?;
+        let password = read_string(stream).await?;
+
+        let verified = credentials.verify(&username, &password).await;
+        let status = if verified {
+            AUTH_SUCCEEDED
+        } else {
+            AUTH_FAILED
+        };
+        write(stream, &[AUTH_VERSION, status]).await?;
+        if !verified {
+            return Err(Error::ProxyAuthRequiredError);
+        }
+    }
+
+    let mut request = [0u8; 4];
+    read(stream, &mut request).await?;
+    if request[1] != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::Socks5Error("Unsupported command"));
+    }
+
+    let host = match request[3] {
+        0x01 => {
+            let mut addr = [0u8; 4];
+            read(stream, &mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        0x03 => read_string(stream).await?,
+        0x04 => {
+            let mut addr = [0u8; 16];
+            read(stream, &mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        _ => {
+            reply(stream, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(Error::Socks5Error("Unsupported address type"));
+        }
+    };
+    let port = stream.read_u16().await.map_err(Error::ReadStreamError)?;
+
+    Ok((host, port))
+}
+
+pub(crate) async fn reply<S>(stream: &mut S, code: u8) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    write(stream, &[VERSION, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await
+}
+
+async fn read_string<S>(stream: &mut S) -> Result<String, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 1];
+    read(stream, &mut len).await?;
+
+    let mut buf = vec![0u8; len[0] as usize];
+    read(stream, &mut buf).await?;
+
+    String::from_utf8(buf).map_err(|_| Error::Socks5Error("Invalid string"))
+}
+
+async fn read<S>(stream: &mut S, buf: &mut [u8]) -> Result<(), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(Error::ReadStreamError)
+}
+
+async fn write<S>(stream: &mut S, buf: &[u8]) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(buf)
+        .await
+        .map_err(Error::WriteStreamError)?;
+    stream.flush().await.map_err(Error::WriteStreamError)
+}
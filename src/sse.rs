@@ -0,0 +1,148 @@
+use std::mem;
+use std::sync::Arc;
+
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use hyper::body::HttpBody;
+use hyper::Body;
+use tracing::Instrument;
+
+use crate::encoding;
+use crate::flow::{FlowEvent, FlowRequest};
+use crate::server::Context;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerSentEvent {
+    // The `event` field, for events other than the default `message`.
+    pub event: Option<String>,
+    // `data` lines, joined by newlines.
+    pub data: String,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+}
+
+// Event streams never end on their own, so their bodies must not be read in full.
+pub(crate) fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+// Event streams that arrive compressed are passed on without being parsed.
+pub(crate) fn parser_for(headers: &HeaderMap) -> Option<EventParser> {
+    (is_event_stream(headers) && !encoding::is_encoded(headers)).then(EventParser::default)
+}
+
+// Splits an event stream into events as its bytes come in, however they are chunked.
+#[derive(Default)]
+pub(crate) struct EventParser {
+    line: Vec<u8>,
+    // A CR ended the last line, so an LF right after it ends nothing more.
+    after_cr: bool,
+    event: ServerSentEvent,
+    data: Option<String>,
+}
+
+impl EventParser {
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<ServerSentEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            let after_cr = mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    let line = mem::take(&mut self.line);
+                    events.extend(self.line_ended(&line));
+                }
+                _ => self.line.push(byte),
+            }
+        }
+
+        events
+    }
+
+    fn line_ended(&mut self, line: &[u8]) -> Option<ServerSentEvent> {
+        if line.is_empty() {
+            // Events without data are not dispatched, but still end.
+            let event = mem::take(&mut self.event);
+            return self
+                .data
+                .take()
+                .map(|data| ServerSentEvent { data, ..event });
+        }
+        if line.starts_with(b":") {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event.event = Some(value.to_string()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            "id" if !value.contains('\0') => self.event.id = Some(value.to_string()),
+            "retry" => self.event.retry = value.parse().ok(),
+            _ => {}
+        }
+
+        None
+    }
+}
+
+pub(crate) async fn dispatch(events: Vec<ServerSentEvent>, flow: &FlowRequest, context: &Context) {
+    for event in events {
+        context
+            .interceptors
+            .on_server_sent_event(flow, &event)
+            .await;
+        context
+            .flows
+            .emit(FlowEvent::ServerSentEvent { id: flow.id, event });
+    }
+}
+
+// Parses a body handed to hyper as it streams through, each chunk going on before its events
+// are dispatched.
+pub(crate) fn observe(
+    mut body: Body,
+    mut parser: EventParser,
+    flow: FlowRequest,
+    context: Arc<Context>,
+) -> Body {
+    let (mut sender, observed) = Body::channel();
+
+    tokio::spawn(
+        async move {
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        sender.abort();
+                        return;
+                    }
+                };
+                let events = parser.push(&chunk);
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+                dispatch(events, &flow, &context).await;
+            }
+            if let Ok(Some(trailers)) = body.trailers().await {
+                let _ = sender.send_trailers(trailers).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    observed
+}
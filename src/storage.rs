@@ -0,0 +1,659 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::Body;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, ToSql};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, warn};
+use ulid::Ulid;
+
+use crate::capture;
+use crate::error::Error;
+use crate::flow::{FlowEvent, FlowRequest};
+use crate::http as http_ext;
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::redact::Redactor;
+use crate::replay::{RecordedFlow, RecordedResponse};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS flows (
+    session INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    ulid TEXT,
+    connection TEXT,
+    started INTEGER,
+    client TEXT,
+    method TEXT,
+    url TEXT,
+    host TEXT,
+    status INTEGER,
+    error TEXT,
+    tls_version TEXT,
+    request_headers TEXT,
+    response_headers TEXT,
+    request_body BLOB,
+    response_body BLOB,
+    request_truncated INTEGER,
+    response_truncated INTEGER,
+    request_bytes INTEGER,
+    response_bytes INTEGER,
+    duration REAL,
+    PRIMARY KEY (session, id)
+);
+CREATE INDEX IF NOT EXISTS flows_started ON flows (started);
+CREATE INDEX IF NOT EXISTS flows_host ON flows (host);
+CREATE INDEX IF NOT EXISTS flows_status ON flows (status);
+";
+
+// Columns added after the table first shipped, for databases created before.
+const ADDED_COLUMNS: [&str; 4] = [
+    "ulid TEXT",
+    "connection TEXT",
+    "request_truncated INTEGER",
+    "response_truncated INTEGER",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub bodies: bool,
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    #[serde(default)]
+    pub max_flows: Option<u64>,
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FlowQuery {
+    // Glob on the host name, `*` matches anything.
+    pub host: Option<String>,
+    pub status: Option<u16>,
+    pub method: Option<String>,
+    pub since: Option<SystemTime>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFlow {
+    pub session: u64,
+    pub id: u64,
+    pub ulid: Option<String>,
+    pub started: SystemTime,
+    pub client: Option<String>,
+    pub method: Option<String>,
+    pub url: Option<String>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub request_bytes: Option<u64>,
+    pub response_bytes: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+// One column group of a flow, written as soon as it is known.
+enum Write {
+    Request {
+        id: u64,
+        ulid: String,
+        connection: Option<String>,
+        started: i64,
+        client: String,
+        method: String,
+        url: String,
+        host: Option<String>,
+        headers: String,
+    },
+    Response {
+        id: u64,
+        status: u16,
+        headers: String,
+    },
+    Error {
+        id: u64,
+        error: String,
+    },
+    Complete {
+        id: u64,
+        status: Option<u16>,
+        tls_version: Option<String>,
+        request_bytes: u64,
+        response_bytes: u64,
+        duration: f64,
+    },
+    // Truncated when the body went past `max_body_size` and only its start is kept.
+    RequestBody {
+        id: u64,
+        body: Vec<u8>,
+        truncated: bool,
+    },
+    ResponseBody {
+        id: u64,
+        body: Vec<u8>,
+        truncated: bool,
+    },
+}
+
+// Persists flows to SQLite. Also an interceptor, to keep bodies when configured to.
+#[derive(Clone)]
+pub struct FlowStore {
+    config: StorageConfig,
+    writes: mpsc::Sender<Write>,
+    redactor: Redactor,
+}
+
+impl FlowStore {
+    pub fn open(config: StorageConfig) -> Result<Self, Error> {
+        let conn = Self::create(&config.path)?;
+
+        let (writes, receiver) = mpsc::channel();
+        let writer = Writer {
+            conn,
+            session: Self::millis(SystemTime::now()),
+            max_flows: config.max_flows,
+            max_age: config.max_age,
+        };
+        std::thread::spawn(move || writer.run(receiver));
+
+        Ok(Self {
+            config,
+            writes,
+            redactor: Redactor::default(),
+        })
+    }
+
+    // Masks the bodies stored from here on. Events arrive already redacted.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    // Stores flows recorded elsewhere, bodies included, as a session of their own. Returns the
+    // session.
+    pub fn import(path: &Path, flows: &[RecordedFlow], redactor: &Redactor) -> Result<u64, Error> {
+        let mut conn = Self::create(path)?;
+        let now = SystemTime::now();
+        let session = Self::millis(now);
+
+        let tx = conn.transaction().map_err(Error::StorageError)?;
+        for (id, flow) in (1..).zip(flows) {
+            let started = flow.started.unwrap_or(now);
+            let uri = redactor.uri(&flow.uri);
+            let body = redactor.body(&flow.headers, flow.body.clone());
+            let response = flow.response.as_ref();
+            let response_body = response.and_then(|res| {
+                res.body
+                    .clone()
+                    .map(|body| redactor.body(&res.headers, body).to_vec())
+            });
+            tx.execute(
+                "INSERT INTO flows (session, id, ulid, started, method, url, host, status,
+                                    request_headers, response_headers, request_body, response_body,
+                                    request_truncated, response_truncated,
+                                    request_bytes, response_bytes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![
+                    session,
+                    id,
+                    Ulid::from_datetime(started).to_string(),
+                    Self::millis(started),
+                    flow.method.as_str(),
+                    uri.to_string(),
+                    uri.host().map(|host| host.to_ascii_lowercase()),
+                    response.map(|res| res.status.as_u16()),
+                    Self::headers(&redactor.headers(&flow.headers)),
+                    response.map(|res| Self::headers(&redactor.headers(&res.headers))),
+                    body.to_vec(),
+                    response_body,
+                    flow.truncated,
+                    response.map(|res| res.truncated),
+                    flow.body.len() as u64,
+                    response.and_then(|res| res.body.as_ref().map(|body| body.len() as u64)),
+                ],
+            )
+            .map_err(Error::StorageError)?;
+        }
+        tx.commit().map_err(Error::StorageError)?;
+
+        Ok(session as u64)
+    }
+
+    pub fn query(path: &Path, query: &FlowQuery) -> Result<Vec<StoredFlow>, Error> {
+        let conn = Self::connect(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        // Databases no run of this version has written to yet lack the newer columns.
+        let ulid = if Self::has_column(&conn, "ulid")? {
+            "ulid"
+        } else {
+            "NULL"
+        };
+        let mut sql = format!(
+            "SELECT session, id, started, client, method, url, status, error,
+                    request_bytes, response_bytes, duration, {}
+             FROM flows WHERE 1 = 1",
+            ulid
+        );
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(host) = &query.host {
+            sql.push_str(" AND host LIKE ? ESCAPE '\\'");
+            let escaped = host
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            values.push(Box::new(escaped.replace('*', "%")));
+        }
+        if let Some(status) = query.status {
+            sql.push_str(" AND status = ?");
+            values.push(Box::new(status));
+        }
+        if let Some(method) = &query.method {
+            sql.push_str(" AND method = ?");
+            values.push(Box::new(method.to_ascii_uppercase()));
+        }
+        if let Some(since) = query.since {
+            sql.push_str(" AND started >= ?");
+            values.push(Box::new(Self::millis(since)));
+        }
+        sql.push_str(" ORDER BY started DESC, id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(" LIMIT ?");
+            values.push(Box::new(limit));
+        }
+
+        let mut statement = conn.prepare(&sql).map_err(Error::StorageError)?;
+        let rows = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(StoredFlow {
+                    session: row.get(0)?,
+                    id: row.get(1)?,
+                    started: UNIX_EPOCH
+                        + Duration::from_millis(row.get::<_, Option<u64>>(2)?.unwrap_or_default()),
+                    client: row.get(3)?,
+                    method: row.get(4)?,
+                    url: row.get(5)?,
+                    status: row.get(6)?,
+                    error: row.get(7)?,
+                    request_bytes: row.get(8)?,
+                    response_bytes: row.get(9)?,
+                    duration: row
+                        .get::<_, Option<f64>>(10)?
+                        .map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+                    ulid: row.get(11)?,
+                })
+            })
+            .map_err(Error::StorageError)?;
+
+        rows.collect::<Result<_, _>>().map_err(Error::StorageError)
+    }
+
+    // Bodies are only there when the store was configured to keep them.
+    pub fn load(path: &Path, session: u64, id: u64) -> Result<Option<RecordedFlow>, Error> {
+        let conn = Self::connect(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        // Databases no run of this version has written to yet lack the newer columns.
+        let truncated = if Self::has_column(&conn, "request_truncated")? {
+            "request_truncated, response_truncated"
+        } else {
+            "0, 0"
+        };
+        let row = conn
+            .query_row(
+                &format!(
+                    "SELECT method, url, request_headers, request_body,
+                            status, response_headers, response_body, started, {}
+                     FROM flows WHERE session = ?1 AND id = ?2",
+                    truncated
+                ),
+                params![session, id],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<Vec<u8>>>(3)?,
+                        row.get::<_, Option<u16>>(4)?,
+                        row.get::<_, Option<String>>(5)?,
+                        row.get::<_, Option<Vec<u8>>>(6)?,
+                        row.get::<_, Option<u64>>(7)?,
+                        row.get::<_, Option<bool>>(8)?.unwrap_or_default(),
+                        row.get::<_, Option<bool>>(9)?.unwrap_or_default(),
+                    ))
+                },
+            )
+            .optional()
+            .map_err(Error::StorageError)?;
+        let (
+            method,
+            url,
+            request_headers,
+            request_body,
+            status,
+            response_headers,
+            response_body,
+            started,
+            request_truncated,
+            response_truncated,
+        ) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let method = Method::from_bytes(method.unwrap_or_default().as_bytes())
+            .map_err(|_| Error::BadRequestError("Stored flow has no method"))?;
+        let uri = Uri::try_from(url.unwrap_or_default())
+            .map_err(|_| Error::BadRequestError("Stored flow has no url"))?;
+        let response = match (
+            status.and_then(|status| StatusCode::from_u16(status).ok()),
+            response_headers,
+        ) {
+            (Some(status), Some(headers)) => Some(RecordedResponse {
+                status,
+                headers: Self::parse_headers(&headers),
+                body: response_body.map(Bytes::from),
+                truncated: response_truncated,
+            }),
+            _ => None,
+        };
+
+        Ok(Some(RecordedFlow {
+            method,
+            uri,
+            headers: Self::parse_headers(&request_headers.unwrap_or_default()),
+            body: request_body.map(Bytes::from).unwrap_or_default(),
+            truncated: request_truncated,
+            started: started.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            response,
+        }))
+    }
+
+    pub(crate) fn keeps_bodies(&self) -> bool {
+        self.config.bodies
+    }
+
+    pub(crate) fn spawn(&self, events: broadcast::Receiver<FlowEvent>) {
+        tokio::spawn(self.clone().record(events));
+    }
+
+    async fn record(self, mut events: broadcast::Receiver<FlowEvent>) {
+        loop {
+            let write = match events.recv().await {
+                Ok(FlowEvent::Request(req)) => Write::Request {
+                    id: req.id,
+                    ulid: req.ulid.to_string(),
+                    connection: req.connection.map(|id| id.to_string()),
+                    started: Self::millis(SystemTime::now()),
+                    client: req.client.to_string(),
+                    method: req.method.to_string(),
+                    url: req.uri.to_string(),
+                    host: req.uri.host().map(|host| host.to_ascii_lowercase()),
+                    headers: Self::headers(&req.headers),
+                },
+                Ok(FlowEvent::Response(res)) => Write::Response {
+                    id: res.id,
+                    status: res.status.as_u16(),
+                    headers: Self::headers(&res.headers),
+                },
+                Ok(FlowEvent::Error { id, error }) => Write::Error { id, error },
+                Ok(FlowEvent::Complete(summary)) => Write::Complete {
+                    id: summary.id,
+                    status: summary.status.map(|status| status.as_u16()),
+                    tls_version: summary.tls_version.map(|version| format!("{:?}", version)),
+                    request_bytes: summary.request_bytes,
+                    response_bytes: summary.response_bytes,
+                    duration: summary.duration.as_secs_f64() * 1000.0,
+                },
+                Ok(
+                    FlowEvent::WebSocketMessage { .. }
+                    | FlowEvent::ServerSentEvent { .. }
+                    | FlowEvent::GrpcMessage { .. },
+                ) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Flow storage fell behind");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if self.writes.send(write).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Opens the database for writing, creating the table or adding the columns it lacks.
+    fn create(path: &Path) -> Result<Connection, Error> {
+        let conn = Self::connect(path, OpenFlags::default())?;
+        conn.execute_batch(SCHEMA).map_err(Error::StorageError)?;
+        for column in ADDED_COLUMNS {
+            let name = column.split(' ').next().unwrap_or_default();
+            if !Self::has_column(&conn, name)? {
+                conn.execute_batch(&format!("ALTER TABLE flows ADD COLUMN {}", column))
+                    .map_err(Error::StorageError)?;
+            }
+        }
+
+        Ok(conn)
+    }
+
+    fn connect(path: &Path, flags: OpenFlags) -> Result<Connection, Error> {
+        Connection::open_with_flags(path, flags).map_err(Error::StorageError)
+    }
+
+    fn has_column(conn: &Connection, name: &str) -> Result<bool, Error> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('flows') WHERE name = ?1",
+            params![name],
+            |row| row.get::<_, u64>(0),
+        )
+        .map(|count| count > 0)
+        .map_err(Error::StorageError)
+    }
+
+    fn headers(headers: &HeaderMap) -> String {
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+            .collect();
+
+        json!(headers).to_string()
+    }
+
+    fn parse_headers(headers: &str) -> HeaderMap {
+        let pairs: Vec<(String, String)> = serde_json::from_str(headers).unwrap_or_default();
+
+        pairs
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(&value).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    fn millis(time: SystemTime) -> i64 {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64
+    }
+}
+
+#[async_trait]
+impl Interceptor for FlowStore {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        if !self.config.bodies || http_ext::is_stream(req.headers()) {
+            return RequestAction::Forward(req);
+        }
+
+        let (parts, body) = req.into_parts();
+        let writes = self.writes.clone();
+        let redactor = self.redactor.clone();
+        let headers = parts.headers.clone();
+        let id = flow.id;
+        let limit = self.config.max_body_size.unwrap_or(usize::MAX);
+        let body = capture::tee(body, limit, move |body, size| {
+            let _ = writes.send(Write::RequestBody {
+                id,
+                truncated: size > body.len() as u64,
+                body: redactor.body(&headers, body).to_vec(),
+            });
+        });
+
+        RequestAction::Forward(Request::from_parts(parts, body))
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        if !self.config.bodies {
+            return ResponseAction::Forward(res);
+        }
+
+        let (parts, body) = res.into_parts();
+        let writes = self.writes.clone();
+        let redactor = self.redactor.clone();
+        let headers = parts.headers.clone();
+        let id = flow.id;
+        let limit = self.config.max_body_size.unwrap_or(usize::MAX);
+        let body = capture::tee(body, limit, move |body, size| {
+            let _ = writes.send(Write::ResponseBody {
+                id,
+                truncated: size > body.len() as u64,
+                body: redactor.body(&headers, body).to_vec(),
+            });
+        });
+
+        ResponseAction::Forward(Response::from_parts(parts, body))
+    }
+}
+
+struct Writer {
+    conn: Connection,
+    // Flow ids restart with every run, so rows are keyed by the run as well.
+    session: i64,
+    max_flows: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl Writer {
+    fn run(self, writes: mpsc::Receiver<Write>) {
+        for write in writes {
+            let complete = matches!(write, Write::Complete { .. });
+
+            if let Err(e) = self.write(write) {
+                error!(?e, "Fail to store flow");
+            }
+            if complete {
+                if let Err(e) = self.expire() {
+                    error!(?e, "Fail to expire stored flows");
+                }
+            }
+        }
+    }
+
+    fn write(&self, write: Write) -> rusqlite::Result<usize> {
+        let session = self.session;
+
+        match write {
+            Write::Request {
+                id,
+                ulid,
+                connection,
+                started,
+                client,
+                method,
+                url,
+                host,
+                headers,
+            } => self.conn.execute(
+                "INSERT INTO flows (session, id, ulid, connection, started, client, method, url, host, request_headers)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                 ON CONFLICT (session, id) DO UPDATE SET
+                    ulid = excluded.ulid, connection = excluded.connection,
+                    started = excluded.started, client = excluded.client,
+                    method = excluded.method, url = excluded.url, host = excluded.host,
+                    request_headers = excluded.request_headers",
+                params![session, id, ulid, connection, started, client, method, url, host, headers],
+            ),
+            Write::Response {
+                id,
+                status,
+                headers,
+            } => self.conn.execute(
+                "INSERT INTO flows (session, id, status, response_headers) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (session, id) DO UPDATE SET
+                    status = excluded.status, response_headers = excluded.response_headers",
+                params![session, id, status, headers],
+            ),
+            Write::Error { id, error } => self.conn.execute(
+                "INSERT INTO flows (session, id, error) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (session, id) DO UPDATE SET error = excluded.error",
+                params![session, id, error],
+            ),
+            Write::Complete {
+                id,
+                status,
+                tls_version,
+                request_bytes,
+                response_bytes,
+                duration,
+            } => self.conn.execute(
+                "INSERT INTO flows (session, id, status, tls_version, request_bytes, response_bytes, duration)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (session, id) DO UPDATE SET
+                    status = excluded.status, tls_version = excluded.tls_version,
+                    request_bytes = excluded.request_bytes,
+                    response_bytes = excluded.response_bytes, duration = excluded.duration",
+                params![session, id, status, tls_version, request_bytes, response_bytes, duration],
+            ),
+            Write::RequestBody {
+                id,
+                body,
+                truncated,
+            } => self.conn.execute(
+                "INSERT INTO flows (session, id, request_body, request_truncated)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (session, id) DO UPDATE SET
+                    request_body = excluded.request_body,
+                    request_truncated = excluded.request_truncated",
+                params![session, id, body, truncated],
+            ),
+            Write::ResponseBody {
+                id,
+                body,
+                truncated,
+            } => self.conn.execute(
+                "INSERT INTO flows (session, id, response_body, response_truncated)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (session, id) DO UPDATE SET
+                    response_body = excluded.response_body,
+                    response_truncated = excluded.response_truncated",
+                params![session, id, body, truncated],
+            ),
+        }
+    }
+
+    fn expire(&self) -> rusqlite::Result<()> {
+        if let Some(max_age) = self.max_age {
+            let cutoff = FlowStore::millis(SystemTime::now()) - max_age.as_millis() as i64;
+            self.conn
+                .execute("DELETE FROM flows WHERE started < ?1", params![cutoff])?;
+        }
+        if let Some(max_flows) = self.max_flows {
+            self.conn.execute(
+                "DELETE FROM flows WHERE rowid IN
+                    (SELECT rowid FROM flows ORDER BY started DESC, id DESC LIMIT -1 OFFSET ?1)",
+                params![max_flows],
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use socket2::{SockRef, Socket, TcpKeepalive};
+use tokio::net::TcpListener;
+
+const DEFAULT_BACKLOG: u32 = 1024;
+
+// Socket options for both legs: set on the connections clients make to the proxy and on the
+// ones it dials.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct TcpTuning {
+    // Sends small writes right away instead of holding them back for Nagle's algorithm.
+    pub nodelay: bool,
+    // Idle time before keepalive probes start. Keepalive is off unless this or
+    // `keepalive_interval` is set; the system defaults fill in the other.
+    #[serde(with = "humantime_serde")]
+    pub keepalive: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub keepalive_interval: Option<Duration>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    // Connections the system queues on each listener before they are accepted; 1024 when unset.
+    pub backlog: Option<u32>,
+}
+
+impl Default for TcpTuning {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            keepalive_interval: None,
+            send_buffer: None,
+            recv_buffer: None,
+            backlog: None,
+        }
+    }
+}
+
+impl TcpTuning {
+    pub(crate) fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if self.keepalive.is_some() || self.keepalive_interval.is_some() {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(time) = self.keepalive {
+                keepalive = keepalive.with_time(time);
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        self.apply_buffers(socket)
+    }
+
+    // Buffers are best set before the handshake, which settles the window scale.
+    fn apply_buffers(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    // Binds `socket` to `addr` and listens on it. Accepted connections inherit its buffers.
+    // `reuse_port` lets other sockets listen on the same address, with the system spreading
+    // connections among them.
+    pub(crate) fn listen(
+        &self,
+        socket: Socket,
+        addr: SocketAddr,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        self.apply_buffers(SockRef::from(&socket))?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.unwrap_or(DEFAULT_BACKLOG) as i32)?;
+
+        TcpListener::from_std(socket.into())
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
@@ -0,0 +1,107 @@
+use http::{HeaderMap, HeaderValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use serde::Deserialize;
+use tracing::{warn, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    // Falls back to OTEL_EXPORTER_OTLP_ENDPOINT, then http://localhost:4318.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "yaler".to_string(),
+        }
+    }
+}
+
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Telemetry {
+    pub fn init(config: &OtlpConfig) -> Result<Self, Error> {
+        let mut exporter = SpanExporter::builder().with_http();
+        if let Some(endpoint) = &config.endpoint {
+            exporter = exporter.with_endpoint(endpoint);
+        }
+        let exporter = exporter.build().map_err(Error::TelemetryError)?;
+
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )]))
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Self { provider })
+    }
+
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer("yaler"))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!(?e, "Fail to flush traces");
+        }
+    }
+}
+
+// Continues the client's trace in the current span and hands it on to the upstream.
+pub(crate) fn propagate(incoming: &HeaderMap, outgoing: &mut HeaderMap) {
+    let span = Span::current();
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(incoming))
+    });
+    span.set_parent(parent);
+
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut HeaderInjector(outgoing))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (key.parse(), HeaderValue::from_str(&value)) {
+            self.0.insert::<http::header::HeaderName>(name, value);
+        }
+    }
+}
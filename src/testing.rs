@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::header::HOST;
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, Uri};
+use hyper::body::Bytes;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::Body;
+use rustls::{ClientConfig, RootCertStore, ServerName};
+use tokio::io::{
+    duplex, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream, DuplexStream,
+};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::debug;
+
+use crate::acceptor::AcceptorMap;
+use crate::builder::ServerBuilder;
+use crate::ca::CertificateAuthority;
+use crate::capture::{CapturedFlow, FlowCapture};
+use crate::dialer::{Dialer, RemoteStream};
+use crate::error::Error;
+use crate::http::{self as http_ext, ReadHttpExt, ALPN_HTTP1};
+use crate::listen::{self, ListenAddr};
+use crate::server::Server;
+use crate::sni;
+use crate::timeout::with_timeout;
+
+const PIPE_CAPACITY: usize = 64 * 1024;
+// How long flows get to complete before the test gives up on them.
+const FLOW_WAIT: Duration = Duration::from_secs(5);
+
+type Handler = Arc<dyn Fn(Request<Bytes>) -> Response<Body> + Send + Sync>;
+
+// A request as a fake origin received it, after the proxy was done with it.
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub host: String,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Default)]
+struct Origins {
+    handlers: HashMap<String, Handler>,
+    received: Vec<ReceivedRequest>,
+}
+
+// Origins the proxy reaches over in-memory pipes instead of the network. Those spoken to over
+// TLS show certificates of a CA of their own, made for the test.
+#[derive(Clone)]
+pub struct FakeUpstream {
+    origins: Arc<Mutex<Origins>>,
+    acceptors: Arc<AcceptorMap>,
+    ca_cert: String,
+}
+
+impl FakeUpstream {
+    pub fn new() -> Result<Self, Error> {
+        let ca = CertificateAuthority::generate()?;
+
+        Ok(Self {
+            origins: Arc::default(),
+            acceptors: Arc::new(AcceptorMap::new(ca.cert.clone(), ca.key)?),
+            ca_cert: ca.cert,
+        })
+    }
+
+    // Answers requests for `host`, on any port, with `handler`. Other hosts refuse connections.
+    pub fn host<F>(self, host: &str, handler: F) -> Self
+    where
+        F: Fn(Request<Bytes>) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.origins
+            .lock()
+            .unwrap()
+            .handlers
+            .insert(host.to_ascii_lowercase(), Arc::new(handler));
+        self
+    }
+
+    // Trusts the CA of the fake origins.
+    pub fn root_store(&self) -> Result<RootCertStore, Error> {
+        root_store(&self.ca_cert)
+    }
+
+    // Oldest first.
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.origins.lock().unwrap().received.clone()
+    }
+
+    async fn serve(
+        self,
+        host: String,
+        handler: Handler,
+        stream: DuplexStream,
+    ) -> Result<(), Error> {
+        let mut stream = BufStream::new(stream);
+        let preface = stream.fill_buf().await.map_err(Error::ReadStreamError)?;
+        if !http_ext::is_tls_handshake(preface) {
+            return self.respond(host, handler, stream).await;
+        }
+
+        // Like real servers, pick the certificate by the name asked for rather than the one dialed.
+        let name = sni::server_name(preface).unwrap_or_else(|| host.clone());
+        let server_config = self.acceptors.get(name).await?;
+        let stream = TlsAcceptor::from(server_config)
+            .accept(stream)
+            .await
+            .map_err(Error::TlsAcceptError)?;
+        self.respond(host, handler, stream).await
+    }
+
+    async fn respond<S>(&self, host: String, handler: Handler, stream: S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let origins = self.origins.clone();
+        let service = service_fn(move |req: Request<Body>| {
+            let origins = origins.clone();
+            let handler = handler.clone();
+            let host = host.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let body = hyper::body::to_bytes(body).await?;
+                origins.lock().unwrap().received.push(ReceivedRequest {
+                    host,
+                    method: parts.method.clone(),
+                    uri: parts.uri.clone(),
+                    headers: parts.headers.clone(),
+                    body: body.clone(),
+                });
+                Ok::<_, hyper::Error>(handler(Request::from_parts(parts, body)))
+            }
+        });
+
+        // Handlers may take over upgraded connections, as WebSocket origins do.
+        Http::new()
+            .serve_connection(stream, service)
+            .with_upgrades()
+            .await
+            .map_err(Error::HttpRequestError)
+    }
+}
+
+#[async_trait]
+impl Dialer for FakeUpstream {
+    async fn dial(&self, host: &str, _port: u16) -> Result<RemoteStream, Error> {
+        let host = host.to_ascii_lowercase();
+        let handler = self.origins.lock().unwrap().handlers.get(&host).cloned();
+        let handler = handler.ok_or_else(|| {
+            Error::TcpConnectError(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "No fake origin for host",
+            ))
+        })?;
+
+        let (client, server) = duplex(PIPE_CAPACITY);
+        let upstream = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = upstream.serve(host, handler, server).await {
+                debug!(?e, "Fake origin failed");
+            }
+        });
+
+        Ok(RemoteStream::Memory(client))
+    }
+}
+
+// A proxy driven over in-memory pipes, with a CA made for the test and every flow captured.
+pub struct TestProxy {
+    server: Arc<Server>,
+    capture: FlowCapture,
+    ca_cert: String,
+}
+
+impl TestProxy {
+    // Builds what `builder` describes, reaching `upstream` instead of the network. Its CA,
+    // dialer, upstream root store and capture are replaced, and its listener goes to an
+    // ephemeral loopback port nothing has to connect to.
+    pub async fn new(builder: ServerBuilder, upstream: &FakeUpstream) -> Result<Self, Error> {
+        let ca = CertificateAuthority::generate()?;
+        let capture = FlowCapture::new();
+        let server = builder
+            .listen(ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .ca(ca.cert.clone(), ca.key)
+            .dialer(upstream.clone())
+            .root_store(upstream.root_store()?)
+            .capture(capture.clone())
+            .build()
+            .await?;
+
+        Ok(Self {
+            server: Arc::new(server),
+            capture,
+            ca_cert: ca.cert,
+        })
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    // A new client connection, served as if the first listener had accepted it.
+    pub fn connect(&self) -> DuplexStream {
+        let (client, proxy) = duplex(PIPE_CAPACITY);
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve_connection(proxy, listen::local_peer()).await {
+                debug!(?e, "Test connection failed");
+            }
+        });
+
+        client
+    }
+
+    // A new connection tunneled to `authority` with CONNECT.
+    pub async fn tunnel(&self, authority: &str) -> Result<DuplexStream, Error> {
+        let mut stream = BufStream::new(self.connect());
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(Error::WriteStreamError)?;
+        stream.flush().await.map_err(Error::WriteStreamError)?;
+
+        let status = stream.read_response_status(None).await?;
+
+        if status.is_success() {
+            Ok(stream.into_inner())
+        } else {
+            Err(Error::TunnelRefusedError(status))
+        }
+    }
+
+    // TLS over `stream` as a client trusting the CA of the proxy would speak it, offering
+    // HTTP/1.1.
+    pub async fn tls<S>(&self, stream: S, host: &str) -> Result<TlsStream<S>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store(&self.ca_cert)?)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+        let server_name = ServerName::try_from(host)
+            .map_err(|_| Error::BadRequestError("Invalid server name"))?;
+
+        TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .map_err(Error::TlsConnectError)
+    }
+
+    // Sends `req` through the proxy on a connection of its own: plain requests as they are,
+    // https ones through a tunnel the proxy intercepts.
+    pub async fn send(&self, mut req: Request<Body>) -> Result<Response<Body>, Error> {
+        let uri = req.uri().clone();
+        let authority = uri
+            .authority()
+            .ok_or(Error::BadRequestError("Request without host"))?;
+        if !req.headers().contains_key(HOST) {
+            let host = HeaderValue::from_str(authority.as_str())
+                .map_err(|_| Error::BadRequestError("Invalid host"))?;
+            req.headers_mut().insert(HOST, host);
+        }
+        if uri.scheme() != Some(&Scheme::HTTPS) {
+            return exchange(self.connect(), req).await;
+        }
+
+        let host = http_ext::strip_brackets(authority.host());
+        let port = authority.port_u16().unwrap_or(443);
+        let stream = self.tunnel(&http_ext::join_host_port(host, port)).await?;
+        let stream = self.tls(stream, host).await?;
+        // Inside the tunnel, requests name only the path.
+        *req.uri_mut() = uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .parse()
+            .map_err(|_| Error::BadRequestError("Invalid path"))?;
+
+        exchange(stream, req).await
+    }
+
+    // The first `count` flows once they have completed, oldest first.
+    pub async fn flows(&self, count: usize) -> Result<Vec<CapturedFlow>, Error> {
+        let mut updates = self.capture.subscribe();
+        with_timeout(Some(FLOW_WAIT), async {
+            loop {
+                let flows = self.completed();
+                if flows.len() >= count {
+                    return Ok(flows.into_iter().take(count).collect());
+                }
+                let _ = updates.recv().await;
+            }
+        })
+        .await
+    }
+
+    // The first completed flow for `method` and `uri`, waiting for it if there is none yet.
+    pub async fn flow(&self, method: Method, uri: &str) -> Result<CapturedFlow, Error> {
+        let mut updates = self.capture.subscribe();
+        with_timeout(Some(FLOW_WAIT), async {
+            loop {
+                let found = self.completed().into_iter().find(|flow| {
+                    flow.request
+                        .as_ref()
+                        .is_some_and(|req| req.method == method && req.uri == uri)
+                });
+                if let Some(flow) = found {
+                    return Ok(flow);
+                }
+                let _ = updates.recv().await;
+            }
+        })
+        .await
+    }
+
+    fn completed(&self) -> Vec<CapturedFlow> {
+        self.capture
+            .ids()
+            .into_iter()
+            .filter_map(|id| self.capture.get(id))
+            .filter(|flow| flow.summary.is_some())
+            .collect()
+    }
+}
+
+async fn exchange<S>(stream: S, req: Request<Body>) -> Result<Response<Body>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(Error::HttpRequestError)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!(?e, "Test client connection failed");
+        }
+    });
+
+    sender
+        .send_request(req)
+        .await
+        .map_err(Error::HttpRequestError)
+}
+
+fn root_store(ca_cert: &str) -> Result<RootCertStore, Error> {
+    let mut store = RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut ca_cert.as_bytes()).map_err(Error::ReadFileError)?;
+    let (added, _) = store.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(Error::InvalidConfigError("No CA certificate"));
+    }
+
+    Ok(store)
+}
@@ -0,0 +1,211 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::error::Error;
+
+// A reader held to a minimum rate gets this long before it has to keep up.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(1);
+pub(crate) const DEFAULT_SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    #[serde(with = "humantime_serde")]
+    pub connect: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub handshake: Option<Duration>,
+    // Reading a request line and headers, from their first byte; the first request of a
+    // connection also waits on it for that byte.
+    #[serde(with = "humantime_serde")]
+    pub header: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub keep_alive: Option<Duration>,
+    // Tunnels where neither side sends anything for this long are closed.
+    #[serde(with = "humantime_serde")]
+    pub idle: Option<Duration>,
+    // How long a tunnel waits for the client to speak before relaying it as a protocol where
+    // the server speaks first, like SMTP; 2 seconds when unset.
+    #[serde(with = "humantime_serde")]
+    pub sniff: Option<Duration>,
+    // Pooled upstream connections unused this long are closed; 90 seconds when unset.
+    #[serde(with = "humantime_serde")]
+    pub pool_idle: Option<Duration>,
+}
+
+pub(crate) async fn with_timeout<F, T>(duration: Option<Duration>, future: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future)
+            .await
+            .map_err(|_| Error::TimeoutError(duration))?,
+        None => future.await,
+    }
+}
+
+// When either of the streams it watches last moved data.
+#[derive(Clone)]
+pub(crate) struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub(crate) fn watch<S>(&self, stream: S) -> Watched<S> {
+        Watched {
+            inner: stream,
+            activity: self.clone(),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+
+    // Runs `future` until it completes or the watched streams have been quiet for `idle`.
+    pub(crate) async fn until_idle<F, T>(
+        &self,
+        idle: Option<Duration>,
+        future: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = T>,
+    {
+        let idle = match idle {
+            Some(idle) => idle,
+            None => return Ok(future.await),
+        };
+        tokio::pin!(future);
+
+        loop {
+            tokio::select! {
+                output = &mut future => return Ok(output),
+                _ = tokio::time::sleep_until(self.last() + idle) => {
+                    if self.last().elapsed() >= idle {
+                        return Err(Error::TimeoutError(idle));
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Watched<S> {
+    inner: S,
+    activity: Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Fails with `TimedOut` once fewer bytes than `rate` per second have been consumed, after a
+// grace period, so a client trickling in a request cannot hold on to the connection.
+pub(crate) struct MinRate<R> {
+    inner: R,
+    rate: Option<f64>,
+    started: Instant,
+    consumed: u64,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> MinRate<R> {
+    pub(crate) fn new(inner: R, rate: Option<f64>) -> Self {
+        let rate = rate.filter(|rate| *rate > 0.0);
+        let started = Instant::now();
+        Self {
+            inner,
+            rate,
+            started,
+            consumed: 0,
+            deadline: rate.map(|_| Box::pin(tokio::time::sleep_until(started + MIN_RATE_GRACE))),
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for MinRate<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = {
+            let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+            let read = available.len().min(buf.remaining());
+            buf.put_slice(&available[..read]);
+            read
+        };
+        self.consume(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for MinRate<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if let Some(deadline) = this.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        Pin::new(&mut self.inner).consume(amount);
+        self.consumed += amount as u64;
+        if let (Some(rate), Some(mut deadline)) = (self.rate, self.deadline.take()) {
+            let behind = Duration::from_secs_f64(self.consumed as f64 / rate);
+            deadline
+                .as_mut()
+                .reset(self.started + MIN_RATE_GRACE + behind);
+            self.deadline = Some(deadline);
+        }
+    }
+}
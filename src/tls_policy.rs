@@ -0,0 +1,72 @@
+use rustls::version::{TLS12, TLS13};
+use rustls::{
+    ConfigBuilder, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion, WantsVerifier,
+    ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
+};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+// rustls has no TLS 1.1 or older, so these are all a policy can choose from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+// Protocol versions and cipher suites a TLS connection may use.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsPolicy {
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    // Names like `TLS13_AES_128_GCM_SHA256`, in order of preference; the defaults of rustls
+    // when empty.
+    pub cipher_suites: Vec<String>,
+}
+
+impl TlsPolicy {
+    pub(crate) fn versions(&self) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+        let min = self.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = self.max_version.unwrap_or(TlsVersion::Tls13);
+        if min > max {
+            return Err(Error::InvalidConfigError(
+                "TLS min_version is above max_version",
+            ));
+        }
+
+        Ok([(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+            .into_iter()
+            .filter(|(version, _)| (min..=max).contains(version))
+            .map(|(_, version)| version)
+            .collect())
+    }
+
+    pub(crate) fn cipher_suites(&self) -> Result<Vec<SupportedCipherSuite>, Error> {
+        if self.cipher_suites.is_empty() {
+            return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+        }
+
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or(Error::InvalidConfigError("Unknown TLS cipher suite"))
+            })
+            .collect()
+    }
+
+    pub(crate) fn server_builder(
+        &self,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, Error> {
+        Ok(ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites()?)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.versions()?)?)
+    }
+}
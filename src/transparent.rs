@@ -0,0 +1,60 @@
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::Error;
+use crate::tcp::TcpTuning;
+
+pub(crate) fn bind(
+    addr: SocketAddr,
+    tcp: &TcpTuning,
+    reuse_port: bool,
+) -> Result<TcpListener, Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .map_err(Error::TcpBindError)?;
+
+    // TPROXY delivers connections addressed to foreign IPs, which needs IP_TRANSPARENT
+    // (CAP_NET_ADMIN). REDIRECT works without it, so a failure here is not fatal.
+    #[cfg(target_os = "linux")]
+    if let Err(e) = socket.set_ip_transparent(true) {
+        tracing::warn!(
+            ?e,
+            "IP_TRANSPARENT is not available, only REDIRECT will work"
+        );
+    }
+
+    tcp.listen(socket, addr, reuse_port)
+        .map_err(Error::TcpBindError)
+}
+
+pub(crate) fn original_dst(stream: &TcpStream, listen: SocketAddr) -> Result<SocketAddr, Error> {
+    let local = stream.local_addr().map_err(Error::OriginalDstError)?;
+    // REDIRECT rewrites the destination and records the original one in conntrack,
+    // TPROXY keeps it as the local address of the accepted socket.
+    let target = query_original_dst(stream, &local).unwrap_or(local);
+
+    let is_listener = target.port() == listen.port()
+        && (listen.ip().is_unspecified() || target.ip() == listen.ip());
+    if is_listener {
+        return Err(Error::BadRequestError("Connection was not redirected"));
+    }
+
+    Ok(target)
+}
+
+#[cfg(target_os = "linux")]
+fn query_original_dst(stream: &TcpStream, local: &SocketAddr) -> Option<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let addr = match local {
+        SocketAddr::V4(_) => socket.original_dst(),
+        SocketAddr::V6(_) => socket.original_dst_ipv6(),
+    };
+
+    addr.ok()?.as_socket()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn query_original_dst(_: &TcpStream, _: &SocketAddr) -> Option<SocketAddr> {
+    None
+}
@@ -0,0 +1,368 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::HeaderMap;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::runtime::Handle;
+
+use yaler::{BodyKind, BodyView, CapturedFlow, FlowCapture, Server};
+
+const TICK: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Request,
+    Response,
+}
+
+enum View {
+    List,
+    Detail { pane: Pane, scroll: u16 },
+}
+
+struct App {
+    server: Arc<Server>,
+    capture: FlowCapture,
+    handle: Handle,
+    ids: Vec<u64>,
+    table: TableState,
+    // Keep the newest flow selected until the user moves away from it.
+    follow: bool,
+    marked: HashSet<u64>,
+    filter: String,
+    editing: bool,
+    view: View,
+    status: String,
+    results: (mpsc::Sender<String>, mpsc::Receiver<String>),
+}
+
+pub fn run(server: Arc<Server>, capture: FlowCapture, handle: Handle) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = App::new(server, capture, handle).run(&mut terminal);
+    ratatui::restore();
+
+    result
+}
+
+impl App {
+    fn new(server: Arc<Server>, capture: FlowCapture, handle: Handle) -> Self {
+        Self {
+            server,
+            capture,
+            handle,
+            ids: Vec::new(),
+            table: TableState::default(),
+            follow: true,
+            marked: HashSet::new(),
+            filter: String::new(),
+            editing: false,
+            view: View::List,
+            status: String::new(),
+            results: mpsc::channel(),
+        }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            self.refresh();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(TICK)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.on_key(key.code) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        while let Ok(status) = self.results.1.try_recv() {
+            self.status = status;
+        }
+
+        let filter = self.filter.to_ascii_lowercase();
+        self.ids = self
+            .capture
+            .ids()
+            .into_iter()
+            .filter(|id| {
+                filter.is_empty()
+                    || self
+                        .capture
+                        .get(*id)
+                        .and_then(|flow| flow.request)
+                        .and_then(|req| req.uri.host().map(|host| host.to_ascii_lowercase()))
+                        .is_some_and(|host| host.contains(&filter))
+            })
+            .collect();
+
+        if self.ids.is_empty() {
+            self.table.select(None);
+        } else if self.follow || self.table.selected().is_none() {
+            self.table.select(Some(self.ids.len() - 1));
+        } else if let Some(selected) = self.table.selected() {
+            self.table.select(Some(selected.min(self.ids.len() - 1)));
+        }
+    }
+
+    fn selected(&self) -> Option<u64> {
+        self.table.selected().and_then(|i| self.ids.get(i)).copied()
+    }
+
+    // Returns false once the user asks to quit.
+    fn on_key(&mut self, code: KeyCode) -> bool {
+        if self.editing {
+            match code {
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.editing = false;
+                    self.filter.clear();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        if let View::Detail { pane, scroll } = &mut self.view {
+            match code {
+                KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => self.view = View::List,
+                KeyCode::Tab => {
+                    *pane = match pane {
+                        Pane::Request => Pane::Response,
+                        Pane::Response => Pane::Request,
+                    };
+                    *scroll = 0;
+                }
+                KeyCode::Down | KeyCode::Char('j') => *scroll = scroll.saturating_add(1),
+                KeyCode::Up | KeyCode::Char('k') => *scroll = scroll.saturating_sub(1),
+                KeyCode::PageDown => *scroll = scroll.saturating_add(20),
+                KeyCode::PageUp => *scroll = scroll.saturating_sub(20),
+                _ => {}
+            }
+            return true;
+        }
+
+        match code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Esc if !self.filter.is_empty() => self.filter.clear(),
+            KeyCode::Esc => return false,
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.table.select_next();
+                self.follow = self.table.selected() >= Some(self.ids.len().saturating_sub(1));
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.table.select_previous();
+                self.follow = false;
+            }
+            KeyCode::Enter if self.selected().is_some() => {
+                self.view = View::Detail {
+                    pane: Pane::Request,
+                    scroll: 0,
+                };
+            }
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Char(' ') => {
+                if let Some(id) = self.selected() {
+                    if !self.marked.remove(&id) {
+                        self.marked.insert(id);
+                    }
+                }
+            }
+            KeyCode::Char('c') => self.marked.clear(),
+            KeyCode::Char('r') => self.resend(),
+            _ => {}
+        }
+
+        true
+    }
+
+    // Sends the marked flows again, or the selected one when nothing is marked.
+    fn resend(&mut self) {
+        let mut ids: Vec<u64> = if self.marked.is_empty() {
+            self.selected().into_iter().collect()
+        } else {
+            self.marked.drain().collect()
+        };
+        ids.sort_unstable();
+
+        for id in ids {
+            let req = match self
+                .capture
+                .get(id)
+                .as_ref()
+                .and_then(CapturedFlow::to_request)
+            {
+                Some(req) => req,
+                None => continue,
+            };
+
+            let server = self.server.clone();
+            let results = self.results.0.clone();
+            self.handle.spawn(async move {
+                let status = match server.resend(req).await {
+                    Ok(new) => format!("#{} resent as #{}", id, new),
+                    Err(e) => format!("#{} resend failed: {}", id, e),
+                };
+                let _ = results.send(status);
+            });
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+
+        match self.view {
+            View::List => self.draw_list(frame, main),
+            View::Detail { pane, scroll } => self.draw_detail(frame, main, pane, scroll),
+        }
+
+        let help = match (&self.view, self.editing) {
+            (_, true) => format!("filter host: {}_", self.filter),
+            (View::List, _) => format!(
+                "q quit  enter inspect  / filter{}  space mark  r resend  c clear marks  {}",
+                if self.filter.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", self.filter)
+                },
+                self.status
+            ),
+            (View::Detail { .. }, _) => "esc back  tab request/response  ↑↓ scroll".to_string(),
+        };
+        frame.render_widget(Line::from(help).reversed(), footer);
+    }
+
+    fn draw_list(&mut self, frame: &mut Frame, area: ratatui::layout::Rect) {
+        let rows: Vec<Row> = self
+            .ids
+            .iter()
+            .filter_map(|id| Some((*id, self.capture.get(*id)?)))
+            .map(|(id, flow)| {
+                let req = flow.request.as_ref();
+                let summary = flow.summary.as_ref();
+                let status = match (&flow.error, &flow.response) {
+                    (Some(_), _) => "ERR".to_string(),
+                    (None, Some(res)) => res.status.as_u16().to_string(),
+                    (None, None) => "…".to_string(),
+                };
+
+                Row::new(vec![
+                    if self.marked.contains(&id) { "*" } else { "" }.to_string(),
+                    id.to_string(),
+                    req.map(|req| req.method.to_string()).unwrap_or_default(),
+                    status,
+                    req.and_then(|req| req.uri.host().map(str::to_string))
+                        .unwrap_or_default(),
+                    req.and_then(|req| req.uri.path_and_query().map(|path| path.to_string()))
+                        .unwrap_or_default(),
+                    summary
+                        .map(|summary| summary.response_bytes.to_string())
+                        .unwrap_or_default(),
+                    summary
+                        .map(|summary| format!("{}ms", summary.duration.as_millis()))
+                        .unwrap_or_default(),
+                ])
+            })
+            .collect();
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(1),
+                Constraint::Length(6),
+                Constraint::Length(7),
+                Constraint::Length(4),
+                Constraint::Percentage(25),
+                Constraint::Fill(1),
+                Constraint::Length(9),
+                Constraint::Length(8),
+            ],
+        )
+        .header(
+            Row::new(["", "#", "Method", "Code", "Host", "Path", "Size", "Time"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().reversed())
+        .block(Block::bordered().title(" yaler "));
+
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: ratatui::layout::Rect, pane: Pane, scroll: u16) {
+        let flow = match self.selected().and_then(|id| self.capture.get(id)) {
+            Some(flow) => flow,
+            None => return,
+        };
+
+        let mut lines: Vec<Line> = Vec::new();
+        let title = match pane {
+            Pane::Request => {
+                if let Some(req) = &flow.request {
+                    lines.push(
+                        format!("{} {} {:?}", req.method, req.uri, req.version)
+                            .bold()
+                            .into(),
+                    );
+                    Self::push_headers(&mut lines, &req.headers);
+                }
+                Self::push_body(&mut lines, flow.request_view(), flow.request_truncated);
+                " Request "
+            }
+            Pane::Response => {
+                if let Some(error) = &flow.error {
+                    lines.push(error.clone().red().into());
+                }
+                if let Some(res) = &flow.response {
+                    lines.push(format!("{:?} {}", res.version, res.status).bold().into());
+                    Self::push_headers(&mut lines, &res.headers);
+                }
+                Self::push_body(&mut lines, flow.response_view(), flow.response_truncated);
+                " Response "
+            }
+        };
+
+        let detail = Paragraph::new(Text::from(lines))
+            .scroll((scroll, 0))
+            .block(Block::bordered().title(title));
+        frame.render_widget(detail, area);
+    }
+
+    fn push_headers(lines: &mut Vec<Line>, headers: &HeaderMap) {
+        for (name, value) in headers {
+            lines.push(Line::from(format!(
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            )));
+        }
+        lines.push(Line::default());
+    }
+
+    fn push_body(lines: &mut Vec<Line>, view: Option<BodyView>, truncated: bool) {
+        if let Some(view) = view {
+            if view.kind == BodyKind::Binary {
+                lines.push("<binary data>".italic().into());
+            }
+            lines.extend(view.text.lines().map(|line| Line::from(line.to_string())));
+        }
+        if truncated {
+            lines.push("<truncated>".italic().into());
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, instrument};
+
+use crate::error::Error;
+use crate::pool::{Buffer, BUFFERS, BUFFER_SIZE};
+use crate::timeout::Activity;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Transferred {
+    pub upstream: u64,
+    pub downstream: u64,
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn relay<C, S>(
+    client: &mut C,
+    server: &mut S,
+    idle: Option<Duration>,
+) -> Result<Transferred, Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let activity = Activity::new();
+    let mut client = activity.watch(client);
+    let mut server = activity.watch(server);
+    let mut up = Transfer::new();
+    let mut down = Transfer::new();
+    let (upstream, downstream) = activity
+        .until_idle(
+            idle,
+            poll_fn(|cx| {
+                let upstream = up.poll(cx, &mut client, &mut server)?;
+                let downstream = down.poll(cx, &mut server, &mut client)?;
+                Poll::Ready(Ok::<_, io::Error>((ready!(upstream), ready!(downstream))))
+            }),
+        )
+        .await?
+        .map_err(Error::RelayError)?;
+
+    let transferred = Transferred {
+        upstream,
+        downstream,
+    };
+    info!(?transferred);
+
+    Ok(transferred)
+}
+
+// One direction of a relay: copies until the reader is done, then shuts the writer down.
+struct Transfer {
+    buf: Buffer,
+    pos: usize,
+    cap: usize,
+    amount: u64,
+    stage: Stage,
+}
+
+enum Stage {
+    Copying,
+    ShuttingDown,
+    Done,
+}
+
+impl Transfer {
+    fn new() -> Self {
+        let mut buf = BUFFERS.get();
+        buf.resize(BUFFER_SIZE, 0);
+
+        Self {
+            buf,
+            pos: 0,
+            cap: 0,
+            amount: 0,
+            stage: Stage::Copying,
+        }
+    }
+
+    fn poll<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Poll<io::Result<u64>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        loop {
+            match self.stage {
+                Stage::Copying => {
+                    if self.pos == self.cap {
+                        let mut read = ReadBuf::new(&mut self.buf[..]);
+                        if Pin::new(&mut *reader)
+                            .poll_read(cx, &mut read)?
+                            .is_pending()
+                        {
+                            // Nothing more to send for now, so what was written goes out.
+                            ready!(Pin::new(&mut *writer).poll_flush(cx))?;
+                            return Poll::Pending;
+                        }
+                        if read.filled().is_empty() {
+                            self.stage = Stage::ShuttingDown;
+                            continue;
+                        }
+                        (self.pos, self.cap) = (0, read.filled().len());
+                    }
+
+                    let written = ready!(
+                        Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap])
+                    )?;
+                    if written == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    self.pos += written;
+                    self.amount += written as u64;
+                }
+                Stage::ShuttingDown => {
+                    ready!(Pin::new(&mut *writer).poll_shutdown(cx))?;
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => return Poll::Ready(Ok(self.amount)),
+            }
+        }
+    }
+}
+
+// Relays between two TCP sockets with splice(2), which moves the bytes through a pipe in the
+// kernel rather than copying them to userspace and back.
+#[cfg(target_os = "linux")]
+#[instrument(skip_all)]
+pub(crate) async fn splice(
+    client: &tokio::net::TcpStream,
+    server: &tokio::net::TcpStream,
+    idle: Option<Duration>,
+) -> Result<Transferred, Error> {
+    let activity = Activity::new();
+    let (upstream, downstream) = activity
+        .until_idle(idle, async {
+            tokio::try_join!(
+                zero_copy::forward(client, server, &activity),
+                zero_copy::forward(server, client, &activity),
+            )
+        })
+        .await?
+        .map_err(Error::RelayError)?;
+
+    let transferred = Transferred {
+        upstream,
+        downstream,
+    };
+    info!(?transferred);
+
+    Ok(transferred)
+}
+
+#[cfg(target_os = "linux")]
+mod zero_copy {
+    use std::io;
+    use std::net::Shutdown;
+
+    use nix::fcntl::{self, OFlag, SpliceFFlags};
+    use nix::unistd;
+    use socket2::SockRef;
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    use crate::timeout::Activity;
+
+    // The default capacity of a pipe.
+    const PIPE_SIZE: usize = 1 << 16;
+
+    // Moves what `from` receives to `to` until it closes, then closes the writing side of `to`
+    // like `copy_bidirectional` does.
+    pub(super) async fn forward(
+        from: &TcpStream,
+        to: &TcpStream,
+        activity: &Activity,
+    ) -> io::Result<u64> {
+        let (pipe_out, pipe_in) = unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+        let mut total = 0;
+
+        loop {
+            // The pipe is drained before each read, so only the socket can make it block.
+            let read = loop {
+                from.readable().await?;
+                match from.try_io(Interest::READABLE, || {
+                    Ok(fcntl::splice(from, None, &pipe_in, None, PIPE_SIZE, flags)?)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    result => break result?,
+                }
+            };
+            if read == 0 {
+                break;
+            }
+            activity.touch();
+
+            let mut left = read;
+            while left > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    Ok(fcntl::splice(&pipe_out, None, to, None, left, flags)?)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    result => left -= result?,
+                }
+            }
+            total += read as u64;
+        }
+
+        match SockRef::from(to).shutdown(Shutdown::Write) {
+            // The other side may have gone already.
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(total),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use http::{HeaderValue, Uri};
+use socket2::SockRef;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::dialer::{Dialer, RemoteStream};
+use crate::error::Error;
+use crate::http::{self as http_ext, ReadHttpExt};
+use crate::tcp::TcpTuning;
+
+// Bounds the reply to CONNECT, which is only a status line and a few headers.
+const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct UpstreamProxy {
+    addr: String,
+    authorization: Option<HeaderValue>,
+    tcp: TcpTuning,
+}
+
+impl UpstreamProxy {
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let uri =
+            Uri::from_str(url).map_err(|_| Error::BadRequestError("Invalid upstream proxy url"))?;
+        let authority = uri
+            .authority()
+            .ok_or(Error::BadRequestError("Upstream proxy url without host"))?;
+
+        let authorization = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => {
+                // Reserved characters in the url's credentials come percent-encoded.
+                let credentials = http_ext::percent_decode(userinfo)
+                    .ok_or(Error::BadRequestError("Invalid upstream proxy credentials"))?;
+                Some(
+                    HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials)))
+                        .map_err(|_| {
+                            Error::BadRequestError("Invalid upstream proxy credentials")
+                        })?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            addr: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(80)
+            ),
+            authorization,
+            tcp: TcpTuning::default(),
+        })
+    }
+
+    pub fn tcp(mut self, tcp: TcpTuning) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub(crate) fn authorization(&self) -> Option<&HeaderValue> {
+        self.authorization.as_ref()
+    }
+
+    pub(crate) async fn connect(&self) -> Result<TcpStream, Error> {
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(Error::TcpConnectError)?;
+        self.tcp
+            .apply(SockRef::from(&stream))
+            .map_err(Error::TcpConnectError)?;
+        Ok(stream)
+    }
+
+    pub(crate) async fn tunnel(&self, target: &str) -> Result<RemoteStream, Error> {
+        let mut stream = self.connect().await?;
+
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some(authorization) = &self.authorization {
+            request.push_str("Proxy-Authorization: ");
+            request.push_str(authorization.to_str().unwrap_or_default());
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(Error::WriteStreamError)?;
+
+        let mut stream = BufReader::new(stream);
+        let status = stream
+            .read_response_status(Some(MAX_RESPONSE_HEAD_SIZE))
+            .await?;
+        if !status.is_success() {
+            return Err(Error::UpstreamProxyError(status));
+        }
+
+        // The remote may have spoken first, and its bytes came in with the reply.
+        let buffered = stream.buffer().to_vec();
+        let stream = stream.into_inner();
+        if buffered.is_empty() {
+            Ok(RemoteStream::Tcp(stream))
+        } else {
+            Ok(RemoteStream::Prefixed(buffered, stream))
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for UpstreamProxy {
+    async fn dial(&self, host: &str, port: u16) -> Result<RemoteStream, Error> {
+        self.tunnel(&http_ext::join_host_port(host, port)).await
+    }
+}
@@ -0,0 +1,379 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{
+    ClientSessionMemoryCache, NoClientSessionStorage, ServerCertVerified, ServerCertVerifier,
+    ServerName, StoresClientSessions, WebPkiVerifier,
+};
+use rustls::version::TLS12;
+use rustls::{
+    Certificate, ClientConfig, KeyLog, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    SupportedProtocolVersion,
+};
+use serde::Deserialize;
+use tokio_rustls::TlsConnector;
+use tracing::{info, warn};
+use x509_parser::extensions::GeneralName;
+
+use crate::error::Error;
+use crate::http::{ALPN_H2, ALPN_HTTP1};
+use crate::policy::HostPattern;
+use crate::reverse::{read_cert_and_key, read_certs};
+use crate::sni::ClientOffer;
+use crate::tls_policy::TlsPolicy;
+
+// Where trust anchors for upstream certificates come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootSource {
+    // The Mozilla roots compiled in.
+    Webpki,
+    // The trust store of the operating system.
+    Native,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verification {
+    #[default]
+    Full,
+    // The chain has to be trusted, but may be issued for another name.
+    SkipHostname,
+    // Anything is accepted, self-signed and expired certificates included.
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerificationRule {
+    pub hosts: Vec<String>,
+    pub verify: Verification,
+}
+
+// A PEM certificate chain and key presented to upstreams asking for one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCertRule {
+    pub hosts: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// How certificates of upstream servers are checked.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpstreamTlsConfig {
+    // Verifies nothing for any host. Dangerous: anyone on the path can read the traffic.
+    pub insecure: bool,
+    // The first rule matching a host wins over `insecure`.
+    pub rules: Vec<VerificationRule>,
+    pub roots: Vec<RootSource>,
+    // PEM bundles, or directories of them, trusted on top of `roots`.
+    pub ca_files: Vec<PathBuf>,
+    // The first rule matching a host picks the certificate; others get none.
+    pub client_certs: Vec<ClientCertRule>,
+    // Sessions remembered for resumption, which saves a round trip on later handshakes;
+    // 0 turns resumption off.
+    pub session_cache: usize,
+    #[serde(flatten)]
+    pub policy: TlsPolicy,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        Self {
+            insecure: false,
+            rules: Vec::new(),
+            roots: vec![RootSource::Webpki],
+            ca_files: Vec::new(),
+            client_certs: Vec::new(),
+            session_cache: 256,
+            policy: TlsPolicy::default(),
+        }
+    }
+}
+
+impl UpstreamTlsConfig {
+    pub(crate) fn root_store(&self) -> Result<RootCertStore, Error> {
+        let mut root_store = RootCertStore::empty();
+        for source in &self.roots {
+            match source {
+                RootSource::Webpki => {
+                    root_store.add_server_trust_anchors(
+                        webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                                ta.subject,
+                                ta.spki,
+                                ta.name_constraints,
+                            )
+                        }),
+                    );
+                }
+                RootSource::Native => {
+                    let certs = rustls_native_certs::load_native_certs()
+                        .map_err(Error::ReadFileError)?
+                        .into_iter()
+                        .map(|cert| cert.0)
+                        .collect::<Vec<_>>();
+                    let (added, ignored) = root_store.add_parsable_certificates(&certs);
+                    info!("{} system roots loaded, {} ignored", added, ignored);
+                }
+            }
+        }
+
+        for path in &self.ca_files {
+            let files = if path.is_dir() {
+                let mut files = fs::read_dir(path)
+                    .map_err(Error::ReadFileError)?
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.is_file())
+                    .collect::<Vec<_>>();
+                files.sort();
+                files
+            } else {
+                vec![path.clone()]
+            };
+            let mut certs = Vec::new();
+            for file in &files {
+                certs.extend(read_certs(file)?);
+            }
+            let (added, _) = root_store.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(Error::InvalidConfigError(
+                    "No CA certificate in ca_files entry",
+                ));
+            }
+        }
+
+        if root_store.is_empty() {
+            return Err(Error::InvalidConfigError("No upstream trust anchors"));
+        }
+
+        Ok(root_store)
+    }
+}
+
+// TLS connectors for upstreams, one for each client certificate. They share the verifier, as
+// rustls picks the client certificate without knowing the server name.
+pub(crate) struct UpstreamConnectors {
+    default: Connector,
+    client_certs: Vec<(Vec<HostPattern>, Connector)>,
+}
+
+// rustls has no way to change the versions of a config once built, so clients limited to
+// TLS 1.2 get a config of their own.
+struct Connector {
+    config: Arc<ClientConfig>,
+    tls12: Arc<ClientConfig>,
+}
+
+impl UpstreamConnectors {
+    pub(crate) fn new(
+        verifier: UpstreamVerifier,
+        config: &UpstreamTlsConfig,
+        key_log: Option<Arc<dyn KeyLog>>,
+    ) -> Result<Self, Error> {
+        let verifier = Arc::new(verifier);
+        // One store for every config, as sessions are looked up by server name anyway.
+        let sessions: Arc<dyn StoresClientSessions> = match config.session_cache {
+            0 => Arc::new(NoClientSessionStorage {}),
+            size => ClientSessionMemoryCache::new(size),
+        };
+        let cipher_suites = config.policy.cipher_suites()?;
+        let versions = config.policy.versions()?;
+        let build = |versions: &[&'static SupportedProtocolVersion],
+                     cert: Option<(Vec<Certificate>, PrivateKey)>|
+         -> Result<Arc<ClientConfig>, Error> {
+            let builder = ClientConfig::builder()
+                .with_cipher_suites(&cipher_suites)
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(versions)?
+                .with_custom_certificate_verifier(verifier.clone());
+            let mut client_config = match cert {
+                Some((chain, key)) => builder.with_single_cert(chain, key)?,
+                None => builder.with_no_client_auth(),
+            };
+            client_config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()];
+            client_config.session_storage = sessions.clone();
+            if let Some(key_log) = &key_log {
+                client_config.key_log = key_log.clone();
+            }
+            Ok(Arc::new(client_config))
+        };
+        let connector = |cert: Option<(Vec<Certificate>, PrivateKey)>| {
+            let config = build(&versions, cert.clone())?;
+            // Without TLS 1.2 allowed, clients limited to it still get TLS 1.3 upstream.
+            let tls12 = if versions.contains(&&TLS12) {
+                build(&[&TLS12], cert)?
+            } else {
+                config.clone()
+            };
+            Ok::<_, Error>(Connector { config, tls12 })
+        };
+
+        let default = connector(None)?;
+        let client_certs = config
+            .client_certs
+            .iter()
+            .map(|rule| {
+                let hosts = rule
+                    .hosts
+                    .iter()
+                    .map(|host| HostPattern::new(host))
+                    .collect::<Result<_, _>>()?;
+                let cert = read_cert_and_key(&rule.cert, &rule.key)?;
+                Ok((hosts, connector(Some(cert))?))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            default,
+            client_certs,
+        })
+    }
+
+    fn find(&self, host: &str) -> &Connector {
+        self.client_certs
+            .iter()
+            .find(|(hosts, _)| hosts.iter().any(|pattern| pattern.matches(host)))
+            .map_or(&self.default, |(_, connector)| connector)
+    }
+
+    pub(crate) fn get(&self, host: &str) -> TlsConnector {
+        TlsConnector::from(self.find(host).config.clone())
+    }
+
+    // Offers what the client did: TLS 1.3 only if it can do it, and the protocols yaler
+    // speaks out of its ALPN list, so both legs settle on the same one.
+    pub(crate) fn mirror(&self, host: &str, offer: &ClientOffer) -> TlsConnector {
+        let connector = self.find(host);
+        let base = if offer.tls13 {
+            &connector.config
+        } else {
+            &connector.tls12
+        };
+        let mut config = (**base).clone();
+        config.alpn_protocols = offer
+            .protocols
+            .iter()
+            .filter(|protocol| [ALPN_H2, ALPN_HTTP1].contains(&protocol.as_slice()))
+            .cloned()
+            .collect();
+
+        TlsConnector::from(Arc::new(config))
+    }
+
+    // For requests of yaler itself, which presents no certificate.
+    pub(crate) fn default(&self) -> TlsConnector {
+        TlsConnector::from(self.default.config.clone())
+    }
+}
+
+pub(crate) struct UpstreamVerifier {
+    webpki: WebPkiVerifier,
+    default: Verification,
+    rules: Vec<(Vec<HostPattern>, Verification)>,
+}
+
+impl UpstreamVerifier {
+    pub(crate) fn new(roots: RootCertStore, config: &UpstreamTlsConfig) -> Result<Self, Error> {
+        let default = if config.insecure {
+            warn!("Upstream certificates are not verified");
+            Verification::None
+        } else {
+            Verification::Full
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let hosts = rule
+                    .hosts
+                    .iter()
+                    .map(|host| HostPattern::new(host))
+                    .collect::<Result<_, _>>()?;
+                Ok((hosts, rule.verify))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            webpki: WebPkiVerifier::new(roots, None),
+            default,
+            rules,
+        })
+    }
+
+    fn verification(&self, host: &str) -> Verification {
+        self.rules
+            .iter()
+            .find(|(hosts, _)| hosts.iter().any(|pattern| pattern.matches(host)))
+            .map_or(self.default, |(_, verify)| *verify)
+    }
+
+    // A name the certificate is valid for, to verify its chain against; wildcards stand for
+    // any one label.
+    fn name_in(end_entity: &Certificate) -> Result<ServerName, rustls::Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificateEncoding)?;
+        let names = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| san.value.general_names.clone())
+            .unwrap_or_default();
+
+        names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name) => {
+                    let name = match name.strip_prefix("*.") {
+                        Some(parent) => format!("x.{}", parent),
+                        None => name.to_string(),
+                    };
+                    ServerName::try_from(name.as_str()).ok()
+                }
+                _ => None,
+            })
+            .ok_or_else(|| {
+                rustls::Error::InvalidCertificateData("Certificate without DNS name".to_string())
+            })
+    }
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(address) => address.to_string(),
+            _ => String::new(),
+        };
+
+        match self.verification(&host) {
+            Verification::Full => self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            Verification::SkipHostname => self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                &Self::name_in(end_entity)?,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            Verification::None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
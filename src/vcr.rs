@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::encoding;
+use crate::error::Error;
+use crate::flow::FlowRequest;
+use crate::har::{self, HarConfig, HarFlush, HarRecorder};
+use crate::http::{self as http_ext, BoundedBody};
+use crate::intercept::{Interceptor, RequestAction, ResponseAction};
+use crate::replay::RecordedFlow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VcrMode {
+    #[default]
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VcrConfig {
+    // A HAR file, so cassettes can be inspected with the usual tools.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub mode: VcrMode,
+    // Request headers that must match too, on top of method, URL and body.
+    #[serde(default)]
+    pub match_headers: Vec<String>,
+    #[serde(default)]
+    pub ignore_body: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Signature {
+    method: String,
+    uri: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Option<Bytes>,
+}
+
+struct Cassette {
+    flows: Vec<RecordedFlow>,
+    index: HashMap<Signature, Vec<usize>>,
+    // How many times each signature was served, so repeated requests walk through the
+    // recorded answers in order and keep getting the last one afterwards.
+    served: Mutex<HashMap<Signature, usize>>,
+}
+
+enum Inner {
+    Record(HarRecorder),
+    Replay {
+        config: VcrConfig,
+        cassette: Cassette,
+    },
+}
+
+// Records upstream responses to a cassette, or answers from one without touching the network.
+#[derive(Clone)]
+pub struct Vcr(Arc<Inner>);
+
+impl Vcr {
+    pub fn new(config: VcrConfig) -> Result<Self, Error> {
+        let inner = match config.mode {
+            VcrMode::Record => Inner::Record(HarRecorder::new(HarConfig {
+                path: config.path,
+                max_body_size: None,
+                flush: HarFlush::Entry,
+            })),
+            VcrMode::Replay => {
+                let flows = RecordedFlow::from_har(&config.path)?;
+                let mut index: HashMap<Signature, Vec<usize>> = HashMap::new();
+                for (i, flow) in flows.iter().enumerate() {
+                    let Some(response) = &flow.response else {
+                        continue;
+                    };
+                    // Played back, only the start of a body would pass for all of it.
+                    if response.truncated {
+                        warn!(method = %flow.method, uri = %flow.uri, "Truncated response not replayed");
+                        continue;
+                    }
+                    let signature = Self::signature(
+                        &config,
+                        flow.method.as_str(),
+                        &flow.uri.to_string(),
+                        &flow.headers,
+                        &flow.body,
+                    );
+                    index.entry(signature).or_default().push(i);
+                }
+
+                Inner::Replay {
+                    config,
+                    cassette: Cassette {
+                        flows,
+                        index,
+                        served: Mutex::new(HashMap::new()),
+                    },
+                }
+            }
+        };
+
+        Ok(Self(Arc::new(inner)))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(*self.0, Inner::Replay { .. })
+    }
+
+    fn signature(
+        config: &VcrConfig,
+        method: &str,
+        uri: &str,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Signature {
+        // Recordings keep bodies decoded, and requests have to match them whatever their coding.
+        let body = (!config.ignore_body)
+            .then(|| encoding::decode(headers, body).map_or_else(|| body.clone(), Bytes::from));
+        let headers = config
+            .match_headers
+            .iter()
+            .map(|name| {
+                let name = name.to_ascii_lowercase();
+                let value = headers
+                    .get_all(name.as_str())
+                    .iter()
+                    .flat_map(|value| value.as_bytes().iter().copied().chain([b'\n']))
+                    .collect();
+                (name, value)
+            })
+            .collect();
+
+        Signature {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers,
+            body,
+        }
+    }
+
+    fn play(cassette: &Cassette, signature: &Signature) -> Option<Response<Body>> {
+        let recorded = cassette.index.get(signature)?;
+        let mut served = cassette.served.lock().unwrap();
+        let count = served.entry(signature.clone()).or_default();
+        let flow = &cassette.flows[recorded[(*count).min(recorded.len() - 1)]];
+        *count += 1;
+        let recorded = flow.response.as_ref()?;
+
+        let body = recorded.body.clone().unwrap_or_default();
+        let mut headers = recorded.headers.clone();
+        // The body was kept decoded unless it still decodes as it is.
+        if encoding::is_encoded(&headers) && encoding::decode(&headers, &body).is_none() {
+            headers.remove(CONTENT_ENCODING);
+        }
+        // The whole body is at hand, so it goes out with a length instead of the original framing.
+        if headers.remove(TRANSFER_ENCODING).is_some() || headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = recorded.status;
+        *response.headers_mut() = headers;
+
+        Some(response)
+    }
+}
+
+#[async_trait]
+impl Interceptor for Vcr {
+    async fn on_request(&self, flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        let (config, cassette) = match &*self.0 {
+            Inner::Record(recorder) => return recorder.on_request(flow, req).await,
+            Inner::Replay { config, cassette } => (config, cassette),
+        };
+
+        // Cassettes keep no more of a body than that, so larger ones could never match.
+        let (parts, body) = req.into_parts();
+        let body = match config.ignore_body {
+            true => Bytes::new(),
+            false => match http_ext::read_body(body, har::MAX_BODY_SIZE).await {
+                Ok(BoundedBody::Full(body)) => body,
+                Ok(BoundedBody::TooLarge(_)) => {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                    return RequestAction::Respond(response);
+                }
+                Err(_) => return RequestAction::Block,
+            },
+        };
+        let signature = Self::signature(
+            config,
+            flow.method.as_str(),
+            &flow.uri.to_string(),
+            &parts.headers,
+            &body,
+        );
+
+        match Self::play(cassette, &signature) {
+            Some(response) => RequestAction::Respond(response),
+            None => {
+                warn!(method = %flow.method, uri = %flow.uri, "No recorded response");
+                let mut response = Response::new(Body::from(format!(
+                    "No recorded response for {} {}\n",
+                    flow.method, flow.uri
+                )));
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                RequestAction::Respond(response)
+            }
+        }
+    }
+
+    async fn on_response(&self, flow: &FlowRequest, res: Response<Body>) -> ResponseAction {
+        match &*self.0 {
+            Inner::Record(recorder) => recorder.on_response(flow, res).await,
+            Inner::Replay { .. } => ResponseAction::Forward(res),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt::Write;
+
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+
+// Binary bodies are only dumped up to this size.
+const MAX_HEXDUMP_SIZE: usize = 64 * 1024;
+const INDENT: &str = "  ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Json,
+    Form,
+    Multipart,
+    Xml,
+    Text,
+    Binary,
+}
+
+impl BodyKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BodyKind::Json => "json",
+            BodyKind::Form => "form",
+            BodyKind::Multipart => "multipart",
+            BodyKind::Xml => "xml",
+            BodyKind::Text => "text",
+            BodyKind::Binary => "binary",
+        }
+    }
+}
+
+// A body laid out for reading by its media type: structured text pretty-printed, binary as a
+// hexdump. Bodies that do not parse as their media type says are shown as they are.
+#[derive(Debug, Clone)]
+pub struct BodyView {
+    pub kind: BodyKind,
+    pub text: String,
+}
+
+impl BodyView {
+    pub fn new(headers: &HeaderMap, body: &[u8]) -> Self {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        Self::with_content_type(content_type, body)
+    }
+
+    fn with_content_type(content_type: &str, body: &[u8]) -> Self {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let text = std::str::from_utf8(body).ok();
+
+        let binary = media_type.contains("protobuf")
+            || media_type.starts_with("application/grpc")
+            || media_type == "application/octet-stream";
+        let structured = match text {
+            _ if binary => None,
+            Some(text) if media_type == "application/json" || media_type.ends_with("+json") => {
+                json(text).map(|text| (BodyKind::Json, text))
+            }
+            Some(text) if media_type == "application/x-www-form-urlencoded" => {
+                Some((BodyKind::Form, form(text)))
+            }
+            _ if media_type.starts_with("multipart/") => {
+                multipart(content_type, body).map(|text| (BodyKind::Multipart, text))
+            }
+            Some(text) if media_type.ends_with("/xml") || media_type.ends_with("+xml") => {
+                xml(text).map(|text| (BodyKind::Xml, text))
+            }
+            // Untyped bodies are often JSON all the same.
+            Some(text) if media_type.is_empty() => json(text).map(|text| (BodyKind::Json, text)),
+            _ => None,
+        };
+
+        let (kind, text) = match (structured, text) {
+            (Some(structured), _) => structured,
+            (None, Some(text)) if !binary => (BodyKind::Text, text.to_string()),
+            (None, _) => (BodyKind::Binary, hexdump(body)),
+        };
+
+        Self { kind, text }
+    }
+}
+
+fn json(text: &str) -> Option<String> {
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with('{') && !trimmed.starts_with('[') {
+        return None;
+    }
+    let value = serde_json::from_str::<serde_json::Value>(text).ok()?;
+
+    serde_json::to_string_pretty(&value).ok()
+}
+
+fn form(text: &str) -> String {
+    text.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            format!("{}: {}", percent_decode(name), percent_decode(value))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| from + position)
+}
+
+// Each part with its headers, and its body viewed by its own content type.
+fn multipart(content_type: &str, body: &[u8]) -> Option<String> {
+    let boundary = content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"'))
+    })?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let mut out = String::new();
+    let mut start = find(body, &delimiter, 0)? + delimiter.len();
+    let mut count = 0;
+    while !body[start..].starts_with(b"--") {
+        let end = find(body, &delimiter, start)?;
+        let part = body[start..end]
+            .strip_prefix(b"\r\n")
+            .unwrap_or(&body[start..end]);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let (head, content) = match find(part, b"\r\n\r\n", 0) {
+            Some(split) => (&part[..split], &part[split + 4..]),
+            None => (&[][..], part),
+        };
+        let head = String::from_utf8_lossy(head);
+        let part_type = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-type"))
+            .map_or("text/plain", |(_, value)| value.trim());
+
+        count += 1;
+        let _ = writeln!(out, "--- part {}", count);
+        for line in head.lines() {
+            let _ = writeln!(out, "{}", line);
+        }
+        let _ = writeln!(out);
+        let _ = writeln!(
+            out,
+            "{}",
+            BodyView::with_content_type(part_type, content).text
+        );
+
+        start = end + delimiter.len();
+    }
+
+    Some(out.trim_end().to_string())
+}
+
+// Puts each element on its own line, indented by depth, keeping elements that only hold text on
+// one line.
+fn xml(text: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut depth = 0;
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let _ = writeln!(out, "{}{}", INDENT.repeat(depth), rest[..end].trim());
+            rest = rest[end..].trim_start();
+            continue;
+        }
+
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->")? + 3
+        } else if rest.starts_with("<![CDATA[") {
+            rest.find("]]>")? + 3
+        } else {
+            rest.find('>')? + 1
+        };
+        let tag = &rest[..end];
+        rest = rest[end..].trim_start();
+
+        if tag.starts_with("</") {
+            depth = depth.saturating_sub(1);
+            let _ = writeln!(out, "{}{}", INDENT.repeat(depth), tag);
+            continue;
+        }
+        let _ = write!(out, "{}{}", INDENT.repeat(depth), tag);
+        let opens = !tag.ends_with("/>") && !tag.starts_with("<?") && !tag.starts_with("<!");
+
+        // `<a>text</a>` stays together.
+        let text_end = rest.find('<').unwrap_or(rest.len());
+        if opens && rest[text_end..].starts_with("</") {
+            if let Some(close) = rest[text_end..].find('>') {
+                let close = text_end + close + 1;
+                let _ = writeln!(out, "{}{}", rest[..text_end].trim(), &rest[text_end..close]);
+                rest = rest[close..].trim_start();
+                continue;
+            }
+        }
+
+        let _ = writeln!(out);
+        if opens {
+            depth += 1;
+        }
+    }
+
+    Some(out.trim_end().to_string())
+}
+
+fn hexdump(body: &[u8]) -> String {
+    let mut out = String::new();
+    let shown = &body[..body.len().min(MAX_HEXDUMP_SIZE)];
+
+    for (i, line) in shown.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&byte| match byte {
+                b' '..=b'~' => byte as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        let _ = writeln!(out, "{:08x}  {:<47}  |{}|", i * 16, hex, ascii);
+    }
+    if body.len() > shown.len() {
+        let _ = writeln!(out, "... {} more bytes", body.len() - shown.len());
+    }
+
+    out.trim_end().to_string()
+}
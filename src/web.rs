@@ -0,0 +1,304 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use http::header::*;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::Body;
+use serde_json::{json, Value};
+use tokio::io::split;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+
+use crate::capture::{CapturedFlow, FlowCapture};
+use crate::error::Error;
+use crate::grpc;
+use crate::http as http_ext;
+use crate::server::{Context, Server};
+use crate::view::BodyView;
+use crate::websocket;
+
+const INDEX: &str = include_str!("web/index.html");
+
+fn overview(id: u64, flow: &CapturedFlow) -> Value {
+    let request = flow.request.as_ref();
+    let summary = flow.summary.as_ref();
+
+    json!({
+        "id": id,
+        "ulid": request.map(|req| req.ulid.to_string()),
+        "connection": request.and_then(|req| req.connection).map(|id| id.to_string()),
+        "method": request.map(|req| req.method.as_str()),
+        "grpc": request
+            .filter(|req| grpc::is_grpc(&req.headers))
+            .map(|req| req.uri.path()),
+        "uri": request.map(|req| req.uri.to_string()),
+        "host": request.and_then(|req| req.uri.host()),
+        "status": flow.response.as_ref().map(|res| res.status.as_u16())
+            .or_else(|| summary.and_then(|summary| summary.status).map(|status| status.as_u16())),
+        "error": flow.error,
+        "messages": flow.messages,
+        "response_bytes": summary.map(|summary| summary.response_bytes),
+        "duration": summary.map(|summary| summary.duration.as_secs_f64() * 1000.0),
+        "complete": summary.is_some(),
+    })
+}
+
+fn detail(id: u64, flow: &CapturedFlow) -> Value {
+    let mut detail = overview(id, flow);
+
+    if let Some(request) = &flow.request {
+        detail["request"] = json!({
+            "version": format!("{:?}", request.version),
+            "headers": headers(&request.headers),
+            "body": body(flow.request_view(), flow.request_truncated),
+        });
+    }
+    if let Some(response) = &flow.response {
+        detail["response"] = json!({
+            "version": format!("{:?}", response.version),
+            "headers": headers(&response.headers),
+            "body": body(flow.response_view(), flow.response_truncated),
+        });
+    }
+
+    if !flow.grpc_messages.is_empty() {
+        detail["grpc_messages"] = flow
+            .grpc_messages
+            .iter()
+            .map(|(direction, message)| {
+                json!({
+                    "direction": format!("{:?}", direction).to_lowercase(),
+                    "size": message.size,
+                    "compressed": message.compressed,
+                    "decoded": message.decoded,
+                })
+            })
+            .collect();
+    }
+
+    detail
+}
+
+fn headers(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+        .collect()
+}
+
+fn body(view: Option<BodyView>, truncated: bool) -> Value {
+    match view {
+        Some(view) => json!({
+            "view": view.kind.name(),
+            "text": view.text,
+            "truncated": truncated,
+        }),
+        None => Value::Null,
+    }
+}
+
+// Dashboard listing live flows, fed over a websocket.
+#[derive(Clone)]
+pub(crate) struct WebUi(FlowCapture);
+
+impl WebUi {
+    pub(crate) fn new(capture: FlowCapture) -> Self {
+        Self(capture)
+    }
+
+    pub(crate) async fn serve(self, addr: SocketAddr, context: Arc<Context>) -> Result<(), Error> {
+        let make_service = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+            let web = self.clone();
+            let context = context.clone();
+            let peer = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let web = web.clone();
+                    let context = context.clone();
+                    async move { Ok::<_, Infallible>(web.handle(req, peer, &context).await) }
+                }))
+            }
+        });
+
+        let server = hyper::Server::try_bind(&addr)
+            .map_err(Error::HttpRequestError)?
+            .serve(make_service);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(?e, "Web UI listener stopped");
+            }
+        });
+
+        Ok(())
+    }
+
+    fn overviews(&self) -> Vec<Value> {
+        self.0
+            .ids()
+            .into_iter()
+            .filter_map(|id| Some(overview(id, &self.0.get(id)?)))
+            .collect()
+    }
+
+    async fn handle(
+        &self,
+        req: Request<Body>,
+        peer: SocketAddr,
+        context: &Context,
+    ) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        // Pages served from elsewhere may open the feed or resend flows from a browser too.
+        let drives = matches!(
+            (req.method(), segments.as_slice()),
+            (&Method::GET, ["ws"]) | (&Method::POST, [..])
+        );
+        if drives && !Self::same_origin(req.headers()) {
+            warn!(%peer, origin = ?req.headers().get(ORIGIN), "Web UI request from another origin");
+            return Self::status(StatusCode::FORBIDDEN);
+        }
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, [""]) => Response::builder()
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(INDEX))
+                .unwrap(),
+            (&Method::GET, ["ws"]) => self.upgrade(req),
+            (&Method::GET, ["flows"]) => Self::json(StatusCode::OK, json!(self.overviews())),
+            (&Method::GET, ["flows", id]) => {
+                match id.parse().ok().and_then(|id| Some((id, self.0.get(id)?))) {
+                    Some((id, flow)) => Self::json(StatusCode::OK, detail(id, &flow)),
+                    None => Self::status(StatusCode::NOT_FOUND),
+                }
+            }
+            (&Method::POST, ["flows", id, "resend"]) => {
+                let flow = id.parse().ok().and_then(|id| self.0.get(id));
+                if flow.as_ref().is_some_and(|flow| flow.request_truncated) {
+                    return Self::status(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                let req = match flow.as_ref().and_then(CapturedFlow::to_request) {
+                    Some(req) => req,
+                    None => return Self::status(StatusCode::NOT_FOUND),
+                };
+
+                match Server::resend_from(req, peer, context).await {
+                    Ok(replayed) => Self::json(StatusCode::OK, json!({ "id": replayed.id })),
+                    Err(e) => Self::json(e.status_code(), json!({ "error": e.to_string() })),
+                }
+            }
+            _ => Self::status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    // Browsers send an Origin with every handshake and POST, other clients none at all.
+    fn same_origin(headers: &HeaderMap) -> bool {
+        let origin = match headers.get(ORIGIN) {
+            Some(origin) => origin,
+            None => return true,
+        };
+
+        let authority = origin
+            .to_str()
+            .ok()
+            .and_then(|origin| origin.split_once("://"))
+            .map(|(_, authority)| authority);
+        match (authority, headers.get(HOST)) {
+            (Some(authority), Some(host)) => {
+                authority.as_bytes().eq_ignore_ascii_case(host.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    fn upgrade(&self, req: Request<Body>) -> Response<Body> {
+        if !http_ext::is_websocket_upgrade(req.headers()) {
+            return Self::status(StatusCode::BAD_REQUEST);
+        }
+        // The only version there is, from RFC 6455 section 4.4.
+        if req
+            .headers()
+            .get(SEC_WEBSOCKET_VERSION)
+            .map(HeaderValue::as_bytes)
+            != Some(b"13")
+        {
+            return Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(SEC_WEBSOCKET_VERSION, "13")
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) => websocket::accept_key(key.as_bytes()),
+            None => return Self::status(StatusCode::BAD_REQUEST),
+        };
+
+        let web = self.clone();
+        tokio::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    if let Err(e) = web.stream(upgraded).await {
+                        warn!(?e, "Web UI client dropped");
+                    }
+                }
+                Err(e) => error!(?e, "Web UI upgrade failed"),
+            }
+        });
+
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn stream(&self, upgraded: Upgraded) -> Result<(), Error> {
+        let (mut reader, mut writer) = split(upgraded);
+
+        // Subscribe before the snapshot so no update falls in between.
+        let mut updates = self.0.subscribe();
+        for flow in self.overviews() {
+            websocket::write_text(&mut writer, &flow.to_string()).await?;
+        }
+
+        let send = async {
+            loop {
+                match updates.recv().await {
+                    Ok(id) => {
+                        if let Some(flow) = self.0.get(id) {
+                            let flow = overview(id, &flow).to_string();
+                            websocket::write_text(&mut writer, &flow).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        };
+
+        tokio::select! {
+            result = send => result,
+            result = websocket::wait_closed(&mut reader) => result,
+        }
+    }
+
+    fn json(status: StatusCode, body: Value) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn status(status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    }
+}
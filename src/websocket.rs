@@ -0,0 +1,428 @@
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{self, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, instrument};
+
+use crate::error::Error;
+use crate::flow::{Direction, FlowEvent, FlowRequest};
+use crate::intercept::MessageAction;
+use crate::server::Context;
+use crate::timeout::Activity;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+// The close code for a message too big to process, from RFC 6455 section 7.4.1.
+const CLOSE_TOO_BIG: u16 = 1009;
+
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASK: u8 = 0x80;
+
+// Larger messages are passed on as they arrive, unseen by interceptors. Nothing reads a bigger
+// frame into memory.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+struct Frame {
+    head: u8,
+    mask: Option<[u8; 4]>,
+    payload: Vec<u8>,
+}
+
+// What comes before the payload of a frame.
+struct FrameHead {
+    head: u8,
+    mask: Option<[u8; 4]>,
+    len: usize,
+}
+
+// The frames of a data message, held back until its last one arrives.
+struct Pending {
+    opcode: u8,
+    frames: Vec<Frame>,
+    size: usize,
+}
+
+impl Frame {
+    // A whole message in one frame, masked as clients have to.
+    fn message(message: &WebSocketMessage, masked: bool) -> Self {
+        let (opcode, payload) = match message {
+            WebSocketMessage::Text(text) => (OPCODE_TEXT, text.as_bytes().to_vec()),
+            WebSocketMessage::Binary(data) => (OPCODE_BINARY, data.clone()),
+        };
+
+        Self {
+            head: FIN | opcode,
+            mask: masked.then(random_mask),
+            payload,
+        }
+    }
+
+    fn close(code: u16, masked: bool) -> Self {
+        Self {
+            head: FIN | OPCODE_CLOSE,
+            mask: masked.then(random_mask),
+            payload: code.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let head = FrameHead {
+            head: self.head,
+            mask: self.mask,
+            len: self.payload.len(),
+        };
+        let mut buf = head.encode();
+        buf.reserve(self.payload.len());
+
+        let start = buf.len();
+        buf.extend_from_slice(&self.payload);
+        if let Some(mask) = self.mask {
+            apply_mask(&mut buf[start..], mask);
+        }
+
+        buf
+    }
+}
+
+impl FrameHead {
+    fn fin(&self) -> bool {
+        self.head & FIN != 0
+    }
+
+    fn compressed(&self) -> bool {
+        self.head & RSV1 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.head & 0x0F
+    }
+
+    async fn read<R>(reader: &mut R) -> Result<Option<Self>, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut head = [0u8; 2];
+        match reader.read_exact(&mut head).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::ReadStreamError(e)),
+        }
+
+        let len = match head[1] & 0x7F {
+            126 => reader.read_u16().await.map_err(Error::ReadStreamError)? as u64,
+            127 => reader.read_u64().await.map_err(Error::ReadStreamError)?,
+            len => len as u64,
+        };
+        let len = usize::try_from(len).map_err(|_| Error::PayloadTooLargeError)?;
+
+        let mask = if head[1] & MASK != 0 {
+            let mut mask = [0u8; 4];
+            reader
+                .read_exact(&mut mask)
+                .await
+                .map_err(Error::ReadStreamError)?;
+            Some(mask)
+        } else {
+            None
+        };
+
+        Ok(Some(Self {
+            head: head[0],
+            mask,
+            len,
+        }))
+    }
+
+    // The payload grows as it arrives, so a peer that stops short holds no more than it sent.
+    async fn read_payload<R>(self, reader: &mut R) -> Result<Frame, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if self.len > MAX_MESSAGE_SIZE {
+            return Err(Error::PayloadTooLargeError);
+        }
+
+        let mut payload = Vec::new();
+        reader
+            .take(self.len as u64)
+            .read_to_end(&mut payload)
+            .await
+            .map_err(Error::ReadStreamError)?;
+        if payload.len() < self.len {
+            return Err(Error::ReadStreamError(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        if let Some(mask) = self.mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Frame {
+            head: self.head,
+            mask: self.mask,
+            payload,
+        })
+    }
+
+    // Passes the frame on untouched, its payload a buffer at a time.
+    async fn forward<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        writer
+            .write_all(&self.encode())
+            .await
+            .map_err(Error::WriteStreamError)?;
+        let copied = io::copy(&mut reader.take(self.len as u64), writer)
+            .await
+            .map_err(Error::RelayError)?;
+        if copied < self.len as u64 {
+            return Err(Error::ReadStreamError(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        writer.flush().await.map_err(Error::WriteStreamError)
+    }
+
+    // The head with the masking key, if any.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(14);
+        buf.push(self.head);
+
+        let mask_bit = if self.mask.is_some() { MASK } else { 0 };
+        match self.len {
+            len if len < 126 => buf.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.push(mask_bit | 126);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                buf.push(mask_bit | 127);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        if let Some(mask) = self.mask {
+            buf.extend_from_slice(&mask);
+        }
+
+        buf
+    }
+}
+
+fn random_mask() -> [u8; 4] {
+    let mut mask = [0u8; 4];
+    let _ = SystemRandom::new().fill(&mut mask);
+    mask
+}
+
+pub(crate) fn accept_key(key: &[u8]) -> String {
+    let mut input = key.to_vec();
+    input.extend_from_slice(ACCEPT_GUID);
+
+    base64::encode(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &input))
+}
+
+pub(crate) async fn write_text<W>(writer: &mut W, text: &str) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let frame = Frame {
+        head: FIN | OPCODE_TEXT,
+        mask: None,
+        payload: text.as_bytes().to_vec(),
+    };
+
+    writer
+        .write_all(&frame.encode())
+        .await
+        .map_err(Error::WriteStreamError)?;
+    writer.flush().await.map_err(Error::WriteStreamError)
+}
+
+// Discards incoming frames until the peer closes the connection.
+pub(crate) async fn wait_closed<R>(reader: &mut R) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    while let Some(head) = FrameHead::read(reader).await? {
+        if head.opcode() == OPCODE_CLOSE {
+            break;
+        }
+        let skipped = io::copy(&mut reader.take(head.len as u64), &mut io::sink())
+            .await
+            .map_err(Error::ReadStreamError)?;
+        if skipped < head.len as u64 {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+#[instrument(skip_all, fields(id = flow.id, flow = %flow.ulid))]
+pub(crate) async fn relay<C, S>(
+    client: C,
+    server: S,
+    flow: &FlowRequest,
+    context: &Context,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::new();
+    let (mut client_read, mut client_write) = split(activity.watch(client));
+    let (mut server_read, mut server_write) = split(activity.watch(server));
+
+    let pumps = async {
+        tokio::try_join!(
+            pump(
+                &mut client_read,
+                &mut server_write,
+                Direction::Upstream,
+                flow,
+                context
+            ),
+            pump(
+                &mut server_read,
+                &mut client_write,
+                Direction::Downstream,
+                flow,
+                context
+            ),
+        )
+    };
+    let result = activity.until_idle(context.timeouts.idle, pumps).await?;
+
+    if let Err(Error::PayloadTooLargeError) = result {
+        // Both ends learn why the connection goes, the one that sent the frame included.
+        let _ = write_frames(&mut client_write, &[Frame::close(CLOSE_TOO_BIG, false)]).await;
+        let _ = write_frames(&mut server_write, &[Frame::close(CLOSE_TOO_BIG, true)]).await;
+    }
+
+    result.map(|_| ())
+}
+
+async fn pump<R, W>(
+    from: &mut R,
+    to: &mut W,
+    direction: Direction,
+    flow: &FlowRequest,
+    context: &Context,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut pending: Option<Pending> = None;
+
+    loop {
+        let head = match FrameHead::read(from).await? {
+            Some(head) => head,
+            None => {
+                let _ = to.shutdown().await;
+                return Ok(());
+            }
+        };
+
+        // Control frames may come between the fragments of a message, and go on right away,
+        // as do compressed messages, which interceptors could not read.
+        match head.opcode() {
+            OPCODE_TEXT | OPCODE_BINARY if !head.compressed() => {
+                pending = Some(Pending {
+                    opcode: head.opcode(),
+                    frames: Vec::new(),
+                    size: 0,
+                });
+            }
+            OPCODE_CONTINUATION if pending.is_some() => {}
+            _ => {
+                head.forward(from, to).await?;
+                continue;
+            }
+        }
+
+        let message = pending.as_mut().unwrap();
+        if message.size + head.len > MAX_MESSAGE_SIZE {
+            let message = pending.take().unwrap();
+            write_frames(to, &message.frames).await?;
+            head.forward(from, to).await?;
+            continue;
+        }
+
+        let fin = head.fin();
+        let frame = head.read_payload(from).await?;
+        message.size += frame.payload.len();
+        message.frames.push(frame);
+        if !fin {
+            continue;
+        }
+
+        let Pending { opcode, frames, .. } = pending.take().unwrap();
+        let payload = frames
+            .iter()
+            .flat_map(|frame| frame.payload.iter().copied())
+            .collect::<Vec<_>>();
+        let original = match opcode {
+            OPCODE_TEXT => match String::from_utf8(payload) {
+                Ok(text) => WebSocketMessage::Text(text),
+                Err(e) => WebSocketMessage::Binary(e.into_bytes()),
+            },
+            _ => WebSocketMessage::Binary(payload),
+        };
+
+        let message = match context
+            .interceptors
+            .on_websocket_message(flow, direction, original.clone())
+            .await
+        {
+            MessageAction::Forward(message) => message,
+            MessageAction::Drop => {
+                debug!(?direction, "WebSocket message dropped");
+                continue;
+            }
+        };
+        if message == original {
+            // Passed on with the framing it came with.
+            write_frames(to, &frames).await?;
+        } else {
+            let masked = frames[0].mask.is_some();
+            write_frames(to, &[Frame::message(&message, masked)]).await?;
+        }
+
+        context.flows.emit(FlowEvent::WebSocketMessage {
+            id: flow.id,
+            direction,
+            message,
+        });
+    }
+}
+
+async fn write_frames<W>(to: &mut W, frames: &[Frame]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    for frame in frames {
+        to.write_all(&frame.encode())
+            .await
+            .map_err(Error::WriteStreamError)?;
+    }
+    to.flush().await.map_err(Error::WriteStreamError)
+}
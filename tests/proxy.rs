@@ -0,0 +1,514 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::header::{
+    CONNECTION, CONTENT_LENGTH, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, TRANSFER_ENCODING,
+    UPGRADE,
+};
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::Body;
+use ring::digest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use yaler::{
+    Direction, FakeUpstream, FlowRequest, Interceptor, MessageAction, Overrides, RecordedFlow,
+    RequestAction, Server, TestProxy, VcrConfig, VcrMode, WebSocketMessage,
+};
+
+fn upstream() -> FakeUpstream {
+    FakeUpstream::new().unwrap().host("origin.test", |req| {
+        let mut body = format!("{} {}", req.method(), req.uri().path()).into_bytes();
+        body.extend_from_slice(req.body());
+        Response::new(Body::from(body))
+    })
+}
+
+async fn body(res: Response<Body>) -> String {
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+// Up to the blank line that ends a head.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    String::from_utf8(head).unwrap()
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> (u8, Vec<u8>) {
+    let head = stream.read_u8().await.unwrap();
+    let len = stream.read_u8().await.unwrap();
+    assert!(len & 0x7F < 126, "test frames are short");
+    let mask = match len & 0x80 {
+        0 => None,
+        _ => {
+            let mut mask = [0u8; 4];
+            stream.read_exact(&mut mask).await.unwrap();
+            Some(mask)
+        }
+    };
+
+    let mut payload = vec![0u8; (len & 0x7F) as usize];
+    stream.read_exact(&mut payload).await.unwrap();
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    (head & 0x0F, payload)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    opcode: u8,
+    payload: &[u8],
+    masked: bool,
+) {
+    let mut frame = vec![0x80 | opcode];
+    if masked {
+        let mask = [1, 2, 3, 4];
+        frame.push(0x80 | payload.len() as u8);
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+    } else {
+        frame.push(payload.len() as u8);
+        frame.extend_from_slice(payload);
+    }
+    stream.write_all(&frame).await.unwrap();
+    stream.flush().await.unwrap();
+}
+
+// Echoes the first message of a WebSocket, then closes it.
+fn websocket_echo(mut req: Request<Bytes>) -> Response<Body> {
+    let mut key = req.headers()[SEC_WEBSOCKET_KEY].as_bytes().to_vec();
+    key.extend_from_slice(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let accept = base64::encode(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &key));
+
+    let upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let mut stream = upgrade.await.unwrap();
+        let (opcode, payload) = read_frame(&mut stream).await;
+        write_frame(&mut stream, opcode, &payload, false).await;
+        write_frame(&mut stream, 0x8, &1000u16.to_be_bytes(), false).await;
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// Shouts text messages on their way upstream.
+struct Shout;
+
+#[async_trait]
+impl Interceptor for Shout {
+    async fn on_websocket_message(
+        &self,
+        _flow: &FlowRequest,
+        direction: Direction,
+        message: WebSocketMessage,
+    ) -> MessageAction {
+        match message {
+            WebSocketMessage::Text(text) if direction == Direction::Upstream => {
+                MessageAction::Forward(WebSocketMessage::Text(text.to_uppercase()))
+            }
+            message => MessageAction::Forward(message),
+        }
+    }
+}
+
+// Answers requests to /forbidden itself, before their body is read.
+struct Forbid;
+
+#[async_trait]
+impl Interceptor for Forbid {
+    async fn on_request(&self, _flow: &FlowRequest, req: Request<Body>) -> RequestAction {
+        if req.uri().path() != "/forbidden" {
+            return RequestAction::Forward(req);
+        }
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        RequestAction::Respond(response)
+    }
+}
+
+// A cassette of the test's own, gone once it is done with.
+struct Cassette(PathBuf);
+
+impl Cassette {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("yaler-{}-{}.har", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    fn config(&self, mode: VcrMode) -> VcrConfig {
+        VcrConfig {
+            path: self.0.clone(),
+            mode,
+            match_headers: Vec::new(),
+            ignore_body: false,
+        }
+    }
+}
+
+impl Drop for Cassette {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn plain_http() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let req = Request::get("http://origin.test/plain")
+        .body(Body::empty())
+        .unwrap();
+    let res = proxy.send(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body(res).await, "GET /plain");
+
+    let received = upstream.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].host, "origin.test");
+    assert_eq!(received[0].uri, "/plain");
+
+    let flow = proxy
+        .flow(Method::GET, "http://origin.test/plain")
+        .await
+        .unwrap();
+    assert_eq!(flow.response.unwrap().status, StatusCode::OK);
+    assert_eq!(flow.response_body.unwrap(), "GET /plain");
+}
+
+#[tokio::test]
+async fn connect_tunnel() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let stream = proxy.tunnel("origin.test:80").await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+    let req = Request::post("/tunneled")
+        .header(HOST, "origin.test")
+        .body(Body::from("hello"))
+        .unwrap();
+    let res = sender.send_request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body(res).await, "POST /tunneledhello");
+
+    let received = upstream.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].method, Method::POST);
+    assert_eq!(received[0].body, "hello");
+
+    let flows = proxy.flows(1).await.unwrap();
+    let request = flows[0].request.as_ref().unwrap();
+    assert_eq!(request.uri.scheme_str(), Some("http"));
+    assert_eq!(request.uri.host(), Some("origin.test"));
+    assert_eq!(request.uri.path(), "/tunneled");
+    assert_eq!(flows[0].request_body.as_ref().unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn mitm_https() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let req = Request::put("https://origin.test/secure")
+        .body(Body::from("secret"))
+        .unwrap();
+    let res = proxy.send(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body(res).await, "PUT /securesecret");
+
+    let received = upstream.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].uri, "/secure");
+    assert_eq!(received[0].body, "secret");
+
+    let flow = proxy
+        .flow(Method::PUT, "https://origin.test/secure")
+        .await
+        .unwrap();
+    assert_eq!(flow.request_body.unwrap(), "secret");
+    assert_eq!(flow.response_body.unwrap(), "PUT /securesecret");
+    assert!(flow.error.is_none());
+}
+
+#[tokio::test]
+async fn large_bodies() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    // Several times what the pipes and buffers on the way hold.
+    let sent = "0123456789abcdef".repeat(64 * 1024);
+    let req = Request::post("http://origin.test/upload")
+        .body(Body::from(sent.clone()))
+        .unwrap();
+    let res = proxy.send(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body(res).await, format!("POST /upload{}", sent));
+
+    let received = upstream.received();
+    assert_eq!(received[0].body, sent);
+    assert_eq!(
+        received[0].headers[CONTENT_LENGTH],
+        sent.len().to_string().as_str()
+    );
+}
+
+#[tokio::test]
+async fn keep_alive() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let (mut sender, connection) = hyper::client::conn::handshake(proxy.connect())
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    for path in ["/first", "/second", "/third"] {
+        let req = Request::get(format!("http://origin.test{}", path))
+            .header(HOST, "origin.test")
+            .body(Body::empty())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, format!("GET {}", path));
+    }
+
+    let received = upstream.received();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[2].uri, "/third");
+    assert_eq!(proxy.flows(3).await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn expect_continue() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder().interceptor(Forbid), &upstream)
+        .await
+        .unwrap();
+
+    let mut stream = proxy.connect();
+    stream
+        .write_all(
+            b"POST http://origin.test/upload HTTP/1.1\r\nHost: origin.test\r\n\
+              Content-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    assert!(read_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 100 Continue\r\n"));
+    stream.write_all(b"hello").await.unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    let mut body = vec![0u8; "POST /uploadhello".len()];
+    stream.read_exact(&mut body).await.unwrap();
+    assert_eq!(body, b"POST /uploadhello");
+
+    // Answered before the body is wanted, the client never gets to send it.
+    let mut stream = proxy.connect();
+    stream
+        .write_all(
+            b"POST http://origin.test/forbidden HTTP/1.1\r\nHost: origin.test\r\n\
+              Content-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", head);
+
+    assert_eq!(upstream.received().len(), 1);
+}
+
+#[tokio::test]
+async fn chunked_bodies() {
+    let upstream = FakeUpstream::new().unwrap().host("origin.test", |req| {
+        let (mut sender, body) = Body::channel();
+        let echoed = req.into_body();
+        tokio::spawn(async move {
+            for chunk in echoed.chunks(4) {
+                sender
+                    .send_data(Bytes::copy_from_slice(chunk))
+                    .await
+                    .unwrap();
+            }
+        });
+        Response::new(body)
+    });
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let mut stream = proxy.connect();
+    stream
+        .write_all(
+            b"POST http://origin.test/chunks HTTP/1.1\r\nHost: origin.test\r\n\
+              Transfer-Encoding: chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 1\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await.to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 200 ok\r\n"), "{}", head);
+    assert!(head.contains("transfer-encoding: chunked\r\n"), "{}", head);
+
+    let mut body = Vec::new();
+    while !body.ends_with(b"0\r\n\r\n") {
+        body.push(stream.read_u8().await.unwrap());
+    }
+    let body = String::from_utf8(body).unwrap();
+    let dechunked: String = body.split("\r\n").skip(1).step_by(2).collect();
+    assert_eq!(dechunked, "hello world");
+
+    let received = upstream.received();
+    assert_eq!(received[0].body, "hello world");
+    assert!(!received[0].headers.contains_key(CONTENT_LENGTH));
+    assert!(received[0].headers.contains_key(TRANSFER_ENCODING));
+}
+
+#[tokio::test]
+async fn websocket_messages() {
+    let upstream = FakeUpstream::new()
+        .unwrap()
+        .host("origin.test", websocket_echo);
+    let proxy = TestProxy::new(Server::builder().interceptor(Shout), &upstream)
+        .await
+        .unwrap();
+
+    let mut stream = proxy.connect();
+    stream
+        .write_all(
+            b"GET http://origin.test/socket HTTP/1.1\r\nHost: origin.test\r\n\
+              Connection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await.to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101 "), "{}", head);
+    assert!(
+        head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=\r\n"),
+        "{}",
+        head
+    );
+
+    write_frame(&mut stream, 0x1, b"hello", true).await;
+    assert_eq!(read_frame(&mut stream).await, (0x1, b"HELLO".to_vec()));
+    assert_eq!(read_frame(&mut stream).await.0, 0x8);
+    drop(stream);
+
+    let flow = proxy
+        .flow(Method::GET, "http://origin.test/socket")
+        .await
+        .unwrap();
+    assert_eq!(
+        flow.response.unwrap().status,
+        StatusCode::SWITCHING_PROTOCOLS
+    );
+    assert_eq!(flow.messages, 2);
+}
+
+#[tokio::test]
+async fn replay() {
+    let upstream = upstream();
+    let proxy = TestProxy::new(Server::builder(), &upstream).await.unwrap();
+
+    let req = Request::post("http://origin.test/original")
+        .body(Body::from("first"))
+        .unwrap();
+    proxy.send(req).await.unwrap();
+    let flow = proxy.flows(1).await.unwrap().remove(0);
+
+    let recorded = RecordedFlow {
+        method: Method::POST,
+        uri: "http://origin.test/original".parse().unwrap(),
+        headers: flow.request.unwrap().headers,
+        body: Bytes::from("first"),
+        truncated: false,
+        started: None,
+        response: None,
+    };
+    let replayed = proxy
+        .server()
+        .replay(&recorded, &Overrides::new().body(Bytes::from("second")))
+        .await
+        .unwrap();
+    assert_eq!(replayed.response.status, StatusCode::OK);
+    assert_eq!(replayed.response.body.unwrap(), "POST /originalsecond");
+
+    let received = upstream.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[1].body, "second");
+    assert_eq!(received[1].headers[CONTENT_LENGTH], "6");
+
+    // Only the start of a body would make a different request.
+    let truncated = RecordedFlow {
+        truncated: true,
+        ..recorded
+    };
+    assert!(proxy
+        .server()
+        .replay(&truncated, &Overrides::new())
+        .await
+        .is_err());
+    assert_eq!(upstream.received().len(), 2);
+}
+
+#[tokio::test]
+async fn vcr() {
+    let cassette = Cassette::new("vcr");
+
+    let upstream = upstream();
+    let recording = Server::builder().vcr(Some(cassette.config(VcrMode::Record)));
+    let proxy = TestProxy::new(recording, &upstream).await.unwrap();
+    let req = Request::post("http://origin.test/recorded")
+        .body(Body::from("tape"))
+        .unwrap();
+    assert_eq!(
+        body(proxy.send(req).await.unwrap()).await,
+        "POST /recordedtape"
+    );
+    proxy.flows(1).await.unwrap();
+    // Entries are written out as they complete, a moment after the flow does.
+    for _ in 0..50 {
+        if RecordedFlow::from_har(&cassette.0).is_ok_and(|flows| !flows.is_empty()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Nothing answers on the way back, but the cassette.
+    let offline = FakeUpstream::new().unwrap();
+    let replaying = Server::builder().vcr(Some(cassette.config(VcrMode::Replay)));
+    let proxy = TestProxy::new(replaying, &offline).await.unwrap();
+    let req = Request::post("http://origin.test/recorded")
+        .body(Body::from("tape"))
+        .unwrap();
+    let res = proxy.send(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body(res).await, "POST /recordedtape");
+
+    let req = Request::post("http://origin.test/recorded")
+        .body(Body::from("other"))
+        .unwrap();
+    assert_ne!(proxy.send(req).await.unwrap().status(), StatusCode::OK);
+    assert!(offline.received().is_empty());
+}